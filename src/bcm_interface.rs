@@ -2,6 +2,7 @@ extern crate dlopen;
 use dlopen::wrapper::{Container, WrapperApi};
 use std::time::Instant;
 use std::ffi::CString;
+use std::io;
 use std::os::raw::c_char;
 use x11cap::Image;
 extern crate custom_error;
@@ -24,30 +25,68 @@ bcm2835_delay: unsafe extern "C" fn(millis: u16),
 bcm2835_spi_end: unsafe extern "C" fn(),
 bcm2835_close: unsafe extern "C" fn() -> u8,
 bcm2835_spi_transfer: unsafe extern "C" fn(value: u8) ->u8 ,
+bcm2835_spi_writenb: unsafe extern "C" fn(buf: *const c_char, len: u32),
 }
 
 
+use super::transport::Transport;
+
+// copied from bcm2835.h
+static BCM2835_SPI_BIT_ORDER_MSBFIRST: u8 = 1;
+static BCM2835_SPI_MODE0: u8 = 0;
+static BCM2835_SPI_CLOCK_DIVIDER_32: u16 = 32;
+static BCM2835_CORE_CLK_HZ: u32 = 250_000_000;
+static BCM2835_GPIO_FSEL_OUTP: u8 = 0x01;
+static BCM2835_GPIO_FSEL_INPT: u8 = 0x00;
+static HIGH: u8 = 0x1;
+static LOW: u8 = 0x0;
+
 custom_error!{pub BCMError 
-    InitFailed = "BCM init failed."
+    InitFailed = "BCM init failed.",
+    LoadFailed{reason: String} = "cannot load libbcm2835: {reason}"
 }
 pub struct BCM {
     api: Container<BCMApi>,
+    cs: u8,
+    hrdy: u8,
+    reset: u8,
 }
 
 impl BCM {
-    pub fn new() -> BCM {
-        BCM {
-            api: BCM::init(),
-        }
+    pub fn new(cs: u8, hrdy: u8, reset: u8) -> Result<BCM, BCMError> {
+        Ok(BCM {
+            api: BCM::init()?,
+            cs: cs,
+            hrdy: hrdy,
+            reset: reset,
+        })
     }
 
-    fn init() -> Container<BCMApi> {
+    fn init() -> Result<Container<BCMApi>, BCMError> {
         let bcm: Container<BCMApi> = unsafe { Container::load("/usr/local/lib/libbcm2835.so") }
-            .expect("Could not open library or load symbols");
+            .map_err(|error| BCMError::LoadFailed {
+                reason: error.to_string(),
+            })?;
         unsafe {
             bcm.bcm2835_init();
         }
-        bcm
+        Ok(bcm)
+    }
+
+    /// Load libbcm2835 and set the SPI bus and the pins up for the IT8951
+    pub fn open(cs: u8, hrdy: u8, reset: u8) -> Result<BCM, BCMError> {
+        let bcm = BCM::new(cs, hrdy, reset)?;
+        bcm.bcm2835_init()?;
+
+        bcm.bcm2835_spi_begin();
+        bcm.bcm2835_spi_setBitOrder(BCM2835_SPI_BIT_ORDER_MSBFIRST); //default
+        bcm.bcm2835_spi_setDataMode(BCM2835_SPI_MODE0); //default
+        bcm.bcm2835_spi_setClockDivider(BCM2835_SPI_CLOCK_DIVIDER_32); //default
+
+        bcm.bcm2835_gpio_fsel(cs, BCM2835_GPIO_FSEL_OUTP);
+        bcm.bcm2835_gpio_fsel(hrdy, BCM2835_GPIO_FSEL_INPT);
+        bcm.bcm2835_gpio_fsel(reset, BCM2835_GPIO_FSEL_OUTP);
+        Ok(bcm)
     }
 
     pub fn bcm2835_init(&self) -> Result<u8, BCMError>{
@@ -120,6 +159,12 @@ impl BCM {
         }
 
     }
+    pub fn bcm2835_spi_writenb(&self, bytes: &[u8]) {
+        unsafe {
+            self.api.bcm2835_spi_writenb(bytes.as_ptr() as *const c_char, bytes.len() as u32);
+        }
+    }
+
     pub fn bcm2835_gpio_lev(&self, pin: u8) -> u8{
         
         
@@ -131,4 +176,65 @@ impl BCM {
 
         
 
-}
\ No newline at end of file
+}
+
+impl Drop for BCM {
+    fn drop(&mut self) {
+        self.bcm2835_spi_end();
+        self.bcm2835_close();
+    }
+}
+
+impl Transport for BCM {
+    fn spi_transfer(&self, value: u8) -> io::Result<u8> {
+        Ok(self.bcm2835_spi_transfer(value))
+    }
+
+    fn spi_write(&self, bytes: &[u8]) -> io::Result<()> {
+        self.bcm2835_spi_writenb(bytes);
+        Ok(())
+    }
+
+    fn set_speed(&self, speed_hz: u32) {
+        self.bcm2835_spi_setClockDivider(clock_divider(speed_hz));
+    }
+
+    // libbcm2835 writes the registers, nothing to fail once they are mapped
+    fn set_chip_select(&self, selected: bool) -> io::Result<()> {
+        // active low
+        self.bcm2835_gpio_write(self.cs, if selected { LOW } else { HIGH });
+        Ok(())
+    }
+
+    fn host_ready(&self) -> io::Result<bool> {
+        Ok(self.bcm2835_gpio_lev(self.hrdy) != 0)
+    }
+
+    fn set_reset(&self, high: bool) -> io::Result<()> {
+        self.bcm2835_gpio_write(self.reset, if high { HIGH } else { LOW });
+        Ok(())
+    }
+
+    fn delay(&self, millis: u16) {
+        self.bcm2835_delay(millis);
+    }
+}
+
+// smallest even divider of the core clock not going over speed_hz, 0 stands for 65536
+fn clock_divider(speed_hz: u32) -> u16 {
+    let divider: u32 = BCM2835_CORE_CLK_HZ.div_ceil(speed_hz.max(1));
+    let divider: u32 = (divider + 1) & !1;
+    if divider >= 65536 {
+        0
+    } else {
+        divider.max(2) as u16
+    }
+}
+
+#[test]
+fn test_clock_divider() {
+    assert_eq!(clock_divider(7_812_500), BCM2835_SPI_CLOCK_DIVIDER_32);
+    assert_eq!(clock_divider(20_000_000), 14);
+    assert_eq!(clock_divider(500_000_000), 2);
+    assert_eq!(clock_divider(1_000), 0);
+}
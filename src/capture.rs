@@ -0,0 +1,1166 @@
+use dlopen::wrapper::{Container, WrapperApi};
+use libc::{c_int, pollfd, POLLIN};
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::{c_char, c_long, c_uchar, c_uint, c_ulong, c_void};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use std::{ptr, slice};
+use x11::{xlib, xrandr};
+use x11cap::Bgr8;
+
+extern crate custom_error;
+use custom_error::custom_error;
+
+use crate::cursor::{CursorImage, CursorOverlay, CursorStyle, XCursor};
+use crate::imagery::{new_bgr8, Rect};
+use crate::vnc;
+
+// how long capture() waits for damage before telling nothing changed, in milliseconds
+pub static DAMAGE_WAIT: c_int = 1000;
+// while the cursor is drawn, how often its position is queried during that wait, in
+// milliseconds : moving it damages nothing, its shape changes come as events
+static CURSOR_WAIT: c_int = 50;
+// past that many damaged rectangles, their bounding box is grabbed instead
+static MAX_DAMAGE_RECTS: usize = 64;
+// XDamageReportNonEmpty : one event each time the damage stops being empty
+static DAMAGE_REPORT_NON_EMPTY: c_int = 3;
+
+// set by the Xlib I/O error handler, the connection cannot be used anymore
+static X_CONNECTION_LOST: AtomicBool = AtomicBool::new(false);
+
+custom_error! {pub CaptureError
+    Open{reason: String} = "cannot open the capture: {reason}",
+    Grab{reason: String} = "capture failed: {reason}",
+    NoDamage = "the X server has no DAMAGE extension",
+    ConnectionLost = "lost the connection to the X server",
+    Ended{status: String} = "the program of the terminal ended: {status}"
+}
+
+// longest _NET_WM_NAME read, in 32 bits units
+static MAX_TITLE_LENGTH: c_long = 256;
+
+/// Where the frames come from, as given to --source
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// The X server of $DISPLAY
+    X11,
+    /// An output of the wlroots compositor of $WAYLAND_DISPLAY, by name or
+    /// number, the first one when None
+    Wayland(Option<String>),
+    /// A Linux framebuffer device, e.g. /dev/fb0
+    Framebuffer(String),
+    /// What a DRM card scans out, e.g. /dev/dri/card0
+    Drm(String),
+    /// The desktop of a VNC server, host and port
+    Vnc(String, u16),
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Source, String> {
+        let (kind, path) = match source.find(':') {
+            Some(colon) => (&source[..colon], Some(&source[colon + 1..])),
+            None => (source, None),
+        };
+        match (kind, path) {
+            ("x11", None) => Ok(Source::X11),
+            ("wayland", None) => Ok(Source::Wayland(None)),
+            ("wayland", Some(output)) => Ok(Source::Wayland(Some(output.to_string()))),
+            ("fb", None) => Ok(Source::Framebuffer("/dev/fb0".to_string())),
+            ("fb", Some(path)) => Ok(Source::Framebuffer(path.to_string())),
+            ("drm", None) => Ok(Source::Drm("/dev/dri/card0".to_string())),
+            ("drm", Some(path)) => Ok(Source::Drm(path.to_string())),
+            ("vnc", Some(address)) => {
+                let (host, port) = vnc::parse_address(address)?;
+                Ok(Source::Vnc(host, port))
+            }
+            _ => Err(format!("unknown source {}", source)),
+        }
+    }
+}
+
+/// A window to capture
+#[derive(Debug, Clone, PartialEq)]
+pub enum WindowSpec {
+    /// As printed by xwininfo
+    Id(u64),
+    /// Part of the title, or the whole class, e.g. FocusWriter
+    Name(String),
+}
+
+impl fmt::Display for WindowSpec {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WindowSpec::Id(id) => write!(formatter, "window 0x{:x}", id),
+            WindowSpec::Name(name) => write!(formatter, "window \"{}\"", name),
+        }
+    }
+}
+
+/// What part of the X screen is captured
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaptureTarget {
+    /// A whole monitor, by number
+    Monitor(usize),
+    /// A fixed rectangle of the X screen
+    Region(Rect),
+    /// A window, followed as it moves and resizes
+    Window(WindowSpec),
+}
+
+/// Where the frames shown on the panel come from
+pub trait CaptureSource {
+    /// Width and height of the frames, in pixels. It changes when the
+    /// resolution does; the next capture then returns None.
+    fn geometry(&self) -> (u16, u16);
+
+    /// Grab a new frame. Returns the rectangles that may have changed since the
+    /// previous frame, or None when anything may have changed.
+    fn capture(&mut self) -> Result<Option<Vec<Rect>>, CaptureError>;
+
+    /// Last grabbed frame, line after line
+    fn frame(&self) -> &[Bgr8];
+}
+
+/// Doubling delay between attempts to reach the X server
+pub struct Backoff {
+    delay: Duration,
+    max_delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(first_delay: Duration, max_delay: Duration) -> Backoff {
+        Backoff {
+            delay: first_delay,
+            max_delay,
+        }
+    }
+
+    /// Delay to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(self.max_delay);
+        delay
+    }
+}
+
+#[derive(WrapperApi)]
+struct XlibExitApi {
+    // libX11 1.7 and later
+    #[dlopen_name = "XSetIOErrorExitHandler"]
+    set_io_error_exit_handler: unsafe extern "C" fn(
+        display: *mut xlib::Display,
+        handler: unsafe extern "C" fn(*mut xlib::Display, *mut c_void),
+        user_data: *mut c_void,
+    ),
+}
+
+// a failed request, e.g. XGetImage outside of a screen that just shrank :
+// the request returns nothing instead of Xlib exiting the process
+unsafe extern "C" fn on_x_error(
+    _display: *mut xlib::Display,
+    _event: *mut xlib::XErrorEvent,
+) -> c_int {
+    0
+}
+
+unsafe extern "C" fn on_x_io_error(_display: *mut xlib::Display) -> c_int {
+    X_CONNECTION_LOST.store(true, Ordering::SeqCst);
+    0
+}
+
+// return instead of exiting, the capture is opened again once the X server is back
+unsafe extern "C" fn on_x_io_error_exit(_display: *mut xlib::Display, _user_data: *mut c_void) {}
+
+/// Connection to the X server and position of the captured part of the X screen
+struct XScreen {
+    display: *mut xlib::Display,
+    root: xlib::Window,
+    target: CaptureTarget,
+    // the captured window once found
+    window: Option<xlib::Window>,
+    x: i32,
+    y: i32,
+    width: u16,
+    height: u16,
+    // None when the cursor is not drawn
+    cursor: Option<(XCursor, CursorOverlay)>,
+}
+
+impl XScreen {
+    fn open(target: &CaptureTarget, cursor: CursorStyle) -> Result<XScreen, CaptureError> {
+        let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            return Err(CaptureError::Open {
+                reason: "cannot connect to the X server".to_string(),
+            });
+        }
+        X_CONNECTION_LOST.store(false, Ordering::SeqCst);
+        unsafe {
+            xlib::XSetErrorHandler(Some(on_x_error));
+            xlib::XSetIOErrorHandler(Some(on_x_io_error));
+            // with an older libX11, losing the server still exits the process
+            if let Ok(exit_api) = Container::<XlibExitApi>::load("libX11.so.6") {
+                exit_api.set_io_error_exit_handler(display, on_x_io_error_exit, ptr::null_mut());
+            }
+        }
+
+        let mut screen = XScreen {
+            display,
+            root: unsafe { xlib::XDefaultRootWindow(display) },
+            target: target.clone(),
+            window: None,
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+            cursor: None,
+        };
+        if cursor != CursorStyle::None {
+            match XCursor::open(display) {
+                Ok(x_cursor) => screen.cursor = Some((x_cursor, CursorOverlay::new(cursor))),
+                Err(error) => println!("{}, the cursor is not drawn", error),
+            }
+        }
+        screen.update_geometry()?;
+        Ok(screen)
+    }
+
+    fn connection_lost(&self) -> bool {
+        X_CONNECTION_LOST.load(Ordering::SeqCst)
+    }
+
+    /// Read the position and size of the captured part again, true when they changed
+    fn update_geometry(&mut self) -> Result<bool, CaptureError> {
+        let geometry = match self.target.clone() {
+            CaptureTarget::Monitor(number) => self.monitor_geometry(number)?,
+            CaptureTarget::Region(region) => {
+                let rect = clip_to_monitor(
+                    region.x as i32,
+                    region.y as i32,
+                    region.width as u32,
+                    region.height as u32,
+                    self.screen_geometry()?,
+                )
+                .ok_or(CaptureError::Open {
+                    reason: "the region is off the screen".to_string(),
+                })?;
+                (rect.x as i32, rect.y as i32, rect.width, rect.height)
+            }
+            CaptureTarget::Window(window) => self.window_geometry(&window)?,
+        };
+
+        if geometry == (self.x, self.y, self.width, self.height) {
+            return Ok(false);
+        }
+        // drawn on the frame that is replaced
+        if let Some((_, overlay)) = &mut self.cursor {
+            overlay.forget();
+        }
+        self.x = geometry.0;
+        self.y = geometry.1;
+        self.width = geometry.2;
+        self.height = geometry.3;
+        Ok(true)
+    }
+
+    fn monitor_geometry(&self, number: usize) -> Result<(i32, i32, u16, u16), CaptureError> {
+        let mut count: c_int = 0;
+        let monitors = unsafe { xrandr::XRRGetMonitors(self.display, self.root, 1, &mut count) };
+        if self.connection_lost() {
+            return Err(CaptureError::ConnectionLost);
+        }
+        if monitors.is_null() {
+            return Err(CaptureError::Open {
+                reason: "no monitor".to_string(),
+            });
+        }
+        let geometry = unsafe { slice::from_raw_parts(monitors, count as usize) }
+            .get(number)
+            .map(|monitor| {
+                (
+                    monitor.x,
+                    monitor.y,
+                    monitor.width as u16,
+                    monitor.height as u16,
+                )
+            });
+        unsafe {
+            xrandr::XRRFreeMonitors(monitors);
+        }
+
+        geometry.ok_or(CaptureError::Open {
+            reason: format!("no monitor {}", number),
+        })
+    }
+
+    // the whole X screen, in the shape clip_to_monitor wants
+    fn screen_geometry(&self) -> Result<(i32, i32, u16, u16), CaptureError> {
+        let mut attributes: xlib::XWindowAttributes = unsafe { std::mem::zeroed() };
+        let status =
+            unsafe { xlib::XGetWindowAttributes(self.display, self.root, &mut attributes) };
+        if self.connection_lost() {
+            return Err(CaptureError::ConnectionLost);
+        }
+        if status == 0 {
+            return Err(CaptureError::Open {
+                reason: "no screen size".to_string(),
+            });
+        }
+        Ok((0, 0, attributes.width as u16, attributes.height as u16))
+    }
+
+    // inside of the window on the X screen, without its decorations
+    fn window_geometry(&mut self, spec: &WindowSpec) -> Result<(i32, i32, u16, u16), CaptureError> {
+        let window = match self.window {
+            Some(window) => window,
+            None => self.find_window(spec)?,
+        };
+        let mut attributes: xlib::XWindowAttributes = unsafe { std::mem::zeroed() };
+        let status = unsafe { xlib::XGetWindowAttributes(self.display, window, &mut attributes) };
+        if self.connection_lost() {
+            return Err(CaptureError::ConnectionLost);
+        }
+        if status == 0 || attributes.map_state != xlib::IsViewable {
+            // closed or minimised, looked up again next time
+            self.window = None;
+            return Err(CaptureError::Open {
+                reason: format!("{} is not shown", spec),
+            });
+        }
+        self.window = Some(window);
+
+        let (mut x, mut y) = (0, 0);
+        let mut child: xlib::Window = 0;
+        unsafe {
+            xlib::XTranslateCoordinates(
+                self.display,
+                window,
+                self.root,
+                0,
+                0,
+                &mut x,
+                &mut y,
+                &mut child,
+            );
+        }
+        let rect = clip_to_monitor(
+            x,
+            y,
+            attributes.width as u32,
+            attributes.height as u32,
+            self.screen_geometry()?,
+        )
+        .ok_or(CaptureError::Open {
+            reason: format!("{} is off the screen", spec),
+        })?;
+        Ok((rect.x as i32, rect.y as i32, rect.width, rect.height))
+    }
+
+    fn find_window(&self, spec: &WindowSpec) -> Result<xlib::Window, CaptureError> {
+        let found = match spec {
+            WindowSpec::Id(id) => Some(*id as xlib::Window),
+            WindowSpec::Name(name) => self.find_window_named(self.root, name),
+        };
+        found.ok_or(CaptureError::Open {
+            reason: format!("no {}", spec),
+        })
+    }
+
+    // depth first below `parent`, the windows on top first
+    fn find_window_named(&self, parent: xlib::Window, name: &str) -> Option<xlib::Window> {
+        let (mut root, mut parent_of_parent): (xlib::Window, xlib::Window) = (0, 0);
+        let mut children: *mut xlib::Window = ptr::null_mut();
+        let mut count: c_uint = 0;
+        let status = unsafe {
+            xlib::XQueryTree(
+                self.display,
+                parent,
+                &mut root,
+                &mut parent_of_parent,
+                &mut children,
+                &mut count,
+            )
+        };
+        if status == 0 || children.is_null() {
+            return None;
+        }
+        let windows: Vec<xlib::Window> =
+            unsafe { slice::from_raw_parts(children, count as usize) }.to_vec();
+        unsafe {
+            xlib::XFree(children as *mut c_void);
+        }
+
+        // bottom to top, as stacked
+        windows.iter().rev().find_map(|window| {
+            let mut attributes: xlib::XWindowAttributes = unsafe { std::mem::zeroed() };
+            let status =
+                unsafe { xlib::XGetWindowAttributes(self.display, *window, &mut attributes) };
+            if status == 0 || attributes.map_state != xlib::IsViewable {
+                return None;
+            }
+            if window_name_matches(name, &self.window_names(*window)) {
+                return Some(*window);
+            }
+            self.find_window_named(*window, name)
+        })
+    }
+
+    // title, UTF-8 title, then instance and class
+    fn window_names(&self, window: xlib::Window) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        let owned = |text: *const c_char| {
+            unsafe { CStr::from_ptr(text) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        unsafe {
+            let mut title: *mut c_char = ptr::null_mut();
+            if xlib::XFetchName(self.display, window, &mut title) != 0 && !title.is_null() {
+                names.push(owned(title));
+                xlib::XFree(title as *mut c_void);
+            }
+
+            let net_wm_name =
+                xlib::XInternAtom(self.display, b"_NET_WM_NAME\0".as_ptr() as *const c_char, 0);
+            let utf8_string =
+                xlib::XInternAtom(self.display, b"UTF8_STRING\0".as_ptr() as *const c_char, 0);
+            let mut actual_type: c_ulong = 0;
+            let mut format: c_int = 0;
+            let (mut item_count, mut bytes_after): (c_ulong, c_ulong) = (0, 0);
+            let mut data: *mut c_uchar = ptr::null_mut();
+            let status = xlib::XGetWindowProperty(
+                self.display,
+                window,
+                net_wm_name,
+                0,
+                MAX_TITLE_LENGTH,
+                0,
+                utf8_string,
+                &mut actual_type,
+                &mut format,
+                &mut item_count,
+                &mut bytes_after,
+                &mut data,
+            );
+            if status == 0 && !data.is_null() {
+                if format == 8 {
+                    let bytes = slice::from_raw_parts(data, item_count as usize);
+                    names.push(String::from_utf8_lossy(bytes).into_owned());
+                }
+                xlib::XFree(data as *mut c_void);
+            }
+
+            let mut class_hint = xlib::XClassHint {
+                res_name: ptr::null_mut(),
+                res_class: ptr::null_mut(),
+            };
+            if xlib::XGetClassHint(self.display, window, &mut class_hint) != 0 {
+                for text in [class_hint.res_name, class_hint.res_class].iter() {
+                    if !text.is_null() {
+                        names.push(owned(*text));
+                        xlib::XFree(*text as *mut c_void);
+                    }
+                }
+            }
+        }
+        names
+    }
+
+    fn whole(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    // copy a rectangle of the monitor into a frame as large as the monitor
+    fn grab(&self, rect: Rect, frame: &mut [Bgr8]) -> Result<(), CaptureError> {
+        let image_ptr = unsafe {
+            xlib::XGetImage(
+                self.display,
+                self.root,
+                self.x + rect.x as i32,
+                self.y + rect.y as i32,
+                rect.width as u32,
+                rect.height as u32,
+                !0,
+                xlib::ZPixmap,
+            )
+        };
+        if self.connection_lost() {
+            return Err(CaptureError::ConnectionLost);
+        }
+        if image_ptr.is_null() {
+            return Err(CaptureError::Grab {
+                reason: "XGetImage returned null pointer".to_string(),
+            });
+        }
+
+        let image = unsafe { &*image_ptr };
+        if image.depth != 24 || image.bits_per_pixel != 32 {
+            unsafe {
+                xlib::XDestroyImage(image_ptr);
+            }
+            return Err(CaptureError::Grab {
+                reason: "wrong layout".to_string(),
+            });
+        }
+
+        for line in 0..rect.height as usize {
+            let row: &[Bgr8] = unsafe {
+                let start = (image.data as *const u8).add(line * image.bytes_per_line as usize);
+                slice::from_raw_parts(start as *const Bgr8, rect.width as usize)
+            };
+            let start: usize = (rect.y as usize + line) * self.width as usize + rect.x as usize;
+            frame[start..start + rect.width as usize].copy_from_slice(row);
+        }
+
+        unsafe {
+            xlib::XDestroyImage(image_ptr);
+        }
+        Ok(())
+    }
+
+    // the cursor, placed on the captured part
+    fn fetch_cursor(&mut self) -> Option<CursorImage> {
+        let (x_cursor, _) = self.cursor.as_mut()?;
+        let mut cursor = x_cursor.fetch(self.display)?;
+        cursor.x -= self.x;
+        cursor.y -= self.y;
+        Some(cursor)
+    }
+
+    /// Put back what the cursor covers, before grabbing
+    fn erase_cursor(&mut self, frame: &mut [Bgr8]) {
+        let width = self.width;
+        if let Some((_, overlay)) = &mut self.cursor {
+            overlay.erase(frame, width);
+        }
+    }
+
+    /// Draw the cursor where it is now, returns the rectangles it changed
+    fn draw_cursor(&mut self, frame: &mut [Bgr8]) -> Vec<Rect> {
+        let cursor = self.fetch_cursor();
+        let (width, height) = (self.width, self.height);
+        match &mut self.cursor {
+            Some((_, overlay)) => overlay.draw(frame, width, height, cursor.as_ref()),
+            None => Vec::new(),
+        }
+    }
+
+    // the cursor moved or changed since it was drawn
+    fn cursor_changed(&mut self) -> bool {
+        let cursor = self.fetch_cursor();
+        match &self.cursor {
+            Some((_, overlay)) => overlay.changed(cursor.as_ref()),
+            None => false,
+        }
+    }
+
+    // an event read by the capture, it may tell the cursor changed shape
+    fn note_event(&mut self, event: &xlib::XEvent) {
+        if let Some((x_cursor, _)) = &mut self.cursor {
+            x_cursor.note_event(event);
+        }
+    }
+
+    fn white_frame(&self) -> Vec<Bgr8> {
+        vec![new_bgr8(255, 255, 255); self.width as usize * self.height as usize]
+    }
+}
+
+impl Drop for XScreen {
+    fn drop(&mut self) {
+        // closing a lost connection would hit the I/O error again, it is left behind
+        if !self.connection_lost() {
+            unsafe {
+                xlib::XCloseDisplay(self.display);
+            }
+        }
+    }
+}
+
+/// Grabs the whole monitor on every call
+pub struct ScreenCapture {
+    screen: XScreen,
+    frame: Vec<Bgr8>,
+}
+
+impl ScreenCapture {
+    pub fn new(target: &CaptureTarget, cursor: CursorStyle) -> Result<ScreenCapture, CaptureError> {
+        let screen = XScreen::open(target, cursor)?;
+        Ok(ScreenCapture {
+            frame: screen.white_frame(),
+            screen,
+        })
+    }
+}
+
+impl CaptureSource for ScreenCapture {
+    fn geometry(&self) -> (u16, u16) {
+        (self.screen.width, self.screen.height)
+    }
+
+    fn capture(&mut self) -> Result<Option<Vec<Rect>>, CaptureError> {
+        if self.screen.update_geometry()? {
+            self.frame = self.screen.white_frame();
+        }
+        self.screen.grab(self.screen.whole(), &mut self.frame)?;
+        self.screen.draw_cursor(&mut self.frame);
+        Ok(None)
+    }
+
+    fn frame(&self) -> &[Bgr8] {
+        self.frame.as_slice()
+    }
+}
+
+#[derive(WrapperApi)]
+struct XDamageApi {
+    #[dlopen_name = "XDamageQueryExtension"]
+    query_extension: unsafe extern "C" fn(
+        display: *mut xlib::Display,
+        event_base: *mut c_int,
+        error_base: *mut c_int,
+    ) -> xlib::Bool,
+    #[dlopen_name = "XDamageCreate"]
+    create: unsafe extern "C" fn(
+        display: *mut xlib::Display,
+        drawable: xlib::Drawable,
+        level: c_int,
+    ) -> xlib::XID,
+    #[dlopen_name = "XDamageSubtract"]
+    subtract: unsafe extern "C" fn(
+        display: *mut xlib::Display,
+        damage: xlib::XID,
+        repair: xlib::XID,
+        parts: xlib::XID,
+    ),
+    #[dlopen_name = "XDamageDestroy"]
+    destroy: unsafe extern "C" fn(display: *mut xlib::Display, damage: xlib::XID),
+}
+
+#[derive(WrapperApi)]
+struct XFixesApi {
+    #[dlopen_name = "XFixesQueryVersion"]
+    query_version: unsafe extern "C" fn(
+        display: *mut xlib::Display,
+        major: *mut c_int,
+        minor: *mut c_int,
+    ) -> xlib::Status,
+    #[dlopen_name = "XFixesCreateRegion"]
+    create_region: unsafe extern "C" fn(
+        display: *mut xlib::Display,
+        rectangles: *mut xlib::XRectangle,
+        count: c_int,
+    ) -> xlib::XID,
+    #[dlopen_name = "XFixesFetchRegion"]
+    fetch_region: unsafe extern "C" fn(
+        display: *mut xlib::Display,
+        region: xlib::XID,
+        count: *mut c_int,
+    ) -> *mut xlib::XRectangle,
+    #[dlopen_name = "XFixesDestroyRegion"]
+    destroy_region: unsafe extern "C" fn(display: *mut xlib::Display, region: xlib::XID),
+}
+
+/// Sleeps until the X server reports damage on the screen, then grabs only
+/// the damaged rectangles
+pub struct DamageCapture {
+    damage_api: Container<XDamageApi>,
+    fixes_api: Container<XFixesApi>,
+    screen: XScreen,
+    damage: xlib::XID,
+    frame: Vec<Bgr8>,
+    first_frame: bool,
+}
+
+impl DamageCapture {
+    pub fn new(target: &CaptureTarget, cursor: CursorStyle) -> Result<DamageCapture, CaptureError> {
+        let damage_api: Container<XDamageApi> = unsafe { Container::load("libXdamage.so.1") }
+            .map_err(|error| CaptureError::Open {
+                reason: error.to_string(),
+            })?;
+        let fixes_api: Container<XFixesApi> = unsafe { Container::load("libXfixes.so.3") }
+            .map_err(|error| CaptureError::Open {
+                reason: error.to_string(),
+            })?;
+
+        let screen = XScreen::open(target, cursor)?;
+
+        let (mut event_base, mut error_base) = (0, 0);
+        let (mut major, mut minor) = (2, 0);
+        let supported = unsafe {
+            damage_api.query_extension(screen.display, &mut event_base, &mut error_base) != 0
+                && fixes_api.query_version(screen.display, &mut major, &mut minor) != 0
+        };
+        if !supported {
+            return Err(CaptureError::NoDamage);
+        }
+
+        let damage =
+            unsafe { damage_api.create(screen.display, screen.root, DAMAGE_REPORT_NON_EMPTY) };
+
+        Ok(DamageCapture {
+            damage_api,
+            fixes_api,
+            frame: screen.white_frame(),
+            screen,
+            damage,
+            first_frame: true,
+        })
+    }
+
+    // sleep until the X server sends something or the wait is over
+    fn wait_for_damage(&mut self) {
+        let display = self.screen.display;
+        let mut waited: c_int = 0;
+        unsafe {
+            xlib::XFlush(display);
+            while waited < DAMAGE_WAIT && xlib::XPending(display) == 0 {
+                // moving the cursor damages nothing, it is queried between shorter waits
+                let wait = match self.screen.cursor {
+                    Some(_) => CURSOR_WAIT.min(DAMAGE_WAIT - waited),
+                    None => DAMAGE_WAIT,
+                };
+                let mut connection = pollfd {
+                    fd: xlib::XConnectionNumber(display),
+                    events: POLLIN,
+                    revents: 0,
+                };
+                libc::poll(&mut connection, 1, wait);
+                waited += wait;
+                if self.screen.connection_lost() || self.screen.cursor_changed() {
+                    break;
+                }
+            }
+            // the events only wake us up, the damage region tells what changed
+            let mut event: xlib::XEvent = std::mem::zeroed();
+            while !self.screen.connection_lost() && xlib::XPending(display) > 0 {
+                xlib::XNextEvent(display, &mut event);
+                self.screen.note_event(&event);
+            }
+        }
+    }
+
+    // empty the damage and return what it covered, in pixels of the captured part
+    fn take_damage(&mut self) -> Vec<Rect> {
+        let display = self.screen.display;
+        let mut damaged: Vec<xlib::XRectangle> = Vec::new();
+        unsafe {
+            let parts = self.fixes_api.create_region(display, ptr::null_mut(), 0);
+            self.damage_api.subtract(display, self.damage, 0, parts);
+            let mut count: c_int = 0;
+            let rectangles = self.fixes_api.fetch_region(display, parts, &mut count);
+            if !rectangles.is_null() {
+                damaged.extend_from_slice(slice::from_raw_parts(rectangles, count as usize));
+                xlib::XFree(rectangles as *mut c_void);
+            }
+            self.fixes_api.destroy_region(display, parts);
+        }
+
+        let monitor = (
+            self.screen.x,
+            self.screen.y,
+            self.screen.width,
+            self.screen.height,
+        );
+        let rects: Vec<Rect> = damaged
+            .iter()
+            .filter_map(|rect| {
+                clip_to_monitor(
+                    rect.x as i32,
+                    rect.y as i32,
+                    rect.width as u32,
+                    rect.height as u32,
+                    monitor,
+                )
+            })
+            .collect();
+
+        limit_damage(rects)
+    }
+}
+
+impl CaptureSource for DamageCapture {
+    fn geometry(&self) -> (u16, u16) {
+        (self.screen.width, self.screen.height)
+    }
+
+    fn capture(&mut self) -> Result<Option<Vec<Rect>>, CaptureError> {
+        if !self.first_frame {
+            self.wait_for_damage();
+        }
+        if self.screen.update_geometry()? {
+            self.frame = self.screen.white_frame();
+            self.first_frame = true;
+        }
+
+        if self.first_frame {
+            self.take_damage();
+            self.screen.grab(self.screen.whole(), &mut self.frame)?;
+            self.screen.draw_cursor(&mut self.frame);
+            self.first_frame = false;
+            return Ok(None);
+        }
+
+        let mut rects = self.take_damage();
+        self.screen.erase_cursor(&mut self.frame);
+        for rect in rects.iter() {
+            self.screen.grab(*rect, &mut self.frame)?;
+        }
+        rects.extend(self.screen.draw_cursor(&mut self.frame));
+        Ok(Some(limit_damage(rects)))
+    }
+
+    fn frame(&self) -> &[Bgr8] {
+        self.frame.as_slice()
+    }
+}
+
+impl Drop for DamageCapture {
+    fn drop(&mut self) {
+        if !self.screen.connection_lost() {
+            unsafe {
+                self.damage_api.destroy(self.screen.display, self.damage);
+            }
+        }
+    }
+}
+
+/// Copy the rectangles of a frame into another one of the same width
+pub fn copy_regions(destination: &mut [Bgr8], source: &[Bgr8], width: u16, regions: &[Rect]) {
+    for region in regions {
+        for line in region.y as usize..region.y as usize + region.height as usize {
+            let start: usize = line * width as usize + region.x as usize;
+            let end: usize = start + region.width as usize;
+            if end > source.len() || end > destination.len() {
+                break;
+            }
+            destination[start..end].copy_from_slice(&source[start..end]);
+        }
+    }
+}
+
+/// A region of the X screen given as x,y,width,height
+pub fn parse_region(text: &str) -> Result<Rect, String> {
+    let numbers: Vec<u16> = text
+        .split(',')
+        .map(|number| number.trim().parse::<u16>())
+        .collect::<Result<Vec<u16>, _>>()
+        .map_err(|_| format!("{}: write the region as x,y,width,height", text))?;
+    match numbers[..] {
+        [x, y, width, height] if width > 0 && height > 0 => Ok(Rect {
+            x,
+            y,
+            width,
+            height,
+        }),
+        _ => Err(format!("{}: write the region as x,y,width,height", text)),
+    }
+}
+
+/// A window given by its id, in hexadecimal like xwininfo prints it or in decimal, or by name
+pub fn parse_window(text: &str) -> WindowSpec {
+    let id = if text.starts_with("0x") || text.starts_with("0X") {
+        u64::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse::<u64>().ok()
+    };
+    match id {
+        Some(id) => WindowSpec::Id(id),
+        None => WindowSpec::Name(text.to_string()),
+    }
+}
+
+// the title contains the name, or the instance or class is the name
+fn window_name_matches(name: &str, window_names: &[String]) -> bool {
+    window_names
+        .iter()
+        .any(|window_name| window_name.contains(name) || window_name.eq_ignore_ascii_case(name))
+}
+
+// part of a rectangle of the X screen inside the captured part (monitor, region
+// or window), in pixels of the captured part
+pub fn clip_to_monitor(
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    monitor: (i32, i32, u16, u16),
+) -> Option<Rect> {
+    let (monitor_x, monitor_y, monitor_width, monitor_height) = monitor;
+    let left = (x - monitor_x).max(0);
+    let top = (y - monitor_y).max(0);
+    let right = (x - monitor_x + width as i32).min(monitor_width as i32);
+    let bottom = (y - monitor_y + height as i32).min(monitor_height as i32);
+    if left >= right || top >= bottom {
+        return None;
+    }
+    Some(Rect {
+        x: left as u16,
+        y: top as u16,
+        width: (right - left) as u16,
+        height: (bottom - top) as u16,
+    })
+}
+
+/// The damage as is, or its bounding box when it has too many rectangles to grab
+/// them one by one
+pub fn limit_damage(rects: Vec<Rect>) -> Vec<Rect> {
+    if rects.len() > MAX_DAMAGE_RECTS {
+        return bounding_rect(&rects).into_iter().collect();
+    }
+    rects
+}
+
+fn bounding_rect(rects: &[Rect]) -> Option<Rect> {
+    let left = rects.iter().map(|rect| rect.x).min()?;
+    let top = rects.iter().map(|rect| rect.y).min()?;
+    let right = rects.iter().map(|rect| rect.x + rect.width).max()?;
+    let bottom = rects.iter().map(|rect| rect.y + rect.height).max()?;
+    Some(Rect {
+        x: left,
+        y: top,
+        width: right - left,
+        height: bottom - top,
+    })
+}
+
+#[test]
+fn test_clip_to_monitor() {
+    // second monitor, right of a 1920 pixels wide one
+    let monitor = (1920, 0, 1872, 1404);
+
+    assert_eq!(
+        clip_to_monitor(1930, 20, 100, 50, monitor),
+        Some(Rect {
+            x: 10,
+            y: 20,
+            width: 100,
+            height: 50
+        })
+    );
+    // a window across both monitors
+    assert_eq!(
+        clip_to_monitor(1800, 1380, 200, 100, monitor),
+        Some(Rect {
+            x: 0,
+            y: 1380,
+            width: 80,
+            height: 24
+        })
+    );
+    // only on the first monitor
+    assert_eq!(clip_to_monitor(0, 0, 1920, 1080, monitor), None);
+}
+
+#[test]
+fn test_bounding_rect() {
+    let rects = [
+        Rect {
+            x: 10,
+            y: 20,
+            width: 5,
+            height: 5,
+        },
+        Rect {
+            x: 100,
+            y: 2,
+            width: 10,
+            height: 1,
+        },
+    ];
+    assert_eq!(
+        bounding_rect(&rects),
+        Some(Rect {
+            x: 10,
+            y: 2,
+            width: 100,
+            height: 23
+        })
+    );
+    assert_eq!(bounding_rect(&[]), None);
+}
+
+#[test]
+fn test_backoff() {
+    let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(3));
+    let delays: Vec<Duration> = (0..5).map(|_| backoff.next_delay()).collect();
+    assert_eq!(
+        delays,
+        vec![
+            Duration::from_millis(500),
+            Duration::from_secs(1),
+            Duration::from_secs(2),
+            Duration::from_secs(3),
+            Duration::from_secs(3)
+        ]
+    );
+}
+
+#[test]
+fn test_copy_regions() {
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+
+    let source = vec![black; 8 * 4];
+    let mut destination = vec![white; 8 * 4];
+    let regions = [Rect {
+        x: 2,
+        y: 1,
+        width: 3,
+        height: 2,
+    }];
+    copy_regions(&mut destination, &source, 8, &regions);
+
+    let copied: Vec<usize> = (0..destination.len())
+        .filter(|n| destination[*n] == black)
+        .collect();
+    assert_eq!(copied, vec![10, 11, 12, 18, 19, 20]);
+}
+
+#[test]
+fn test_parse_region() {
+    assert_eq!(
+        parse_region("100,50,800,600"),
+        Ok(Rect {
+            x: 100,
+            y: 50,
+            width: 800,
+            height: 600
+        })
+    );
+    assert!(parse_region("100,50,800").is_err());
+    assert!(parse_region("0,0,0,600").is_err());
+    assert!(parse_region("-1,0,800,600").is_err());
+}
+
+#[test]
+fn test_parse_window() {
+    assert_eq!(parse_window("0x3c00007"), WindowSpec::Id(0x3c0_0007));
+    assert_eq!(parse_window("62914567"), WindowSpec::Id(62_914_567));
+    assert_eq!(
+        parse_window("FocusWriter"),
+        WindowSpec::Name("FocusWriter".to_string())
+    );
+}
+
+#[test]
+fn test_window_name_matches() {
+    let names = vec![
+        "novel.odt - FocusWriter".to_string(),
+        "focuswriter".to_string(),
+        "FocusWriter".to_string(),
+    ];
+    assert!(window_name_matches("FocusWriter", &names));
+    assert!(window_name_matches("novel.odt", &names));
+    // a class, whatever the case
+    assert!(window_name_matches("FOCUSWRITER", &names));
+    assert!(!window_name_matches("xterm", &names));
+}
+
+#[test]
+fn test_parse_source() {
+    assert_eq!("x11".parse::<Source>(), Ok(Source::X11));
+    assert_eq!(
+        "fb:/dev/fb1".parse::<Source>(),
+        Ok(Source::Framebuffer("/dev/fb1".to_string()))
+    );
+    assert_eq!(
+        "fb".parse::<Source>(),
+        Ok(Source::Framebuffer("/dev/fb0".to_string()))
+    );
+    assert_eq!(
+        "drm:/dev/dri/card1".parse::<Source>(),
+        Ok(Source::Drm("/dev/dri/card1".to_string()))
+    );
+    assert_eq!("wayland".parse::<Source>(), Ok(Source::Wayland(None)));
+    assert_eq!(
+        "wayland:HDMI-A-1".parse::<Source>(),
+        Ok(Source::Wayland(Some("HDMI-A-1".to_string())))
+    );
+    assert_eq!(
+        "vnc:pi.local:1".parse::<Source>(),
+        Ok(Source::Vnc("pi.local".to_string(), 5901))
+    );
+    assert!("x11:/dev/fb0".parse::<Source>().is_err());
+    assert!("vnc".parse::<Source>().is_err());
+}
+
+/// Needs an Xvfb, e.g. `Xvfb :2 -screen 0 1024x768x24`, then
+/// `DISPLAY=:2 cargo test -- --ignored test_damage_from_xvfb`
+#[ignore]
+#[test]
+fn test_damage_from_xvfb() {
+    let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+    assert!(!display.is_null(), "cannot open the X display of the Xvfb");
+    let (window, gc, black) = unsafe {
+        let screen = xlib::XDefaultScreen(display);
+        let black = xlib::XBlackPixel(display, screen);
+        let white = xlib::XWhitePixel(display, screen);
+        let root = xlib::XDefaultRootWindow(display);
+        let window = xlib::XCreateSimpleWindow(display, root, 100, 80, 200, 150, 0, black, white);
+        xlib::XMapWindow(display, window);
+        xlib::XSync(display, xlib::False);
+        let gc = xlib::XCreateGC(display, window, 0, ptr::null_mut());
+        (window, gc, black)
+    };
+
+    let mut capture = DamageCapture::new(&CaptureTarget::Monitor(0), CursorStyle::None).unwrap();
+    let (width, height) = capture.geometry();
+    assert_eq!(capture.capture().unwrap(), None);
+
+    // at (20, 30) in the window, (120, 110) on the screen
+    let drawn = Rect {
+        x: 120,
+        y: 110,
+        width: 60,
+        height: 40,
+    };
+    unsafe {
+        xlib::XSetForeground(display, gc, black);
+        xlib::XFillRectangle(display, window, gc, 20, 30, 60, 40);
+        xlib::XSync(display, xlib::False);
+    }
+    let inside = |rect: &Rect, x: u16, y: u16| {
+        x >= rect.x && x < rect.x + rect.width && y >= rect.y && y < rect.y + rect.height
+    };
+    let drawn_pixels = || {
+        (drawn.y..drawn.y + drawn.height)
+            .flat_map(|y| (drawn.x..drawn.x + drawn.width).map(move |x| (x, y)))
+    };
+    let covered = |damaged: &[Rect]| {
+        drawn_pixels().all(|(x, y)| damaged.iter().any(|rect| inside(rect, x, y)))
+    };
+    let mut damaged: Vec<Rect> = Vec::new();
+    for _ in 0..10 {
+        damaged.extend(capture.capture().unwrap().unwrap_or_default());
+        if covered(&damaged) {
+            break;
+        }
+    }
+
+    // the rects cover the drawing, not the whole screen
+    assert!(covered(&damaged));
+    let damaged_area: usize = damaged
+        .iter()
+        .map(|rect| rect.width as usize * rect.height as usize)
+        .sum();
+    assert!(damaged_area < width as usize * height as usize / 4);
+    assert!(drawn_pixels().all(|(x, y)| {
+        capture.frame()[y as usize * width as usize + x as usize] == new_bgr8(0, 0, 0)
+    }));
+
+    unsafe {
+        xlib::XFreeGC(display, gc);
+        xlib::XDestroyWindow(display, window);
+        xlib::XCloseDisplay(display);
+    }
+}
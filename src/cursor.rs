@@ -0,0 +1,512 @@
+use dlopen::wrapper::{Container, WrapperApi};
+use std::mem;
+use std::os::raw::{c_char, c_int, c_short, c_uint, c_ulong, c_ushort, c_void};
+use std::slice;
+use std::str::FromStr;
+use x11::xlib;
+use x11cap::Bgr8;
+
+use crate::imagery::{new_bgr8, Rect};
+
+// the contrast arrow : X black, . white, blank left alone
+static CONTRAST_ARROW: [&str; 19] = [
+    ".",
+    "..",
+    ".X.",
+    ".XX.",
+    ".XXX.",
+    ".XXXX.",
+    ".XXXXX.",
+    ".XXXXXX.",
+    ".XXXXXXX.",
+    ".XXXXXXXX.",
+    ".XXXXXXXXX.",
+    ".XXXXXX.....",
+    ".XXX.XX.",
+    ".XX..XX.",
+    ".X.  .XX.",
+    "..   .XX.",
+    ".     .XX.",
+    "      .XX.",
+    "       ..",
+];
+// each pixel of the arrow drawn as a square of that many pixels
+static CONTRAST_SCALE: usize = 2;
+
+/// How the mouse cursor is drawn on the frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CursorStyle {
+    /// Not drawn
+    None,
+    /// The image the X server shows
+    X11,
+    /// A large black arrow outlined in white, whatever the X server shows
+    Contrast,
+}
+
+impl FromStr for CursorStyle {
+    type Err = String;
+
+    fn from_str(style: &str) -> Result<CursorStyle, String> {
+        match style {
+            "none" => Ok(CursorStyle::None),
+            "x11" => Ok(CursorStyle::X11),
+            "contrast" => Ok(CursorStyle::Contrast),
+            _ => Err(format!("unknown cursor {}", style)),
+        }
+    }
+}
+
+/// A cursor image placed on the frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct CursorImage {
+    /// Position of the hot spot, in pixels of the frame
+    pub x: i32,
+    pub y: i32,
+    pub width: u16,
+    pub height: u16,
+    pub xhot: u16,
+    pub yhot: u16,
+    /// Changes with the image
+    pub serial: c_ulong,
+    /// ARGB with premultiplied alpha, line after line
+    pub pixels: Vec<u32>,
+}
+
+impl CursorImage {
+    // nothing to draw, e.g. a program hiding the pointer while typing
+    fn is_blank(&self) -> bool {
+        self.pixels.iter().all(|pixel| pixel >> 24 == 0)
+    }
+
+    // the contrast arrow with the hot spot of this one
+    fn as_contrast(&self) -> CursorImage {
+        let width = CONTRAST_ARROW
+            .iter()
+            .map(|row| row.len())
+            .max()
+            .unwrap_or(0);
+        let height = CONTRAST_ARROW.len();
+        let mut pixels = vec![0; width * height * CONTRAST_SCALE * CONTRAST_SCALE];
+        for (row_y, row) in CONTRAST_ARROW.iter().enumerate() {
+            for (row_x, dot) in row.chars().enumerate() {
+                let pixel: u32 = match dot {
+                    'X' => 0xff00_0000,
+                    '.' => 0xffff_ffff,
+                    _ => continue,
+                };
+                for y in row_y * CONTRAST_SCALE..(row_y + 1) * CONTRAST_SCALE {
+                    let start = y * width * CONTRAST_SCALE + row_x * CONTRAST_SCALE;
+                    for target in pixels[start..start + CONTRAST_SCALE].iter_mut() {
+                        *target = pixel;
+                    }
+                }
+            }
+        }
+        CursorImage {
+            x: self.x,
+            y: self.y,
+            width: (width * CONTRAST_SCALE) as u16,
+            height: (height * CONTRAST_SCALE) as u16,
+            xhot: 0,
+            yhot: 0,
+            serial: self.serial,
+            pixels,
+        }
+    }
+
+    // part of the frame covered, None when off the frame
+    fn rect(&self, frame_width: u16, frame_height: u16) -> Option<Rect> {
+        let left = (self.x - self.xhot as i32).max(0);
+        let top = (self.y - self.yhot as i32).max(0);
+        let right = (self.x - self.xhot as i32 + self.width as i32).min(frame_width as i32);
+        let bottom = (self.y - self.yhot as i32 + self.height as i32).min(frame_height as i32);
+        if left >= right || top >= bottom {
+            return None;
+        }
+        Some(Rect {
+            x: left as u16,
+            y: top as u16,
+            width: (right - left) as u16,
+            height: (bottom - top) as u16,
+        })
+    }
+}
+
+// premultiplied source over the frame pixel
+fn blend(pixel: u32, under: Bgr8) -> Bgr8 {
+    let alpha = pixel >> 24;
+    let channel = |shift: u32, under: u8| {
+        let source = (pixel >> shift) & 0xff;
+        (source + under as u32 * (255 - alpha) / 255).min(255) as u8
+    };
+    new_bgr8(
+        channel(0, under.b),
+        channel(8, under.g),
+        channel(16, under.r),
+    )
+}
+
+/// Draws the cursor on the frames of a capture, and puts back what it covered
+/// before the next grab
+pub struct CursorOverlay {
+    style: CursorStyle,
+    // where the cursor is drawn and the pixels under it
+    drawn: Option<(Rect, Vec<Bgr8>)>,
+    // rectangle erased since the last draw
+    erased: Option<Rect>,
+    // position and serial of the cursor last drawn, to tell when it changed
+    shown: Option<(i32, i32, c_ulong)>,
+}
+
+impl CursorOverlay {
+    pub fn new(style: CursorStyle) -> CursorOverlay {
+        CursorOverlay {
+            style,
+            drawn: None,
+            erased: None,
+            shown: None,
+        }
+    }
+
+    /// The frame was replaced, nothing is drawn on it
+    pub fn forget(&mut self) {
+        self.drawn = None;
+        self.erased = None;
+        self.shown = None;
+    }
+
+    // what is drawn for the cursor of the X server
+    fn image_of(&self, cursor: Option<&CursorImage>) -> Option<CursorImage> {
+        match cursor {
+            Some(cursor) if !cursor.is_blank() => match self.style {
+                CursorStyle::None => None,
+                CursorStyle::X11 => Some(cursor.clone()),
+                CursorStyle::Contrast => Some(cursor.as_contrast()),
+            },
+            _ => None,
+        }
+    }
+
+    /// The cursor moved, changed or was hidden since it was drawn
+    pub fn changed(&self, cursor: Option<&CursorImage>) -> bool {
+        let image = match cursor {
+            Some(cursor) if !cursor.is_blank() && self.style != CursorStyle::None => Some(cursor),
+            _ => None,
+        };
+        image.map(|image| (image.x, image.y, image.serial)) != self.shown
+    }
+
+    /// Put back the pixels under the cursor
+    pub fn erase(&mut self, frame: &mut [Bgr8], frame_width: u16) {
+        if let Some((rect, under)) = self.drawn.take() {
+            for line in 0..rect.height as usize {
+                let start = (rect.y as usize + line) * frame_width as usize + rect.x as usize;
+                let row = &under[line * rect.width as usize..(line + 1) * rect.width as usize];
+                frame[start..start + rect.width as usize].copy_from_slice(row);
+            }
+            self.erased = Some(rect);
+        }
+    }
+
+    /// Draw the cursor, none when hidden. Returns the rectangles changed since
+    /// the previous draw : where the cursor was and where it is.
+    pub fn draw(
+        &mut self,
+        frame: &mut [Bgr8],
+        frame_width: u16,
+        frame_height: u16,
+        cursor: Option<&CursorImage>,
+    ) -> Vec<Rect> {
+        let erased = self.erased.take();
+        let image = self.image_of(cursor);
+        let shown = image.as_ref().map(|image| (image.x, image.y, image.serial));
+
+        let rect = image
+            .as_ref()
+            .and_then(|image| image.rect(frame_width, frame_height));
+        if let (Some(image), Some(rect)) = (image.as_ref(), rect) {
+            let mut under: Vec<Bgr8> =
+                Vec::with_capacity(rect.width as usize * rect.height as usize);
+            let left = rect.x as i32 - (image.x - image.xhot as i32);
+            let top = rect.y as i32 - (image.y - image.yhot as i32);
+            for line in 0..rect.height as usize {
+                let start = (rect.y as usize + line) * frame_width as usize + rect.x as usize;
+                let row = &mut frame[start..start + rect.width as usize];
+                under.extend_from_slice(row);
+                let image_start = (top as usize + line) * image.width as usize + left as usize;
+                let pixels = &image.pixels[image_start..image_start + rect.width as usize];
+                for (target, pixel) in row.iter_mut().zip(pixels.iter()) {
+                    *target = blend(*pixel, *target);
+                }
+            }
+            self.drawn = Some((rect, under));
+        }
+
+        if shown == self.shown {
+            return Vec::new();
+        }
+        self.shown = shown;
+        erased.into_iter().chain(rect).collect()
+    }
+}
+
+#[derive(WrapperApi)]
+struct XFixesCursorApi {
+    #[dlopen_name = "XFixesQueryExtension"]
+    query_extension: unsafe extern "C" fn(
+        display: *mut xlib::Display,
+        event_base: *mut c_int,
+        error_base: *mut c_int,
+    ) -> xlib::Bool,
+    #[dlopen_name = "XFixesQueryVersion"]
+    query_version: unsafe extern "C" fn(
+        display: *mut xlib::Display,
+        major: *mut c_int,
+        minor: *mut c_int,
+    ) -> xlib::Status,
+    #[dlopen_name = "XFixesSelectCursorInput"]
+    select_cursor_input:
+        unsafe extern "C" fn(display: *mut xlib::Display, window: xlib::Window, mask: c_ulong),
+    #[dlopen_name = "XFixesGetCursorImage"]
+    get_cursor_image: unsafe extern "C" fn(display: *mut xlib::Display) -> *mut XFixesCursorImage,
+}
+
+// as in Xfixes.h
+#[repr(C)]
+struct XFixesCursorImage {
+    x: c_short,
+    y: c_short,
+    width: c_ushort,
+    height: c_ushort,
+    xhot: c_ushort,
+    yhot: c_ushort,
+    cursor_serial: c_ulong,
+    pixels: *mut c_ulong,
+    atom: xlib::Atom,
+    name: *const c_char,
+}
+
+// from Xfixes.h
+static XFIXES_CURSOR_NOTIFY: c_int = 1;
+static XFIXES_DISPLAY_CURSOR_NOTIFY_MASK: c_ulong = 1;
+
+/// The mouse cursor of an X server, read with XFixes. The image is only read
+/// again when the X server tells its shape changed, the position is queried.
+pub struct XCursor {
+    api: Container<XFixesCursorApi>,
+    root: xlib::Window,
+    // type of the events telling the shape changed
+    event_type: c_int,
+    // None until read, or read again
+    shape: Option<CursorImage>,
+}
+
+impl XCursor {
+    pub fn open(display: *mut xlib::Display) -> Result<XCursor, String> {
+        let api: Container<XFixesCursorApi> =
+            unsafe { Container::load("libXfixes.so.3") }.map_err(|error| error.to_string())?;
+        let (mut event_base, mut error_base) = (0, 0);
+        let (mut major, mut minor) = (2, 0);
+        let supported = unsafe {
+            api.query_extension(display, &mut event_base, &mut error_base) != 0
+                && api.query_version(display, &mut major, &mut minor) != 0
+        };
+        if !supported {
+            return Err("the X server has no XFIXES extension".to_string());
+        }
+        let root = unsafe { xlib::XDefaultRootWindow(display) };
+        unsafe {
+            api.select_cursor_input(display, root, XFIXES_DISPLAY_CURSOR_NOTIFY_MASK);
+        }
+        Ok(XCursor {
+            api,
+            root,
+            event_type: event_base + XFIXES_CURSOR_NOTIFY,
+            shape: None,
+        })
+    }
+
+    /// Look at an event read from the X server, the next fetch reads the image
+    /// again when it tells the shape changed
+    pub fn note_event(&mut self, event: &xlib::XEvent) {
+        if event.get_type() == self.event_type {
+            self.shape = None;
+        }
+    }
+
+    /// Image and position of the cursor, on the X screen. None when the image
+    /// cannot be read or the pointer is on another screen.
+    pub fn fetch(&mut self, display: *mut xlib::Display) -> Option<CursorImage> {
+        unsafe {
+            // shape changes left in the queue by the capture
+            let mut event: xlib::XEvent = mem::zeroed();
+            while xlib::XCheckTypedEvent(display, self.event_type, &mut event) != 0 {
+                self.shape = None;
+            }
+        }
+        if self.shape.is_none() {
+            self.shape = self.fetch_image(display);
+        }
+        let (x, y) = self.pointer(display)?;
+        let shape = self.shape.as_ref()?;
+        Some(CursorImage {
+            x,
+            y,
+            ..shape.clone()
+        })
+    }
+
+    fn pointer(&self, display: *mut xlib::Display) -> Option<(i32, i32)> {
+        let (mut root, mut child): (xlib::Window, xlib::Window) = (0, 0);
+        let (mut x, mut y, mut window_x, mut window_y): (c_int, c_int, c_int, c_int) = (0, 0, 0, 0);
+        let mut mask: c_uint = 0;
+        let same_screen = unsafe {
+            xlib::XQueryPointer(
+                display,
+                self.root,
+                &mut root,
+                &mut child,
+                &mut x,
+                &mut y,
+                &mut window_x,
+                &mut window_y,
+                &mut mask,
+            )
+        };
+        if same_screen == 0 {
+            return None;
+        }
+        Some((x, y))
+    }
+
+    fn fetch_image(&self, display: *mut xlib::Display) -> Option<CursorImage> {
+        unsafe {
+            let image_ptr = self.api.get_cursor_image(display);
+            if image_ptr.is_null() {
+                return None;
+            }
+            let image = &*image_ptr;
+            let count = image.width as usize * image.height as usize;
+            // 32 bits values in longs
+            let pixels = slice::from_raw_parts(image.pixels, count)
+                .iter()
+                .map(|pixel| *pixel as u32)
+                .collect();
+            let cursor = CursorImage {
+                x: image.x as i32,
+                y: image.y as i32,
+                width: image.width,
+                height: image.height,
+                xhot: image.xhot,
+                yhot: image.yhot,
+                serial: image.cursor_serial,
+                pixels,
+            };
+            xlib::XFree(image_ptr as *mut c_void);
+            Some(cursor)
+        }
+    }
+}
+
+#[test]
+fn test_parse_cursor_style() {
+    assert_eq!("none".parse(), Ok(CursorStyle::None));
+    assert_eq!("x11".parse(), Ok(CursorStyle::X11));
+    assert_eq!("contrast".parse(), Ok(CursorStyle::Contrast));
+    assert!("arrow".parse::<CursorStyle>().is_err());
+}
+
+#[test]
+fn test_draw_and_erase() {
+    let grey = new_bgr8(100, 100, 100);
+    let mut frame = vec![grey; 8 * 6];
+    let mut overlay = CursorOverlay::new(CursorStyle::X11);
+    // 2x2, opaque red, half transparent black, then transparent, hot spot on the second pixel
+    let cursor = CursorImage {
+        x: 3,
+        y: 2,
+        width: 2,
+        height: 2,
+        xhot: 1,
+        yhot: 0,
+        serial: 1,
+        pixels: vec![0xffff_0000, 0x8000_0000, 0, 0],
+    };
+
+    let damage = overlay.draw(&mut frame, 8, 6, Some(&cursor));
+    let rect = Rect {
+        x: 2,
+        y: 2,
+        width: 2,
+        height: 2,
+    };
+    assert_eq!(damage, vec![rect]);
+    assert_eq!(frame[2 * 8 + 2], new_bgr8(0, 0, 255));
+    assert_eq!(frame[2 * 8 + 3], new_bgr8(49, 49, 49));
+    assert_eq!(frame[3 * 8 + 2], grey);
+
+    // still in place : nothing to refresh
+    overlay.erase(&mut frame, 8);
+    assert!(frame.iter().all(|pixel| *pixel == grey));
+    assert_eq!(overlay.draw(&mut frame, 8, 6, Some(&cursor)), Vec::new());
+
+    // moved : the old place and the new one
+    overlay.erase(&mut frame, 8);
+    let moved = CursorImage { x: 6, ..cursor };
+    let moved_rect = Rect { x: 5, ..rect };
+    assert_eq!(
+        overlay.draw(&mut frame, 8, 6, Some(&moved)),
+        vec![rect, moved_rect]
+    );
+    assert_eq!(frame[2 * 8 + 2], grey);
+    assert_eq!(frame[2 * 8 + 5], new_bgr8(0, 0, 255));
+
+    // hidden : only the old place
+    overlay.erase(&mut frame, 8);
+    assert_eq!(overlay.draw(&mut frame, 8, 6, None), vec![moved_rect]);
+    assert!(frame.iter().all(|pixel| *pixel == grey));
+}
+
+#[test]
+fn test_cursor_on_the_edge() {
+    let white = new_bgr8(255, 255, 255);
+    let mut frame = vec![white; 30 * 20];
+    let mut overlay = CursorOverlay::new(CursorStyle::Contrast);
+    let cursor = CursorImage {
+        x: 25,
+        y: 12,
+        width: 1,
+        height: 1,
+        xhot: 0,
+        yhot: 0,
+        serial: 7,
+        pixels: vec![0xff00_0000],
+    };
+
+    // the arrow is cut at the right and bottom of the frame
+    let damage = overlay.draw(&mut frame, 30, 20, Some(&cursor));
+    assert_eq!(
+        damage,
+        vec![Rect {
+            x: 25,
+            y: 12,
+            width: 5,
+            height: 8
+        }]
+    );
+    // white tip, black inside
+    assert_eq!(frame[12 * 30 + 25], white);
+    assert_eq!(frame[17 * 30 + 27], new_bgr8(0, 0, 0));
+
+    overlay.erase(&mut frame, 30);
+    assert!(frame.iter().all(|pixel| *pixel == white));
+
+    // a hidden X cursor hides the arrow too
+    let blank = CursorImage {
+        pixels: vec![0],
+        ..cursor
+    };
+    assert_eq!(overlay.draw(&mut frame, 30, 20, Some(&blank)).len(), 1);
+    assert!(frame.iter().all(|pixel| *pixel == white));
+}
@@ -0,0 +1,357 @@
+use std::str::FromStr;
+use x11cap::Bgr8;
+
+use crate::imagery::{new_bgr8, Area};
+
+static BAYER_8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// How the 256 greys of the capture are brought down to the greys of the panel
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum DitherMode {
+    /// Truncate to 16 greys, gradients show bands
+    None,
+    /// 16 greys, 8x8 Bayer matrix
+    Ordered,
+    /// 16 greys, Floyd–Steinberg over whole frames, ordered on partial updates
+    ErrorDiffusion,
+    /// Black and white only, as ErrorDiffusion : fit for A2 refreshes
+    Mono,
+}
+
+impl FromStr for DitherMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<DitherMode, String> {
+        match mode.to_lowercase().as_str() {
+            "none" => Ok(DitherMode::None),
+            "ordered" => Ok(DitherMode::Ordered),
+            "diffusion" => Ok(DitherMode::ErrorDiffusion),
+            "mono" => Ok(DitherMode::Mono),
+            _ => Err(format!("unknown dithering mode {}", mode)),
+        }
+    }
+}
+
+/// Replace the pixels of an unrotated area by dithered greys, all multiples of 17
+/// so that transform_to_grey_4bpp keeps them. `frame` is the capture the area was
+/// taken from. Error diffusion needs the whole frame : on a part of it, the error
+/// would not reach the pixels around, left as they are on the panel. So the partial
+/// areas are ordered dithered to the same greys, every pixel only depending on its
+/// place in the frame.
+pub fn dither_area(mut area: Area, frame: &[Bgr8], frame_width: u16, mode: DitherMode) -> Area {
+    if mode == DitherMode::None || frame_width == 0 || area.width == 0 || area.height == 0 {
+        return area;
+    }
+    let frame_width: usize = frame_width as usize;
+    let frame_height: usize = frame.len() / frame_width;
+    let (x, y) = (area.x as usize, area.y as usize);
+    let (width, height) = (area.width as usize, area.height as usize);
+    let levels: u8 = if mode == DitherMode::Mono { 2 } else { 16 };
+    let step: u8 = 255 / (levels - 1);
+
+    let whole_frame = x == 0 && y == 0 && width == frame_width && height == frame_height;
+    if mode == DitherMode::Ordered || !whole_frame {
+        for line in 0..height.min(frame_height.saturating_sub(y)) {
+            for col in 0..width.min(frame_width.saturating_sub(x)) {
+                let index = line * width + col;
+                let grey = luminance(&area.bgr_vec[index]);
+                let level = ordered_level(grey, x + col, y + line, levels);
+                area.bgr_vec[index] = grey_bgr(level * step);
+            }
+        }
+        return area;
+    }
+
+    let greys = diffuse_frame(frame, frame_width, frame_height, levels);
+    for (pixel, grey) in area.bgr_vec.iter_mut().zip(greys) {
+        *pixel = grey_bgr(grey);
+    }
+    area
+}
+
+fn luminance(bgr: &Bgr8) -> f32 {
+    bgr.r as f32 * 0.2125 + bgr.g as f32 * 0.7154 + bgr.b as f32 * 0.0721
+}
+
+fn grey_bgr(grey: u8) -> Bgr8 {
+    new_bgr8(grey, grey, grey)
+}
+
+// one of the levels, chosen by the Bayer threshold of the pixel place
+fn ordered_level(grey: f32, col: usize, line: usize, levels: u8) -> u8 {
+    let threshold: f32 = (BAYER_8[line % 8][col % 8] as f32 + 0.5) / 64.0;
+    let step: f32 = 255.0 / (levels - 1) as f32;
+    ((grey / step + threshold) as u8).min(levels - 1)
+}
+
+// serpentine Floyd–Steinberg over the whole frame, returns its greys
+fn diffuse_frame(frame: &[Bgr8], frame_width: usize, frame_height: usize, levels: u8) -> Vec<u8> {
+    let step: f32 = 255.0 / (levels - 1) as f32;
+    let mut errors: Vec<f32> = vec![0.0; frame_width];
+    let mut next_errors: Vec<f32> = vec![0.0; frame_width];
+    let mut greys: Vec<u8> = vec![0; frame_width * frame_height];
+
+    for line in 0..frame_height {
+        // odd lines from right to left
+        let backwards = line % 2 == 1;
+        for step_n in 0..frame_width {
+            let col = if backwards {
+                frame_width - 1 - step_n
+            } else {
+                step_n
+            };
+            let wanted: f32 = luminance(&frame[line * frame_width + col]) + errors[col];
+            let level: f32 = (wanted / step).round().clamp(0.0, (levels - 1) as f32);
+            let grey: f32 = level * step;
+            greys[line * frame_width + col] = grey.round() as u8;
+
+            let error: f32 = wanted - grey;
+            let ahead = if backwards {
+                col.checked_sub(1)
+            } else {
+                Some(col + 1).filter(|ahead| *ahead < frame_width)
+            };
+            let behind = if backwards {
+                Some(col + 1).filter(|behind| *behind < frame_width)
+            } else {
+                col.checked_sub(1)
+            };
+            if let Some(ahead) = ahead {
+                errors[ahead] += error * 7.0 / 16.0;
+                next_errors[ahead] += error / 16.0;
+            }
+            if let Some(behind) = behind {
+                next_errors[behind] += error * 3.0 / 16.0;
+            }
+            next_errors[col] += error * 5.0 / 16.0;
+        }
+        std::mem::swap(&mut errors, &mut next_errors);
+        next_errors.iter_mut().for_each(|error| *error = 0.0);
+    }
+    greys
+}
+
+#[test]
+fn test_parse_dither_mode() {
+    assert_eq!("ordered".parse::<DitherMode>(), Ok(DitherMode::Ordered));
+    assert_eq!("Mono".parse::<DitherMode>(), Ok(DitherMode::Mono));
+    assert!("random".parse::<DitherMode>().is_err());
+}
+
+#[test]
+fn test_dither_keeps_panel_greys() {
+    let frame: Vec<Bgr8> = (0..64).map(|n| grey_bgr((n % 16) as u8 * 17)).collect();
+
+    for mode in [DitherMode::Ordered, DitherMode::ErrorDiffusion].iter() {
+        let area = Area {
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 8,
+            bgr_vec: frame.clone(),
+        };
+        assert_eq!(dither_area(area, &frame, 8, *mode).bgr_vec, frame);
+    }
+}
+
+#[test]
+fn test_dither_mid_grey() {
+    let grey = grey_bgr(8 * 17 + 8);
+    let frame: Vec<Bgr8> = vec![grey; 32 * 32];
+
+    for mode in [DitherMode::Ordered, DitherMode::ErrorDiffusion].iter() {
+        let area = Area {
+            x: 0,
+            y: 0,
+            width: 32,
+            height: 32,
+            bgr_vec: frame.clone(),
+        };
+        let area = dither_area(area, &frame, 32, *mode);
+        let darker = area.bgr_vec.iter().filter(|bgr| bgr.g == 8 * 17).count();
+        let lighter = area.bgr_vec.iter().filter(|bgr| bgr.g == 9 * 17).count();
+        assert_eq!(darker + lighter, 32 * 32);
+        assert!(darker > 32 * 32 / 4 && lighter > 32 * 32 / 4);
+    }
+}
+
+#[test]
+fn test_dither_mono() {
+    // black to white from left to right
+    let frame: Vec<Bgr8> = (0..64 * 16)
+        .map(|n| grey_bgr(((n % 64) * 255 / 63) as u8))
+        .collect();
+    let area = Area {
+        x: 0,
+        y: 0,
+        width: 64,
+        height: 16,
+        bgr_vec: frame.clone(),
+    };
+    let area = dither_area(area, &frame, 64, DitherMode::Mono);
+
+    assert!(area.bgr_vec.iter().all(|bgr| bgr.g == 0 || bgr.g == 255));
+    // darker on the left
+    let black_in = |left: bool| {
+        (0..area.bgr_vec.len())
+            .filter(|n| (n % 64 < 32) == left && area.bgr_vec[*n].g == 0)
+            .count()
+    };
+    assert!(black_in(true) > black_in(false));
+}
+
+// the pixels of a 100 pixels wide frame in a rectangle
+#[cfg(test)]
+fn sub_area(frame: &[Bgr8], x: u16, y: u16, width: u16, height: u16) -> Area {
+    let mut bgr_vec: Vec<Bgr8> = Vec::new();
+    for line in y as usize..(y + height) as usize {
+        let start = line * 100 + x as usize;
+        bgr_vec.extend_from_slice(&frame[start..start + width as usize]);
+    }
+    Area {
+        x,
+        y,
+        width,
+        height,
+        bgr_vec,
+    }
+}
+
+// a gradient from left to right, with some colour
+#[cfg(test)]
+fn gradient_frame() -> Vec<Bgr8> {
+    (0..100 * 40)
+        .map(|n| {
+            let grey = ((n % 100) * 255 / 99) as u8;
+            new_bgr8(grey, grey, grey.wrapping_add((n / 100) as u8))
+        })
+        .collect()
+}
+
+#[test]
+fn test_dither_without_seams() {
+    let frame = gradient_frame();
+
+    for mode in [
+        DitherMode::Ordered,
+        DitherMode::ErrorDiffusion,
+        DitherMode::Mono,
+    ]
+    .iter()
+    {
+        let large = dither_area(sub_area(&frame, 0, 0, 60, 40), &frame, 100, *mode);
+        // a small dirty area, not aligned on anything
+        let part = dither_area(sub_area(&frame, 21, 7, 30, 25), &frame, 100, *mode);
+        let mut large_frame = frame.clone();
+        for line in 0..40 {
+            large_frame[line * 100..line * 100 + 60]
+                .copy_from_slice(&large.bgr_vec[line * 60..(line + 1) * 60]);
+        }
+        assert_eq!(part, sub_area(&large_frame, 21, 7, 30, 25));
+    }
+}
+
+#[test]
+fn test_redithered_block_matches_a_fresh_dither() {
+    let old_frame = gradient_frame();
+    let mut new_frame = old_frame.clone();
+    for line in 13..19 {
+        for col in 21..31 {
+            new_frame[line * 100 + col] = grey_bgr(200);
+        }
+    }
+    // the panel as updated by four partial areas, each quarter of the frame
+    let dither_quarters = |frame: &[Bgr8], mode: DitherMode| -> Vec<Bgr8> {
+        let mut panel = frame.to_vec();
+        for (x, y) in [(0, 0), (50, 0), (0, 20), (50, 20)].iter() {
+            let area = dither_area(sub_area(frame, *x, *y, 50, 20), frame, 100, mode);
+            for line in 0..20 {
+                let start = (*y as usize + line) * 100 + *x as usize;
+                panel[start..start + 50].copy_from_slice(&area.bgr_vec[line * 50..(line + 1) * 50]);
+            }
+        }
+        panel
+    };
+
+    for mode in [DitherMode::ErrorDiffusion, DitherMode::Mono].iter() {
+        // only the changed block is dithered again and sent
+        let mut panel = dither_quarters(&old_frame, *mode);
+        let block = dither_area(sub_area(&new_frame, 21, 13, 10, 6), &new_frame, 100, *mode);
+        for line in 0..6 {
+            let start = (13 + line) * 100 + 21;
+            panel[start..start + 10].copy_from_slice(&block.bgr_vec[line * 10..(line + 1) * 10]);
+        }
+        assert!(panel == dither_quarters(&new_frame, *mode));
+    }
+}
+
+#[test]
+fn test_partial_areas_without_seams() {
+    // a slow gradient, from a little lighter than grey 8 to grey 9
+    let (width, height) = (128, 64);
+    let frame: Vec<Bgr8> = (0..width * height)
+        .map(|n| grey_bgr((8 * 17 + 2 + (n % width) * 14 / (width - 1)) as u8))
+        .collect();
+    // dithered as columns of 16 pixels, one after the other
+    let block = 16;
+    let mut dithered = frame.clone();
+    for left in (0..width).step_by(block) {
+        let mut bgr_vec: Vec<Bgr8> = Vec::new();
+        for line in 0..height {
+            bgr_vec.extend_from_slice(&frame[line * width + left..line * width + left + block]);
+        }
+        let area = Area {
+            x: left as u16,
+            y: 0,
+            width: block as u16,
+            height: height as u16,
+            bgr_vec,
+        };
+        let area = dither_area(area, &frame, width as u16, DitherMode::ErrorDiffusion);
+        for line in 0..height {
+            dithered[line * width + left..line * width + left + block]
+                .copy_from_slice(&area.bgr_vec[line * block..(line + 1) * block]);
+        }
+    }
+
+    // how far from the gradient the 4x4 squares starting in a column look, on average
+    let deviation = |col: usize| -> f32 {
+        let mut total = 0.0;
+        for top in 0..height - 3 {
+            let mut sum = 0.0;
+            for line in top..top + 4 {
+                let start = line * width + col;
+                for (pixel, wanted) in dithered[start..start + 4]
+                    .iter()
+                    .zip(&frame[start..start + 4])
+                {
+                    sum += pixel.g as f32 - wanted.g as f32;
+                }
+            }
+            total += (sum / 16.0).abs();
+        }
+        total / (height - 3) as f32
+    };
+    let (mut across, mut inside) = (Vec::new(), Vec::new());
+    for col in 0..width - 3 {
+        let crosses = (col + 1..col + 4).any(|n| n % block == 0);
+        if crosses {
+            across.push(deviation(col));
+        } else {
+            inside.push(deviation(col));
+        }
+    }
+    let mean = |deviations: &[f32]| deviations.iter().sum::<f32>() / deviations.len() as f32;
+
+    // no seam at the edges of the areas
+    assert!(mean(&across) < mean(&inside) * 1.2);
+}
@@ -0,0 +1,179 @@
+use std::error::Error;
+use std::str::FromStr;
+
+// refreshes up to that many pixels are fast enough for A2 or GL16
+static SMALL_AREA: u32 = 128 * 128;
+
+/// Waveform the panel controller drives the pixels with during a refresh
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum WaveformMode {
+    /// Flashing clear of the whole panel to white
+    Init,
+    /// Direct update: fast, any grey to black or white
+    Du,
+    /// Flashing update with 16 greys, no ghosting
+    Gc16,
+    /// Non flashing update with 16 greys, a bit of ghosting
+    Gl16,
+    /// Fastest, black to white and white to black only
+    A2,
+}
+
+impl WaveformMode {
+    /// Pick the fastest mode able to show a packed 4bpp area. A2 and DU only
+    /// when the pixels they replace were black and white too.
+    pub fn choose(
+        grey_vec: &[u8],
+        width: u16,
+        height: u16,
+        replaced_monochrome: bool,
+    ) -> WaveformMode {
+        let monochrome = grey_vec.iter().all(|two_greys| {
+            let low = two_greys & 0x0F;
+            let high = two_greys >> 4;
+            (low == 0 || low == 0x0F) && (high == 0 || high == 0x0F)
+        });
+        let small = width as u32 * height as u32 <= SMALL_AREA;
+
+        match (monochrome, replaced_monochrome, small) {
+            (true, true, true) => WaveformMode::A2,
+            (true, true, false) => WaveformMode::Du,
+            // greys left behind would ghost
+            (true, false, _) | (false, _, true) => WaveformMode::Gl16,
+            (false, _, false) => WaveformMode::Gc16,
+        }
+    }
+}
+
+impl FromStr for WaveformMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<WaveformMode, String> {
+        match mode.to_lowercase().as_str() {
+            "init" => Ok(WaveformMode::Init),
+            "du" => Ok(WaveformMode::Du),
+            "gc16" => Ok(WaveformMode::Gc16),
+            "gl16" => Ok(WaveformMode::Gl16),
+            "a2" => Ok(WaveformMode::A2),
+            _ => Err(format!("unknown waveform mode {}", mode)),
+        }
+    }
+}
+
+/// Panel rectangle covered by a rectangle of the panel rotated by `rotation` degrees
+pub fn rotate_rect(
+    rotation: u16,
+    panel_width: u16,
+    panel_height: u16,
+    rect: (u16, u16, u16, u16),
+) -> (u16, u16, u16, u16) {
+    let (x, y, width, height) = rect;
+    match rotation {
+        90 => (panel_width.saturating_sub(y + height), x, height, width),
+        180 => (
+            panel_width.saturating_sub(x + width),
+            panel_height.saturating_sub(y + height),
+            width,
+            height,
+        ),
+        270 => (y, panel_height.saturating_sub(x + width), height, width),
+        _ => rect,
+    }
+}
+
+/// What every e-ink panel driver has to offer to the capture/diff loop.
+///
+/// Areas are uploaded packed at 4bpp, as produced by `Imagery::transform_to_grey_4bpp`,
+/// then refreshed with `display`.
+pub trait EinkDisplay {
+    /// Panel width and height, in pixels
+    fn size(&self) -> (u16, u16);
+
+    /// Upload the packed 4bpp pixels of the area refreshed by the next `display` call
+    fn load_buffer_from_vec(&mut self, grey_vec: Vec<u8>);
+
+    /// Refresh a rectangle of the panel with the last uploaded pixels
+    fn display(
+        &mut self,
+        x: u16,
+        y: u16,
+        rect_width: u16,
+        rect_height: u16,
+        mode: WaveformMode,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Paint the whole panel white
+    fn clear(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Put the panel controller in standby, quicker to wake than sleep.
+    /// Panels without a standby mode sleep instead.
+    fn standby(&mut self) -> Result<(), Box<dyn Error>> {
+        self.sleep()
+    }
+
+    /// Put the panel controller to sleep
+    fn sleep(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Wake the panel controller up
+    fn wake(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Reset the panel controller and set it up again, after it stopped answering
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Let the panel rotate the uploaded areas by 90, 180 or 270 degrees.
+    /// When it returns true, `display` takes coordinates on the rotated panel
+    /// and the pixels unrotated; false when the panel cannot rotate.
+    fn set_rotation(&mut self, _rotation: u16) -> bool {
+        false
+    }
+}
+
+#[test]
+fn test_choose_waveform_mode() {
+    // a typed character : black and white, small
+    assert_eq!(
+        WaveformMode::choose(&[0x00, 0xFF, 0x0F, 0xF0], 4, 2, true),
+        WaveformMode::A2
+    );
+    // a whole black and white page
+    assert_eq!(
+        WaveformMode::choose(&vec![0xF0; 1872 * 1404 / 2], 1872, 1404, true),
+        WaveformMode::Du
+    );
+    // black and white over anti-aliased text or a photo
+    assert_eq!(
+        WaveformMode::choose(&[0x00, 0xFF, 0x0F, 0xF0], 4, 2, false),
+        WaveformMode::Gl16
+    );
+    assert_eq!(
+        WaveformMode::choose(&vec![0xF0; 1872 * 1404 / 2], 1872, 1404, false),
+        WaveformMode::Gl16
+    );
+    // anti-aliased text
+    assert_eq!(
+        WaveformMode::choose(&[0x00, 0x7F, 0x0F, 0xF0], 4, 2, true),
+        WaveformMode::Gl16
+    );
+    // a photo
+    assert_eq!(
+        WaveformMode::choose(&vec![0x37; 1872 * 1404 / 2], 1872, 1404, true),
+        WaveformMode::Gc16
+    );
+}
+
+#[test]
+fn test_parse_waveform_mode() {
+    assert_eq!("a2".parse::<WaveformMode>(), Ok(WaveformMode::A2));
+    assert_eq!("GC16".parse::<WaveformMode>(), Ok(WaveformMode::Gc16));
+    assert!("gc42".parse::<WaveformMode>().is_err());
+}
+
+#[test]
+fn test_rotate_rect() {
+    assert_eq!(rotate_rect(0, 1872, 1404, (8, 10, 4, 2)), (8, 10, 4, 2));
+    assert_eq!(rotate_rect(90, 1872, 1404, (8, 10, 4, 2)), (1860, 8, 2, 4));
+    assert_eq!(rotate_rect(180, 1872, 1404, (8, 10, 4, 2)), (1860, 1392, 4, 2));
+    assert_eq!(rotate_rect(270, 1872, 1404, (8, 10, 4, 2)), (10, 1392, 2, 4));
+}
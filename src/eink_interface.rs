@@ -5,9 +5,9 @@ use std::time::Instant;
 use std::ffi::CString;
 use std::os::raw::c_char;
 use x11cap::Image;
+use std::error::Error;
 
-#[path = "it8951.rs"]
-mod it8951;
+use crate::eink_display::{EinkDisplay, WaveformMode};
 
 
 #[derive(WrapperApi)]
@@ -26,12 +26,19 @@ pub struct IT8952Api {
 
 pub struct Interface {
     api: Container<IT8952Api>,
+    // libIT8951 does not report the panel size
+    panel_width: u16,
+    panel_height: u16,
+    frame_buffer: Vec<u8>,
 }
 
 impl Interface {
-    pub fn new() -> Interface {
+    pub fn new(panel_width: u16, panel_height: u16) -> Interface {
         Interface {
             api: Interface::init(),
+            panel_width: panel_width,
+            panel_height: panel_height,
+            frame_buffer: Vec::new(),
         }
         
     }
@@ -65,3 +72,79 @@ impl Interface {
         }
     }
 }
+
+impl EinkDisplay for Interface {
+    fn size(&self) -> (u16, u16) {
+        (self.panel_width, self.panel_height)
+    }
+
+    fn load_buffer_from_vec(&mut self, grey_vec: Vec<u8>) {
+        self.frame_buffer = grey_vec;
+    }
+
+    // libIT8951 always refreshes with its own mode, mode is ignored
+    fn display(
+        &mut self,
+        x: u16,
+        y: u16,
+        rect_width: u16,
+        rect_height: u16,
+        _mode: WaveformMode,
+    ) -> Result<(), Box<dyn Error>> {
+        let byte_width: u16 = byte_width(rect_width);
+        for (y_in_area, grey_chunks) in buffer_lines(&self.frame_buffer, rect_width, rect_height) {
+            for (x_in_area, grey) in grey_chunks.iter().enumerate() {
+                self.draw_buffer_pixel(x_in_area as u16, y_in_area as u16, byte_width, *grey);
+            }
+        }
+
+        Interface::display(self, x, y, rect_width, rect_height);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+        let (width, height) = (self.panel_width, self.panel_height);
+        self.frame_buffer = vec![0xFF; width as usize * height as usize / 2];
+        EinkDisplay::display(self, 0, 0, width, height, WaveformMode::Init)
+    }
+
+    fn sleep(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn wake(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+// two pixels per byte, an odd pixel at the end of a line takes a whole byte
+fn byte_width(rect_width: u16) -> u16 {
+    rect_width.div_ceil(2)
+}
+
+// the lines of the area in the packed buffer, with their numbers
+fn buffer_lines(
+    frame_buffer: &[u8],
+    rect_width: u16,
+    rect_height: u16,
+) -> impl Iterator<Item = (usize, &[u8])> {
+    frame_buffer
+        .chunks(byte_width(rect_width).max(1) as usize)
+        .take(rect_height as usize)
+        .enumerate()
+}
+
+#[test]
+fn test_buffer_lines() {
+    let frame_buffer: Vec<u8> = vec![0x01, 0x23, 0x45, 0x67];
+    let lines = |width: u16, height: u16| -> Vec<(usize, Vec<u8>)> {
+        buffer_lines(&frame_buffer, width, height)
+            .map(|(line, greys)| (line, greys.to_vec()))
+            .collect()
+    };
+
+    assert_eq!(lines(4, 2), vec![(0, vec![0x01, 0x23]), (1, vec![0x45, 0x67])]);
+    // one pixel wide : a byte per line
+    assert_eq!(lines(1, 3), vec![(0, vec![0x01]), (1, vec![0x23]), (2, vec![0x45])]);
+    assert_eq!(lines(3, 1), vec![(0, vec![0x01, 0x23])]);
+}
@@ -0,0 +1,314 @@
+use miniz_oxide::inflate::decompress_to_vec;
+use std::collections::HashMap;
+use std::fs;
+
+extern crate custom_error;
+use custom_error::custom_error;
+
+// DejaVu Sans Mono, rendered in 16x32 cells
+static BUNDLED: &[u8] = include_bytes!("../resources/dejavu-sans-mono-16x32.psf");
+
+static PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+static PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+static GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// PSF1 mode bits
+static PSF1_MODE_512: u8 = 0x01;
+static PSF1_MODE_HAS_TABLE: u8 = 0x02;
+static PSF1_MODE_HAS_SEQUENCES: u8 = 0x04;
+// PSF1 unicode table : a sequence follows, end of the glyph
+static PSF1_START_SEQUENCE: u16 = 0xFFFE;
+static PSF1_SEPARATOR: u16 = 0xFFFF;
+// PSF2 flags and unicode table
+static PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+static PSF2_START_SEQUENCE: u8 = 0xFE;
+static PSF2_SEPARATOR: u8 = 0xFF;
+// gzip header flags
+static GZIP_EXTRA: u8 = 0x04;
+static GZIP_NAME: u8 = 0x08;
+static GZIP_COMMENT: u8 = 0x10;
+static GZIP_HEADER_CRC: u8 = 0x02;
+
+custom_error! {pub FontError
+    Read{path: String, reason: String} = "cannot read {path}: {reason}",
+    Format{reason: String} = "not a PSF font: {reason}"
+}
+
+fn format_error(reason: &str) -> FontError {
+    FontError::Format {
+        reason: reason.to_string(),
+    }
+}
+
+/// A bitmap font of the Linux console (PSF 1 or 2), every glyph in a cell of
+/// the same size
+#[derive(Clone)]
+pub struct Font {
+    width: u16,
+    height: u16,
+    bytes_per_row: usize,
+    // height rows of bytes_per_row bytes for each glyph, leftmost pixel in the high bit
+    glyphs: Vec<u8>,
+    glyph_count: usize,
+    map: HashMap<char, usize>,
+    // glyph of the characters missing from the font
+    fallback: usize,
+}
+
+impl Font {
+    /// The font built into ardoise
+    pub fn bundled() -> Font {
+        Font::parse(BUNDLED).expect("bundled font")
+    }
+
+    /// A .psf or .psf.gz file, e.g. from /usr/share/consolefonts
+    pub fn open(path: &str) -> Result<Font, FontError> {
+        match fs::read(path) {
+            Ok(data) => Font::parse(&data),
+            Err(error) => Err(FontError::Read {
+                path: path.to_string(),
+                reason: error.to_string(),
+            }),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Font, FontError> {
+        if data.starts_with(&GZIP_MAGIC) {
+            return Font::parse(&gunzip(data)?);
+        }
+        if data.starts_with(&PSF2_MAGIC) {
+            return parse_psf2(data);
+        }
+        if data.starts_with(&PSF1_MAGIC) {
+            return parse_psf1(data);
+        }
+        Err(format_error("unknown magic number"))
+    }
+
+    fn new(
+        width: u16,
+        height: u16,
+        glyphs: &[u8],
+        glyph_count: usize,
+        mut map: HashMap<char, usize>,
+    ) -> Result<Font, FontError> {
+        if width == 0 || height == 0 || glyph_count == 0 {
+            return Err(format_error("empty glyphs"));
+        }
+        let bytes_per_row = (width as usize).div_ceil(8);
+        let glyph_size = bytes_per_row * height as usize;
+        if glyphs.len() < glyph_size * glyph_count {
+            return Err(format_error("truncated glyphs"));
+        }
+        // without a unicode table, glyph n is character n
+        if map.is_empty() {
+            for n in 0..glyph_count.min(256) {
+                map.insert(n as u8 as char, n);
+            }
+        }
+        let fallback = ['\u{FFFD}', '?']
+            .iter()
+            .filter_map(|c| map.get(c))
+            .next()
+            .cloned()
+            .unwrap_or(0);
+        Ok(Font {
+            width,
+            height,
+            bytes_per_row,
+            glyphs: glyphs[..glyph_size * glyph_count].to_vec(),
+            glyph_count,
+            map,
+            fallback,
+        })
+    }
+
+    /// Width and height of a glyph, in pixels
+    pub fn cell_size(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+
+    /// Glyph drawing `c`, the replacement glyph when the font has none
+    pub fn glyph(&self, c: char) -> usize {
+        self.map.get(&c).cloned().unwrap_or(self.fallback)
+    }
+
+    /// True when the pixel at `x`, `y` of the glyph is inked
+    pub fn pixel(&self, glyph: usize, x: u16, y: u16) -> bool {
+        if glyph >= self.glyph_count || x >= self.width || y >= self.height {
+            return false;
+        }
+        let row = (glyph * self.height as usize + y as usize) * self.bytes_per_row;
+        self.glyphs[row + x as usize / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+fn parse_psf1(data: &[u8]) -> Result<Font, FontError> {
+    if data.len() < 4 {
+        return Err(format_error("truncated header"));
+    }
+    let mode = data[2];
+    let height = data[3] as usize;
+    let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+    let glyphs = &data[4..];
+    let mut map = HashMap::new();
+    if mode & (PSF1_MODE_HAS_TABLE | PSF1_MODE_HAS_SEQUENCES) != 0 {
+        let table = glyphs.get(glyph_count * height..).unwrap_or(&[]);
+        let mut glyph = 0;
+        // the characters before a sequence stand alone
+        let mut in_sequence = false;
+        for pair in table.chunks_exact(2) {
+            let value = u16::from_le_bytes([pair[0], pair[1]]);
+            if value == PSF1_SEPARATOR {
+                glyph += 1;
+                in_sequence = false;
+            } else if value == PSF1_START_SEQUENCE {
+                in_sequence = true;
+            } else if !in_sequence {
+                if let Some(c) = std::char::from_u32(value as u32) {
+                    map.entry(c).or_insert(glyph);
+                }
+            }
+        }
+    }
+    Font::new(8, height as u16, glyphs, glyph_count, map)
+}
+
+fn parse_psf2(data: &[u8]) -> Result<Font, FontError> {
+    if data.len() < 32 {
+        return Err(format_error("truncated header"));
+    }
+    let word = |n: usize| {
+        u32::from_le_bytes([
+            data[4 * n],
+            data[4 * n + 1],
+            data[4 * n + 2],
+            data[4 * n + 3],
+        ])
+    };
+    let header_size = word(2) as usize;
+    let flags = word(3);
+    let glyph_count = word(4) as usize;
+    let glyph_size = word(5) as usize;
+    let height = word(6);
+    let width = word(7);
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(format_error("glyphs too big"));
+    }
+    if glyph_size != (width as usize).div_ceil(8) * height as usize {
+        return Err(format_error(
+            "glyph size does not match the width and height",
+        ));
+    }
+    let glyphs = data.get(header_size..).unwrap_or(&[]);
+    let mut map = HashMap::new();
+    if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+        let table = glyphs.get(glyph_count * glyph_size..).unwrap_or(&[]);
+        for (glyph, entry) in table.split(|&byte| byte == PSF2_SEPARATOR).enumerate() {
+            // UTF-8 characters, then the sequences
+            let single = entry
+                .split(|&byte| byte == PSF2_START_SEQUENCE)
+                .next()
+                .unwrap_or(&[]);
+            if let Ok(single) = std::str::from_utf8(single) {
+                for c in single.chars() {
+                    map.entry(c).or_insert(glyph);
+                }
+            }
+        }
+    }
+    Font::new(width as u16, height as u16, glyphs, glyph_count, map)
+}
+
+/// Inflate a .gz file, the console fonts are often compressed
+fn gunzip(data: &[u8]) -> Result<Vec<u8>, FontError> {
+    if data.len() < 10 {
+        return Err(format_error("truncated gzip header"));
+    }
+    let flags = data[3];
+    let mut start = 10;
+    if flags & GZIP_EXTRA != 0 {
+        let length = data
+            .get(start..start + 2)
+            .ok_or_else(|| format_error("truncated gzip"))?;
+        start += 2 + u16::from_le_bytes([length[0], length[1]]) as usize;
+    }
+    for &flag in [GZIP_NAME, GZIP_COMMENT].iter() {
+        if flags & flag != 0 {
+            let end = data.iter().skip(start).position(|&byte| byte == 0);
+            start += end.ok_or_else(|| format_error("truncated gzip"))? + 1;
+        }
+    }
+    if flags & GZIP_HEADER_CRC != 0 {
+        start += 2;
+    }
+    match data.get(start..).map(decompress_to_vec) {
+        Some(Ok(inflated)) => Ok(inflated),
+        _ => Err(format_error("broken gzip")),
+    }
+}
+
+#[test]
+fn test_bundled_font() {
+    let font = Font::bundled();
+    assert_eq!(font.cell_size(), (16, 32));
+    assert_ne!(font.glyph('A'), font.glyph('B'));
+    assert_eq!(font.glyph('\u{10FFFF}'), font.glyph('\u{FFFD}'));
+
+    let inked = |c: char| {
+        let glyph = font.glyph(c);
+        (0..32)
+            .flat_map(|y| (0..16).map(move |x| (x, y)))
+            .filter(|&(x, y)| font.pixel(glyph, x, y))
+            .count()
+    };
+    assert_eq!(inked(' '), 0);
+    assert!(inked('M') > inked('.'));
+    assert!(inked('█') > inked('M'));
+}
+
+#[test]
+fn test_psf1_with_table() {
+    let mut data = vec![0x36, 0x04, PSF1_MODE_HAS_TABLE, 2];
+    // glyph 0 : top left pixel, glyph 1 : bottom right
+    for n in 0..256 {
+        match n {
+            0 => data.extend_from_slice(&[0x80, 0x00]),
+            1 => data.extend_from_slice(&[0x00, 0x01]),
+            _ => data.extend_from_slice(&[0x00, 0x00]),
+        }
+    }
+    for n in 0..256u16 {
+        match n {
+            0 => data.extend_from_slice(&[b'x', 0, 0xFE, 0xFF, b'y', 0, 0xFF, 0xFF]),
+            1 => data.extend_from_slice(&[0xAC, 0x20, 0xFF, 0xFF]),
+            _ => data.extend_from_slice(&[0xFF, 0xFF]),
+        }
+    }
+    let font = Font::parse(&data).unwrap();
+    assert_eq!(font.cell_size(), (8, 2));
+    assert_eq!(font.glyph('x'), 0);
+    assert_eq!(font.glyph('€'), 1);
+    // only in a sequence
+    assert_eq!(font.glyph('y'), font.fallback);
+    assert!(font.pixel(0, 0, 0));
+    assert!(!font.pixel(0, 1, 0));
+    assert!(font.pixel(1, 7, 1));
+    assert!(!font.pixel(1, 8, 1));
+}
+
+#[test]
+fn test_gzipped_psf2() {
+    let deflated = miniz_oxide::deflate::compress_to_vec(BUNDLED, 6);
+    let mut data = vec![0x1f, 0x8b, 8, GZIP_NAME, 0, 0, 0, 0, 0, 3];
+    data.extend_from_slice(b"font.psf\0");
+    data.extend_from_slice(&deflated);
+    // crc and size, unchecked
+    data.extend_from_slice(&[0; 8]);
+
+    let font = Font::parse(&data).unwrap();
+    let bundled = Font::bundled();
+    assert_eq!(font.cell_size(), bundled.cell_size());
+    assert_eq!(font.glyph('é'), bundled.glyph('é'));
+    assert!(Font::parse(b"not a font").is_err());
+    assert!(Font::parse(&data[..20]).is_err());
+}
@@ -0,0 +1,774 @@
+use libc::{c_ulong, c_void};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::Duration;
+use std::{ptr, slice};
+use x11cap::Bgr8;
+
+use crate::capture::{limit_damage, CaptureError, CaptureSource};
+use crate::imagery::{new_bgr8, Rect};
+
+// framebuffers tell nothing about changes, they are read that often
+pub static POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// from linux/fb.h
+static FBIOGET_VSCREENINFO: c_ulong = 0x4600;
+static FBIOGET_FSCREENINFO: c_ulong = 0x4602;
+
+// from drm/drm.h and drm/drm_mode.h
+static DRM_IOCTL_GEM_CLOSE: c_ulong = 0x4008_6409;
+static DRM_IOCTL_MODE_GETRESOURCES: c_ulong = 0xC040_64A0;
+static DRM_IOCTL_MODE_GETCRTC: c_ulong = 0xC068_64A1;
+static DRM_IOCTL_MODE_GETFB: c_ulong = 0xC01C_64AD;
+static DRM_IOCTL_MODE_MAP_DUMB: c_ulong = 0xC010_64B3;
+
+/// Place of a colour in a pixel, in bits from the least significant one
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Channel {
+    pub offset: u32,
+    pub length: u32,
+}
+
+/// Layout of the pixels of a framebuffer, little endian like the kernel describes them
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct PixelFormat {
+    pub bits_per_pixel: u32,
+    pub red: Channel,
+    pub green: Channel,
+    pub blue: Channel,
+}
+
+impl PixelFormat {
+    pub fn rgb565() -> PixelFormat {
+        PixelFormat::new(16, (11, 5), (5, 6), (0, 5))
+    }
+
+    /// Blue first in memory, the usual 32 bits layout
+    pub fn xrgb8888() -> PixelFormat {
+        PixelFormat::new(32, (16, 8), (8, 8), (0, 8))
+    }
+
+    pub fn new(
+        bits_per_pixel: u32,
+        red: (u32, u32),
+        green: (u32, u32),
+        blue: (u32, u32),
+    ) -> PixelFormat {
+        let channel = |(offset, length)| Channel {
+            offset,
+            length,
+        };
+        PixelFormat {
+            bits_per_pixel,
+            red: channel(red),
+            green: channel(green),
+            blue: channel(blue),
+        }
+    }
+
+    /// Format of a DRM framebuffer, which only tells its depth
+    pub fn from_depth(bits_per_pixel: u32, depth: u32) -> Option<PixelFormat> {
+        match (bits_per_pixel, depth) {
+            (16, 16) => Some(PixelFormat::rgb565()),
+            (32, 24) | (32, 32) => Some(PixelFormat::xrgb8888()),
+            (32, 30) => Some(PixelFormat::new(32, (20, 10), (10, 10), (0, 10))),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bits_per_pixel as usize / 8
+    }
+
+    /// Whether the pixels can be read : whole bytes, channels inside the pixel
+    pub fn is_supported(&self) -> bool {
+        let fits = |channel: &Channel| channel.offset + channel.length <= self.bits_per_pixel;
+        (2..=4).contains(&self.bytes_per_pixel())
+            && self.bits_per_pixel.is_multiple_of(8)
+            && fits(&self.red)
+            && fits(&self.green)
+            && fits(&self.blue)
+    }
+
+    /// Convert packed pixels, as many as both slices hold
+    pub fn convert(&self, bytes: &[u8], pixels: &mut [Bgr8]) {
+        let bytes_per_pixel = self.bytes_per_pixel();
+        for (raw, pixel) in bytes.chunks_exact(bytes_per_pixel).zip(pixels.iter_mut()) {
+            let value: u32 = raw
+                .iter()
+                .rev()
+                .fold(0, |value, byte| (value << 8) | *byte as u32);
+            *pixel = new_bgr8(
+                channel_value(value, self.blue),
+                channel_value(value, self.green),
+                channel_value(value, self.red),
+            );
+        }
+    }
+}
+
+// a channel brought to 8 bits, the short ones repeat their bits : 0x1F gives 0xFF
+fn channel_value(pixel: u32, channel: Channel) -> u8 {
+    if channel.length == 0 {
+        return 0;
+    }
+    let value = (pixel >> channel.offset) & ((1u64 << channel.length) - 1) as u32;
+    if channel.length >= 8 {
+        return (value >> (channel.length - 8)) as u8;
+    }
+    let length = channel.length as i32;
+    let mut result: u32 = 0;
+    let mut shift: i32 = 8 - length;
+    while shift > -length {
+        result |= if shift >= 0 {
+            value << shift
+        } else {
+            value >> -shift
+        };
+        shift -= length;
+    }
+    result as u8
+}
+
+/// Frame kept up to date from the raw pixels of a framebuffer. The lines that
+/// changed since the previous update are the damage.
+struct RawFrame {
+    width: u16,
+    height: u16,
+    format: PixelFormat,
+    // previous raw pixels, line after line without padding
+    raw: Vec<u8>,
+    frame: Vec<Bgr8>,
+    first_frame: bool,
+}
+
+impl RawFrame {
+    fn new(width: u16, height: u16, format: PixelFormat) -> RawFrame {
+        let pixel_count = width as usize * height as usize;
+        RawFrame {
+            width,
+            height,
+            format,
+            raw: vec![0; pixel_count * format.bytes_per_pixel()],
+            frame: vec![new_bgr8(255, 255, 255); pixel_count],
+            first_frame: true,
+        }
+    }
+
+    // lines `pitch` bytes apart in `raw`, None for a first whole frame
+    fn update(&mut self, raw: &[u8], pitch: usize) -> Option<Vec<Rect>> {
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        let line_length = self.width as usize * bytes_per_pixel;
+        let first_frame = self.first_frame;
+        self.first_frame = false;
+
+        let mut rects: Vec<Rect> = Vec::new();
+        // lines in a row that changed : first line, first and last column
+        let mut band: Option<(usize, usize, usize)> = None;
+        for line_n in 0..self.height as usize {
+            let new_line = &raw[line_n * pitch..line_n * pitch + line_length];
+            let old_line = &mut self.raw[line_n * line_length..(line_n + 1) * line_length];
+            let changed = if first_frame {
+                Some((0, self.width as usize - 1))
+            } else {
+                old_line
+                    .iter()
+                    .zip(new_line.iter())
+                    .position(|(old, new)| old != new)
+                    .map(|first_byte| {
+                        let last_byte = old_line
+                            .iter()
+                            .zip(new_line.iter())
+                            .rposition(|(old, new)| old != new)
+                            .unwrap();
+                        (first_byte / bytes_per_pixel, last_byte / bytes_per_pixel)
+                    })
+            };
+
+            if let Some((first_col, last_col)) = changed {
+                let bytes = first_col * bytes_per_pixel..(last_col + 1) * bytes_per_pixel;
+                old_line[bytes.clone()].copy_from_slice(&new_line[bytes.clone()]);
+                let start = line_n * self.width as usize;
+                self.format.convert(
+                    &new_line[bytes],
+                    &mut self.frame[start + first_col..=start + last_col],
+                );
+                band = Some(match band {
+                    Some((first_line, left, right)) => {
+                        (first_line, left.min(first_col), right.max(last_col))
+                    }
+                    None => (line_n, first_col, last_col),
+                });
+            } else if let Some(band) = band.take() {
+                rects.push(band_rect(band, line_n));
+            }
+        }
+        if let Some(band) = band {
+            rects.push(band_rect(band, self.height as usize));
+        }
+
+        if first_frame {
+            None
+        } else {
+            Some(limit_damage(rects))
+        }
+    }
+}
+
+fn band_rect(band: (usize, usize, usize), end_line: usize) -> Rect {
+    let (first_line, left, right) = band;
+    Rect {
+        x: left as u16,
+        y: first_line as u16,
+        width: (right - left + 1) as u16,
+        height: (end_line - first_line) as u16,
+    }
+}
+
+fn ioctl<T>(
+    file: &File,
+    request: c_ulong,
+    argument: *mut T,
+    what: &str,
+) -> Result<(), CaptureError> {
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), request, argument as *mut c_void) };
+    if result < 0 {
+        return Err(CaptureError::Grab {
+            reason: format!("{} failed: {}", what, io::Error::last_os_error()),
+        });
+    }
+    Ok(())
+}
+
+// read-write only where the ioctls need it, e.g. the DRM ones
+fn open_device(path: &str, write: bool) -> Result<File, CaptureError> {
+    OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open(path)
+        .map_err(|error| CaptureError::Open {
+            reason: format!("{}: {}", path, error),
+        })
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct FbBitfield {
+    offset: u32,
+    length: u32,
+    msb_right: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct FbVarScreeninfo {
+    xres: u32,
+    yres: u32,
+    xres_virtual: u32,
+    yres_virtual: u32,
+    xoffset: u32,
+    yoffset: u32,
+    bits_per_pixel: u32,
+    grayscale: u32,
+    red: FbBitfield,
+    green: FbBitfield,
+    blue: FbBitfield,
+    transp: FbBitfield,
+    nonstd: u32,
+    activate: u32,
+    height: u32,
+    width: u32,
+    accel_flags: u32,
+    // timings, unused
+    timings: [u32; 11],
+    reserved: [u32; 4],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct FbFixScreeninfo {
+    id: [u8; 16],
+    smem_start: c_ulong,
+    smem_len: u32,
+    fb_type: u32,
+    type_aux: u32,
+    visual: u32,
+    xpanstep: u16,
+    ypanstep: u16,
+    ywrapstep: u16,
+    line_length: u32,
+    mmio_start: c_ulong,
+    mmio_len: u32,
+    accel: u32,
+    capabilities: u16,
+    reserved: [u16; 2],
+}
+
+/// Reads a Linux framebuffer device, e.g. the console of a Pi without X
+pub struct FramebufferCapture {
+    device: File,
+    path: String,
+    // where the visible part starts in the device, and the bytes from a line to the next
+    offset: u64,
+    pitch: usize,
+    buffer: Vec<u8>,
+    frame: RawFrame,
+}
+
+impl FramebufferCapture {
+    pub fn open(path: &str) -> Result<FramebufferCapture, CaptureError> {
+        let device = open_device(path, false)?;
+        let mut capture = FramebufferCapture {
+            device,
+            path: path.to_string(),
+            offset: 0,
+            pitch: 0,
+            buffer: Vec::new(),
+            frame: RawFrame::new(0, 0, PixelFormat::xrgb8888()),
+        };
+        capture.update_geometry()?;
+        Ok(capture)
+    }
+
+    // read the resolution and format again, a new frame when they changed
+    fn update_geometry(&mut self) -> Result<(), CaptureError> {
+        let mut var_info = FbVarScreeninfo::default();
+        ioctl(
+            &self.device,
+            FBIOGET_VSCREENINFO,
+            &mut var_info,
+            "reading the framebuffer resolution",
+        )?;
+        let mut fix_info = FbFixScreeninfo::default();
+        ioctl(
+            &self.device,
+            FBIOGET_FSCREENINFO,
+            &mut fix_info,
+            "reading the framebuffer layout",
+        )?;
+
+        let channel = |bitfield: FbBitfield| Channel {
+            offset: bitfield.offset,
+            length: bitfield.length,
+        };
+        let format = PixelFormat {
+            bits_per_pixel: var_info.bits_per_pixel,
+            red: channel(var_info.red),
+            green: channel(var_info.green),
+            blue: channel(var_info.blue),
+        };
+        if !format.is_supported() || var_info.grayscale != 0 {
+            return Err(CaptureError::Open {
+                reason: format!("{}: unsupported pixel format {:?}", self.path, format),
+            });
+        }
+
+        // panned, e.g. double buffered
+        self.offset = var_info.yoffset as u64 * fix_info.line_length as u64
+            + var_info.xoffset as u64 * format.bytes_per_pixel() as u64;
+        self.pitch = fix_info.line_length as usize;
+        let (width, height) = (var_info.xres as u16, var_info.yres as u16);
+        // e.g. a disconnected output
+        if width == 0 || height == 0 {
+            return Err(CaptureError::Open {
+                reason: format!("{}: empty {}x{} picture", self.path, width, height),
+            });
+        }
+        if (width, height, format) != (self.frame.width, self.frame.height, self.frame.format) {
+            self.frame = RawFrame::new(width, height, format);
+            self.buffer = vec![0; self.pitch * height as usize];
+        }
+        Ok(())
+    }
+}
+
+impl CaptureSource for FramebufferCapture {
+    fn geometry(&self) -> (u16, u16) {
+        (self.frame.width, self.frame.height)
+    }
+
+    fn capture(&mut self) -> Result<Option<Vec<Rect>>, CaptureError> {
+        thread::sleep(POLL_INTERVAL);
+        self.update_geometry()?;
+        self.device
+            .read_exact_at(&mut self.buffer, self.offset)
+            .map_err(|error| CaptureError::Grab {
+                reason: format!("{}: {}", self.path, error),
+            })?;
+        Ok(self.frame.update(&self.buffer, self.pitch))
+    }
+
+    fn frame(&self) -> &[Bgr8] {
+        self.frame.frame.as_slice()
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeCardRes {
+    fb_id_ptr: u64,
+    crtc_id_ptr: u64,
+    connector_id_ptr: u64,
+    encoder_id_ptr: u64,
+    count_fbs: u32,
+    count_crtcs: u32,
+    count_connectors: u32,
+    count_encoders: u32,
+    min_width: u32,
+    max_width: u32,
+    min_height: u32,
+    max_height: u32,
+}
+
+#[repr(C)]
+struct DrmModeCrtc {
+    set_connectors_ptr: u64,
+    count_connectors: u32,
+    crtc_id: u32,
+    fb_id: u32,
+    x: u32,
+    y: u32,
+    gamma_size: u32,
+    mode_valid: u32,
+    // struct drm_mode_modeinfo, unused
+    mode: [u8; 68],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeFbCmd {
+    fb_id: u32,
+    width: u32,
+    height: u32,
+    pitch: u32,
+    bpp: u32,
+    depth: u32,
+    handle: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmModeMapDumb {
+    handle: u32,
+    pad: u32,
+    offset: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct DrmGemClose {
+    handle: u32,
+    pad: u32,
+}
+
+/// A framebuffer of a DRM card mapped in memory
+struct DrmMapping {
+    fb_id: u32,
+    pointer: *mut c_void,
+    length: usize,
+    pitch: usize,
+}
+
+impl Drop for DrmMapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.pointer, self.length);
+        }
+    }
+}
+
+/// Reads what a DRM card scans out, e.g. a kiosk without X. The first CRTC
+/// showing something is captured; mapping its buffers needs CAP_SYS_ADMIN.
+pub struct DrmCapture {
+    card: File,
+    path: String,
+    mapping: Option<DrmMapping>,
+    frame: RawFrame,
+}
+
+impl DrmCapture {
+    pub fn open(path: &str) -> Result<DrmCapture, CaptureError> {
+        let mut capture = DrmCapture {
+            card: open_device(path, true)?,
+            path: path.to_string(),
+            mapping: None,
+            frame: RawFrame::new(0, 0, PixelFormat::xrgb8888()),
+        };
+        capture.update_mapping()?;
+        Ok(capture)
+    }
+
+    // framebuffer shown by the first active CRTC
+    fn scanned_out_framebuffer(&self) -> Result<u32, CaptureError> {
+        let mut resources = DrmModeCardRes::default();
+        ioctl(
+            &self.card,
+            DRM_IOCTL_MODE_GETRESOURCES,
+            &mut resources,
+            "listing the CRTCs",
+        )?;
+        let mut crtc_ids: Vec<u32> = vec![0; resources.count_crtcs as usize];
+        // only the CRTCs are wanted, the other lists are left out
+        let mut resources = DrmModeCardRes {
+            crtc_id_ptr: crtc_ids.as_mut_ptr() as u64,
+            count_crtcs: crtc_ids.len() as u32,
+            ..DrmModeCardRes::default()
+        };
+        ioctl(
+            &self.card,
+            DRM_IOCTL_MODE_GETRESOURCES,
+            &mut resources,
+            "listing the CRTCs",
+        )?;
+
+        for crtc_id in crtc_ids.iter().take(resources.count_crtcs as usize) {
+            let mut crtc = DrmModeCrtc {
+                set_connectors_ptr: 0,
+                count_connectors: 0,
+                crtc_id: *crtc_id,
+                fb_id: 0,
+                x: 0,
+                y: 0,
+                gamma_size: 0,
+                mode_valid: 0,
+                mode: [0; 68],
+            };
+            ioctl(
+                &self.card,
+                DRM_IOCTL_MODE_GETCRTC,
+                &mut crtc,
+                "reading a CRTC",
+            )?;
+            if crtc.mode_valid != 0 && crtc.fb_id != 0 {
+                return Ok(crtc.fb_id);
+            }
+        }
+        Err(CaptureError::Grab {
+            reason: format!("{}: nothing is shown", self.path),
+        })
+    }
+
+    // map the scanned out framebuffer when it is another one, e.g. after a page flip
+    fn update_mapping(&mut self) -> Result<(), CaptureError> {
+        let fb_id = self.scanned_out_framebuffer()?;
+        if self.mapping.as_ref().map(|mapping| mapping.fb_id) == Some(fb_id) {
+            return Ok(());
+        }
+        self.mapping = None;
+
+        let mut fb = DrmModeFbCmd {
+            fb_id,
+            ..DrmModeFbCmd::default()
+        };
+        ioctl(
+            &self.card,
+            DRM_IOCTL_MODE_GETFB,
+            &mut fb,
+            "reading the framebuffer",
+        )?;
+        if fb.handle == 0 {
+            return Err(CaptureError::Open {
+                reason: format!("{}: reading the screen needs CAP_SYS_ADMIN", self.path),
+            });
+        }
+        let format = PixelFormat::from_depth(fb.bpp, fb.depth);
+
+        let mut map_dumb = DrmModeMapDumb {
+            handle: fb.handle,
+            ..DrmModeMapDumb::default()
+        };
+        let mapped = ioctl(
+            &self.card,
+            DRM_IOCTL_MODE_MAP_DUMB,
+            &mut map_dumb,
+            "mapping the framebuffer",
+        );
+        let length = fb.pitch as usize * fb.height as usize;
+        let pointer = match mapped {
+            Ok(()) => unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    length,
+                    libc::PROT_READ,
+                    libc::MAP_SHARED,
+                    self.card.as_raw_fd(),
+                    map_dumb.offset as libc::off_t,
+                )
+            },
+            Err(_) => libc::MAP_FAILED,
+        };
+        // the mapping keeps the buffer alive
+        let mut gem_close = DrmGemClose {
+            handle: fb.handle,
+            pad: 0,
+        };
+        ioctl(
+            &self.card,
+            DRM_IOCTL_GEM_CLOSE,
+            &mut gem_close,
+            "closing the buffer",
+        )?;
+        mapped?;
+        if pointer == libc::MAP_FAILED {
+            return Err(CaptureError::Grab {
+                reason: format!("{}: {}", self.path, io::Error::last_os_error()),
+            });
+        }
+        self.mapping = Some(DrmMapping {
+            fb_id,
+            pointer,
+            length,
+            pitch: fb.pitch as usize,
+        });
+
+        let format = format.ok_or(CaptureError::Open {
+            reason: format!(
+                "{}: unsupported pixel format, {} bits per pixel, depth {}",
+                self.path, fb.bpp, fb.depth
+            ),
+        })?;
+        let (width, height) = (fb.width as u16, fb.height as u16);
+        if (width, height, format) != (self.frame.width, self.frame.height, self.frame.format) {
+            self.frame = RawFrame::new(width, height, format);
+        }
+        Ok(())
+    }
+}
+
+impl CaptureSource for DrmCapture {
+    fn geometry(&self) -> (u16, u16) {
+        (self.frame.width, self.frame.height)
+    }
+
+    fn capture(&mut self) -> Result<Option<Vec<Rect>>, CaptureError> {
+        thread::sleep(POLL_INTERVAL);
+        self.update_mapping()?;
+        let mapping = self.mapping.as_ref().unwrap();
+        let raw: &[u8] =
+            unsafe { slice::from_raw_parts(mapping.pointer as *const u8, mapping.length) };
+        Ok(self.frame.update(raw, mapping.pitch))
+    }
+
+    fn frame(&self) -> &[Bgr8] {
+        self.frame.frame.as_slice()
+    }
+}
+
+#[test]
+fn test_kernel_struct_sizes() {
+    // the sizes are part of the ioctl numbers
+    assert_eq!(std::mem::size_of::<FbVarScreeninfo>(), 160);
+    assert_eq!(std::mem::size_of::<DrmModeCardRes>(), 64);
+    assert_eq!(std::mem::size_of::<DrmModeCrtc>(), 104);
+    assert_eq!(std::mem::size_of::<DrmModeFbCmd>(), 28);
+    assert_eq!(std::mem::size_of::<DrmModeMapDumb>(), 16);
+    assert_eq!(std::mem::size_of::<DrmGemClose>(), 8);
+    for (request, size) in [
+        (DRM_IOCTL_MODE_GETRESOURCES, 64),
+        (DRM_IOCTL_MODE_GETCRTC, 104),
+        (DRM_IOCTL_MODE_GETFB, 28),
+        (DRM_IOCTL_MODE_MAP_DUMB, 16),
+        (DRM_IOCTL_GEM_CLOSE, 8),
+    ]
+    .iter()
+    {
+        assert_eq!((request >> 16) & 0x3FFF, *size);
+    }
+}
+
+#[test]
+fn test_convert_rgb565() {
+    let format = PixelFormat::rgb565();
+    // white, red, green, blue, then a mid grey : 0b10000_100000_10000
+    let bytes: Vec<u8> = [0xFFFFu16, 0xF800, 0x07E0, 0x001F, 0x8410]
+        .iter()
+        .flat_map(|pixel| pixel.to_le_bytes().to_vec())
+        .collect();
+    let mut pixels = vec![new_bgr8(0, 0, 0); 5];
+    format.convert(&bytes, &mut pixels);
+    assert_eq!(
+        pixels,
+        vec![
+            new_bgr8(255, 255, 255),
+            new_bgr8(0, 0, 255),
+            new_bgr8(0, 255, 0),
+            new_bgr8(255, 0, 0),
+            new_bgr8(132, 130, 132),
+        ]
+    );
+}
+
+#[test]
+fn test_convert_32_bits() {
+    let mut pixels = vec![new_bgr8(0, 0, 0); 2];
+    // memory order B, G, R, X
+    PixelFormat::xrgb8888().convert(
+        &[0x10, 0x20, 0x30, 0x00, 0xFF, 0x00, 0x80, 0xFF],
+        &mut pixels,
+    );
+    assert_eq!(
+        pixels,
+        vec![new_bgr8(0x10, 0x20, 0x30), new_bgr8(0xFF, 0x00, 0x80)]
+    );
+
+    // BGRA8888, memory order A, R, G, B
+    PixelFormat::new(32, (8, 8), (16, 8), (24, 8)).convert(
+        &[0xFF, 0x30, 0x20, 0x10, 0x00, 0x80, 0x00, 0xFF],
+        &mut pixels,
+    );
+    assert_eq!(
+        pixels,
+        vec![new_bgr8(0x10, 0x20, 0x30), new_bgr8(0xFF, 0x00, 0x80)]
+    );
+
+    // 10 bits per channel
+    let format = PixelFormat::from_depth(32, 30).unwrap();
+    let pixel: u32 = (0x3FF << 20) | (0x200 << 10) | 0x001;
+    format.convert(&pixel.to_le_bytes(), &mut pixels);
+    assert_eq!(pixels[0], new_bgr8(0x00, 0x80, 0xFF));
+}
+
+#[test]
+fn test_unsupported_formats() {
+    assert!(PixelFormat::rgb565().is_supported());
+    assert!(!PixelFormat::new(8, (5, 3), (2, 3), (0, 2)).is_supported());
+    assert!(!PixelFormat::new(16, (11, 8), (5, 6), (0, 5)).is_supported());
+    assert_eq!(PixelFormat::from_depth(8, 8), None);
+}
+
+#[test]
+fn test_raw_frame_damage() {
+    let format = PixelFormat::rgb565();
+    // 4x3 pixels, lines padded to 10 bytes
+    let mut raw: Vec<u8> = vec![0xFF; 10 * 3];
+    let mut frame = RawFrame::new(4, 3, format);
+    assert_eq!(frame.update(&raw, 10), None);
+    assert!(frame
+        .frame
+        .iter()
+        .all(|pixel| *pixel == new_bgr8(255, 255, 255)));
+
+    // nothing changed, the padding does not count
+    raw[9] = 0;
+    assert_eq!(frame.update(&raw, 10), Some(Vec::new()));
+
+    // pixel 1 of line 1 and pixel 2 of line 2 go black
+    raw[10 + 2..10 + 4].copy_from_slice(&[0, 0]);
+    raw[20 + 4..20 + 6].copy_from_slice(&[0, 0]);
+    assert_eq!(
+        frame.update(&raw, 10),
+        Some(vec![Rect {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 2,
+        }])
+    );
+    let black: Vec<usize> = (0..12)
+        .filter(|n| frame.frame[*n] == new_bgr8(0, 0, 0))
+        .collect();
+    assert_eq!(black, vec![5, 10]);
+}
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+/// Counts the partial refreshes and tells when the ghosting they leave
+/// calls for a full refresh of the panel.
+pub struct GhostingControl {
+    panel_pixels: u64,
+    // 0 : no limit
+    max_partial_refreshes: u32,
+    // percentage of the panel area, 0 : no limit
+    max_refreshed_percent: u32,
+    idle_delay: Option<Duration>,
+    partial_refreshes: u32,
+    refreshed_pixels: u64,
+    last_refresh: Instant,
+}
+
+impl GhostingControl {
+    pub fn new(
+        panel_width: u16,
+        panel_height: u16,
+        max_partial_refreshes: u32,
+        max_refreshed_percent: u32,
+        idle_delay: Option<Duration>,
+    ) -> GhostingControl {
+        GhostingControl {
+            panel_pixels: panel_width as u64 * panel_height as u64,
+            max_partial_refreshes,
+            max_refreshed_percent,
+            idle_delay,
+            partial_refreshes: 0,
+            refreshed_pixels: 0,
+            last_refresh: Instant::now(),
+        }
+    }
+
+    pub fn record_partial_refresh(&mut self, width: u16, height: u16, now: Instant) {
+        self.partial_refreshes += 1;
+        self.refreshed_pixels += width as u64 * height as u64;
+        self.last_refresh = now;
+    }
+
+    pub fn needs_full_refresh(&self, now: Instant) -> bool {
+        if self.partial_refreshes == 0 {
+            return false;
+        }
+        if self.max_partial_refreshes > 0 && self.partial_refreshes >= self.max_partial_refreshes {
+            return true;
+        }
+        if self.max_refreshed_percent > 0
+            && self.refreshed_pixels * 100 >= self.panel_pixels * self.max_refreshed_percent as u64
+        {
+            return true;
+        }
+        match self.idle_delay {
+            Some(delay) => now.duration_since(self.last_refresh) >= delay,
+            None => false,
+        }
+    }
+
+    pub fn full_refresh_done(&mut self, now: Instant) {
+        self.partial_refreshes = 0;
+        self.refreshed_pixels = 0;
+        self.last_refresh = now;
+    }
+}
+
+#[test]
+fn test_full_refresh_after_partial_count() {
+    let now = Instant::now();
+    let mut ghosting = GhostingControl::new(1872, 1404, 3, 0, None);
+
+    ghosting.record_partial_refresh(16, 16, now);
+    ghosting.record_partial_refresh(16, 16, now);
+    assert!(!ghosting.needs_full_refresh(now));
+
+    ghosting.record_partial_refresh(16, 16, now);
+    assert!(ghosting.needs_full_refresh(now));
+
+    ghosting.full_refresh_done(now);
+    assert!(!ghosting.needs_full_refresh(now));
+}
+
+#[test]
+fn test_full_refresh_after_refreshed_area() {
+    let now = Instant::now();
+    let mut ghosting = GhostingControl::new(100, 100, 0, 150, None);
+
+    ghosting.record_partial_refresh(100, 100, now);
+    assert!(!ghosting.needs_full_refresh(now));
+
+    ghosting.record_partial_refresh(100, 50, now);
+    assert!(ghosting.needs_full_refresh(now));
+}
+
+#[test]
+fn test_full_refresh_after_idle() {
+    let now = Instant::now();
+    let mut ghosting = GhostingControl::new(1872, 1404, 0, 0, Some(Duration::from_secs(10)));
+
+    // nothing to clean yet
+    assert!(!ghosting.needs_full_refresh(now + Duration::from_secs(60)));
+
+    ghosting.record_partial_refresh(16, 16, now);
+    assert!(!ghosting.needs_full_refresh(now + Duration::from_secs(9)));
+    assert!(ghosting.needs_full_refresh(now + Duration::from_secs(10)));
+
+    ghosting.full_refresh_done(now + Duration::from_secs(10));
+    assert!(!ghosting.needs_full_refresh(now + Duration::from_secs(60)));
+}
@@ -2,8 +2,14 @@ use rayon::prelude::*;
 use x11cap::{Bgr8, Image};
 use captrs::Capturer;
 
-#[path = "eink_interface.rs"]
-mod eink_interface;
+use crate::dithering::{dither_area, DitherMode};
+use crate::eink_interface;
+
+// side of the square tiles used to find the changed areas
+static TILE_SIZE: u16 = 16;
+// what one more refresh costs, in refreshed pixels : two areas are
+// merged when refreshing the space between them is cheaper than that
+static REFRESH_COST: u32 = 48 * 48;
 
 pub struct Imagery {
     pub eink_width: u16,
@@ -11,6 +17,8 @@ pub struct Imagery {
     capture_width: u16,
     capture_height: u16,
     rotation: u16,
+    // the panel controller rotates the areas, rotate() is not used
+    hardware_rotation: bool,
 }
 
 impl Imagery {
@@ -27,11 +35,17 @@ impl Imagery {
             capture_width: capture_width,
             capture_height: capture_height,
             rotation: rotation,
+            hardware_rotation: false,
         };
 
         imagery
     }
 
+    /// Areas are sent unrotated, for a panel rotating them itself
+    pub fn set_hardware_rotation(&mut self, hardware_rotation: bool) {
+        self.hardware_rotation = hardware_rotation;
+    }
+
     pub fn get_slice_adapted_to_eink_size(&self, image: &Option<Image>) -> Vec<Bgr8> {
         let bgr_slice = image.as_ref().unwrap().as_slice();
         let screen_width: u32 = image.as_ref().unwrap().get_dimensions().0 as u32;
@@ -96,10 +110,292 @@ impl Imagery {
         self.create_pixel_area(geometry, new_slice)
     }
 
-    pub fn compare(&self, old_image: &Option<Image>, new_image: &Option<Image>) -> Option<Area> {
-        if new_image.is_none() {
+    /// Like compare_image_slices, but distant changes give separate areas
+    pub fn find_changed_areas(&self, old_slice: &[Bgr8], new_slice: &[Bgr8]) -> Vec<Area> {
+        self.changed_areas(old_slice, new_slice, None)
+    }
+
+    /// Like find_changed_areas, but only looks inside the given rectangles of the capture,
+    /// e.g. the damage reported by the X server
+    pub fn compare_regions(
+        &self,
+        old_slice: &[Bgr8],
+        new_slice: &[Bgr8],
+        regions: &[Rect],
+    ) -> Vec<Area> {
+        self.changed_areas(old_slice, new_slice, Some(regions))
+    }
+
+    fn changed_areas(
+        &self,
+        old_slice: &[Bgr8],
+        new_slice: &[Bgr8],
+        regions: Option<&[Rect]>,
+    ) -> Vec<Area> {
+        let (col_limit, line_limit) = self.comparable_size(new_slice);
+        if col_limit == 0 || line_limit == 0 {
+            return Vec::new();
+        }
+        let candidate_tiles: Option<Vec<Vec<bool>>> =
+            regions.map(|regions| Imagery::tiles_in_regions(regions, col_limit, line_limit));
+
+        let boxes: Vec<[u16; 4]> = self.merge_tile_boxes(self.dirty_tile_boxes(
+            old_slice,
+            new_slice,
+            col_limit,
+            line_limit,
+            candidate_tiles.as_ref(),
+        ));
+
+        boxes
+            .iter()
+            .filter_map(|tile_box| {
+                let geometry =
+                    self.refine_tile_box(old_slice, new_slice, *tile_box, col_limit, line_limit)?;
+                self.create_pixel_area(geometry, new_slice)
+            })
+            .collect()
+    }
+
+    /// Area of everything of the capture that ends up on the panel
+    pub fn full_area(&self, new_slice: &[Bgr8]) -> Option<Area> {
+        let (col_limit, line_limit) = self.comparable_size(new_slice);
+        if col_limit == 0 || line_limit == 0 {
             return None;
         }
+        self.create_pixel_area([0, 0, col_limit, line_limit], new_slice)
+    }
+
+    // columns and lines of the capture that end up on the panel
+    fn comparable_size(&self, slice: &[Bgr8]) -> (u16, u16) {
+        let (eink_cols, eink_lines) = if self.rotation == 90 || self.rotation == 270 {
+            (self.eink_height, self.eink_width)
+        } else {
+            (self.eink_width, self.eink_height)
+        };
+        if self.capture_width == 0 {
+            return (0, 0);
+        }
+        let captured_lines: usize = slice.len() / self.capture_width as usize;
+
+        (
+            eink_cols.min(self.capture_width),
+            (eink_lines as usize).min(captured_lines) as u16,
+        )
+    }
+
+    // tiles touched by the regions, lines of tiles first
+    fn tiles_in_regions(regions: &[Rect], col_limit: u16, line_limit: u16) -> Vec<Vec<bool>> {
+        let tile_columns: usize = col_limit.div_ceil(TILE_SIZE) as usize;
+        let tile_lines: usize = line_limit.div_ceil(TILE_SIZE) as usize;
+        let mut tiles: Vec<Vec<bool>> = vec![vec![false; tile_columns]; tile_lines];
+
+        for region in regions {
+            if region.width == 0 || region.height == 0 {
+                continue;
+            }
+            if region.x >= col_limit || region.y >= line_limit {
+                continue;
+            }
+            let last_col: u32 =
+                (region.x as u32 + region.width as u32 - 1).min(col_limit as u32 - 1);
+            let last_line: u32 =
+                (region.y as u32 + region.height as u32 - 1).min(line_limit as u32 - 1);
+            let first_tile_x: usize = (region.x / TILE_SIZE) as usize;
+            let first_tile_y: usize = (region.y / TILE_SIZE) as usize;
+            let last_tile_x: usize = (last_col / TILE_SIZE as u32) as usize;
+            let last_tile_y: usize = (last_line / TILE_SIZE as u32) as usize;
+            for tile_line in tiles[first_tile_y..=last_tile_y].iter_mut() {
+                for tile in tile_line[first_tile_x..=last_tile_x].iter_mut() {
+                    *tile = true;
+                }
+            }
+        }
+        tiles
+    }
+
+    // bounding boxes, in tiles [x0, y0, x1, y1] inclusive, of the groups of touching changed tiles,
+    // only looking at the candidate tiles when some are given
+    fn dirty_tile_boxes(
+        &self,
+        old_slice: &[Bgr8],
+        new_slice: &[Bgr8],
+        col_limit: u16,
+        line_limit: u16,
+        candidate_tiles: Option<&Vec<Vec<bool>>>,
+    ) -> Vec<[u16; 4]> {
+        let tile_columns: usize = col_limit.div_ceil(TILE_SIZE) as usize;
+        let tile_lines: usize = line_limit.div_ceil(TILE_SIZE) as usize;
+        let capture_width: usize = self.capture_width as usize;
+
+        let dirty_tiles: Vec<Vec<bool>> = (0..tile_lines)
+            .into_par_iter()
+            .map(|tile_y| {
+                let mut dirty_line: Vec<bool> = vec![false; tile_columns];
+                let candidate_line: Option<&Vec<bool>> =
+                    candidate_tiles.map(|candidates| &candidates[tile_y]);
+                if candidate_line.is_some_and(|candidates| !candidates.contains(&true)) {
+                    return dirty_line;
+                }
+                let first_line: usize = tile_y * TILE_SIZE as usize;
+                let last_line: usize = (first_line + TILE_SIZE as usize).min(line_limit as usize);
+                for line_n in first_line..last_line {
+                    let start = line_n * capture_width;
+                    let old_line = &old_slice[start..start + col_limit as usize];
+                    let new_line = &new_slice[start..start + col_limit as usize];
+                    if candidate_line.is_none() && old_line == new_line {
+                        continue;
+                    }
+                    let old_tiles = old_line.chunks(TILE_SIZE as usize);
+                    let new_tiles = new_line.chunks(TILE_SIZE as usize);
+                    for (tile_x, (old_tile, new_tile)) in old_tiles.zip(new_tiles).enumerate() {
+                        let candidate =
+                            candidate_line.is_none_or(|candidates| candidates[tile_x]);
+                        if candidate && !dirty_line[tile_x] && old_tile != new_tile {
+                            dirty_line[tile_x] = true;
+                        }
+                    }
+                }
+                dirty_line
+            })
+            .collect();
+
+        // group touching tiles, diagonals included
+        let mut visited: Vec<Vec<bool>> = vec![vec![false; tile_columns]; tile_lines];
+        let mut boxes: Vec<[u16; 4]> = Vec::new();
+        for start_y in 0..tile_lines {
+            for start_x in 0..tile_columns {
+                if !dirty_tiles[start_y][start_x] || visited[start_y][start_x] {
+                    continue;
+                }
+                let mut tile_box: [u16; 4] = [
+                    start_x as u16,
+                    start_y as u16,
+                    start_x as u16,
+                    start_y as u16,
+                ];
+                let mut stack: Vec<(usize, usize)> = vec![(start_x, start_y)];
+                visited[start_y][start_x] = true;
+                while let Some((x, y)) = stack.pop() {
+                    tile_box[0] = tile_box[0].min(x as u16);
+                    tile_box[1] = tile_box[1].min(y as u16);
+                    tile_box[2] = tile_box[2].max(x as u16);
+                    tile_box[3] = tile_box[3].max(y as u16);
+                    for neighbour_y in y.saturating_sub(1)..(y + 2).min(tile_lines) {
+                        for neighbour_x in x.saturating_sub(1)..(x + 2).min(tile_columns) {
+                            if dirty_tiles[neighbour_y][neighbour_x]
+                                && !visited[neighbour_y][neighbour_x]
+                            {
+                                visited[neighbour_y][neighbour_x] = true;
+                                stack.push((neighbour_x, neighbour_y));
+                            }
+                        }
+                    }
+                }
+                boxes.push(tile_box);
+            }
+        }
+        boxes
+    }
+
+    // merge the boxes overlapping each other or close enough to be refreshed together,
+    // each box growing against the ones kept so far instead of starting over
+    fn merge_tile_boxes(&self, boxes: Vec<[u16; 4]>) -> Vec<[u16; 4]> {
+        let tile_pixels: u32 = TILE_SIZE as u32 * TILE_SIZE as u32;
+        let cost = |tile_box: &[u16; 4]| -> u32 {
+            (tile_box[2] - tile_box[0] + 1) as u32
+                * (tile_box[3] - tile_box[1] + 1) as u32
+                * tile_pixels
+        };
+        let union_of = |a: &[u16; 4], b: &[u16; 4]| -> [u16; 4] {
+            [
+                a[0].min(b[0]),
+                a[1].min(b[1]),
+                a[2].max(b[2]),
+                a[3].max(b[3]),
+            ]
+        };
+        let mergeable = |a: &[u16; 4], b: &[u16; 4]| -> bool {
+            let overlap = a[0] <= b[2] && b[0] <= a[2] && a[1] <= b[3] && b[1] <= a[3];
+            overlap || cost(&union_of(a, b)) <= cost(a) + cost(b) + REFRESH_COST
+        };
+
+        // no two kept boxes can be merged
+        let mut kept: Vec<[u16; 4]> = Vec::with_capacity(boxes.len());
+        for mut tile_box in boxes {
+            while let Some(index) = kept.iter().position(|other| mergeable(&tile_box, other)) {
+                tile_box = union_of(&tile_box, &kept.swap_remove(index));
+            }
+            kept.push(tile_box);
+        }
+        kept
+    }
+
+    // exact changed pixels [x, y, width, height] inside a box of tiles
+    fn refine_tile_box(
+        &self,
+        old_slice: &[Bgr8],
+        new_slice: &[Bgr8],
+        tile_box: [u16; 4],
+        col_limit: u16,
+        line_limit: u16,
+    ) -> Option<[u16; 4]> {
+        let first_col: usize = (tile_box[0] * TILE_SIZE) as usize;
+        let end_col: usize =
+            (((tile_box[2] + 1) as u32 * TILE_SIZE as u32).min(col_limit as u32)) as usize;
+        let first_line: usize = (tile_box[1] * TILE_SIZE) as usize;
+        let end_line: usize =
+            (((tile_box[3] + 1) as u32 * TILE_SIZE as u32).min(line_limit as u32)) as usize;
+
+        let mut min_col: Option<usize> = None;
+        let mut max_col: Option<usize> = None;
+        let mut min_line: Option<usize> = None;
+        let mut max_line: Option<usize> = None;
+
+        for line_n in first_line..end_line {
+            let start = line_n * self.capture_width as usize;
+            let old_line = &old_slice[start + first_col..start + end_col];
+            let new_line = &new_slice[start + first_col..start + end_col];
+            let first_change = old_line
+                .iter()
+                .zip(new_line.iter())
+                .position(|(old, new)| old != new);
+            let first_change = match first_change {
+                Some(col) => col,
+                None => continue,
+            };
+            let last_change = old_line
+                .iter()
+                .zip(new_line.iter())
+                .rposition(|(old, new)| old != new)
+                .unwrap();
+
+            if min_line.is_none() {
+                min_line = Some(line_n);
+            }
+            max_line = Some(line_n);
+            min_col = Some(min_col.map_or(first_col + first_change, |col| {
+                col.min(first_col + first_change)
+            }));
+            max_col = Some(max_col.map_or(first_col + last_change, |col| {
+                col.max(first_col + last_change)
+            }));
+        }
+
+        let (min_col, max_col) = (min_col?, max_col?);
+        let (min_line, max_line) = (min_line?, max_line?);
+
+        Some([
+            min_col as u16,
+            min_line as u16,
+            (max_col - min_col + 1) as u16,
+            (max_line - min_line + 1) as u16,
+        ])
+    }
+    pub fn compare(&self, old_image: &Option<Image>, new_image: &Option<Image>) -> Vec<Area> {
+        if new_image.is_none() {
+            return Vec::new();
+        }
 
           let new_slice: &[Bgr8] = new_image.as_ref().unwrap().as_slice();
 
@@ -125,7 +421,7 @@ impl Imagery {
          
 
         //let width: u16 = new_image.as_ref().unwrap().get_dimensions().0 as u16;
-        self.compare_image_slices(old_slice, new_slice)
+        self.find_changed_areas(old_slice, new_slice)
     }
 
     pub fn rotate(&self, base_area: Area, rotation_angle: u16) -> Area {
@@ -169,10 +465,69 @@ impl Imagery {
                 }
             }
 
+            180 => {
+                let rotated_x = self.eink_width - base_area.x - base_area.width;
+                let rotated_y = self.eink_height - base_area.y - base_area.height;
+
+                // upside down : last pixel first
+                let mut rotated_bgr_vector: Vec<Bgr8> = base_area.bgr_vec;
+                rotated_bgr_vector.reverse();
+
+                Area {
+                    x: rotated_x,
+                    y: rotated_y,
+                    width: base_area.width,
+                    height: base_area.height,
+                    bgr_vec: rotated_bgr_vector,
+                }
+            }
+
+            270 => {
+                let rotated_x = base_area.y;
+                let rotated_y = self.eink_height - base_area.x - base_area.width;
+                let rotated_width = base_area.height;
+                let rotated_height = base_area.width;
+
+                let base_bgr_vec: Vec<Bgr8> = base_area.bgr_vec;
+
+                let rotated_bgr_vec_vec: Vec<Vec<Bgr8>> = (0..rotated_height)
+                    .into_par_iter()
+                    .map(|line_number| {
+                        // rotated line n is the base column (width - 1 - n), top to bottom
+                        let base_col = rotated_height as usize - 1 - line_number as usize;
+                        let mut sub_bgr_vec: Vec<Bgr8> = Vec::new();
+
+                        for index in (0..rotated_width).into_iter() {
+                            let calculated_index =
+                                index as usize * rotated_height as usize + base_col;
+                            sub_bgr_vec.push(base_bgr_vec[calculated_index]);
+                        }
+
+                        sub_bgr_vec
+                    })
+                    .collect();
+
+                let rotated_bgr_vector = rotated_bgr_vec_vec.concat();
+
+                Area {
+                    x: rotated_x,
+                    y: rotated_y,
+                    width: rotated_width,
+                    height: rotated_height,
+                    bgr_vec: rotated_bgr_vector,
+                }
+            }
+
             _ => base_area,
         }
     }
 
+    /// Dither an area found by compare, before it is rotated.
+    /// `new_slice` is the capture the area was taken from.
+    pub fn dither(&self, area: Area, new_slice: &[Bgr8], mode: DitherMode) -> Area {
+        dither_area(area, new_slice, self.capture_width, mode)
+    }
+
     fn transform_to_grey(bgr_vec: &Vec<Bgr8>) -> Vec<u8> {
         let mut grey_vector: Vec<u8> = Vec::new();
 
@@ -189,6 +544,18 @@ impl Imagery {
         grey_vector
     }
 
+    /// True when the rectangle of the area only shows black and white in the slice,
+    /// e.g. what the panel showed before the area is refreshed
+    pub fn is_monochrome(&self, slice: &[Bgr8], area: &Area) -> bool {
+        (area.y as usize..area.y as usize + area.height as usize).all(|line| {
+            let start: usize = line * self.capture_width as usize + area.x as usize;
+            slice[start..start + area.width as usize].iter().all(|bgr| {
+                let grey = grey_4bpp(bgr);
+                grey == 0 || grey == 0x0F
+            })
+        })
+    }
+
     pub fn transform_to_grey_4bpp(bgr_vec: &Vec<Bgr8>) -> Vec<u8> {
         let bgr_vec_len = bgr_vec.len();
         let mut grey_vector: Vec<u8> = Vec::new();
@@ -196,14 +563,7 @@ impl Imagery {
 
         assert_eq!(bgr_vec_len.rem_euclid(4), 0);
         let mut index: u32 = 0;
-        let grey_par_iter = bgr_vec.par_iter().map(|bgr| {
-            let r: f32 = bgr.r.into();
-            let g: f32 = bgr.g.into();
-            let b: f32 = bgr.b.into();
-            let grey: f32 = (r * 0.2125 + g * 0.7154 + b * 0.0721).div_euclid(16.0);
-            let result: u8 = grey as u8;
-            result
-        });
+        let grey_par_iter = bgr_vec.par_iter().map(grey_4bpp);
 
         for grey_u8 in grey_par_iter.collect::<Vec<u8>>().iter() {
             if index.rem_euclid(2) > 0 {
@@ -388,7 +748,14 @@ impl Imagery {
          );  */
         // shift x :
 
-        if self.rotation == 0 || self.rotation == 180 {
+        // unrotated areas are packed along their lines
+        let capture_line_width: u16 = if self.rotation == 90 || self.rotation == 270 {
+            self.eink_height
+        } else {
+            self.eink_width
+        };
+
+        if self.rotation == 0 || self.rotation == 180 || self.hardware_rotation {
             if changed_area_x.rem_euclid(4) > 0 {
                 let x_shift: u16 = changed_area_x.rem_euclid(4);
                 changed_area_x -= x_shift;
@@ -402,7 +769,7 @@ impl Imagery {
                 changed_area_width += shift_width;
             }
             // if superior to screen width, move x to the left
-            if (changed_area_x + changed_area_width) > self.eink_width {
+            if (changed_area_x + changed_area_width) > capture_line_width {
                 //println!("+++ end shift used");
                 changed_area_x -= 4;
             }
@@ -488,6 +855,29 @@ impl Imagery {
     }
 }
 
+// 0 black to 15 white
+fn grey_4bpp(bgr: &Bgr8) -> u8 {
+    let r: f32 = bgr.r.into();
+    let g: f32 = bgr.g.into();
+    let b: f32 = bgr.b.into();
+    (r * 0.2125 + g * 0.7154 + b * 0.0721).div_euclid(16.0) as u8
+}
+
+/// Build a Bgr8 without a capture, its padding field is private
+pub fn new_bgr8(b: u8, g: u8, r: u8) -> Bgr8 {
+    // Bgr8 is #[repr(C)] : b, g, r then the padding byte
+    unsafe { std::mem::transmute::<[u8; 4], Bgr8>([b, g, r, 0]) }
+}
+
+/// Rectangle of the capture, in pixels
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Area {
     pub x: u16,
@@ -1129,3 +1519,358 @@ fn test_rotation_90() {
 
     assert_eq!(rotated_area.bgr_vec, rotated_bgr_vec);
 }
+
+#[test]
+fn test_find_changed_areas_far_apart() {
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+
+    let imagery = Imagery::new(256, 128, 256, 128, 0);
+    let old_slice = vec![white; 256 * 128];
+    let mut new_slice = old_slice.clone();
+    // a clock in the top left corner, a character in the bottom right one
+    new_slice[2 * 256 + 5] = black;
+    new_slice[120 * 256 + 250] = black;
+
+    let mut areas = imagery.find_changed_areas(old_slice.as_slice(), new_slice.as_slice());
+    areas.sort_by_key(|area| area.x);
+    assert_eq!(areas.len(), 2);
+
+    assert_eq!(
+        (areas[0].x, areas[0].y, areas[0].width, areas[0].height),
+        (4, 2, 4, 1)
+    );
+    assert_eq!(areas[0].bgr_vec, vec![white, black, white, white]);
+    assert_eq!(
+        (areas[1].x, areas[1].y, areas[1].width, areas[1].height),
+        (248, 120, 4, 1)
+    );
+}
+
+#[test]
+fn test_find_changed_areas_close_together() {
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+
+    let imagery = Imagery::new(256, 128, 256, 128, 0);
+    let old_slice = vec![white; 256 * 128];
+    let mut new_slice = old_slice.clone();
+    new_slice[10 * 256 + 10] = black;
+    new_slice[10 * 256 + 40] = black;
+
+    let areas = imagery.find_changed_areas(old_slice.as_slice(), new_slice.as_slice());
+    assert_eq!(areas.len(), 1);
+    assert_eq!(
+        (areas[0].x, areas[0].y, areas[0].width, areas[0].height),
+        (8, 10, 36, 1)
+    );
+}
+
+#[test]
+fn test_find_changed_areas_nothing() {
+    let white = new_bgr8(255, 255, 255);
+
+    let imagery = Imagery::new(256, 128, 256, 128, 0);
+    let old_slice = vec![white; 256 * 128];
+
+    let areas = imagery.find_changed_areas(old_slice.as_slice(), old_slice.as_slice());
+    assert!(areas.is_empty());
+}
+
+#[test]
+fn test_find_changed_areas_out_of_panel() {
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+
+    // capture wider and taller than the panel
+    let imagery = Imagery::new(64, 32, 128, 64, 0);
+    let old_slice = vec![white; 128 * 64];
+    let mut new_slice = old_slice.clone();
+    new_slice[5 * 128 + 100] = black;
+    new_slice[40 * 128 + 10] = black;
+
+    let areas = imagery.find_changed_areas(old_slice.as_slice(), new_slice.as_slice());
+    assert!(areas.is_empty());
+}
+
+#[test]
+fn test_find_changed_areas_disjoint() {
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+
+    let imagery = Imagery::new(512, 512, 512, 512, 0);
+    let old_slice = vec![white; 512 * 512];
+    let mut new_slice = old_slice.clone();
+    for n in 0..40 {
+        new_slice[(30 + n) * 512 + 300] = black;
+        new_slice[300 * 512 + 30 + n] = black;
+        new_slice[(250 + n) * 512 + 250 + n] = black;
+    }
+
+    let areas = imagery.find_changed_areas(old_slice.as_slice(), new_slice.as_slice());
+    assert!(!areas.is_empty());
+    for (i, a) in areas.iter().enumerate() {
+        for b in areas.iter().skip(i + 1) {
+            let overlap = a.x < b.x + b.width
+                && b.x < a.x + a.width
+                && a.y < b.y + b.height
+                && b.y < a.y + a.height;
+            assert!(!overlap);
+        }
+    }
+    let changed: usize = areas
+        .iter()
+        .map(|area| area.bgr_vec.iter().filter(|bgr| **bgr == black).count())
+        .sum();
+    assert_eq!(changed, 120);
+}
+
+#[test]
+fn test_compare_regions() {
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+
+    let imagery = Imagery::new(256, 128, 256, 128, 0);
+    let old_slice = vec![white; 256 * 128];
+    let mut new_slice = old_slice.clone();
+    new_slice[2 * 256 + 5] = black;
+    new_slice[120 * 256 + 250] = black;
+
+    // only the bottom right corner was damaged
+    let damage = [Rect {
+        x: 240,
+        y: 110,
+        width: 16,
+        height: 18,
+    }];
+    let areas = imagery.compare_regions(old_slice.as_slice(), new_slice.as_slice(), &damage);
+    assert_eq!(areas.len(), 1);
+    assert_eq!(
+        (areas[0].x, areas[0].y, areas[0].width, areas[0].height),
+        (248, 120, 4, 1)
+    );
+
+    // damage out of the panel, or empty
+    let damage = [
+        Rect {
+            x: 300,
+            y: 0,
+            width: 16,
+            height: 16,
+        },
+        Rect {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+        },
+    ];
+    let areas = imagery.compare_regions(old_slice.as_slice(), new_slice.as_slice(), &damage);
+    assert!(areas.is_empty());
+
+    // damage covering everything finds the same areas as a full scan
+    let damage = [Rect {
+        x: 0,
+        y: 0,
+        width: 256,
+        height: 128,
+    }];
+    assert_eq!(
+        imagery.compare_regions(old_slice.as_slice(), new_slice.as_slice(), &damage),
+        imagery.find_changed_areas(old_slice.as_slice(), new_slice.as_slice())
+    );
+}
+
+#[test]
+fn test_full_area() {
+    let white = new_bgr8(255, 255, 255);
+
+    // capture wider than the panel, but not as tall
+    let imagery = Imagery::new(64, 32, 128, 16, 0);
+    let slice = vec![white; 128 * 16];
+
+    let area = imagery.full_area(slice.as_slice()).unwrap();
+    assert_eq!((area.x, area.y, area.width, area.height), (0, 0, 64, 16));
+    assert_eq!(area.bgr_vec.len(), 64 * 16);
+}
+
+#[test]
+fn test_rotation_180() {
+    let imagery = Imagery::new(1872, 1404, 4, 2, 180);
+
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+
+    let base_bgr_vec: Vec<Bgr8> = vec![black, white, white, white, white, white, black, white];
+
+    let base_area = Area {
+        x: 5,
+        y: 10,
+        width: 4,
+        height: 2,
+        bgr_vec: base_bgr_vec,
+    };
+
+    let rotated_area = imagery.rotate(base_area.clone(), 180);
+
+    assert_eq!(
+        rotated_area.x,
+        imagery.eink_width - base_area.x - base_area.width
+    );
+    assert_eq!(
+        rotated_area.y,
+        imagery.eink_height - base_area.y - base_area.height
+    );
+    assert_eq!(rotated_area.width, base_area.width);
+    assert_eq!(rotated_area.height, base_area.height);
+
+    let rotated_bgr_vec: Vec<Bgr8> = vec![white, black, white, white, white, white, white, black];
+
+    assert_eq!(rotated_area.bgr_vec, rotated_bgr_vec);
+}
+
+#[test]
+fn test_rotation_270() {
+    let imagery = Imagery::new(1872, 1404, 4, 2, 270);
+
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+
+    let base_bgr_vec: Vec<Bgr8> = vec![black, white, white, white, white, white, black, white];
+
+    let base_area = Area {
+        x: 5,
+        y: 10,
+        width: 4,
+        height: 2,
+        bgr_vec: base_bgr_vec,
+    };
+
+    let rotated_area = imagery.rotate(base_area.clone(), 270);
+
+    assert_eq!(rotated_area.x, base_area.y);
+    assert_eq!(
+        rotated_area.y,
+        imagery.eink_height - base_area.x - base_area.width
+    );
+    assert_eq!(rotated_area.width, base_area.height);
+    assert_eq!(rotated_area.height, base_area.width);
+
+    let rotated_bgr_vec: Vec<Bgr8> = vec![white, white, white, black, white, white, black, white];
+
+    assert_eq!(rotated_area.bgr_vec, rotated_bgr_vec);
+}
+
+#[test]
+fn test_rotation_full_turn() {
+    let imagery = Imagery::new(1872, 1404, 4, 2, 0);
+
+    let base_bgr_vec: Vec<Bgr8> = (0..8).map(|n| new_bgr8(n, n, n)).collect();
+    let base_area = Area {
+        x: 8,
+        y: 12,
+        width: 4,
+        height: 2,
+        bgr_vec: base_bgr_vec,
+    };
+
+    // 90 then 270 on the rotated panel gets back the base area
+    let imagery_90 = Imagery::new(1872, 1404, 4, 2, 90);
+    let rotated_area = imagery_90.rotate(base_area.clone(), 90);
+    let imagery_back = Imagery::new(1404, 1872, 4, 2, 270);
+    assert_eq!(imagery_back.rotate(rotated_area, 270), base_area);
+
+    let twice = imagery.rotate(imagery.rotate(base_area.clone(), 180), 180);
+    assert_eq!(twice, base_area);
+}
+
+#[test]
+fn test_area_for_hardware_rotation() {
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+
+    let mut imagery = Imagery::new(64, 32, 32, 64, 90);
+    imagery.set_hardware_rotation(true);
+    let old_slice = vec![white; 32 * 64];
+    let mut new_slice = old_slice.clone();
+    new_slice[9 * 32 + 6] = black;
+
+    // unrotated, aligned along the capture lines and columns
+    let area = imagery
+        .compare_image_slices(old_slice.as_slice(), new_slice.as_slice())
+        .unwrap();
+    assert_eq!((area.x, area.y, area.width, area.height), (4, 8, 4, 4));
+}
+
+#[test]
+fn test_dither_keeps_the_area() {
+    let grey = new_bgr8(100, 100, 100);
+    let black = new_bgr8(0, 0, 0);
+
+    let imagery = Imagery::new(64, 32, 64, 32, 0);
+    let old_slice = vec![grey; 64 * 32];
+    let mut new_slice = old_slice.clone();
+    new_slice[7 * 64 + 21] = black;
+
+    let areas = imagery.find_changed_areas(old_slice.as_slice(), new_slice.as_slice());
+    assert_eq!(areas.len(), 1);
+
+    let ordered = imagery.dither(areas[0].clone(), new_slice.as_slice(), DitherMode::Ordered);
+    assert_eq!(
+        (ordered.x, ordered.y, ordered.width, ordered.height),
+        (20, 7, 4, 1)
+    );
+
+    // the error is not diffused out of a partial area, nothing to grow
+    let mono = imagery.dither(areas[0].clone(), new_slice.as_slice(), DitherMode::Mono);
+    assert_eq!((mono.x, mono.y, mono.width, mono.height), (20, 7, 4, 1));
+    assert!(mono
+        .bgr_vec
+        .iter()
+        .all(|bgr| *bgr == black || *bgr == new_bgr8(255, 255, 255)));
+}
+
+#[test]
+fn test_merge_tile_boxes_grown_box() {
+    let imagery = Imagery::new(512, 512, 512, 512, 0);
+    // too far from each other, until the box in the middle joins the first one
+    let boxes = vec![[0, 0, 0, 0], [20, 0, 20, 0], [10, 0, 10, 0]];
+
+    assert_eq!(imagery.merge_tile_boxes(boxes), vec![[0, 0, 20, 0]]);
+}
+
+#[test]
+fn test_merge_tile_boxes_scattered() {
+    let imagery = Imagery::new(512, 512, 512, 512, 0);
+    // small changes all over a large screen, each too far to be merged
+    let mut boxes: Vec<[u16; 4]> = Vec::new();
+    for y in 0..50 {
+        for x in 0..50 {
+            boxes.push([x * 12, y * 12, x * 12, y * 12]);
+        }
+    }
+
+    assert_eq!(imagery.merge_tile_boxes(boxes).len(), 2500);
+}
+
+#[test]
+fn test_is_monochrome() {
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+    let grey = new_bgr8(128, 128, 128);
+
+    let imagery = Imagery::new(16, 8, 16, 8, 0);
+    let mut slice = vec![white; 16 * 8];
+    slice[2 * 16 + 3] = black;
+    slice[6 * 16 + 12] = grey;
+    let area = |x: u16, y: u16| Area {
+        x,
+        y,
+        width: 4,
+        height: 2,
+        bgr_vec: Vec::new(),
+    };
+
+    assert!(imagery.is_monochrome(&slice, &area(2, 1)));
+    assert!(!imagery.is_monochrome(&slice, &area(10, 5)));
+}
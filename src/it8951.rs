@@ -1,26 +1,35 @@
 use std::error::Error;
+use std::io;
 use std::ops::Drop;
 use std::time;
 extern crate custom_error;
 use custom_error::custom_error;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 mod bcm_interface;
 use bcm_interface::BCM;
+mod spidev_interface;
+use spidev_interface::SpidevInterface;
+pub mod transport;
+use transport::{Transport, DEFAULT_SPEED_HZ};
+
+use crate::eink_display::{rotate_rect, EinkDisplay, WaveformMode};
 
 static CS: u8 = 8;
 static HRDY: u8 = 24;
 static RESET: u8 = 17;
-static VCOM: u16 = 1610; //e.g. -1.53 = 1530 = 0x5FA
+// words answered to USDEF_I80_CMD_GET_DEV_INFO
+static DEV_INFO_WORDS: usize = 20;
+// highest VCOM magnitude accepted, in mV
+static MAX_VCOM: u16 = 5000;
+
+// SPI self test: words written to the image buffer and read back, slowest clock tried
+static SELF_TEST_WORDS: u32 = 1024;
+static MIN_SPEED_HZ: u32 = 500_000;
 
-// copied from bcm2835.h
-static BCM2835_SPI_BIT_ORDER_MSBFIRST: u8 = 1;
-static BCM2835_SPI_MODE0: u8 = 0;
-static BCM2835_SPI_CLOCK_DIVIDER_32: u16 = 32;
-static BCM2835_GPIO_FSEL_OUTP: u8 = 0x01;
-static BCM2835_GPIO_FSEL_INPT: u8 = 0x00;
-static HIGH: u8 = 0x1;
-static LOW: u8 = 0x0;
+// longest waits before giving the controller up: HRDY before a transfer, end of a refresh
+static HRDY_TIMEOUT: Duration = Duration::from_secs(3);
+static DISPLAY_TIMEOUT: Duration = Duration::from_secs(10);
 
 //Built in I80 Command Code
 static IT8951_TCON_SYS_RUN: u16 = 0x0001;
@@ -42,10 +51,6 @@ static USDEF_I80_CMD_GET_DEV_INFO: u16 = 0x0302;
 static USDEF_I80_CMD_DPY_BUF_AREA: u16 = 0x0037;
 static USDEF_I80_CMD_VCOM: u16 = 0x0039;
 
-//Panel
-static IT8951_PANEL_WIDTH: u16 = 1024; //it Get Device information
-static IT8951_PANEL_HEIGHT: u16 = 758;
-
 //Rotate mode
 static IT8951_ROTATE_0: u16 = 0;
 static IT8951_ROTATE_90: u16 = 1;
@@ -64,6 +69,7 @@ static IT8951_MODE_1: u16 = 1;
 static IT8951_MODE_2: u16 = 2;
 static IT8951_MODE_3: u16 = 3;
 static IT8951_MODE_4: u16 = 4;
+static IT8951_MODE_6: u16 = 6; // A2, except on the 6" panels : see DevInfo::a2_mode
 //Endian Type
 static IT8951_LDIMG_L_ENDIAN: u16 = 0;
 static IT8951_LDIMG_B_ENDIAN: u16 = 1;
@@ -103,28 +109,109 @@ static MCSR_BASE_ADDR: u16 = 0x0200;
 static MCSR: u16 = (MCSR_BASE_ADDR + 0x0000);
 static LISAR: u16 = (MCSR_BASE_ADDR + 0x0008);
 
-custom_error! {ITError
+custom_error! {pub ITError
     InitFailed = "IT init failed.",
-    DowncastFailed = "Downcast failed"
+    DowncastFailed = "Downcast failed",
+    ShortDevInfo{words: usize} = "device info of {words} words, 20 expected",
+    Timeout{stage: String} = "IT8951 not answering while {stage}",
+    Bus{source: io::Error} = "IT8951 bus failure: {source}"
 }
 
 pub struct IT {
-    _bcm_interface: BCM,
+    _transport: Box<dyn Transport>,
     _dev_info: DevInfo,
+    // 4bpp pixels in the order they go on the wire: the bytes of each word swapped
     _frame_buffer: Vec<u8>,
+    // degrees, done by the controller while loading the image
+    _rotation: u16,
+    _read_speed_hz: u32,
+    _write_speed_hz: u32,
+    _hrdy_timeout: Duration,
+    // longest refresh of the previous area before the next image is loaded
+    _display_timeout: Duration,
+    // set on each init, None : left as the controller has it
+    _vcom: Option<u16>,
 }
 
 impl Drop for IT {
     fn drop(&mut self) {
-        /*
-                 self._bcm_interface.bcm2835_spi_end();
-        self._bcm_interface.bcm2835_close();  */
+        // the panel keeps its image, the controller has nothing left to do
+        if let Err(error) = self.sleep() {
+            println!("{}", error);
+        }
+        if let Err(error) = self._transport.set_chip_select(false) {
+            println!("{}", error);
+        }
+        // the transport closes the SPI bus when dropped with the IT
     }
 }
 
 impl IT {
     pub fn new() -> Result<IT, Box<Error>> {
-        let mut it: IT = Self::init()?;
+        IT::with_transport(
+            IT::open_transport("auto", "/dev/spidev0.0", "/dev/gpiochip0")?,
+            None,
+        )
+    }
+
+    /// Wires to the IT8951: "spidev" (kernel drivers), "bcm2835" (libbcm2835, as root)
+    /// or "auto" for spidev then bcm2835
+    pub fn open_transport(
+        kind: &str,
+        spi_device: &str,
+        gpio_chip: &str,
+    ) -> Result<Box<dyn Transport>, Box<Error>> {
+        match kind {
+            "spidev" => Ok(Box::new(SpidevInterface::open(
+                spi_device, gpio_chip, CS, HRDY, RESET,
+            )?)),
+            "bcm2835" => Ok(Box::new(BCM::open(CS, HRDY, RESET)?)),
+            "auto" => match SpidevInterface::open(spi_device, gpio_chip, CS, HRDY, RESET) {
+                Ok(spidev) => Ok(Box::new(spidev)),
+                Err(error) => {
+                    println!("{}, trying libbcm2835", error);
+                    Ok(Box::new(BCM::open(CS, HRDY, RESET)?))
+                }
+            },
+            _ => Err(format!("unknown transport {}", kind).into()),
+        }
+    }
+
+    /// `vcom` in mV, as printed on the panel cable without the minus sign;
+    /// None leaves the value of the controller untouched
+    pub fn with_transport(
+        transport: Box<dyn Transport>,
+        vcom: Option<u16>,
+    ) -> Result<IT, Box<Error>> {
+        IT::with_transport_at_speeds(transport, vcom, DEFAULT_SPEED_HZ, DEFAULT_SPEED_HZ)
+    }
+
+    /// Like `with_transport`, the SPI clocks are set before the first read
+    pub fn with_transport_at_speeds(
+        transport: Box<dyn Transport>,
+        vcom: Option<u16>,
+        read_speed_hz: u32,
+        write_speed_hz: u32,
+    ) -> Result<IT, Box<dyn Error>> {
+        let mut it: IT = Self::init(transport, read_speed_hz, write_speed_hz)?;
+        it._vcom = vcom;
+        it.set_up()?;
+        Ok(it)
+    }
+
+    /// Pulse RESET and set the controller up again, e.g. after a timeout.
+    /// The SPI clocks and the rotation are kept.
+    pub fn reset(&mut self) -> Result<(), Box<Error>> {
+        self._transport.set_chip_select(false)?;
+        self._transport.set_reset(false)?;
+        self._transport.delay(100);
+        self._transport.set_reset(true)?;
+        self._transport.set_speed(self._write_speed_hz);
+        self.set_up()
+    }
+
+    fn set_up(&mut self) -> Result<(), Box<Error>> {
+        let it = self;
 
         //Get Device Info
         let dev_info = it.get_system_info()?;
@@ -150,52 +237,90 @@ impl IT {
         */
 
         //Set to Enable I80 Packed mode
-        it.write_reg(I80CPCR, 0x0001);
+        it.write_reg(I80CPCR, 0x0001)?;
 
-        if VCOM != it.get_VCOM() {
-            it.set_VCOM(VCOM);
-            println!("VCOM = {}", it.get_VCOM());
+        if let Some(vcom) = it._vcom {
+            if vcom != it.get_VCOM()? {
+                it.set_VCOM(vcom)?;
+                println!("VCOM = {}", it.get_VCOM()?);
+            }
         }
 
-        Ok(it)
+        Ok(())
     }
 
-    fn init() -> Result<IT, Box<Error>> {
-        let bcm_interface: BCM = BCM::new();
-
-        bcm_interface.bcm2835_init()?;
-
-        bcm_interface.bcm2835_spi_begin();
-        bcm_interface.bcm2835_spi_setBitOrder(BCM2835_SPI_BIT_ORDER_MSBFIRST); //default
-        bcm_interface.bcm2835_spi_setDataMode(BCM2835_SPI_MODE0); //default
-        bcm_interface.bcm2835_spi_setClockDivider(BCM2835_SPI_CLOCK_DIVIDER_32); //default
+    fn init(
+        transport: Box<dyn Transport>,
+        read_speed_hz: u32,
+        write_speed_hz: u32,
+    ) -> Result<IT, Box<Error>> {
+        transport.set_speed(write_speed_hz);
+        transport.set_chip_select(false)?;
 
-        bcm_interface.bcm2835_gpio_fsel(CS, BCM2835_GPIO_FSEL_OUTP);
-        bcm_interface.bcm2835_gpio_fsel(HRDY, BCM2835_GPIO_FSEL_INPT);
-        bcm_interface.bcm2835_gpio_fsel(RESET, BCM2835_GPIO_FSEL_OUTP);
-        bcm_interface.bcm2835_gpio_write(CS, HIGH);
-
-        bcm_interface.bcm2835_gpio_write(RESET, LOW);
-        bcm_interface.bcm2835_delay(100);
-        bcm_interface.bcm2835_gpio_write(RESET, HIGH);
+        transport.set_reset(false)?;
+        transport.delay(100);
+        transport.set_reset(true)?;
 
         // dummy DevInfo :
-        let dev_info: DevInfo = DevInfo {
-            panel_width: 0,
-            panel_height: 0,
-            image_buffer_base_address_l: 0,
-            image_buffer_base_address_h: 0,
-            firmware_version: [0; 8],
-            lut_version: [0; 8],
-        };
+        let dev_info: DevInfo = DevInfo::with_panel_size(0, 0);
 
         Ok(IT {
-            _bcm_interface: bcm_interface,
+            _transport: transport,
             _dev_info: dev_info,
             _frame_buffer: Vec::new(),
+            _rotation: 0,
+            _read_speed_hz: read_speed_hz,
+            _write_speed_hz: write_speed_hz,
+            _hrdy_timeout: HRDY_TIMEOUT,
+            _display_timeout: DISPLAY_TIMEOUT,
+            _vcom: None,
         })
     }
 
+    /// SPI clocks of the reads and of the writes, reads often need a slower one
+    pub fn set_spi_speeds(&mut self, read_speed_hz: u32, write_speed_hz: u32) {
+        self._read_speed_hz = read_speed_hz;
+        self._write_speed_hz = write_speed_hz;
+        self._transport.set_speed(write_speed_hz);
+    }
+
+    /// Write a pattern in the image buffer and read it back at the read clock, halving
+    /// the write clock from `from_speed_hz` until they match. The write clock found is
+    /// kept and returned, None if even the slowest one fails.
+    pub fn self_test_spi_speed(&mut self, from_speed_hz: u32) -> Result<Option<u32>, ITError> {
+        let address: u32 = self._dev_info.image_buffer_address();
+        let pattern: Vec<u16> = (0..SELF_TEST_WORDS).map(self_test_word).collect();
+        let read_speed_hz = self._read_speed_hz;
+
+        let mut speed_hz = from_speed_hz;
+        while speed_hz >= MIN_SPEED_HZ {
+            self.set_spi_speeds(read_speed_hz, speed_hz);
+            self.mem_burst_write(address, &pattern)?;
+            let errors = self
+                .mem_burst_read(address, SELF_TEST_WORDS)?
+                .iter()
+                .zip(pattern.iter())
+                .filter(|(read, written)| read != written)
+                .count();
+            println!("SPI write clock {} Hz : {} wrong words", speed_hz, errors);
+            if errors == 0 {
+                return Ok(Some(speed_hz));
+            }
+            speed_hz /= 2;
+        }
+        Ok(None)
+    }
+
+    /// What the controller told about itself and its panel
+    pub fn dev_info(&self) -> &DevInfo {
+        &self._dev_info
+    }
+
+    /// VCOM of the controller in mV, e.g. 1530 for -1.53 V
+    pub fn vcom(&self) -> Result<u16, ITError> {
+        self.get_VCOM()
+    }
+
     pub fn size(&self) -> (u16, u16) {
 
         println!("panel size : {}, {}", self._dev_info.panel_width, self._dev_info.panel_height);
@@ -205,196 +330,234 @@ impl IT {
 
     fn get_system_info(&self) -> Result<DevInfo, Box<Error>> {
         //Send I80 CMD
-        self.lcd_write_cmd_code(USDEF_I80_CMD_GET_DEV_INFO);
+        self.lcd_write_cmd_code(USDEF_I80_CMD_GET_DEV_INFO)?;
         //Burst Read Request for SPI interface only
-        let boxed_data: Box<[u16]> =
-            self.lcd_read_n_data(((std::mem::size_of::<DevInfo>()) / 2) as u32); //Polling HRDY for each words(2-bytes) if possible
-        let dev_info_ptr = Box::into_raw(boxed_data) as *mut DevInfo;
-        let boxed_dev_info: Box<DevInfo> = unsafe { Box::from_raw(dev_info_ptr) };
-
-        let dev_info = *boxed_dev_info;
+        //Polling HRDY for each words(2-bytes) if possible
+        let words: Box<[u16]> = self.lcd_read_n_data(DEV_INFO_WORDS as u32)?;
+        let dev_info = DevInfo::from_words(&words)?;
 
         println!(
             "Panel(W,H) = ({},{})",
             dev_info.panel_width, dev_info.panel_height
         );
 
-        let base_address: u32 = (dev_info.image_buffer_base_address_l as u32)
-            | ((dev_info.image_buffer_base_address_h as u32) << 16);
-        println!("Image Buffer Address = {}", base_address);
+        println!("Image Buffer Address = {:#X}", dev_info.image_buffer_address());
         //Show Firmware and LUT Version
-        println!("FW Version = {:?}", dev_info.firmware_version);
-        println!("LUT Version = {:?}", dev_info.lut_version);
+        println!("FW Version = {}", dev_info.firmware());
+        println!("LUT Version = {}", dev_info.lut());
 
         Ok(dev_info)
     }
 
-    fn read_reg(&self, reg_address: u16) -> u16 {
+    fn read_reg(&self, reg_address: u16) -> Result<u16, ITError> {
         //Send Cmd , Register Address and Write Value
-        self.lcd_write_cmd_code(IT8951_TCON_REG_RD);
-        self.lcd_write_data(reg_address);
+        self.lcd_write_cmd_code(IT8951_TCON_REG_RD)?;
+        self.lcd_write_data(reg_address)?;
         self.lcd_read_data()
     }
 
-    fn write_reg(&self, reg_address: u16, value: u16) {
+    fn write_reg(&self, reg_address: u16, value: u16) -> Result<(), ITError> {
         //Send Cmd , Register Address and Write Value
-        self.lcd_write_cmd_code(IT8951_TCON_REG_WR);
-        self.lcd_write_data(reg_address);
-        self.lcd_write_data(value);
+        self.lcd_write_cmd_code(IT8951_TCON_REG_WR)?;
+        self.lcd_write_data(reg_address)?;
+        self.lcd_write_data(value)
     }
 
-    fn get_VCOM(&self) -> u16 {
-        self.lcd_write_cmd_code(USDEF_I80_CMD_VCOM);
-        self.lcd_write_data(0);
+    fn get_VCOM(&self) -> Result<u16, ITError> {
+        self.lcd_write_cmd_code(USDEF_I80_CMD_VCOM)?;
+        self.lcd_write_data(0)?;
         //Read data from Host Data bus
-        let vcom: u16 = self.lcd_read_data();
-        vcom
+        let vcom: u16 = self.lcd_read_data()?;
+        Ok(vcom)
     }
-    fn set_VCOM(&self, vcom: u16) {
-        self.lcd_write_cmd_code(USDEF_I80_CMD_VCOM);
-        self.lcd_write_data(1);
+    fn set_VCOM(&self, vcom: u16) -> Result<(), ITError> {
+        self.lcd_write_cmd_code(USDEF_I80_CMD_VCOM)?;
+        self.lcd_write_data(1)?;
         //Read data from Host Data bus
-        self.lcd_write_data(vcom);
+        self.lcd_write_data(vcom)
+    }
+
+    fn mem_burst_args(address: u32, word_count: u32) -> [u16; 5] {
+        [
+            address as u16,
+            (address >> 16) as u16,
+            word_count as u16,
+            (word_count >> 16) as u16,
+            0,
+        ]
     }
 
-    fn lcd_write_cmd_code(&self, cmd_code: u16) {
+    fn mem_burst_write(&self, address: u32, words: &[u16]) -> Result<(), ITError> {
+        let arg = IT::mem_burst_args(address, words.len() as u32);
+        self.lcd_send_cmd_arg(IT8951_TCON_MEM_BST_WR, arg, 4)?;
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect();
+        self.lcd_write_bytes(&bytes)?;
+        self.lcd_write_cmd_code(IT8951_TCON_MEM_BST_END)
+    }
+
+    fn mem_burst_read(&self, address: u32, word_count: u32) -> Result<Box<[u16]>, ITError> {
+        let arg = IT::mem_burst_args(address, word_count);
+        self.lcd_send_cmd_arg(IT8951_TCON_MEM_BST_RD_T, arg, 4)?;
+        self.lcd_write_cmd_code(IT8951_TCON_MEM_BST_RD_S)?;
+        let words = self.lcd_read_n_data(word_count)?;
+        self.lcd_write_cmd_code(IT8951_TCON_MEM_BST_END)?;
+        Ok(words)
+    }
+
+    fn lcd_write_cmd_code(&self, cmd_code: u16) -> Result<(), ITError> {
         //Set Preamble for Write Command
         let w_preamble: u16 = 0x6000;
 
-        self.lcd_wait_for_ready();
+        self.lcd_wait_for_ready("sending a command")?;
 
-        self._bcm_interface.bcm2835_gpio_write(CS, LOW);
+        self._transport.set_chip_select(true)?;
 
-        self._bcm_interface
-            .bcm2835_spi_transfer((w_preamble >> 8) as u8);
-        self._bcm_interface.bcm2835_spi_transfer(w_preamble as u8);
+        self._transport.spi_transfer((w_preamble >> 8) as u8)?;
+        self._transport.spi_transfer(w_preamble as u8)?;
 
         //LCDWaitForReady();
 
-        self._bcm_interface
-            .bcm2835_spi_transfer((cmd_code >> 8) as u8);
-        self._bcm_interface.bcm2835_spi_transfer(cmd_code as u8);
+        self._transport.spi_transfer((cmd_code >> 8) as u8)?;
+        self._transport.spi_transfer(cmd_code as u8)?;
 
-        self._bcm_interface.bcm2835_gpio_write(CS, HIGH);
+        self._transport.set_chip_select(false)?;
+        Ok(())
     }
-    fn lcd_send_cmd_arg(&self, cmd_code: u16, arg: [u16; 5], arg_number: u16) {
-        self.lcd_write_cmd_code(cmd_code);
+    fn lcd_send_cmd_arg(
+        &self,
+        cmd_code: u16,
+        arg: [u16; 5],
+        arg_number: u16,
+    ) -> Result<(), ITError> {
+        self.lcd_write_cmd_code(cmd_code)?;
 
         for n in 0..arg_number {
-            self.lcd_write_data(arg[n as usize]);
+            self.lcd_write_data(arg[n as usize])?;
         }
+        Ok(())
     }
 
-    fn lcd_write_data(&self, data: u16) {
+    fn lcd_write_data(&self, data: u16) -> Result<(), ITError> {
         //Set Preamble for Write Data
         let w_preamble: u16 = 0x0000;
 
-        self.lcd_wait_for_ready();
+        self.lcd_wait_for_ready("sending data")?;
 
-        self._bcm_interface.bcm2835_gpio_write(CS, LOW);
+        self._transport.set_chip_select(true)?;
 
-        self._bcm_interface
-            .bcm2835_spi_transfer((w_preamble >> 8) as u8);
-        self._bcm_interface.bcm2835_spi_transfer(w_preamble as u8);
+        self._transport.spi_transfer((w_preamble >> 8) as u8)?;
+        self._transport.spi_transfer(w_preamble as u8)?;
 
         //LCDWaitForReady();
 
-        self._bcm_interface.bcm2835_spi_transfer((data >> 8) as u8);
-        self._bcm_interface.bcm2835_spi_transfer(data as u8);
+        self._transport.spi_transfer((data >> 8) as u8)?;
+        self._transport.spi_transfer(data as u8)?;
 
-        self._bcm_interface.bcm2835_gpio_write(CS, HIGH);
+        self._transport.set_chip_select(false)?;
+        Ok(())
+    }
+
+    fn lcd_write_n_data(&self, word_count: u32) -> Result<(), ITError> {
+        let data_vec = self._frame_buffer.as_slice();
+        //println!(" data_vec.len() {} wc: {}",  data_vec.len(), word_count);
+        if data_vec.len() < (word_count as usize) {
+            println!("len: {} , word_count: {}", data_vec.len(), word_count );
+            panic!("data_vec too short");
+        }
+        self.lcd_write_bytes(&data_vec[..word_count as usize])
     }
 
-    fn lcd_write_n_data(&self, word_count: u32) {
+    // bytes already in wire order, after a single write preamble
+    fn lcd_write_bytes(&self, bytes: &[u8]) -> Result<(), ITError> {
         //Set Preamble for Write Data
         let w_preamble: u16 = 0x0000;
 
         //self.lcd_wait_for_ready();
 
-        self._bcm_interface.bcm2835_gpio_write(CS, LOW);
+        self._transport.set_chip_select(true)?;
 
-        self._bcm_interface
-            .bcm2835_spi_transfer((w_preamble >> 8) as u8);
-        self._bcm_interface.bcm2835_spi_transfer(w_preamble as u8);
+        self._transport.spi_transfer((w_preamble >> 8) as u8)?;
+        self._transport.spi_transfer(w_preamble as u8)?;
 
         //LCDWaitForReady();
-        let data_vec = self._frame_buffer.as_slice();
-        //println!(" data_vec.len() {} wc: {}",  data_vec.len(), word_count);
-        if data_vec.len() < (word_count as usize) {
-            println!("len: {} , word_count: {}", data_vec.len(), word_count );
-            panic!("data_vec too short");
-        }
-        let mut n: usize = 0;
-        while n < (word_count as usize) {
-            self._bcm_interface.bcm2835_spi_transfer(data_vec[n + 1]);
-            self._bcm_interface.bcm2835_spi_transfer(data_vec[n]);
-            n += 2;
-        }
+        self._transport.spi_write(bytes)?;
 
-        self._bcm_interface.bcm2835_gpio_write(CS, HIGH);
+        self._transport.set_chip_select(false)?;
         //println!("gg: {}", now.elapsed().as_millis());
+        Ok(())
     }
 
-    fn lcd_read_data(&self) -> u16 {
+    fn lcd_read_data(&self) -> Result<u16, ITError> {
         let w_preamble: u16 = 0x1000;
-        self.lcd_wait_for_ready();
+        self.lcd_wait_for_ready("reading data")?;
+        self._transport.set_speed(self._read_speed_hz);
 
-        self._bcm_interface.bcm2835_gpio_write(CS, LOW);
+        self._transport.set_chip_select(true)?;
 
-        self._bcm_interface
-            .bcm2835_spi_transfer((w_preamble >> 8) as u8);
-        self._bcm_interface.bcm2835_spi_transfer(w_preamble as u8);
-        self.lcd_wait_for_ready();
+        self._transport.spi_transfer((w_preamble >> 8) as u8)?;
+        self._transport.spi_transfer(w_preamble as u8)?;
+        self.lcd_wait_for_ready("reading data")?;
 
-        let mut read_data: u16 = self._bcm_interface.bcm2835_spi_transfer(0x00) as u16; //dummy
-        read_data = self._bcm_interface.bcm2835_spi_transfer(0x00) as u16; //dummy
-        self.lcd_wait_for_ready();
+        let mut read_data: u16 = self._transport.spi_transfer(0x00)? as u16; //dummy
+        read_data = self._transport.spi_transfer(0x00)? as u16; //dummy
+        self.lcd_wait_for_ready("reading data")?;
 
-        read_data = (self._bcm_interface.bcm2835_spi_transfer(0x00) as u16) << 8;
-        read_data |= self._bcm_interface.bcm2835_spi_transfer(0x00) as u16;
+        read_data = (self._transport.spi_transfer(0x00)? as u16) << 8;
+        read_data |= self._transport.spi_transfer(0x00)? as u16;
 
-        self._bcm_interface.bcm2835_gpio_write(CS, HIGH);
-        read_data
+        self._transport.set_chip_select(false)?;
+        self._transport.set_speed(self._write_speed_hz);
+        Ok(read_data)
     }
 
-    fn lcd_read_n_data(&self, word_count: u32) -> Box<[u16]> {
+    fn lcd_read_n_data(&self, word_count: u32) -> Result<Box<[u16]>, ITError> {
         //Set Preamble for Write Data
         let w_preamble: u16 = 0x1000;
-        self.lcd_wait_for_ready();
+        self.lcd_wait_for_ready("reading data")?;
+        self._transport.set_speed(self._read_speed_hz);
 
-        self._bcm_interface.bcm2835_gpio_write(CS, LOW);
+        self._transport.set_chip_select(true)?;
 
-        self._bcm_interface
-            .bcm2835_spi_transfer((w_preamble >> 8) as u8);
-        self._bcm_interface.bcm2835_spi_transfer(w_preamble as u8);
-        self.lcd_wait_for_ready();
+        self._transport.spi_transfer((w_preamble >> 8) as u8)?;
+        self._transport.spi_transfer(w_preamble as u8)?;
+        self.lcd_wait_for_ready("reading data")?;
 
         // initialise:
         let mut data_vec: Vec<u16> = vec![0; word_count as usize];
 
-        data_vec[0] = self._bcm_interface.bcm2835_spi_transfer(0x00).into(); //dummy
-        data_vec[0] = self._bcm_interface.bcm2835_spi_transfer(0x00).into(); //dummy
+        data_vec[0] = self._transport.spi_transfer(0x00)?.into(); //dummy
+        data_vec[0] = self._transport.spi_transfer(0x00)?.into(); //dummy
 
         for n in 0..word_count {
-            let mut word: u16 = (self._bcm_interface.bcm2835_spi_transfer(0x00) as u16) << 8;
-            word = word ^ self._bcm_interface.bcm2835_spi_transfer(0x00) as u16;
+            let mut word: u16 = (self._transport.spi_transfer(0x00)? as u16) << 8;
+            word = word ^ self._transport.spi_transfer(0x00)? as u16;
             data_vec[n as usize] = word;
         }
 
-        self._bcm_interface.bcm2835_gpio_write(CS, HIGH);
+        self._transport.set_chip_select(false)?;
+        self._transport.set_speed(self._write_speed_hz);
 
         let boxed: Box<[u16]> = data_vec.into_boxed_slice();
-        boxed
+        Ok(boxed)
     }
 
-    fn lcd_wait_for_ready(&self) {
-        let mut data: u8 = self._bcm_interface.bcm2835_gpio_lev(HRDY);
-        while data == 0 {
-            data = self._bcm_interface.bcm2835_gpio_lev(HRDY);
+    fn lcd_wait_for_ready(&self, stage: &str) -> Result<(), ITError> {
+        let deadline = Instant::now() + self._hrdy_timeout;
+        while !self._transport.host_ready()? {
+            if Instant::now() > deadline {
+                return Err(ITError::Timeout {
+                    stage: stage.to_string(),
+                });
+            }
         }
+        Ok(())
     }
 
-    fn load_image_area_start(&self, load_image_info: &LdImgInfo, area_image_info: &AreaImgInfo) {
+    fn load_image_area_start(
+        &self,
+        load_image_info: &LdImgInfo,
+        area_image_info: &AreaImgInfo,
+    ) -> Result<(), ITError> {
         let mut arg: [u16; 5] = [0; 5];
         //Setting Argument for Load image start
         arg[0] = (load_image_info.endian_type << 8)
@@ -405,11 +568,11 @@ impl IT {
         arg[3] = area_image_info.width;
         arg[4] = area_image_info.height;
         //Send Cmd and Args
-        self.lcd_send_cmd_arg(IT8951_TCON_LD_IMG_AREA, arg, 5);
+        self.lcd_send_cmd_arg(IT8951_TCON_LD_IMG_AREA, arg, 5)
     }
 
-    fn load_image_end(&self) {
-        self.lcd_write_cmd_code(IT8951_TCON_LD_IMG_END);
+    fn load_image_end(&self) -> Result<(), ITError> {
+        self.lcd_write_cmd_code(IT8951_TCON_LD_IMG_END)
     }
 
     fn write_host_area_packed_pixel(
@@ -417,36 +580,77 @@ impl IT {
         load_image_info: &LdImgInfo,
         area_image_info: &AreaImgInfo,
         factor: u8,
-    ) {
+    ) -> Result<(), ITError> {
         //Send Load Image start Cmd
-        self.load_image_area_start(load_image_info, area_image_info);
+        self.load_image_area_start(load_image_info, area_image_info)?;
 
         let word_count: u32 = ((area_image_info.height as u32) * (area_image_info.width as u32))
             / (2 * factor as u32) as u32;
 
-        self.lcd_write_n_data(word_count);
+        self.lcd_write_n_data(word_count)?;
 
-        self.load_image_end();
+        self.load_image_end()
     }
 
-    fn wait_for_display_ready(&self) {
-        while self.read_reg(LUTAFSR) == 1 {}
+    fn wait_for_display_ready(&self) -> Result<(), ITError> {
+        let deadline = Instant::now() + self._display_timeout;
+        while self.read_reg(LUTAFSR)? == 1 {
+            if Instant::now() > deadline {
+                return Err(ITError::Timeout {
+                    stage: "waiting for the refresh".to_string(),
+                });
+            }
+        }
+        Ok(())
     }
 
-    fn display_area(&self, x: u16, y: u16, width: u16, height: u16, dpy_mode: u16) {
+    fn display_area(
+        &self,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        dpy_mode: u16,
+    ) -> Result<(), ITError> {
         //Send I80 Display Command (User defined command of IT8951)
-        self.lcd_write_cmd_code(USDEF_I80_CMD_DPY_AREA); //0x0034
+        self.lcd_write_cmd_code(USDEF_I80_CMD_DPY_AREA)?; //0x0034
                                                          //Write arguments
-        self.lcd_write_data(x);
-        self.lcd_write_data(y);
-        self.lcd_write_data(width);
-        self.lcd_write_data(height);
-        self.lcd_write_data(dpy_mode);
+        self.lcd_write_data(x)?;
+        self.lcd_write_data(y)?;
+        self.lcd_write_data(width)?;
+        self.lcd_write_data(height)?;
+        self.lcd_write_data(dpy_mode)
+    }
+
+    /// Panel size once rotated
+    fn rotated_size(&self) -> (u16, u16) {
+        if self._rotation == 90 || self._rotation == 270 {
+            (self._dev_info.panel_height, self._dev_info.panel_width)
+        } else {
+            (self._dev_info.panel_width, self._dev_info.panel_height)
+        }
+    }
+
+    pub fn set_rotation(&mut self, rotation: u16) -> bool {
+        match rotation {
+            0 | 90 | 180 | 270 => {
+                self._rotation = rotation;
+                true
+            }
+            _ => false,
+        }
     }
 
-    pub fn display(&self, x: u16, y: u16, rect_width: u16, rect_height: u16) {
-        let width: u16 = self._dev_info.panel_width;
-        let height: u16 = self._dev_info.panel_height;
+    // coordinates are on the rotated panel
+    pub fn display(
+        &self,
+        x: u16,
+        y: u16,
+        rect_width: u16,
+        rect_height: u16,
+        dpy_mode: u16,
+    ) -> Result<(), ITError> {
+        let (width, height) = self.rotated_size();
         let mut rect_width = rect_width;
         let mut rect_height = rect_height;
         if rect_width > width {
@@ -459,7 +663,8 @@ impl IT {
         //EPD_Clear(0xff);
         //EPD_FillRect(x, y, rectWidth, rectHeight, 0x00);
 
-        //self.wait_for_display_ready();
+        // the image buffer is still read by the refresh going on
+        self.wait_for_display_ready()?;
 
         //println!("aa: {}", now.elapsed().as_millis());
 
@@ -467,7 +672,12 @@ impl IT {
         let load_image_info = LdImgInfo {
             endian_type: IT8951_LDIMG_L_ENDIAN,
             pixel_format: IT8951_4BPP,
-            rotate: IT8951_ROTATE_0,
+            rotate: match self._rotation {
+                90 => IT8951_ROTATE_90,
+                180 => IT8951_ROTATE_180,
+                270 => IT8951_ROTATE_270,
+                _ => IT8951_ROTATE_0,
+            },
         };
         //Set Load Area
         let area_image_info = AreaImgInfo {
@@ -477,21 +687,137 @@ impl IT {
             height: rect_height,
         };
         //Load Image from Host to IT8951 Image Buffer
-        self.write_host_area_packed_pixel(&load_image_info, &area_image_info, 1); //Display function 2
-
-        self.display_area(x, y, rect_width, rect_height, 2);
+        self.write_host_area_packed_pixel(&load_image_info, &area_image_info, 1)?; //Display function 2
+
+        // the display area is given on the unrotated panel
+        let (panel_x, panel_y, panel_width, panel_height) = rotate_rect(
+            self._rotation,
+            self._dev_info.panel_width,
+            self._dev_info.panel_height,
+            (x, y, rect_width, rect_height),
+        );
+        self.display_area(panel_x, panel_y, panel_width, panel_height, dpy_mode)
     }
 
     pub fn draw_buffer_pixel(&mut self, x: u16, y: u16, width: u16, color: u8) {
         let index: usize = y as usize * width as usize + x as usize;
 
-        self._frame_buffer[index] = color;
+        // the frame buffer is swapped by words
+        self._frame_buffer[index ^ 1] = color;
     }
 
-    pub fn load_buffer_from_vec(&mut self, grey_vec: Vec<u8>) {
+    pub fn load_buffer_from_vec(&mut self, mut grey_vec: Vec<u8>) {
+        for word in grey_vec.chunks_exact_mut(2) {
+            word.swap(0, 1);
+        }
         self._frame_buffer = grey_vec;
     }
+
+    pub fn clear(&mut self) -> Result<(), ITError> {
+        let (width, height) = self.rotated_size();
+        // 0xFF : two white pixels per byte
+        self._frame_buffer = vec![0xFF; width as usize * height as usize / 2];
+        IT::display(self, 0, 0, width, height, IT8951_MODE_0)
+    }
+
+    pub fn standby(&self) -> Result<(), ITError> {
+        self.lcd_write_cmd_code(IT8951_TCON_STANDBY)
+    }
+
+    pub fn sleep(&self) -> Result<(), ITError> {
+        self.lcd_write_cmd_code(IT8951_TCON_SLEEP)
+    }
+
+    pub fn system_run(&self) -> Result<(), ITError> {
+        self.lcd_write_cmd_code(IT8951_TCON_SYS_RUN)
+    }
+
+    fn waveform_mode_value(&self, mode: WaveformMode) -> u16 {
+        match mode {
+            WaveformMode::Init => IT8951_MODE_0,
+            WaveformMode::Du => IT8951_MODE_1,
+            WaveformMode::Gc16 => IT8951_MODE_2,
+            WaveformMode::Gl16 => IT8951_MODE_3,
+            WaveformMode::A2 => self._dev_info.a2_mode(),
+        }
+    }
+}
+
+impl EinkDisplay for IT {
+    fn size(&self) -> (u16, u16) {
+        IT::size(self)
+    }
+
+    fn load_buffer_from_vec(&mut self, grey_vec: Vec<u8>) {
+        IT::load_buffer_from_vec(self, grey_vec);
+    }
+
+    fn display(
+        &mut self,
+        x: u16,
+        y: u16,
+        rect_width: u16,
+        rect_height: u16,
+        mode: WaveformMode,
+    ) -> Result<(), Box<dyn Error>> {
+        let dpy_mode = self.waveform_mode_value(mode);
+        IT::display(self, x, y, rect_width, rect_height, dpy_mode)?;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+        IT::clear(self)?;
+        Ok(())
+    }
+
+    fn standby(&mut self) -> Result<(), Box<dyn Error>> {
+        IT::standby(self)?;
+        Ok(())
+    }
+
+    fn sleep(&mut self) -> Result<(), Box<dyn Error>> {
+        IT::sleep(self)?;
+        Ok(())
+    }
+
+    fn wake(&mut self) -> Result<(), Box<dyn Error>> {
+        self.system_run()?;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        IT::reset(self)
+    }
+
+    fn set_rotation(&mut self, rotation: u16) -> bool {
+        IT::set_rotation(self, rotation)
+    }
+}
+
+/// VCOM in mV from "-1.53" (volts, as on the panel cable) or "1530"
+pub fn parse_vcom(text: &str) -> Result<u16, String> {
+    let text = text.trim().trim_start_matches('-');
+    let millivolts: f32 = if text.contains('.') {
+        text.parse::<f32>().map_err(|_| format!("bad VCOM {}", text))? * 1000.0
+    } else {
+        text.parse::<u16>().map_err(|_| format!("bad VCOM {}", text))? as f32
+    };
+    if millivolts.round() > MAX_VCOM as f32 {
+        return Err(format!("VCOM {} out of range, at most -5.00 V", text));
+    }
+    Ok(millivolts.round() as u16)
+}
+
+/// "-1.53 V (1530)" from 1530 mV
+pub fn format_vcom(vcom: u16) -> String {
+    format!("-{}.{:02} V ({})", vcom / 1000, vcom % 1000 / 10, vcom)
 }
+
+// word n of the self test pattern, every bit flips often
+fn self_test_word(n: u32) -> u16 {
+    (n as u16).wrapping_mul(0x9E37) ^ 0xA5A5
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 struct LdImgInfo {
     endian_type: u16,  //little or Big Endian
@@ -509,14 +835,123 @@ struct AreaImgInfo {
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
-//#[repr(C)]
-struct DevInfo {
-    panel_width: u16,
-    panel_height: u16,
-    image_buffer_base_address_l: u16,
-    image_buffer_base_address_h: u16,
-    firmware_version: [u16; 8], //16 Bytes String
-    lut_version: [u16; 8],      //16 Bytes String
+pub struct DevInfo {
+    pub panel_width: u16,
+    pub panel_height: u16,
+    pub image_buffer_base_address_l: u16,
+    pub image_buffer_base_address_h: u16,
+    pub firmware_version: [u16; 8], //16 Bytes String
+    pub lut_version: [u16; 8],      //16 Bytes String
+}
+
+impl DevInfo {
+    /// DevInfo of a panel that has not been queried, only its size is known
+    pub fn with_panel_size(panel_width: u16, panel_height: u16) -> DevInfo {
+        DevInfo {
+            panel_width: panel_width,
+            panel_height: panel_height,
+            image_buffer_base_address_l: 0,
+            image_buffer_base_address_h: 0,
+            firmware_version: [0; 8],
+            lut_version: [0; 8],
+        }
+    }
+
+    /// Parse the words answered to USDEF_I80_CMD_GET_DEV_INFO, in their order
+    pub fn from_words(words: &[u16]) -> Result<DevInfo, ITError> {
+        if words.len() < DEV_INFO_WORDS {
+            return Err(ITError::ShortDevInfo { words: words.len() });
+        }
+        let mut firmware_version: [u16; 8] = [0; 8];
+        firmware_version.copy_from_slice(&words[4..12]);
+        let mut lut_version: [u16; 8] = [0; 8];
+        lut_version.copy_from_slice(&words[12..20]);
+        Ok(DevInfo {
+            panel_width: words[0],
+            panel_height: words[1],
+            image_buffer_base_address_l: words[2],
+            image_buffer_base_address_h: words[3],
+            firmware_version: firmware_version,
+            lut_version: lut_version,
+        })
+    }
+
+    pub fn image_buffer_address(&self) -> u32 {
+        (self.image_buffer_base_address_l as u32)
+            | ((self.image_buffer_base_address_h as u32) << 16)
+    }
+
+    pub fn firmware(&self) -> String {
+        DevInfo::decode_string(&self.firmware_version)
+    }
+
+    pub fn lut(&self) -> String {
+        DevInfo::decode_string(&self.lut_version)
+    }
+
+    /// Waveform mode number of A2 in the LUT of the panel : 4 in the M641 one of
+    /// the 6" panels, 6 in the others (7.8", 9.7", 10.3", 13.3")
+    pub fn a2_mode(&self) -> u16 {
+        if self.lut().starts_with("M641") {
+            IT8951_MODE_4
+        } else {
+            IT8951_MODE_6
+        }
+    }
+
+    // the controller stores the strings little endian: low byte first in each word,
+    // up to the first NUL
+    fn decode_string(words: &[u16]) -> String {
+        let bytes: Vec<u8> = words
+            .iter()
+            .flat_map(|word| word.to_le_bytes().to_vec())
+            .take_while(|byte| *byte != 0)
+            .collect();
+        String::from_utf8_lossy(&bytes).trim().to_string()
+    }
+
+    /// One line per field, `vcom` in mV
+    pub fn describe(&self, vcom: u16) -> String {
+        format!(
+            "panel : {}x{}\nimage buffer : {:#X}\nfirmware : {}\nLUT : {}\nVCOM : {}",
+            self.panel_width,
+            self.panel_height,
+            self.image_buffer_address(),
+            self.firmware(),
+            self.lut(),
+            format_vcom(vcom)
+        )
+    }
+
+    /// A JSON object, `vcom` in mV
+    pub fn to_json(&self, vcom: u16) -> String {
+        format!(
+            concat!(
+                "{{\"panel_width\": {}, \"panel_height\": {}, \"image_buffer_address\": {}, ",
+                "\"firmware\": {}, \"lut\": {}, \"vcom_mv\": {}}}"
+            ),
+            self.panel_width,
+            self.panel_height,
+            self.image_buffer_address(),
+            json_string(&self.firmware()),
+            json_string(&self.lut()),
+            vcom
+        )
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
 }
 
 #[ignore]
@@ -526,7 +961,10 @@ fn test_init() {
     assert!(it_result.is_ok());
 
     let mut it = it_result.unwrap();
-    assert_eq!(it._dev_info.panel_height, 1404);
+    // whatever panel is plugged in
+    let (width, height) = it.size();
+    assert!(width > 0 && height > 0);
+    assert_eq!(it._frame_buffer.len(), width as usize * height as usize / 2);
 
     let mut grey_vec: Vec<u8> = vec![
         0b1111_1111,
@@ -660,7 +1098,7 @@ fn test_init() {
     ];
 
     it.load_buffer_from_vec(grey_vec);
-    it.display(0, 0, 16, 16);
+    it.display(0, 0, 16, 16, 2).unwrap();
 
     grey_vec = vec![
         0b0000_0000,
@@ -794,7 +1232,7 @@ fn test_init() {
     ];
 
     it.load_buffer_from_vec(grey_vec);
-    it.display(40, 0, 16, 16);
+    it.display(40, 0, 16, 16, 2).unwrap();
 
     // fn test_vertical_line() {
 
@@ -814,5 +1252,276 @@ fn test_init() {
     }
 
     it.load_buffer_from_vec(grey_vec);
-    it.display(500, 500, 400, 400);
+    it.display(500, 500, 400, 400, 2).unwrap();
+}
+
+#[test]
+fn test_init_with_mock_transport() {
+    let transport = transport::MockTransport::new();
+    let bus = transport.bus();
+    let mut dev_info_words: Vec<u16> = vec![1872, 1404, 0x1234, 0x0012];
+    dev_info_words.extend_from_slice(&[0; 16]);
+    bus.borrow_mut().read_words.extend(dev_info_words);
+    // VCOM already right
+    bus.borrow_mut().read_words.push_back(1530);
+
+    let it = IT::with_transport(Box::new(transport), Some(1530)).unwrap();
+
+    assert_eq!(it.size(), (1872, 1404));
+    assert_eq!(it._frame_buffer.len(), 1872 * 1404 / 2);
+    let bus = bus.borrow();
+    assert_eq!(bus.resets, 1);
+    assert_eq!(bus.frames[0], vec![0x60, 0x00, 0x03, 0x02]);
+    assert_eq!(&bus.frames[1][..2], &[0x10, 0x00]);
+    assert_eq!(bus.frames[1].len(), 4 + 20 * 2);
+    // I80CPCR = 1
+    assert_eq!(bus.frames[2], vec![0x60, 0x00, 0x00, 0x11]);
+    assert_eq!(bus.frames[3], vec![0x00, 0x00, 0x00, 0x04]);
+    assert_eq!(bus.frames[4], vec![0x00, 0x00, 0x00, 0x01]);
+    // VCOM read, not written again
+    assert_eq!(bus.frames.len(), 8);
+}
+
+#[test]
+fn test_upload_swaps_the_bytes_of_each_word() {
+    let transport = transport::MockTransport::new();
+    let bus = transport.bus();
+    bus.borrow_mut()
+        .read_words
+        .extend(vec![4, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut it = IT::with_transport(Box::new(transport), None).unwrap();
+    bus.borrow_mut().frames.clear();
+
+    it.load_buffer_from_vec(vec![0x01, 0x23, 0x45, 0x67]);
+    it.draw_buffer_pixel(1, 1, 2, 0x89);
+    it.display(0, 0, 4, 2, IT8951_MODE_2).unwrap();
+
+    // LUTAFSR read, load image area command and its 5 arguments, then the pixels
+    let frames = &bus.borrow().frames;
+    assert_eq!(frames[0], vec![0x60, 0x00, 0x00, 0x10]);
+    assert_eq!(frames[1], vec![0x00, 0x00, 0x12, 0x24]);
+    assert_eq!(frames[3], vec![0x60, 0x00, 0x00, 0x21]);
+    assert_eq!(frames[9], vec![0x00, 0x00, 0x23, 0x01, 0x89, 0x45]);
+}
+
+#[test]
+fn test_timeout_when_the_refresh_never_ends() {
+    let transport = transport::MockTransport::new();
+    let bus = transport.bus();
+    bus.borrow_mut()
+        .read_words
+        .extend(vec![4, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut it = IT::with_transport(Box::new(transport), None).unwrap();
+
+    // LUTAFSR busy twice, then idle : read three times before the image is loaded
+    bus.borrow_mut().read_words.extend(vec![1, 1, 0]);
+    bus.borrow_mut().frames.clear();
+    it.display(0, 0, 4, 2, IT8951_MODE_2).unwrap();
+    let lutafsr_reads = bus
+        .borrow()
+        .frames
+        .iter()
+        .filter(|frame| **frame == vec![0x00, 0x00, 0x12, 0x24])
+        .count();
+    assert_eq!(lutafsr_reads, 3);
+    assert_eq!(bus.borrow().frames[9], vec![0x60, 0x00, 0x00, 0x21]);
+
+    it._display_timeout = Duration::from_millis(0);
+    bus.borrow_mut().read_words.extend(vec![1, 1, 1]);
+    match it.display(0, 0, 4, 2, IT8951_MODE_2) {
+        Err(ITError::Timeout { stage }) => assert_eq!(stage, "waiting for the refresh"),
+        _ => panic!("no timeout"),
+    }
+}
+
+#[test]
+fn test_first_read_at_the_read_clock() {
+    let transport = transport::MockTransport::new();
+    let bus = transport.bus();
+    bus.borrow_mut()
+        .read_words
+        .extend(vec![4, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    IT::with_transport_at_speeds(Box::new(transport), None, 1_000_000, 2_000_000).unwrap();
+
+    // the device info is read at the read clock, the default one is never used
+    let speeds = bus.borrow().speeds.clone();
+    assert_eq!(&speeds[..2], &[2_000_000, 1_000_000]);
+    assert!(!speeds.contains(&DEFAULT_SPEED_HZ));
+}
+
+#[test]
+fn test_self_test_steps_the_clock_down() {
+    let transport = transport::MockTransport::new();
+    let bus = transport.bus();
+    bus.borrow_mut()
+        .read_words
+        .extend(vec![4, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut it = IT::with_transport(Box::new(transport), None).unwrap();
+    it.set_spi_speeds(1_000_000, 2_000_000);
+    bus.borrow_mut().speeds.clear();
+
+    // one wrong word at 16 MHz and 8 MHz, then right
+    for wrong in [true, true, false].iter() {
+        let mut words: Vec<u16> = (0..SELF_TEST_WORDS).map(self_test_word).collect();
+        if *wrong {
+            words[100] ^= 0x0010;
+        }
+        bus.borrow_mut().read_words.extend(words);
+    }
+
+    assert_eq!(it.self_test_spi_speed(16_000_000).unwrap(), Some(4_000_000));
+    assert_eq!(it._write_speed_hz, 4_000_000);
+    // each write at the clock tried, each read back at the read clock
+    let speeds = bus.borrow().speeds.clone();
+    assert_eq!(&speeds[..4], &[16_000_000, 1_000_000, 16_000_000, 8_000_000]);
+    assert_eq!(speeds.last(), Some(&4_000_000));
+}
+
+#[test]
+fn test_timeout_when_hrdy_stays_busy() {
+    let transport = transport::MockTransport::new();
+    let bus = transport.bus();
+    bus.borrow_mut()
+        .read_words
+        .extend(vec![4, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut it = IT::with_transport(Box::new(transport), None).unwrap();
+    it._hrdy_timeout = Duration::from_millis(10);
+
+    // a loose ribbon cable
+    bus.borrow_mut().busy = true;
+    it.load_buffer_from_vec(vec![0; 4]);
+    match it.display(0, 0, 4, 2, IT8951_MODE_2) {
+        Err(ITError::Timeout { stage }) => assert_eq!(stage, "sending a command"),
+        _ => panic!("no timeout"),
+    }
+
+    // plugged back in
+    bus.borrow_mut().busy = false;
+    bus.borrow_mut()
+        .read_words
+        .extend(vec![4, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert!(it.reset().is_ok());
+    assert_eq!(bus.borrow().resets, 2);
+    assert!(it.display(0, 0, 4, 2, IT8951_MODE_2).is_ok());
+}
+
+#[test]
+fn test_bus_failure_is_an_error() {
+    let transport = transport::MockTransport::new();
+    let bus = transport.bus();
+    bus.borrow_mut()
+        .read_words
+        .extend(vec![4, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut it = IT::with_transport(Box::new(transport), None).unwrap();
+
+    // the SPI device gone
+    bus.borrow_mut().broken = true;
+    it.load_buffer_from_vec(vec![0; 4]);
+    match it.display(0, 0, 4, 2, IT8951_MODE_2) {
+        Err(ITError::Bus { .. }) => (),
+        _ => panic!("no bus error"),
+    }
+    assert!(it.vcom().is_err());
+    assert!(it.reset().is_err());
+
+    bus.borrow_mut().broken = false;
+    bus.borrow_mut()
+        .read_words
+        .extend(vec![4, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert!(it.reset().is_ok());
+}
+
+#[test]
+fn test_vcom_written_only_when_given() {
+    let dev_info_words: Vec<u16> = vec![4, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    // untouched : no VCOM command at all
+    let transport = transport::MockTransport::new();
+    let bus = transport.bus();
+    bus.borrow_mut().read_words.extend(dev_info_words.clone());
+    let it = IT::with_transport(Box::new(transport), None).unwrap();
+    assert_eq!(bus.borrow().frames.len(), 5);
+    drop(it);
+
+    // given and different : read, written, read again
+    let transport = transport::MockTransport::new();
+    let bus = transport.bus();
+    bus.borrow_mut().read_words.extend(dev_info_words);
+    bus.borrow_mut().read_words.extend(vec![1610, 1530]);
+    let it = IT::with_transport(Box::new(transport), Some(1530)).unwrap();
+    let frames = bus.borrow().frames.clone();
+    assert_eq!(frames.len(), 5 + 3 + 3 + 3);
+    assert_eq!(frames[8], vec![0x60, 0x00, 0x00, 0x39]);
+    assert_eq!(frames[9], vec![0x00, 0x00, 0x00, 0x01]);
+    assert_eq!(frames[10], vec![0x00, 0x00, 0x05, 0xFA]);
+    bus.borrow_mut().read_words.push_back(1530);
+    assert_eq!(it.vcom().unwrap(), 1530);
+}
+
+#[test]
+fn test_parse_vcom() {
+    assert_eq!(parse_vcom("-1.53"), Ok(1530));
+    assert_eq!(parse_vcom("1.53"), Ok(1530));
+    assert_eq!(parse_vcom("1530"), Ok(1530));
+    assert_eq!(parse_vcom("-2.1"), Ok(2100));
+    assert!(parse_vcom("-15.3").is_err());
+    assert!(parse_vcom("abc").is_err());
+}
+
+#[test]
+fn test_dev_info_from_words() {
+    // "SWv_0.1.1" and "M641", low byte first
+    let text_words = |text: &str| -> Vec<u16> {
+        let mut bytes: Vec<u8> = text.bytes().collect();
+        bytes.resize(16, 0);
+        bytes
+            .chunks(2)
+            .map(|pair| pair[0] as u16 | (pair[1] as u16) << 8)
+            .collect()
+    };
+    let mut words: Vec<u16> = vec![1872, 1404, 0x36E0, 0x0012];
+    words.extend(text_words("SWv_0.1.1"));
+    words.extend(text_words("M641"));
+
+    let dev_info = DevInfo::from_words(&words).unwrap();
+    assert_eq!(dev_info.panel_width, 1872);
+    assert_eq!(dev_info.panel_height, 1404);
+    assert_eq!(dev_info.image_buffer_address(), 0x0012_36E0);
+    assert_eq!(dev_info.firmware(), "SWv_0.1.1");
+    assert_eq!(dev_info.lut(), "M641");
+    assert_eq!(
+        dev_info.to_json(1530),
+        "{\"panel_width\": 1872, \"panel_height\": 1404, \"image_buffer_address\": 1193696, \
+         \"firmware\": \"SWv_0.1.1\", \"lut\": \"M641\", \"vcom_mv\": 1530}"
+    );
+    assert!(dev_info.describe(1530).contains("VCOM : -1.53 V (1530)"));
+
+    match DevInfo::from_words(&words[..19]) {
+        Err(ITError::ShortDevInfo { words }) => assert_eq!(words, 19),
+        _ => panic!("short device info accepted"),
+    }
+}
+
+#[test]
+fn test_a2_mode_of_the_panel() {
+    let with_lut = |lut: &str| -> DevInfo {
+        let mut bytes: Vec<u8> = lut.bytes().collect();
+        bytes.resize(16, 0);
+        let mut dev_info = DevInfo::with_panel_size(800, 600);
+        for (word, pair) in dev_info.lut_version.iter_mut().zip(bytes.chunks(2)) {
+            *word = pair[0] as u16 | (pair[1] as u16) << 8;
+        }
+        dev_info
+    };
+
+    assert_eq!(with_lut("M641").a2_mode(), 4);
+    assert_eq!(with_lut("M841_TFA5210").a2_mode(), 6);
+    assert_eq!(with_lut("M841_TFAB512").a2_mode(), 6);
+    // not queried yet
+    assert_eq!(DevInfo::with_panel_size(1872, 1404).a2_mode(), 6);
+}
+
+#[test]
+fn test_json_string_escapes() {
+    assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
 }
@@ -0,0 +1,354 @@
+use libc::{c_int, c_ulong, input_event};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::str::FromStr;
+
+// from linux/input.h
+static EV_KEY: u16 = 0x01;
+// _IOW('E', 0x90, int) : the other readers of the device stop getting its events
+static EVIOCGRAB: c_ulong = 0x4004_4590;
+static KEY_RELEASED: i32 = 0;
+
+static KEY_ESC: u16 = 1;
+static KEY_BACKSPACE: u16 = 14;
+static KEY_TAB: u16 = 15;
+static KEY_ENTER: u16 = 28;
+static KEY_LEFTCTRL: u16 = 29;
+static KEY_LEFTSHIFT: u16 = 42;
+static KEY_RIGHTSHIFT: u16 = 54;
+static KEY_LEFTALT: u16 = 56;
+static KEY_CAPSLOCK: u16 = 58;
+static KEY_KPENTER: u16 = 96;
+static KEY_RIGHTCTRL: u16 = 97;
+static KEY_RIGHTALT: u16 = 100;
+
+// US layout : key code, character, with shift
+static PRINTABLE_KEYS: [(u16, char, char); 60] = [
+    (2, '1', '!'),
+    (3, '2', '@'),
+    (4, '3', '#'),
+    (5, '4', '$'),
+    (6, '5', '%'),
+    (7, '6', '^'),
+    (8, '7', '&'),
+    (9, '8', '*'),
+    (10, '9', '('),
+    (11, '0', ')'),
+    (12, '-', '_'),
+    (13, '=', '+'),
+    (16, 'q', 'Q'),
+    (17, 'w', 'W'),
+    (18, 'e', 'E'),
+    (19, 'r', 'R'),
+    (20, 't', 'T'),
+    (21, 'y', 'Y'),
+    (22, 'u', 'U'),
+    (23, 'i', 'I'),
+    (24, 'o', 'O'),
+    (25, 'p', 'P'),
+    (26, '[', '{'),
+    (27, ']', '}'),
+    (30, 'a', 'A'),
+    (31, 's', 'S'),
+    (32, 'd', 'D'),
+    (33, 'f', 'F'),
+    (34, 'g', 'G'),
+    (35, 'h', 'H'),
+    (36, 'j', 'J'),
+    (37, 'k', 'K'),
+    (38, 'l', 'L'),
+    (39, ';', ':'),
+    (40, '\'', '"'),
+    (41, '`', '~'),
+    (43, '\\', '|'),
+    (44, 'z', 'Z'),
+    (45, 'x', 'X'),
+    (46, 'c', 'C'),
+    (47, 'v', 'V'),
+    (48, 'b', 'B'),
+    (49, 'n', 'N'),
+    (50, 'm', 'M'),
+    (51, ',', '<'),
+    (52, '.', '>'),
+    (53, '/', '?'),
+    (55, '*', '*'),
+    (57, ' ', ' '),
+    (71, '7', '7'),
+    (72, '8', '8'),
+    (73, '9', '9'),
+    (74, '-', '-'),
+    (75, '4', '4'),
+    (76, '5', '5'),
+    (77, '6', '6'),
+    (78, '+', '+'),
+    (79, '1', '1'),
+    (80, '2', '2'),
+    (98, '/', '/'),
+];
+// the rest of the keypad, num lock on
+static KEYPAD_KEYS: [(u16, char); 3] = [(81, '3'), (82, '0'), (83, '.')];
+
+// key code, what xterm sends
+static FUNCTION_KEYS: [(u16, &str); 16] = [
+    (59, "\x1bOP"),
+    (60, "\x1bOQ"),
+    (61, "\x1bOR"),
+    (62, "\x1bOS"),
+    (63, "\x1b[15~"),
+    (64, "\x1b[17~"),
+    (65, "\x1b[18~"),
+    (66, "\x1b[19~"),
+    (67, "\x1b[20~"),
+    (68, "\x1b[21~"),
+    (87, "\x1b[23~"),
+    (88, "\x1b[24~"),
+    (104, "\x1b[5~"),
+    (109, "\x1b[6~"),
+    (110, "\x1b[2~"),
+    (111, "\x1b[3~"),
+];
+// key code, last byte after ESC [ or ESC O
+static CURSOR_KEYS: [(u16, char); 6] = [
+    (103, 'A'),
+    (108, 'B'),
+    (106, 'C'),
+    (105, 'D'),
+    (102, 'H'),
+    (107, 'F'),
+];
+
+/// Where the keys typed in the terminal come from, as given to --keyboard
+#[derive(Debug, Clone, PartialEq)]
+pub enum Keyboard {
+    /// The terminal ardoise runs in, e.g. the Linux console
+    Stdin,
+    /// An evdev device, e.g. /dev/input/event0, read with the US layout
+    Evdev(String),
+}
+
+impl FromStr for Keyboard {
+    type Err = String;
+
+    fn from_str(keyboard: &str) -> Result<Keyboard, String> {
+        match keyboard {
+            "stdin" => Ok(Keyboard::Stdin),
+            path if path.starts_with('/') => Ok(Keyboard::Evdev(path.to_string())),
+            _ => Err(format!(
+                "unknown keyboard {}, write stdin or a /dev/input path",
+                keyboard
+            )),
+        }
+    }
+}
+
+/// Modifiers held down, and caps lock
+#[derive(Debug, Default)]
+pub struct KeyState {
+    shift: bool,
+    ctrl: bool,
+    alt: bool,
+    caps_lock: bool,
+}
+
+impl KeyState {
+    /// Bytes a terminal sends for an evdev key event, none for releases and
+    /// modifiers
+    pub fn translate(&mut self, code: u16, value: i32, application_cursor: bool) -> Vec<u8> {
+        let pressed = value != KEY_RELEASED;
+        if code == KEY_LEFTSHIFT || code == KEY_RIGHTSHIFT {
+            self.shift = pressed;
+        } else if code == KEY_LEFTCTRL || code == KEY_RIGHTCTRL {
+            self.ctrl = pressed;
+        } else if code == KEY_LEFTALT || code == KEY_RIGHTALT {
+            self.alt = pressed;
+        } else if code == KEY_CAPSLOCK && value == 1 {
+            self.caps_lock = !self.caps_lock;
+        }
+        if !pressed {
+            return Vec::new();
+        }
+
+        let mut bytes = Vec::new();
+        if self.alt {
+            bytes.push(0x1b);
+        }
+        if let Some(&(_, normal, shifted)) = PRINTABLE_KEYS.iter().find(|key| key.0 == code) {
+            let mut c = if self.shift { shifted } else { normal };
+            if self.caps_lock && c.is_ascii_alphabetic() {
+                c = if self.shift {
+                    c.to_ascii_lowercase()
+                } else {
+                    c.to_ascii_uppercase()
+                };
+            }
+            match c {
+                ' ' if self.ctrl => bytes.push(0),
+                '/' if self.ctrl => bytes.push(0x1f),
+                '@'..='_' | 'a'..='z' if self.ctrl => {
+                    bytes.push(c.to_ascii_uppercase() as u8 & 0x1f)
+                }
+                _ => bytes.push(c as u8),
+            }
+        } else if let Some(&(_, c)) = KEYPAD_KEYS.iter().find(|key| key.0 == code) {
+            bytes.push(c as u8);
+        } else if let Some(&(_, text)) = FUNCTION_KEYS.iter().find(|key| key.0 == code) {
+            bytes.extend_from_slice(text.as_bytes());
+        } else if let Some(&(_, last)) = CURSOR_KEYS.iter().find(|key| key.0 == code) {
+            let introducer = if application_cursor { 'O' } else { '[' };
+            bytes.extend_from_slice(format!("\x1b{}{}", introducer, last).as_bytes());
+        } else if code == KEY_ENTER || code == KEY_KPENTER {
+            bytes.push(b'\r');
+        } else if code == KEY_BACKSPACE {
+            bytes.push(0x7f);
+        } else if code == KEY_TAB {
+            bytes.push(b'\t');
+        } else if code == KEY_ESC {
+            bytes.push(0x1b);
+        } else {
+            return Vec::new();
+        }
+        bytes
+    }
+}
+
+/// Keys typed for the program of the terminal
+pub struct KeyboardInput {
+    file: File,
+    // None for stdin, whose bytes are passed as they are
+    evdev: Option<KeyState>,
+    // settings of stdin before the raw mode, put back on drop
+    saved_termios: Option<libc::termios>,
+}
+
+impl KeyboardInput {
+    pub fn open(keyboard: &Keyboard) -> Result<KeyboardInput, io::Error> {
+        match keyboard {
+            Keyboard::Stdin => {
+                let fd = unsafe { libc::dup(libc::STDIN_FILENO) };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(KeyboardInput {
+                    file: unsafe { File::from_raw_fd(fd) },
+                    evdev: None,
+                    saved_termios: raw_mode(libc::STDIN_FILENO),
+                })
+            }
+            Keyboard::Evdev(path) => {
+                let file = OpenOptions::new().read(true).open(path)?;
+                // keep the console behind from getting the keys too
+                if unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGRAB, 1 as c_int) } < 0 {
+                    println!("{}: {}", path, io::Error::last_os_error());
+                }
+                Ok(KeyboardInput {
+                    file,
+                    evdev: Some(KeyState::default()),
+                    saved_termios: None,
+                })
+            }
+        }
+    }
+
+    /// What the keys typed since the last call send, to be read once poll
+    /// tells the input is readable. Err(UnexpectedEof) once stdin is closed.
+    pub fn read(&mut self, application_cursor: bool) -> Result<Vec<u8>, io::Error> {
+        let mut buffer = [0u8; 64 * mem::size_of::<input_event>()];
+        let length = self.file.read(&mut buffer)?;
+        if length == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "keyboard closed",
+            ));
+        }
+        let state = match &mut self.evdev {
+            Some(state) => state,
+            None => return Ok(buffer[..length].to_vec()),
+        };
+        let mut bytes = Vec::new();
+        for event in buffer[..length].chunks_exact(mem::size_of::<input_event>()) {
+            let event: input_event =
+                unsafe { std::ptr::read_unaligned(event.as_ptr() as *const input_event) };
+            if event.type_ == EV_KEY {
+                bytes.extend(state.translate(event.code, event.value, application_cursor));
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+impl AsRawFd for KeyboardInput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for KeyboardInput {
+    fn drop(&mut self) {
+        if let Some(termios) = &self.saved_termios {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios) };
+        }
+    }
+}
+
+/// Pass every key of the terminal `fd` as it is typed, Ctrl-C included.
+/// Returns the settings to restore, None when `fd` is not a terminal.
+fn raw_mode(fd: RawFd) -> Option<libc::termios> {
+    unsafe {
+        let mut termios: libc::termios = mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) < 0 {
+            return None;
+        }
+        let saved = termios;
+        libc::cfmakeraw(&mut termios);
+        libc::tcsetattr(fd, libc::TCSANOW, &termios);
+        Some(saved)
+    }
+}
+
+#[test]
+fn test_parse_keyboard() {
+    assert_eq!("stdin".parse(), Ok(Keyboard::Stdin));
+    assert_eq!(
+        "/dev/input/event3".parse(),
+        Ok(Keyboard::Evdev("/dev/input/event3".to_string()))
+    );
+    assert!("event3".parse::<Keyboard>().is_err());
+}
+
+#[test]
+fn test_translate_keys() {
+    let mut state = KeyState::default();
+    let mut typed = |code: u16, value: i32| state.translate(code, value, false);
+
+    assert_eq!(typed(30, 1), b"a".to_vec());
+    assert_eq!(typed(30, 2), b"a".to_vec());
+    assert!(typed(30, 0).is_empty());
+
+    assert!(typed(KEY_LEFTSHIFT, 1).is_empty());
+    assert_eq!(typed(3, 1), b"@".to_vec());
+    assert_eq!(typed(30, 1), b"A".to_vec());
+    typed(KEY_LEFTSHIFT, 0);
+
+    typed(KEY_CAPSLOCK, 1);
+    typed(KEY_CAPSLOCK, 0);
+    assert_eq!(typed(30, 1), b"A".to_vec());
+    assert_eq!(typed(3, 1), b"2".to_vec());
+    typed(KEY_CAPSLOCK, 1);
+
+    typed(KEY_LEFTCTRL, 1);
+    assert_eq!(typed(46, 1), vec![0x03]);
+    assert_eq!(typed(26, 1), vec![0x1b]);
+    typed(KEY_LEFTCTRL, 0);
+
+    typed(KEY_LEFTALT, 1);
+    assert_eq!(typed(49, 1), b"\x1bn".to_vec());
+    typed(KEY_LEFTALT, 0);
+
+    assert_eq!(typed(KEY_ENTER, 1), b"\r".to_vec());
+    assert_eq!(typed(KEY_BACKSPACE, 1), vec![0x7f]);
+    assert_eq!(typed(111, 1), b"\x1b[3~".to_vec());
+    assert_eq!(typed(103, 1), b"\x1b[A".to_vec());
+    assert_eq!(state.translate(103, 1, true), b"\x1bOA".to_vec());
+}
@@ -1,31 +1,198 @@
 extern crate captrs;
 extern crate clap;
 extern crate x11cap;
-use clap::App;
+use clap::{App, AppSettings, SubCommand};
 
 use captrs::Capturer;
 use std::error::Error;
-use std::time::Instant;
+use std::fs;
+use libc::c_int;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 //use x11_screenshot::Screen;
-use x11cap::{Bgr8, Image};
+use x11cap::Bgr8;
 #[macro_use]
 extern crate dlopen_derive;
 
 mod imagery;
-use imagery::{Area, Imagery};
+use imagery::{new_bgr8, Imagery};
+#[cfg(test)]
+use imagery::Area;
 //use imagery::
 
+mod dithering;
+use dithering::DitherMode;
+
+mod eink_display;
+use eink_display::{EinkDisplay, WaveformMode};
+
+mod eink_interface;
+
+mod simulated;
+use simulated::SimulatedDisplay;
+
+mod ghosting;
+use ghosting::GhostingControl;
+
+mod power;
+use power::{PowerControl, PowerState};
+
+mod scaling;
+use scaling::{view_size, ScaleFilter, ScaleMode, Scaler};
+
+mod capture;
+use capture::{
+    copy_regions, Backoff, CaptureError, CaptureSource, CaptureTarget, DamageCapture, ScreenCapture,
+    Source,
+};
+
+mod cursor;
+use cursor::CursorStyle;
+
+mod framebuffer;
+use framebuffer::{DrmCapture, FramebufferCapture};
+
+mod wayland;
+use wayland::WaylandCapture;
+
+mod vnc;
+use vnc::VncCapture;
+
+mod font;
+use font::Font;
+
+mod vt;
+
+mod keyboard;
+
+mod term;
+use term::{TerminalCapture, TerminalSettings};
+
 #[path = "it8951.rs"]
 mod it8951;
+use it8951::transport::DEFAULT_SPEED_HZ;
+
+/// Command line settings of the capture and refresh loop
+struct Settings {
+    source: Source,
+    vnc_password: Option<String>,
+    // ardoise term : a terminal instead of the source
+    terminal: Option<TerminalSettings>,
+    // monitor, region or window
+    target: CaptureTarget,
+    // grab the whole screen on each loop instead of waiting for X damage
+    poll: bool,
+    // how the mouse cursor is drawn on the X screen
+    cursor: CursorStyle,
+    rotation: u16,
+    // rotate on the CPU even when the panel can do it
+    software_rotation: bool,
+    // None : chosen for each area
+    mode: Option<WaveformMode>,
+    dither: DitherMode,
+    scale_mode: ScaleMode,
+    scale_filter: ScaleFilter,
+    full_refresh_count: u32,
+    full_refresh_area: u32,
+    full_refresh_idle: Option<Duration>,
+    standby_idle: Option<Duration>,
+    sleep_idle: Option<Duration>,
+}
+
+// set by SIGINT and SIGTERM: the loop ends and the panel controller goes to sleep
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_stop_signal(_signal: c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+fn parse_number_arg<T: FromStr>(matches: &clap::ArgMatches, name: &str, default: T) -> T {
+    match matches.value_of(name) {
+        None => default,
+        Some(value) => match value.parse() {
+            Ok(n) => n,
+            Err(_) => {
+                println!("{}: write a number", name);
+                default
+            }
+        },
+    }
+}
+
+/// A delay given in seconds, 0 for never
+fn parse_delay_arg(matches: &clap::ArgMatches, name: &str, default: u64) -> Option<Duration> {
+    match parse_number_arg(matches, name, default) {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
+}
 
 fn main() {
     let matches = App::new("Ardoise")
         .version("1.0")
         .author("Cyril Jacquet <cyril.jacquet.libre@mailfence.com>")
         .about("E-Ink")
+        // --vcom -1.53
+        .setting(AppSettings::AllowNegativeNumbers)
         .args_from_usage(
             "-r, --rotate=[ROTATION] 'rotate'
-                              -d, --display=[DISPLAY] 'select display'",
+                              -d, --display=[DISPLAY] 'select display'
+                              --region=[REGION] 'capture only this rectangle of the X screen: x,y,width,height'
+                              --window=[WINDOW] 'capture only this window, by id (e.g. 0x3c00007) or by title or class, e.g. FocusWriter'
+                              --source=[SOURCE] 'where the frames come from: x11, wayland[:OUTPUT], fb:/dev/fb0, drm:/dev/dri/card0 or vnc:HOST[:DISPLAY] (default x11)'
+                              --vnc-password-file=[FILE] 'read the password of the VNC server from this file'
+                              --cursor=[CURSOR] 'mouse cursor: x11, contrast for a large black arrow, or none (default x11)'
+                              --poll 'grab the whole screen on each loop instead of waiting for X damage events'
+                              -s, --simulate 'drive a simulated panel instead of the IT8951'
+                              --simulate-size=[SIZE] 'size of the simulated panel, e.g. 1872x1404'
+                              --png-dir=[DIR] 'dump each simulated refresh as a PNG file in DIR'
+                              --outline 'outline the refreshed rectangle in the simulated PNGs'
+                              --transport=[TRANSPORT] 'wires to the IT8951: auto, spidev or bcm2835 (default auto)'
+                              --spi-device=[PATH] 'SPI device of the spidev transport (default /dev/spidev0.0)'
+                              --gpio-chip=[PATH] 'GPIO chip of the spidev transport (default /dev/gpiochip0)'
+                              --spi-write-speed=[HZ] 'SPI clock of the writes (default 7812500)'
+                              --spi-read-speed=[HZ] 'SPI clock of the reads (default 7812500)'
+                              --vcom=[VCOM] 'VCOM printed on the panel cable, e.g. -1.53 or 1530, set on start (default: left as the controller has it)'
+                              --software-rotation 'rotate on the CPU instead of the panel controller'
+                              -m, --mode=[MODE] 'waveform: auto, du, gc16, gl16 or a2'
+                              --dither=[DITHER] 'dithering: none, ordered, diffusion or mono (default none)'
+                              --scale=[SCALE] 'fitting of the screen to the panel: fit, fill or integer (default fit)'
+                              --scale-filter=[FILTER] 'scaling filter: nearest, bilinear or area (default area)'
+                              --full-refresh-count=[N] 'full refresh after N partial refreshes, 0 for never (default 100)'
+                              --full-refresh-area=[PERCENT] 'full refresh once partial refreshes covered PERCENT of the panel, 0 for never (default 300)'
+                              --full-refresh-idle=[SECONDS] 'full refresh after SECONDS without change, 0 for never (default 30)'
+                              --standby-idle=[SECONDS] 'panel controller in standby after SECONDS without refresh, 0 for never (default 60)'
+                              --sleep-idle=[SECONDS] 'panel controller asleep after SECONDS without refresh, 0 for never (default 600)'",
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("measure the SPI throughput, byte by byte and in bulk")
+                .args_from_usage("--bytes=[N] 'bytes to send (default a full 1872x1404 4bpp frame)'"),
+        )
+        .subcommand(SubCommand::with_name("vcom").about("print the VCOM of the panel controller"))
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("print what the panel controller tells about itself")
+                .args_from_usage("--json 'one JSON object instead of text'"),
+        )
+        .subcommand(
+            SubCommand::with_name("selftest")
+                .about("find the fastest SPI write clock the panel controller copes with")
+                .args_from_usage("--from=[HZ] 'first clock tried, halved on each error (default 32000000)'"),
+        )
+        .subcommand(
+            SubCommand::with_name("term")
+                .about("run a shell, or another program, in a terminal drawn on the panel")
+                .setting(AppSettings::TrailingVarArg)
+                .args_from_usage(
+                    "--font=[FILE] 'PSF console font, e.g. /usr/share/consolefonts/Uni2-TerminusBold32x16.psf.gz (default: built in, 16x32)'
+                              --font-scale=[N] 'draw the font N times bigger (default 1)'
+                              --keyboard=[INPUT] 'where the keys come from: stdin or an evdev device, e.g. /dev/input/event0 (default stdin)'
+                              [COMMAND]... 'program run in the terminal, and its arguments (default $SHELL)'",
+                ),
         )
         .get_matches();
 
@@ -46,53 +213,333 @@ fn main() {
     };
 
 
+    let source_arg: Source = match matches.value_of("source").unwrap_or("x11").parse() {
+        Ok(source) => source,
+        Err(error) => panic!("{}", error),
+    };
+
+    // first line of the file
+    let vnc_password_arg: Option<String> = matches.value_of("vnc-password-file").map(|path| {
+        match fs::read_to_string(path) {
+            Ok(password) => password.lines().next().unwrap_or("").to_string(),
+            Err(error) => panic!("{}: {}", path, error),
+        }
+    });
+
+    let terminal_arg: Option<TerminalSettings> =
+        matches.subcommand_matches("term").map(|term_matches| {
+            let font = match term_matches.value_of("font") {
+                Some(path) => match Font::open(path) {
+                    Ok(font) => font,
+                    Err(error) => panic!("{}", error),
+                },
+                None => Font::bundled(),
+            };
+            let keyboard = match term_matches.value_of("keyboard").unwrap_or("stdin").parse() {
+                Ok(keyboard) => keyboard,
+                Err(error) => panic!("{}", error),
+            };
+            TerminalSettings {
+                command: match term_matches.values_of("COMMAND") {
+                    Some(values) => values.map(String::from).collect(),
+                    None => Vec::new(),
+                },
+                font: font,
+                font_scale: parse_number_arg(term_matches, "font-scale", 1).max(1),
+                keyboard: keyboard,
+            }
+        });
+
+    let cursor_arg: CursorStyle = match matches.value_of("cursor").unwrap_or("x11").parse() {
+        Ok(cursor) => cursor,
+        Err(error) => {
+            println!("{}, using x11", error);
+            CursorStyle::X11
+        }
+    };
+
+    let target_arg = match (matches.value_of("region"), matches.value_of("window")) {
+        (Some(_), Some(_)) => panic!("--region and --window cannot be used together"),
+        (Some(region), None) => match capture::parse_region(region) {
+            Ok(region) => CaptureTarget::Region(region),
+            Err(error) => panic!("{}", error),
+        },
+        (None, Some(window)) => CaptureTarget::Window(capture::parse_window(window)),
+        (None, None) => CaptureTarget::Monitor(display_number_arg),
+    };
+
+    // None : chosen for each area
+    let mode_arg: Option<WaveformMode> = match matches.value_of("mode").unwrap_or("auto") {
+        "auto" => None,
+        mode => match mode.parse() {
+            Ok(mode) => Some(mode),
+            Err(error) => {
+                println!("{}, using auto", error);
+                None
+            }
+        },
+    };
+
+    let dither_arg: DitherMode = match matches.value_of("dither").unwrap_or("none").parse() {
+        Ok(dither) => dither,
+        Err(error) => {
+            println!("{}, using none", error);
+            DitherMode::None
+        }
+    };
+
+    let scale_mode_arg: ScaleMode = match matches.value_of("scale").unwrap_or("fit").parse() {
+        Ok(scale_mode) => scale_mode,
+        Err(error) => {
+            println!("{}, using fit", error);
+            ScaleMode::Fit
+        }
+    };
+
+    let scale_filter_arg: ScaleFilter =
+        match matches.value_of("scale-filter").unwrap_or("area").parse() {
+            Ok(scale_filter) => scale_filter,
+            Err(error) => {
+                println!("{}, using area", error);
+                ScaleFilter::Area
+            }
+        };
+
+    let settings = Settings {
+        source: source_arg,
+        vnc_password: vnc_password_arg,
+        terminal: terminal_arg,
+        target: target_arg,
+        poll: matches.is_present("poll"),
+        cursor: cursor_arg,
+        rotation: rotation_arg,
+        software_rotation: matches.is_present("software-rotation"),
+        mode: mode_arg,
+        dither: dither_arg,
+        scale_mode: scale_mode_arg,
+        scale_filter: scale_filter_arg,
+        full_refresh_count: parse_number_arg(&matches, "full-refresh-count", 100),
+        full_refresh_area: parse_number_arg(&matches, "full-refresh-area", 300),
+        full_refresh_idle: parse_delay_arg(&matches, "full-refresh-idle", 30),
+        standby_idle: parse_delay_arg(&matches, "standby-idle", 60),
+        sleep_idle: parse_delay_arg(&matches, "sleep-idle", 600),
+    };
+
+    unsafe {
+        let handler = on_stop_signal as extern "C" fn(c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
 
 //------------------------------------------
 
-    let now = Instant::now();
+    if matches.is_present("simulate") {
+        let size_arg = matches.value_of("simulate-size").unwrap_or("1872x1404");
+        let size: Vec<u16> = size_arg.split('x').filter_map(|n| n.parse().ok()).collect();
+        if size.len() != 2 {
+            panic!("simulate-size must look like 1872x1404");
+        }
+        let output_dir = matches.value_of("png-dir").map(PathBuf::from);
 
-    let mut image_array: [Option<Image>; 2] = [None, None];
+        let mut simulated = SimulatedDisplay::new(
+            it8951::DevInfo::with_panel_size(size[0], size[1]),
+            output_dir,
+        );
+        simulated.set_outline(matches.is_present("outline"));
 
-    let interface: Result<it8951::IT, Box<Error>> = it8951::IT::new();
+        run(&mut simulated, &settings);
+        return;
+    }
 
-    if interface.is_err() {
-        panic!("no interface");
+    let transport = it8951::IT::open_transport(
+        matches.value_of("transport").unwrap_or("auto"),
+        matches.value_of("spi-device").unwrap_or("/dev/spidev0.0"),
+        matches.value_of("gpio-chip").unwrap_or("/dev/gpiochip0"),
+    );
+
+    let read_speed: u32 = parse_number_arg(&matches, "spi-read-speed", DEFAULT_SPEED_HZ);
+    let write_speed: u32 = parse_number_arg(&matches, "spi-write-speed", DEFAULT_SPEED_HZ);
+
+    if let Some(bench_matches) = matches.subcommand_matches("bench") {
+        let transport = match transport {
+            Ok(transport) => transport,
+            Err(error) => panic!("no transport: {}", error),
+        };
+        transport.set_speed(write_speed);
+        let byte_count: usize = parse_number_arg(bench_matches, "bytes", 1872 * 1404 / 2);
+        let (bytewise, bulk) =
+            match it8951::transport::measure_throughput(&*transport, byte_count) {
+                Ok(throughput) => throughput,
+                Err(error) => panic!("bench failed: {}", error),
+            };
+        println!("{} bytes", byte_count);
+        println!("byte by byte : {:.0} bytes/s", bytewise);
+        println!("bulk : {:.0} bytes/s ({:.1}x)", bulk, bulk / bytewise);
+        return;
     }
-    let mut interface = interface.unwrap();
 
-    let capt = Capturer::new(display_number_arg).unwrap();
-    println!("geom : {}, {}", capt.geometry().0, capt.geometry().1);
+    // the queries never write it
+    let query = matches.subcommand_matches("vcom").is_some()
+        || matches.subcommand_matches("info").is_some();
+    let vcom: Option<u16> = match matches.value_of("vcom") {
+        Some(_) if query => None,
+        Some(text) => match it8951::parse_vcom(text) {
+            Ok(vcom) => Some(vcom),
+            Err(error) => panic!("{}", error),
+        },
+        None => None,
+    };
 
-    let imagery = Imagery::new(
+    let interface: Result<it8951::IT, Box<Error>> = transport.and_then(|transport| {
+        it8951::IT::with_transport_at_speeds(transport, vcom, read_speed, write_speed)
+    });
+
+    let mut interface = match interface {
+        Ok(interface) => interface,
+        Err(error) => panic!("no interface: {}", error),
+    };
+
+    if let Some(info_matches) = matches.subcommand_matches("info") {
+        let dev_info = interface.dev_info();
+        match interface.vcom() {
+            Ok(vcom) if info_matches.is_present("json") => println!("{}", dev_info.to_json(vcom)),
+            Ok(vcom) => println!("{}", dev_info.describe(vcom)),
+            Err(error) => println!("{}", error),
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("vcom").is_some() {
+        match interface.vcom() {
+            Ok(vcom) => println!("VCOM = {}", it8951::format_vcom(vcom)),
+            Err(error) => println!("{}", error),
+        }
+        return;
+    }
+
+    if let Some(selftest_matches) = matches.subcommand_matches("selftest") {
+        let from_speed: u32 = parse_number_arg(selftest_matches, "from", 32_000_000);
+        match interface.self_test_spi_speed(from_speed) {
+            Ok(Some(speed)) => println!("use --spi-write-speed {}", speed),
+            Ok(None) => println!("no SPI write clock works, check the wiring"),
+            Err(error) => println!("{}", error),
+        }
+        return;
+    }
+
+    run(&mut interface, &settings);
+}
+
+fn run<D: EinkDisplay>(interface: &mut D, settings: &Settings) {
+    let now = Instant::now();
+    let rotation_arg = settings.rotation;
+    let mode_arg = settings.mode;
+
+    let panel_view_size = view_size(interface.size(), rotation_arg);
+    let mut capture = open_capture_retrying(settings, panel_view_size);
+    let (mut capture_width, mut capture_height) = capture.geometry();
+    println!("geom : {}, {}", capture_width, capture_height);
+
+    // the capture brought to the panel size, what is compared and refreshed
+    let mut scaler = Scaler::for_panel(
+        capture_width,
+        capture_height,
+        interface.size(),
+        rotation_arg,
+        settings.scale_mode,
+        settings.scale_filter,
+    );
+    let (view_width, view_height) = scaler.view_size();
+    println!("shown in {:?}", scaler.placement());
+
+    // what the panel shows, white at first
+    let mut old_frame: Vec<Bgr8> =
+        vec![new_bgr8(255, 255, 255); view_width as usize * view_height as usize];
+
+    let mut imagery = Imagery::new(
         interface.size().0,
         interface.size().1,
-        capt.geometry().0 as u16,
-        capt.geometry().1 as u16,
+        view_width,
+        view_height,
         rotation_arg,
     );
+    let hardware_rotation =
+        rotation_arg != 0 && !settings.software_rotation && interface.set_rotation(rotation_arg);
+    imagery.set_hardware_rotation(hardware_rotation);
     //let mut it_c_interface: eink_interface::Interface = eink_interface::Interface::new();
 
+    let mut ghosting = GhostingControl::new(
+        interface.size().0,
+        interface.size().1,
+        settings.full_refresh_count,
+        settings.full_refresh_area,
+        settings.full_refresh_idle,
+    );
+    let mut power = PowerControl::new(settings.standby_idle, settings.sleep_idle);
+    // a refresh failed, the panel may not show old_frame
+    let mut pending_full_refresh = false;
+
     loop {
+        if STOP.load(Ordering::SeqCst) {
+            println!("stopping");
+            return;
+        }
         let now2 = Instant::now();
 
-        let capt_result = Capturer::new(display_number_arg);
-        let mut capt = capt_result.unwrap();
+        // None : anything may have changed
+        let damage = match capture.capture() {
+            Ok(damage) => damage,
+            Err(error @ CaptureError::Ended { .. }) => {
+                println!("{}", error);
+                return;
+            }
+            Err(error) => {
+                // the panel keeps the last frame until the capture is back
+                println!("{}", error);
+                capture = open_capture_retrying(settings, panel_view_size);
+                continue;
+            }
+        };
+
+        if capture.geometry() != (capture_width, capture_height) {
+            capture_width = capture.geometry().0;
+            capture_height = capture.geometry().1;
+            println!("geom : {}, {}", capture_width, capture_height);
+            // same view, the capture only lands somewhere else in it
+            scaler = Scaler::for_panel(
+                capture_width,
+                capture_height,
+                interface.size(),
+                rotation_arg,
+                settings.scale_mode,
+                settings.scale_filter,
+            );
+            println!("shown in {:?}", scaler.placement());
+            scaler.update(capture.frame(), None);
+            let new_slice = scaler.frame();
+            old_frame = new_slice.to_vec();
+            pending_full_refresh = !full_refresh(
+                interface,
+                &mut power,
+                &imagery,
+                new_slice,
+                settings,
+                hardware_rotation,
+            );
+            ghosting.full_refresh_done(Instant::now());
+            continue;
+        }
 
-        let _result = capt.capture_store_frame();
-        let image = capt.image.unwrap();
-        image_array.reverse();
-        image_array[1] = Some(image);
+        // damage of the view
+        let damage = scaler.update(capture.frame(), damage.as_ref().map(|regions| &regions[..]));
+        let new_slice = scaler.frame();
 
         let a = now2.elapsed().as_millis();
         //println!("a: {}", &a);
 
-        let old_image = &image_array[0];
-        let new_image = &image_array[1];
-
-        let area_choice;
+        let areas;
 
-        if new_image.is_none() {
-            println!("new_image is none");
+        if new_slice.len() != old_frame.len() {
             continue;
         }
 /*         if old_image.is_none() {
@@ -111,17 +558,43 @@ fn main() {
             });
         } else { */
             //let a1 = now2.elapsed().as_millis();
-            area_choice = imagery.compare(old_image, new_image);
+            areas = match &damage {
+                Some(regions) => imagery.compare_regions(&old_frame, new_slice, regions),
+                None => imagery.find_changed_areas(&old_frame, new_slice),
+            };
             //let a2 = now2.elapsed().as_millis();
             //println!("compare: {}", { a2 - a1 });
         //}
-        if area_choice.is_none() {
+        // what the panel showed under each area, for the waveform
+        let replaced_monochrome: Vec<bool> = areas
+            .iter()
+            .map(|area| imagery.is_monochrome(&old_frame, area))
+            .collect();
+        match &damage {
+            Some(regions) => copy_regions(&mut old_frame, new_slice, view_width, regions),
+            None => old_frame.copy_from_slice(new_slice),
+        }
+        if areas.is_empty() {
+            if pending_full_refresh || ghosting.needs_full_refresh(Instant::now()) {
+                pending_full_refresh = !full_refresh(
+                    interface,
+                    &mut power,
+                    &imagery,
+                    new_slice,
+                    settings,
+                    hardware_rotation,
+                );
+                ghosting.full_refresh_done(Instant::now());
+            }
+            rest_when_idle(interface, &mut power);
             continue;
         }
-        if area_choice.is_some() {
-            let mut area = area_choice.unwrap();
-
-            area = imagery.rotate(area, rotation_arg);
+        wake_up(interface, &mut power);
+        for (mut area, replaced_monochrome) in areas.into_iter().zip(replaced_monochrome) {
+            area = imagery.dither(area, new_slice, settings.dither);
+            if !hardware_rotation {
+                area = imagery.rotate(area, rotation_arg);
+            }
             let grey_vec = Imagery::transform_to_grey_4bpp(&area.bgr_vec);
             /*
                                       let c1 = now2.elapsed().as_millis();
@@ -130,9 +603,20 @@ fn main() {
             let c2 = now2.elapsed().as_millis();
             println!("c: {}", { c2 - c1 });   */
 
+            let mode = mode_arg.unwrap_or_else(|| {
+                WaveformMode::choose(&grey_vec, area.width, area.height, replaced_monochrome)
+            });
+
             let r1 = now2.elapsed().as_millis();
-            interface = send_to_buffer_4bpp(grey_vec, interface);
-            interface.display(area.x, area.y, area.width, area.height);
+            send_to_buffer_4bpp(grey_vec, interface);
+            if let Err(error) = interface.display(area.x, area.y, area.width, area.height, mode) {
+                // the other areas wait for the full refresh after the reset
+                reset_after(interface, error);
+                pending_full_refresh = true;
+                break;
+            }
+            ghosting.record_partial_refresh(area.width, area.height, Instant::now());
+            power.record_refresh(Instant::now());
             let r2 = now2.elapsed().as_millis();
             //println!("r: {}", { r2 - r1 });
 
@@ -143,46 +627,148 @@ fn main() {
             let duration = time::Duration::from_secs(10);
             thread::sleep(duration); */
         }
+
+        if pending_full_refresh || ghosting.needs_full_refresh(Instant::now()) {
+            pending_full_refresh = !full_refresh(
+                interface,
+                &mut power,
+                &imagery,
+                new_slice,
+                settings,
+                hardware_rotation,
+            );
+            ghosting.full_refresh_done(Instant::now());
+        }
+        rest_when_idle(interface, &mut power);
     }
 
     //println!("total : {}", now.elapsed().as_millis());
 }
 
-fn draw_buffer(area: &Area, grey_vec: &Vec<u8>, mut interface: it8951::IT) -> it8951::IT {
-    let mut y = 0;
-
-    let mut width = area.width;
-    if width > 1872 {
-        width = 1872;
+/// X damage events when the server has them, whole screen grabs otherwise,
+/// unless the frames come from somewhere else than X. A terminal fills
+/// `view_size`, the panel once rotated.
+fn open_capture(
+    settings: &Settings,
+    view_size: (u16, u16),
+) -> Result<Box<dyn CaptureSource>, CaptureError> {
+    if let Some(terminal) = &settings.terminal {
+        return Ok(Box::new(TerminalCapture::open(terminal, view_size)?));
     }
-
-    let mut height = area.height;
-    if height > 1404 {
-        height = 1404;
+    match &settings.source {
+        Source::X11 => (),
+        Source::Wayland(output) => {
+            return Ok(Box::new(WaylandCapture::open(output.as_deref())?))
+        }
+        Source::Framebuffer(path) => return Ok(Box::new(FramebufferCapture::open(path)?)),
+        Source::Drm(path) => return Ok(Box::new(DrmCapture::open(path)?)),
+        Source::Vnc(host, port) => {
+            let password = settings.vnc_password.as_deref();
+            return Ok(Box::new(VncCapture::open(host, *port, password)?));
+        }
     }
-
-    for grey_chunks in grey_vec.chunks_exact(usize::from(area.width)) {
-        if y > height {
-            break;
+    if !settings.poll {
+        match DamageCapture::new(&settings.target, settings.cursor) {
+            Ok(capture) => return Ok(Box::new(capture)),
+            Err(error) => println!("{}, grabbing the whole screen instead", error),
         }
+    }
+    Ok(Box::new(ScreenCapture::new(&settings.target, settings.cursor)?))
+}
 
-        let mut x = 0;
-        for grey in grey_chunks.iter() {
-            if x > width {
-                break;
+/// Wait for the X server as long as it takes, e.g. while it restarts, or for the
+/// captured window to come back
+fn open_capture_retrying(settings: &Settings, view_size: (u16, u16)) -> Box<dyn CaptureSource> {
+    let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(30));
+    loop {
+        match open_capture(settings, view_size) {
+            Ok(capture) => return capture,
+            Err(error) => {
+                let delay = backoff.next_delay();
+                println!("{}, retrying in {} ms", error, delay.as_millis());
+                thread::sleep(delay);
             }
-            interface.draw_buffer_pixel(x, y, width, grey.clone());
-            x += 1;
         }
-        y += 1;
     }
+}
+
+/// Log what the panel controller failed at, then reset it until it answers again,
+/// e.g. once a loose ribbon cable is plugged back in
+fn reset_after<D: EinkDisplay>(interface: &mut D, error: Box<dyn Error>) {
+    println!("display failed: {}, resetting the panel controller", error);
+    let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(30));
+    while let Err(error) = interface.reset() {
+        let delay = backoff.next_delay();
+        println!("{}, retrying in {} ms", error, delay.as_millis());
+        thread::sleep(delay);
+    }
+}
+
+/// Bring the panel controller back to run before a refresh
+fn wake_up<D: EinkDisplay>(interface: &mut D, power: &mut PowerControl) {
+    if power.state() == PowerState::Run {
+        return;
+    }
+    if let Err(error) = interface.wake() {
+        reset_after(interface, error);
+    }
+    power.set_state(PowerState::Run);
+}
+
+/// Standby, then sleep, once the panel has not been refreshed for long enough
+fn rest_when_idle<D: EinkDisplay>(interface: &mut D, power: &mut PowerControl) {
+    let state = match power.idle_state(Instant::now()) {
+        Some(state) => state,
+        None => return,
+    };
+    let result = match state {
+        PowerState::Run => interface.wake(),
+        PowerState::Standby => interface.standby(),
+        PowerState::Sleep => interface.sleep(),
+    };
+    // a controller not answering is reset when woken up
+    if let Err(error) = result {
+        println!("{:?} failed: {}", state, error);
+    }
+    power.set_state(state);
+}
 
-    interface
+/// Redraw everything with a flashing GC16 refresh to wipe the ghosting out.
+/// False when the panel controller failed and had to be reset.
+fn full_refresh<D: EinkDisplay>(
+    interface: &mut D,
+    power: &mut PowerControl,
+    imagery: &Imagery,
+    new_slice: &[Bgr8],
+    settings: &Settings,
+    hardware_rotation: bool,
+) -> bool {
+    let area = match imagery.full_area(new_slice) {
+        Some(area) => imagery.dither(area, new_slice, settings.dither),
+        None => return true,
+    };
+    let area = if hardware_rotation {
+        area
+    } else {
+        imagery.rotate(area, settings.rotation)
+    };
+    let grey_vec = Imagery::transform_to_grey_4bpp(&area.bgr_vec);
+    wake_up(interface, power);
+    send_to_buffer_4bpp(grey_vec, interface);
+    match interface.display(area.x, area.y, area.width, area.height, WaveformMode::Gc16) {
+        Ok(()) => {
+            power.record_refresh(Instant::now());
+            true
+        }
+        Err(error) => {
+            reset_after(interface, error);
+            false
+        }
+    }
 }
 
-pub fn send_to_buffer_4bpp(grey_vec: Vec<u8>, mut interface: it8951::IT) -> it8951::IT {
+pub fn send_to_buffer_4bpp<D: EinkDisplay>(grey_vec: Vec<u8>, interface: &mut D) {
     interface.load_buffer_from_vec(grey_vec);
-    interface
 }
 
 #[test]
@@ -196,7 +782,13 @@ fn test_compare_slices_and_send_black_cross() {
 
     let mut capt = Capturer::new(0).unwrap();
 
-    let imagery = Imagery::new(1872, 1404, 400, capt.geometry().1 as u16, 0);
+    let imagery = Imagery::new(
+        interface.size().0,
+        interface.size().1,
+        400,
+        capt.geometry().1 as u16,
+        0,
+    );
 
     let _result = capt.capture_store_frame();
     let image = capt.image.unwrap();
@@ -231,9 +823,24 @@ fn test_compare_slices_and_send_black_cross() {
 
     let grey_vec = Imagery::transform_to_grey_4bpp(&area.bgr_vec);
 
-    interface = send_to_buffer_4bpp(grey_vec, interface);
+    send_to_buffer_4bpp(grey_vec, &mut interface);
+
+    interface.display(1100, 500, area.width, area.height, 2).unwrap();
 
-    interface.display(1100, 500, area.width, area.height);
+    interface.display(100, 500, 400, 400, 2).unwrap();
+}
 
-    interface.display(100, 500, 400, 400);
+#[test]
+fn test_wake_up_after_standby() {
+    let mut simulated = SimulatedDisplay::new(it8951::DevInfo::with_panel_size(32, 16), None);
+    let mut power = PowerControl::new(Some(Duration::from_secs(0)), None);
+
+    rest_when_idle(&mut simulated, &mut power);
+    assert_eq!(power.state(), PowerState::Standby);
+    simulated.load_buffer_from_vec(vec![0xFF; 32 * 16 / 2]);
+    assert!(simulated.display(0, 0, 32, 16, WaveformMode::Gc16).is_err());
+
+    wake_up(&mut simulated, &mut power);
+    assert_eq!(power.state(), PowerState::Run);
+    assert!(simulated.display(0, 0, 32, 16, WaveformMode::Gc16).is_ok());
 }
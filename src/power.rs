@@ -0,0 +1,103 @@
+use std::time::{Duration, Instant};
+
+/// Power states of the panel controller, from the most to the least awake
+#[derive(Debug, Eq, PartialEq, Clone, Copy, PartialOrd, Ord)]
+pub enum PowerState {
+    Run,
+    /// Clocks stopped, wakes up quickly
+    Standby,
+    /// Almost everything off, the panel keeps its image
+    Sleep,
+}
+
+/// Tells when the panel controller has been idle long enough for standby, then
+/// for sleep, and whether it has to be woken up before the next refresh.
+pub struct PowerControl {
+    standby_delay: Option<Duration>,
+    sleep_delay: Option<Duration>,
+    state: PowerState,
+    last_refresh: Instant,
+}
+
+impl PowerControl {
+    pub fn new(standby_delay: Option<Duration>, sleep_delay: Option<Duration>) -> PowerControl {
+        PowerControl {
+            standby_delay,
+            sleep_delay,
+            state: PowerState::Run,
+            last_refresh: Instant::now(),
+        }
+    }
+
+    pub fn state(&self) -> PowerState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: PowerState) {
+        self.state = state;
+    }
+
+    pub fn record_refresh(&mut self, now: Instant) {
+        self.last_refresh = now;
+    }
+
+    /// State to enter after the idle time at `now`, None to stay as is.
+    /// Idleness only ever lowers the state, refreshes wake the controller.
+    pub fn idle_state(&self, now: Instant) -> Option<PowerState> {
+        let idle = now.duration_since(self.last_refresh);
+        let elapsed = |delay: Option<Duration>| delay.is_some_and(|delay| idle >= delay);
+        let wanted = if elapsed(self.sleep_delay) {
+            PowerState::Sleep
+        } else if elapsed(self.standby_delay) {
+            PowerState::Standby
+        } else {
+            PowerState::Run
+        };
+        if wanted > self.state {
+            Some(wanted)
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn test_standby_then_sleep_when_idle() {
+    let now = Instant::now();
+    let mut power = PowerControl::new(
+        Some(Duration::from_secs(60)),
+        Some(Duration::from_secs(600)),
+    );
+    power.record_refresh(now);
+
+    assert_eq!(power.idle_state(now + Duration::from_secs(59)), None);
+    assert_eq!(
+        power.idle_state(now + Duration::from_secs(60)),
+        Some(PowerState::Standby)
+    );
+    power.set_state(PowerState::Standby);
+    assert_eq!(power.idle_state(now + Duration::from_secs(61)), None);
+    assert_eq!(
+        power.idle_state(now + Duration::from_secs(600)),
+        Some(PowerState::Sleep)
+    );
+    power.set_state(PowerState::Sleep);
+    assert_eq!(power.idle_state(now + Duration::from_secs(3600)), None);
+}
+
+#[test]
+fn test_power_delays_disabled() {
+    let now = Instant::now();
+    let mut power = PowerControl::new(None, Some(Duration::from_secs(600)));
+    power.record_refresh(now);
+
+    // straight to sleep, no standby on the way
+    assert_eq!(power.idle_state(now + Duration::from_secs(599)), None);
+    assert_eq!(
+        power.idle_state(now + Duration::from_secs(600)),
+        Some(PowerState::Sleep)
+    );
+
+    let power = PowerControl::new(None, None);
+    assert_eq!(power.idle_state(now + Duration::from_secs(86400)), None);
+}
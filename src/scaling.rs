@@ -0,0 +1,688 @@
+use rayon::prelude::*;
+use std::str::FromStr;
+use x11cap::Bgr8;
+
+use crate::imagery::{new_bgr8, Rect};
+
+// weights below this are left out of the area filter
+static MIN_WEIGHT: f32 = 1e-4;
+
+/// How the capture is fitted into the panel
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ScaleMode {
+    /// All of the capture, white bars on the sides it does not fill
+    Fit,
+    /// All of the panel, the sides of the capture that stick out are cut
+    Fill,
+    /// Scaled by a whole number, or divided by one when too big : sharp pixels
+    Integer,
+}
+
+impl FromStr for ScaleMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<ScaleMode, String> {
+        match mode.to_lowercase().as_str() {
+            "fit" => Ok(ScaleMode::Fit),
+            "fill" => Ok(ScaleMode::Fill),
+            "integer" => Ok(ScaleMode::Integer),
+            _ => Err(format!("unknown scaling mode {}", mode)),
+        }
+    }
+}
+
+/// How the pixels of the panel are computed from the pixels of the capture
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ScaleFilter {
+    /// The capture pixel under the panel pixel : fast, uneven lines once scaled
+    Nearest,
+    /// Blend of the 4 capture pixels around the panel pixel
+    Bilinear,
+    /// Average of the capture pixels the panel pixel covers : best for shrinking text
+    Area,
+}
+
+impl FromStr for ScaleFilter {
+    type Err = String;
+
+    fn from_str(filter: &str) -> Result<ScaleFilter, String> {
+        match filter.to_lowercase().as_str() {
+            "nearest" => Ok(ScaleFilter::Nearest),
+            "bilinear" => Ok(ScaleFilter::Bilinear),
+            "area" => Ok(ScaleFilter::Area),
+            _ => Err(format!("unknown scaling filter {}", filter)),
+        }
+    }
+}
+
+/// Capture pixels, along one axis, a scaled pixel is made of
+#[derive(Debug, PartialEq, Clone)]
+struct Taps {
+    first: usize,
+    // one per capture pixel from the first on, they add up to 1
+    weights: Vec<f32>,
+}
+
+impl Taps {
+    fn last(&self) -> usize {
+        self.first + self.weights.len() - 1
+    }
+}
+
+/// Brings the capture to the size of the panel, see ScaleMode. The rest of the
+/// pipeline only sees the scaled frame, the "view", unrotated like the captures.
+pub struct Scaler {
+    capture_width: u16,
+    capture_height: u16,
+    view_width: u16,
+    view_height: u16,
+    // where the scaled capture starts in the view, left of or above it when cut
+    offset_x: i32,
+    offset_y: i32,
+    // part of the view showing the capture
+    placement: Rect,
+    // for each column of the scaled capture, and each line
+    col_taps: Vec<Taps>,
+    line_taps: Vec<Taps>,
+    // every scaled pixel is a single capture pixel : copied, not blended
+    copy_only: bool,
+    frame: Vec<Bgr8>,
+}
+
+impl Scaler {
+    pub fn new(
+        capture_width: u16,
+        capture_height: u16,
+        view_width: u16,
+        view_height: u16,
+        mode: ScaleMode,
+        filter: ScaleFilter,
+    ) -> Scaler {
+        let (offset_x, offset_y, scaled_width, scaled_height) =
+            place(mode, capture_width, capture_height, view_width, view_height);
+        let visible = |offset: i32, scaled: u32, view: u16| -> (u16, u16) {
+            let start = offset.max(0).min(view as i32);
+            let end = (offset + scaled as i32).max(0).min(view as i32);
+            (start as u16, (end - start) as u16)
+        };
+        let (x, width) = visible(offset_x, scaled_width, view_width);
+        let (y, height) = visible(offset_y, scaled_height, view_height);
+        let col_taps = taps(filter, capture_width, scaled_width);
+        let line_taps = taps(filter, capture_height, scaled_height);
+        let copy_only = col_taps
+            .iter()
+            .chain(line_taps.iter())
+            .all(|taps| taps.weights.len() == 1);
+
+        Scaler {
+            capture_width,
+            capture_height,
+            view_width,
+            view_height,
+            offset_x,
+            offset_y,
+            placement: Rect {
+                x,
+                y,
+                width,
+                height,
+            },
+            col_taps,
+            line_taps,
+            copy_only,
+            frame: vec![new_bgr8(255, 255, 255); view_width as usize * view_height as usize],
+        }
+    }
+
+    /// Scaler from a capture to the panel, (width, height) of the panel as the
+    /// controller reports it, before any rotation
+    pub fn for_panel(
+        capture_width: u16,
+        capture_height: u16,
+        panel_size: (u16, u16),
+        rotation: u16,
+        mode: ScaleMode,
+        filter: ScaleFilter,
+    ) -> Scaler {
+        let (view_width, view_height) = view_size(panel_size, rotation);
+        Scaler::new(capture_width, capture_height, view_width, view_height, mode, filter)
+    }
+
+    pub fn view_size(&self) -> (u16, u16) {
+        (self.view_width, self.view_height)
+    }
+
+    /// Rectangle of the view the capture is drawn in, the rest stays white
+    pub fn placement(&self) -> Rect {
+        self.placement
+    }
+
+    /// The scaled frame, view_size() wide and high
+    pub fn frame(&self) -> &[Bgr8] {
+        &self.frame
+    }
+
+    /// Rectangle of the view made from a rectangle of the capture, blended
+    /// neighbours included
+    pub fn map_rect(&self, rect: &Rect) -> Option<Rect> {
+        let placement = self.placement;
+        if placement.width == 0 || placement.height == 0 {
+            return None;
+        }
+        let x_end = (rect.x as usize + rect.width as usize).min(self.capture_width as usize);
+        let y_end = (rect.y as usize + rect.height as usize).min(self.capture_height as usize);
+        if rect.x as usize >= x_end || rect.y as usize >= y_end {
+            return None;
+        }
+        let (first_col, end_col) = view_range(
+            &self.col_taps,
+            (rect.x as usize, x_end),
+            self.offset_x,
+            (placement.x, placement.width),
+        )?;
+        let (first_line, end_line) = view_range(
+            &self.line_taps,
+            (rect.y as usize, y_end),
+            self.offset_y,
+            (placement.y, placement.height),
+        )?;
+        Some(Rect {
+            x: first_col,
+            y: first_line,
+            width: end_col - first_col,
+            height: end_line - first_line,
+        })
+    }
+
+    /// Scale the regions of the capture into the view, everything when there are no
+    /// regions. Returns the regions of the view that were redrawn, None for all of it.
+    pub fn update(&mut self, capture: &[Bgr8], regions: Option<&[Rect]>) -> Option<Vec<Rect>> {
+        if capture.len() < self.capture_width as usize * self.capture_height as usize {
+            return Some(Vec::new());
+        }
+        let regions = match regions {
+            Some(regions) => regions,
+            None => {
+                let placement = self.placement;
+                self.draw(capture, &placement);
+                return None;
+            }
+        };
+        let view_regions: Vec<Rect> = regions
+            .iter()
+            .filter_map(|region| self.map_rect(region))
+            .collect();
+        for view_region in view_regions.iter() {
+            self.draw(capture, view_region);
+        }
+        Some(view_regions)
+    }
+
+    // scale the capture into a rectangle of the placement
+    fn draw(&mut self, capture: &[Bgr8], rect: &Rect) {
+        let capture_width: usize = self.capture_width as usize;
+        let (first_line, end_line) = (rect.y as usize, (rect.y + rect.height) as usize);
+        let (first_col, end_col) = (rect.x as usize, (rect.x + rect.width) as usize);
+        let (offset_x, offset_y) = (self.offset_x, self.offset_y);
+        let col_taps = &self.col_taps;
+        let line_taps = &self.line_taps;
+        let copy_only = self.copy_only;
+
+        self.frame
+            .par_chunks_mut(self.view_width as usize)
+            .enumerate()
+            .filter(|(line_n, _line)| first_line <= *line_n && *line_n < end_line)
+            .for_each(|(line_n, line)| {
+                let taps_y = &line_taps[(line_n as i32 - offset_y) as usize];
+                for col_n in first_col..end_col {
+                    let taps_x = &col_taps[(col_n as i32 - offset_x) as usize];
+                    line[col_n] = if copy_only {
+                        capture[taps_y.first * capture_width + taps_x.first]
+                    } else {
+                        blend(capture, capture_width, taps_x, taps_y)
+                    };
+                }
+            });
+    }
+}
+
+// weighted sum of the capture pixels under both taps
+fn blend(capture: &[Bgr8], capture_width: usize, taps_x: &Taps, taps_y: &Taps) -> Bgr8 {
+    let mut sum: [f32; 3] = [0.0; 3];
+    for (j, weight_y) in taps_y.weights.iter().enumerate() {
+        let start = (taps_y.first + j) * capture_width + taps_x.first;
+        for (pixel, weight_x) in capture[start..].iter().zip(taps_x.weights.iter()) {
+            let weight = weight_y * weight_x;
+            sum[0] += pixel.b as f32 * weight;
+            sum[1] += pixel.g as f32 * weight;
+            sum[2] += pixel.r as f32 * weight;
+        }
+    }
+    let channel = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+    new_bgr8(channel(sum[0]), channel(sum[1]), channel(sum[2]))
+}
+
+// (offset x, offset y, width, height) of the scaled capture in the view
+fn place(
+    mode: ScaleMode,
+    capture_width: u16,
+    capture_height: u16,
+    view_width: u16,
+    view_height: u16,
+) -> (i32, i32, u32, u32) {
+    if capture_width == 0 || capture_height == 0 {
+        return (0, 0, 0, 0);
+    }
+    let (capture_w, capture_h) = (capture_width as u32, capture_height as u32);
+    let (view_w, view_h) = (view_width as u32, view_height as u32);
+    let (width, height) = match mode {
+        ScaleMode::Fit => {
+            let rect = fit(capture_width, capture_height, view_width, view_height);
+            (rect.width as u32, rect.height as u32)
+        }
+        // the side limiting the scale is cut instead
+        ScaleMode::Fill => {
+            if view_w * capture_h >= view_h * capture_w {
+                (view_w, (view_w * capture_h).div_ceil(capture_w))
+            } else {
+                ((view_h * capture_w).div_ceil(capture_h), view_h)
+            }
+        }
+        ScaleMode::Integer => {
+            let factor = (view_w / capture_w).min(view_h / capture_h);
+            if factor >= 1 {
+                (capture_w * factor, capture_h * factor)
+            } else {
+                let divisor = capture_w.div_ceil(view_w).max(capture_h.div_ceil(view_h));
+                ((capture_w / divisor).max(1), (capture_h / divisor).max(1))
+            }
+        }
+    };
+    (
+        (view_w as i32 - width as i32) / 2,
+        (view_h as i32 - height as i32) / 2,
+        width,
+        height,
+    )
+}
+
+/// Width and height of the panel seen rotated by `rotation` degrees
+pub fn view_size(panel_size: (u16, u16), rotation: u16) -> (u16, u16) {
+    let (panel_width, panel_height) = panel_size;
+    if rotation == 90 || rotation == 270 {
+        (panel_height, panel_width)
+    } else {
+        (panel_width, panel_height)
+    }
+}
+
+/// Largest rectangle of the capture proportions inside the view, centred
+pub fn fit(capture_width: u16, capture_height: u16, view_width: u16, view_height: u16) -> Rect {
+    if capture_width == 0 || capture_height == 0 {
+        return Rect {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+        };
+    }
+    // the side limiting the scale keeps the whole view
+    let (width, height) =
+        if view_width as u32 * capture_height as u32 <= view_height as u32 * capture_width as u32 {
+            let height = view_width as u32 * capture_height as u32 / capture_width as u32;
+            (view_width, height.max(1) as u16)
+        } else {
+            let width = view_height as u32 * capture_width as u32 / capture_height as u32;
+            (width.max(1) as u16, view_height)
+        };
+    Rect {
+        x: (view_width - width) / 2,
+        y: (view_height - height) / 2,
+        width,
+        height,
+    }
+}
+
+// taps of each of the `scaled_size` pixels along an axis of `capture_size` pixels,
+// the capture edges are repeated
+fn taps(filter: ScaleFilter, capture_size: u16, scaled_size: u32) -> Vec<Taps> {
+    let capture_size = capture_size as usize;
+    let ratio: f64 = capture_size as f64 / scaled_size as f64;
+    let single = |first: usize| Taps {
+        first: first.min(capture_size - 1),
+        weights: vec![1.0],
+    };
+
+    (0..scaled_size as usize)
+        .map(|n| match filter {
+            ScaleFilter::Nearest => single((2 * n + 1) * capture_size / (2 * scaled_size as usize)),
+            ScaleFilter::Bilinear => {
+                let centre = ((n as f64 + 0.5) * ratio - 0.5)
+                    .max(0.0)
+                    .min((capture_size - 1) as f64);
+                let first = centre.floor();
+                let fraction = (centre - first) as f32;
+                if fraction == 0.0 {
+                    single(first as usize)
+                } else {
+                    Taps {
+                        first: first as usize,
+                        weights: vec![1.0 - fraction, fraction],
+                    }
+                }
+            }
+            ScaleFilter::Area => {
+                let start = n as f64 * ratio;
+                let end = ((n + 1) as f64 * ratio).min(capture_size as f64);
+                let mut first = start.floor() as usize;
+                let last = ((end.ceil() as usize).max(first + 1) - 1).min(capture_size - 1);
+                // share of each capture pixel inside [start, end)
+                let mut weights: Vec<f32> = (first..=last)
+                    .map(|pixel| {
+                        let covered = (end.min(pixel as f64 + 1.0) - start.max(pixel as f64))
+                            .max(0.0);
+                        (covered / (end - start)) as f32
+                    })
+                    .collect();
+                // rounding leaves crumbs of the neighbours on the ends
+                while weights.len() > 1 && weights[weights.len() - 1] < MIN_WEIGHT {
+                    weights.pop();
+                }
+                while weights.len() > 1 && weights[0] < MIN_WEIGHT {
+                    weights.remove(0);
+                    first += 1;
+                }
+                if weights.len() == 1 {
+                    single(first)
+                } else {
+                    Taps {
+                        first,
+                        weights,
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+// pixels [first, end) of the view, along an axis, whose taps reach the capture
+// pixels [start, end), within the visible part (first visible, visible length)
+fn view_range(
+    taps: &[Taps],
+    capture_range: (usize, usize),
+    offset: i32,
+    visible: (u16, u16),
+) -> Option<(u16, u16)> {
+    let (start, end) = capture_range;
+    // taps only move forward along the axis
+    let first = taps.partition_point(|taps| taps.last() < start);
+    let last = taps.partition_point(|taps| taps.first < end);
+    let first = (first as i32 + offset).max(visible.0 as i32);
+    let last = (last as i32 + offset).min(visible.0 as i32 + visible.1 as i32);
+    if first >= last {
+        return None;
+    }
+    Some((first as u16, last as u16))
+}
+
+#[test]
+fn test_fit_letterboxes() {
+    let rect = |x, y, width, height| Rect {
+        x,
+        y,
+        width,
+        height,
+    };
+    // same size, nothing to do
+    assert_eq!(fit(1872, 1404, 1872, 1404), rect(0, 0, 1872, 1404));
+    // wider capture : bars above and below
+    assert_eq!(fit(1920, 1080, 1872, 1404), rect(0, 175, 1872, 1053));
+    // narrower capture : bars on the sides
+    assert_eq!(fit(1024, 1024, 1600, 1200), rect(200, 0, 1200, 1200));
+    // smaller capture : scaled up
+    assert_eq!(fit(400, 300, 800, 600), rect(0, 0, 800, 600));
+}
+
+#[test]
+fn test_map_rect_covers_scaled_pixels() {
+    let scaler = Scaler::new(8, 4, 4, 2, ScaleMode::Fit, ScaleFilter::Area);
+    let region = Rect {
+        x: 3,
+        y: 1,
+        width: 2,
+        height: 1,
+    };
+    // capture columns 3 and 4 are drawn by view columns 1 and 2
+    assert_eq!(
+        scaler.map_rect(&region),
+        Some(Rect {
+            x: 1,
+            y: 0,
+            width: 2,
+            height: 1,
+        })
+    );
+    let outside = Rect {
+        x: 8,
+        y: 0,
+        width: 4,
+        height: 4,
+    };
+    assert_eq!(scaler.map_rect(&outside), None);
+}
+
+#[test]
+fn test_update_scales_and_letterboxes() {
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+    // 4x2 capture with its left half black, onto an 8x8 view : 8x4, 2 lines down
+    let capture: Vec<Bgr8> = (0..8)
+        .map(|n| if n % 4 < 2 { black } else { white })
+        .collect();
+    let mut scaler = Scaler::new(4, 2, 8, 8, ScaleMode::Fit, ScaleFilter::Nearest);
+    assert_eq!(scaler.update(&capture, None), None);
+
+    for line in 0..8 {
+        for col in 0..8 {
+            let expected = if (2..6).contains(&line) && col < 4 {
+                black
+            } else {
+                white
+            };
+            assert_eq!(
+                scaler.frame()[line * 8 + col],
+                expected,
+                "{}, {}",
+                col,
+                line
+            );
+        }
+    }
+
+    // only the damaged region is redrawn
+    let mut capture = capture;
+    capture[3] = black;
+    capture[5] = white;
+    let damage = [Rect {
+        x: 3,
+        y: 0,
+        width: 1,
+        height: 1,
+    }];
+    let redrawn = scaler.update(&capture, Some(&damage));
+    assert_eq!(
+        redrawn,
+        Some(vec![Rect {
+            x: 6,
+            y: 2,
+            width: 2,
+            height: 2,
+        }])
+    );
+    assert_eq!(scaler.frame()[2 * 8 + 7], black);
+    // not in the damage, not redrawn
+    assert_eq!(scaler.frame()[4 * 8 + 2], black);
+}
+
+#[test]
+fn test_parse_scaling() {
+    assert_eq!("fill".parse::<ScaleMode>(), Ok(ScaleMode::Fill));
+    assert_eq!("Integer".parse::<ScaleMode>(), Ok(ScaleMode::Integer));
+    assert!("stretch".parse::<ScaleMode>().is_err());
+    assert_eq!("bilinear".parse::<ScaleFilter>(), Ok(ScaleFilter::Bilinear));
+    assert_eq!("AREA".parse::<ScaleFilter>(), Ok(ScaleFilter::Area));
+    assert!("lanczos".parse::<ScaleFilter>().is_err());
+}
+
+#[test]
+fn test_place_modes() {
+    // 1080p on the 10.3" panel
+    assert_eq!(
+        place(ScaleMode::Fit, 1920, 1080, 1872, 1404),
+        (0, 175, 1872, 1053)
+    );
+    assert_eq!(
+        place(ScaleMode::Fill, 1920, 1080, 1872, 1404),
+        (-312, 0, 2496, 1404)
+    );
+    assert_eq!(
+        place(ScaleMode::Integer, 1920, 1080, 1872, 1404),
+        (456, 432, 960, 540)
+    );
+    // small enough to be doubled
+    assert_eq!(
+        place(ScaleMode::Integer, 800, 600, 1872, 1404),
+        (136, 102, 1600, 1200)
+    );
+
+    let scaler = Scaler::new(1920, 1080, 1872, 1404, ScaleMode::Fill, ScaleFilter::Area);
+    assert_eq!(
+        scaler.placement(),
+        Rect {
+            x: 0,
+            y: 0,
+            width: 1872,
+            height: 1404,
+        }
+    );
+}
+
+#[test]
+fn test_filters_on_fractional_scale() {
+    let grey = new_bgr8(100, 120, 140);
+    let capture = vec![grey; 1920 * 1080];
+    for filter in [
+        ScaleFilter::Nearest,
+        ScaleFilter::Bilinear,
+        ScaleFilter::Area,
+    ]
+    .iter()
+    {
+        let mut scaler = Scaler::new(1920, 1080, 1872, 1404, ScaleMode::Fit, *filter);
+        scaler.update(&capture, None);
+        // flat stays flat whatever the blend
+        let placement = scaler.placement();
+        let shown = &scaler.frame()[placement.y as usize * 1872..];
+        assert!(
+            shown[..1053 * 1872].iter().all(|bgr| *bgr == grey),
+            "{:?}",
+            filter
+        );
+        assert_eq!(scaler.frame()[0], new_bgr8(255, 255, 255));
+    }
+}
+
+#[test]
+fn test_area_filter_averages() {
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+    // black and white stripes, two pixels of the capture per pixel of the view
+    let capture: Vec<Bgr8> = (0..16)
+        .map(|n| if n % 2 == 0 { black } else { white })
+        .collect();
+    let mut scaler = Scaler::new(4, 4, 2, 2, ScaleMode::Fit, ScaleFilter::Area);
+    scaler.update(&capture, None);
+    assert_eq!(scaler.frame(), &[new_bgr8(128, 128, 128); 4][..]);
+
+    let mut scaler = Scaler::new(4, 4, 2, 2, ScaleMode::Fit, ScaleFilter::Nearest);
+    scaler.update(&capture, None);
+    assert!(scaler
+        .frame()
+        .iter()
+        .all(|bgr| *bgr == black || *bgr == white));
+}
+
+#[test]
+fn test_damage_redraws_like_a_full_update() {
+    // not a multiple of each other, both ways
+    let geometries: [(u16, u16, u16, u16); 3] =
+        [(37, 23, 29, 31), (13, 9, 40, 30), (30, 20, 30, 20)];
+    let filters = [
+        ScaleFilter::Nearest,
+        ScaleFilter::Bilinear,
+        ScaleFilter::Area,
+    ];
+    let modes = [ScaleMode::Fit, ScaleMode::Fill, ScaleMode::Integer];
+    let damage = [
+        Rect {
+            x: 5,
+            y: 3,
+            width: 4,
+            height: 2,
+        },
+        // on the edges of the capture
+        Rect {
+            x: 0,
+            y: 8,
+            width: 1,
+            height: 1,
+        },
+    ];
+
+    for (capture_width, capture_height, view_width, view_height) in geometries.iter() {
+        let (capture_width, capture_height) = (*capture_width, *capture_height);
+        let mut capture: Vec<Bgr8> = (0..capture_width as usize * capture_height as usize)
+            .map(|n| new_bgr8((n * 7) as u8, (n * 13) as u8, (n * 29) as u8))
+            .collect();
+        for filter in filters.iter() {
+            for mode in modes.iter() {
+                let scaler = || {
+                    Scaler::new(
+                        capture_width,
+                        capture_height,
+                        *view_width,
+                        *view_height,
+                        *mode,
+                        *filter,
+                    )
+                };
+                let mut incremental = scaler();
+                incremental.update(&capture, None);
+
+                for region in damage.iter() {
+                    for line in region.y..region.y + region.height {
+                        for col in region.x..region.x + region.width {
+                            let pixel =
+                                &mut capture[line as usize * capture_width as usize + col as usize];
+                            *pixel = new_bgr8(255 - pixel.b, pixel.r, 255 - pixel.g);
+                        }
+                    }
+                }
+                incremental.update(&capture, Some(&damage));
+
+                let mut full = scaler();
+                full.update(&capture, None);
+                assert!(
+                    incremental.frame() == full.frame(),
+                    "{}x{} to {}x{}, {:?}, {:?}",
+                    capture_width,
+                    capture_height,
+                    view_width,
+                    view_height,
+                    mode,
+                    filter
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,502 @@
+use image::{GrayImage, Rgb, RgbImage};
+use std::error::Error;
+use std::path::PathBuf;
+
+extern crate custom_error;
+use custom_error::custom_error;
+
+use crate::eink_display::{rotate_rect, EinkDisplay, WaveformMode};
+use crate::it8951::DevInfo;
+
+custom_error! {pub SimulatedError
+    BufferTooShort{len: usize, needed: usize} = "packed buffer too short: {len} bytes, {needed} needed",
+    Asleep = "display called while the panel sleeps"
+}
+
+/// Software e-ink panel: keeps a 4bpp frame buffer in memory and, when an
+/// output directory is given, dumps it to a PNG file after every refresh.
+pub struct SimulatedDisplay {
+    dev_info: DevInfo,
+    // packed like the panel : two pixels per byte, even pixel in the low nibble
+    frame_buffer: Vec<u8>,
+    area_buffer: Vec<u8>,
+    output_dir: Option<PathBuf>,
+    outline: bool,
+    refresh_count: u32,
+    last_mode: Option<WaveformMode>,
+    sleeping: bool,
+    // degrees, applied to the uploaded areas like the IT8951 does
+    rotation: u16,
+}
+
+impl SimulatedDisplay {
+    pub fn new(dev_info: DevInfo, output_dir: Option<PathBuf>) -> SimulatedDisplay {
+        let size: usize = dev_info.panel_width as usize * dev_info.panel_height as usize / 2;
+        SimulatedDisplay {
+            dev_info,
+            frame_buffer: vec![0xFF; size],
+            area_buffer: Vec::new(),
+            output_dir,
+            outline: false,
+            refresh_count: 0,
+            last_mode: None,
+            sleeping: false,
+            rotation: 0,
+        }
+    }
+
+    /// Draw the refreshed rectangle in red on each dumped PNG
+    pub fn set_outline(&mut self, outline: bool) {
+        self.outline = outline;
+    }
+
+    #[cfg(test)]
+    pub fn refresh_count(&self) -> u32 {
+        self.refresh_count
+    }
+
+    /// Waveform of the last refresh
+    #[cfg(test)]
+    pub fn last_mode(&self) -> Option<WaveformMode> {
+        self.last_mode
+    }
+
+    /// 4 bits grey level (0 black, 15 white) of a panel pixel
+    pub fn pixel(&self, x: u16, y: u16) -> u8 {
+        let index: usize = y as usize * self.dev_info.panel_width as usize + x as usize;
+        let byte: u8 = self.frame_buffer[index / 2];
+        if index.is_multiple_of(2) {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        }
+    }
+
+    // panel pixel showing a pixel of the rotated panel
+    fn rotate_point(&self, x: u16, y: u16) -> (u16, u16) {
+        let width = self.dev_info.panel_width;
+        let height = self.dev_info.panel_height;
+        match self.rotation {
+            90 => (width - 1 - y, x),
+            180 => (width - 1 - x, height - 1 - y),
+            270 => (y, height - 1 - x),
+            _ => (x, y),
+        }
+    }
+
+    fn rotated_size(&self) -> (u16, u16) {
+        if self.rotation == 90 || self.rotation == 270 {
+            (self.dev_info.panel_height, self.dev_info.panel_width)
+        } else {
+            (self.dev_info.panel_width, self.dev_info.panel_height)
+        }
+    }
+
+    fn set_pixel(&mut self, x: u16, y: u16, grey: u8) {
+        let index: usize = y as usize * self.dev_info.panel_width as usize + x as usize;
+        let byte: &mut u8 = &mut self.frame_buffer[index / 2];
+        if index.is_multiple_of(2) {
+            *byte = (*byte & 0xF0) | (grey & 0x0F);
+        } else {
+            *byte = (*byte & 0x0F) | (grey << 4);
+        }
+    }
+
+    pub fn to_image(&self) -> GrayImage {
+        let width = self.dev_info.panel_width;
+        let height = self.dev_info.panel_height;
+        let mut image = GrayImage::new(width as u32, height as u32);
+        for y in 0..height {
+            for x in 0..width {
+                // 0..15 -> 0..255
+                image.put_pixel(x as u32, y as u32, image::Luma([self.pixel(x, y) * 17]));
+            }
+        }
+        image
+    }
+
+    pub fn save_png(&self, path: &PathBuf) -> Result<(), Box<dyn Error>> {
+        self.to_image().save(path)?;
+        Ok(())
+    }
+
+    fn save_refresh(&self, x: u16, y: u16, width: u16, height: u16) -> Result<(), Box<dyn Error>> {
+        let output_dir = match &self.output_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let path = output_dir.join(format!("frame_{:05}.png", self.refresh_count));
+
+        if !self.outline {
+            return self.save_png(&path);
+        }
+
+        let grey_image = self.to_image();
+        let mut image = RgbImage::new(grey_image.width(), grey_image.height());
+        for (x, y, grey) in grey_image.enumerate_pixels() {
+            image.put_pixel(x, y, Rgb([grey[0], grey[0], grey[0]]));
+        }
+        let red = Rgb([255, 0, 0]);
+        let right = (x as u32 + width as u32).min(image.width()) - 1;
+        let bottom = (y as u32 + height as u32).min(image.height()) - 1;
+        for outline_x in x as u32..=right {
+            image.put_pixel(outline_x, y as u32, red);
+            image.put_pixel(outline_x, bottom, red);
+        }
+        for outline_y in y as u32..=bottom {
+            image.put_pixel(x as u32, outline_y, red);
+            image.put_pixel(right, outline_y, red);
+        }
+        image.save(&path)?;
+        Ok(())
+    }
+}
+
+impl EinkDisplay for SimulatedDisplay {
+    fn size(&self) -> (u16, u16) {
+        (self.dev_info.panel_width, self.dev_info.panel_height)
+    }
+
+    fn load_buffer_from_vec(&mut self, grey_vec: Vec<u8>) {
+        self.area_buffer = grey_vec;
+    }
+
+    fn display(
+        &mut self,
+        x: u16,
+        y: u16,
+        rect_width: u16,
+        rect_height: u16,
+        mode: WaveformMode,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.sleeping {
+            return Err(Box::new(SimulatedError::Asleep));
+        }
+        let needed: usize = rect_width as usize * rect_height as usize / 2;
+        if self.area_buffer.len() < needed {
+            return Err(Box::new(SimulatedError::BufferTooShort {
+                len: self.area_buffer.len(),
+                needed,
+            }));
+        }
+
+        let (width, height) = self.rotated_size();
+        let area_buffer = std::mem::take(&mut self.area_buffer);
+        for area_y in 0..rect_height {
+            if y as u32 + area_y as u32 >= height as u32 {
+                break;
+            }
+            for area_x in 0..rect_width {
+                if x as u32 + area_x as u32 >= width as u32 {
+                    break;
+                }
+                let index: usize = area_y as usize * rect_width as usize + area_x as usize;
+                let byte: u8 = area_buffer[index / 2];
+                let grey: u8 = if index.is_multiple_of(2) {
+                    byte & 0x0F
+                } else {
+                    byte >> 4
+                };
+                let (panel_x, panel_y) = self.rotate_point(x + area_x, y + area_y);
+                self.set_pixel(panel_x, panel_y, grey);
+            }
+        }
+        self.area_buffer = area_buffer;
+
+        self.refresh_count += 1;
+        self.last_mode = Some(mode);
+        if rect_width > 0 && rect_height > 0 {
+            let (panel_x, panel_y, panel_width, panel_height) = rotate_rect(
+                self.rotation,
+                self.dev_info.panel_width,
+                self.dev_info.panel_height,
+                (x, y, rect_width, rect_height),
+            );
+            self.save_refresh(panel_x, panel_y, panel_width, panel_height)?;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), Box<dyn Error>> {
+        for byte in self.frame_buffer.iter_mut() {
+            *byte = 0xFF;
+        }
+        self.refresh_count += 1;
+        self.last_mode = Some(WaveformMode::Init);
+        let (width, height) = self.size();
+        self.save_refresh(0, 0, width, height)
+    }
+
+    fn sleep(&mut self) -> Result<(), Box<dyn Error>> {
+        self.sleeping = true;
+        Ok(())
+    }
+
+    fn wake(&mut self) -> Result<(), Box<dyn Error>> {
+        self.sleeping = false;
+        Ok(())
+    }
+
+    fn set_rotation(&mut self, rotation: u16) -> bool {
+        match rotation {
+            0 | 90 | 180 | 270 => {
+                self.rotation = rotation;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[test]
+fn test_simulated_display_area() {
+    let mut display = SimulatedDisplay::new(DevInfo::with_panel_size(16, 8), None);
+
+    // 4 x 2 area : black, white, grey 5, grey 10 on each line
+    display.load_buffer_from_vec(vec![0b1111_0000, 0b1010_0101, 0b1111_0000, 0b1010_0101]);
+    display.display(4, 2, 4, 2, WaveformMode::Gc16).unwrap();
+
+    assert_eq!(display.pixel(4, 2), 0);
+    assert_eq!(display.pixel(5, 2), 15);
+    assert_eq!(display.pixel(6, 2), 5);
+    assert_eq!(display.pixel(7, 3), 10);
+    // untouched pixels stay white
+    assert_eq!(display.pixel(3, 2), 15);
+    assert_eq!(display.pixel(4, 4), 15);
+    assert_eq!(display.refresh_count(), 1);
+    assert_eq!(display.last_mode(), Some(WaveformMode::Gc16));
+}
+
+#[test]
+fn test_simulated_display_clips_to_panel() {
+    let mut display = SimulatedDisplay::new(DevInfo::with_panel_size(8, 4), None);
+
+    display.load_buffer_from_vec(vec![0; 8]);
+    display.display(4, 2, 8, 2, WaveformMode::Gc16).unwrap();
+
+    assert_eq!(display.pixel(7, 3), 0);
+    assert_eq!(display.pixel(3, 3), 15);
+}
+
+#[test]
+fn test_simulated_display_buffer_too_short() {
+    let mut display = SimulatedDisplay::new(DevInfo::with_panel_size(8, 4), None);
+
+    display.load_buffer_from_vec(vec![0; 3]);
+    assert!(display.display(0, 0, 4, 2, WaveformMode::Gc16).is_err());
+}
+
+#[test]
+fn test_simulated_display_compare_path() {
+    use crate::imagery::{new_bgr8, Imagery};
+
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+
+    let imagery = Imagery::new(16, 8, 16, 8, 0);
+    let old_slice = vec![white; 16 * 8];
+    let mut new_slice = old_slice.clone();
+    new_slice[3 * 16 + 9] = black;
+    new_slice[4 * 16 + 10] = black;
+
+    let area = imagery
+        .compare_image_slices(old_slice.as_slice(), new_slice.as_slice())
+        .unwrap();
+    let grey_vec = Imagery::transform_to_grey_4bpp(&area.bgr_vec);
+
+    let mut display = SimulatedDisplay::new(DevInfo::with_panel_size(16, 8), None);
+    display.load_buffer_from_vec(grey_vec);
+    display
+        .display(area.x, area.y, area.width, area.height, WaveformMode::Gc16)
+        .unwrap();
+
+    for y in 0..8 {
+        for x in 0..16 {
+            let expected = if (x, y) == (9, 3) || (x, y) == (10, 4) {
+                0
+            } else {
+                15
+            };
+            assert_eq!(display.pixel(x, y), expected, "pixel {}, {}", x, y);
+        }
+    }
+}
+
+#[test]
+fn test_simulated_display_png_dump() {
+    let output_dir = std::env::temp_dir().join("ardoise_test_simulated_display_png_dump");
+    std::fs::create_dir_all(&output_dir).unwrap();
+
+    let mut display =
+        SimulatedDisplay::new(DevInfo::with_panel_size(8, 4), Some(output_dir.clone()));
+    display.set_outline(true);
+    display.load_buffer_from_vec(vec![0; 4]);
+    display.display(0, 0, 4, 2, WaveformMode::Gc16).unwrap();
+
+    let dumped = image::open(output_dir.join("frame_00001.png"))
+        .unwrap()
+        .to_rgb8();
+    assert_eq!(dumped.dimensions(), (8, 4));
+    assert_eq!(dumped.get_pixel(0, 0), &Rgb([255, 0, 0]));
+    assert_eq!(dumped.get_pixel(7, 3), &Rgb([255, 255, 255]));
+
+    std::fs::remove_dir_all(&output_dir).unwrap();
+}
+
+#[test]
+fn test_simulated_display_rotation_matches_imagery() {
+    use crate::imagery::{new_bgr8, Area, Imagery};
+
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+    let base_area = Area {
+        x: 4,
+        y: 2,
+        width: 4,
+        height: 2,
+        bgr_vec: vec![black, white, white, white, white, white, black, white],
+    };
+
+    for rotation in [90, 180, 270].iter() {
+        // rotated by the CPU
+        let imagery = Imagery::new(16, 12, 16, 16, *rotation);
+        let rotated_area = imagery.rotate(base_area.clone(), *rotation);
+        let mut cpu_display = SimulatedDisplay::new(DevInfo::with_panel_size(16, 12), None);
+        cpu_display.load_buffer_from_vec(Imagery::transform_to_grey_4bpp(&rotated_area.bgr_vec));
+        cpu_display
+            .display(
+                rotated_area.x,
+                rotated_area.y,
+                rotated_area.width,
+                rotated_area.height,
+                WaveformMode::Gc16,
+            )
+            .unwrap();
+
+        // rotated by the panel
+        let mut panel_display = SimulatedDisplay::new(DevInfo::with_panel_size(16, 12), None);
+        assert!(panel_display.set_rotation(*rotation));
+        panel_display.load_buffer_from_vec(Imagery::transform_to_grey_4bpp(&base_area.bgr_vec));
+        panel_display
+            .display(
+                base_area.x,
+                base_area.y,
+                base_area.width,
+                base_area.height,
+                WaveformMode::Gc16,
+            )
+            .unwrap();
+
+        assert_eq!(cpu_display.frame_buffer, panel_display.frame_buffer);
+    }
+}
+
+#[test]
+fn test_panel_geometries() {
+    use crate::imagery::{new_bgr8, Imagery, Rect};
+    use crate::scaling::{ScaleFilter, ScaleMode, Scaler};
+
+    let white = new_bgr8(255, 255, 255);
+    let black = new_bgr8(0, 0, 0);
+    // a 1080p desktop, black square in the middle
+    let (capture_width, capture_height) = (1920u16, 1080u16);
+    let mut capture = vec![white; capture_width as usize * capture_height as usize];
+    for line in 440..640 {
+        for col in 860..1060 {
+            capture[line * capture_width as usize + col] = black;
+        }
+    }
+
+    // 6", 6" HD, 10.3" and 13.3" panels
+    let geometries: [(u16, u16); 4] = [(800, 600), (1448, 1072), (1872, 1404), (1600, 1200)];
+    for (panel_width, panel_height) in geometries.iter() {
+        for rotation in [0, 90].iter() {
+            let panel_size = (*panel_width, *panel_height);
+            let mut display =
+                SimulatedDisplay::new(DevInfo::with_panel_size(panel_size.0, panel_size.1), None);
+            let mut scaler = Scaler::for_panel(
+                capture_width,
+                capture_height,
+                panel_size,
+                *rotation,
+                ScaleMode::Fit,
+                ScaleFilter::Area,
+            );
+            let (view_width, view_height) = scaler.view_size();
+            let imagery = Imagery::new(
+                panel_size.0,
+                panel_size.1,
+                view_width,
+                view_height,
+                *rotation,
+            );
+            let show = |display: &mut SimulatedDisplay, area| {
+                let area = imagery.rotate(area, *rotation);
+                display.load_buffer_from_vec(Imagery::transform_to_grey_4bpp(&area.bgr_vec));
+                display
+                    .display(area.x, area.y, area.width, area.height, WaveformMode::Gc16)
+                    .unwrap();
+            };
+            // panel pixel showing a pixel of the view
+            let on_panel = |x: u16, y: u16| {
+                let (x, y, _, _) = rotate_rect(*rotation, panel_size.0, panel_size.1, (x, y, 1, 1));
+                (x, y)
+            };
+
+            scaler.update(&capture, None);
+            show(&mut display, imagery.full_area(scaler.frame()).unwrap());
+
+            let placement = scaler.placement();
+            let context = format!("{:?} at {}", panel_size, rotation);
+            assert_eq!(placement.width, view_width, "{}", context);
+            let (x, y) = on_panel(view_width / 2, view_height / 2);
+            assert_eq!(display.pixel(x, y), 0, "{}", context);
+            // letterbox bars
+            let (x, y) = on_panel(0, 0);
+            assert_eq!(display.pixel(x, y), 15, "{}", context);
+            let (x, y) = on_panel(view_width - 1, view_height - 1);
+            assert_eq!(display.pixel(x, y), 15, "{}", context);
+            // the square keeps its proportions
+            let black_pixels = display.frame_buffer.iter().fold(0, |count, byte| {
+                count + (byte & 0x0F == 0) as u32 + (byte >> 4 == 0) as u32
+            });
+            let side = 200.0 * placement.width as f32 / capture_width as f32;
+            assert!(
+                (black_pixels as f32 - side * side).abs() < 4.0 * side,
+                "{}: {} black pixels",
+                context,
+                black_pixels
+            );
+
+            // a damaged corner of the desktop
+            let old_frame = scaler.frame().to_vec();
+            for line in 0..32 {
+                for col in 0..32 {
+                    capture[line * capture_width as usize + col] = black;
+                }
+            }
+            let damage = [Rect {
+                x: 0,
+                y: 0,
+                width: 32,
+                height: 32,
+            }];
+            let view_damage = scaler.update(&capture, Some(&damage)).unwrap();
+            let areas = imagery.compare_regions(&old_frame, scaler.frame(), &view_damage);
+            assert!(!areas.is_empty(), "{}", context);
+            for area in areas {
+                show(&mut display, area);
+            }
+            let (x, y) = on_panel(placement.x, placement.y);
+            assert_eq!(display.pixel(x, y), 0, "{}", context);
+            let (x, y) = on_panel(0, 0);
+            assert_eq!(display.pixel(x, y), 15, "{}", context);
+
+            for line in 0..32 {
+                for col in 0..32 {
+                    capture[line * capture_width as usize + col] = white;
+                }
+            }
+        }
+    }
+}
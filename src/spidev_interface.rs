@@ -0,0 +1,284 @@
+use libc::{c_ulong, c_void};
+use std::cell::Cell;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::thread;
+use std::time::Duration;
+
+extern crate custom_error;
+use custom_error::custom_error;
+
+use super::transport::{Transport, DEFAULT_SPEED_HZ};
+
+// from linux/spi/spidev.h
+static SPI_IOC_WR_MODE: c_ulong = 0x4001_6b01;
+static SPI_IOC_WR_BITS_PER_WORD: c_ulong = 0x4001_6b03;
+static SPI_IOC_WR_MAX_SPEED_HZ: c_ulong = 0x4004_6b04;
+// SPI_IOC_MESSAGE(1)
+static SPI_IOC_MESSAGE_1: c_ulong = 0x4020_6b00;
+static SPI_MODE_0: u8 = 0x00;
+// chip select is a GPIO line, held across several transfers
+static SPI_NO_CS: u8 = 0x40;
+
+// from linux/gpio.h, first version of the character device ABI
+static GPIO_GET_LINEHANDLE_IOCTL: c_ulong = 0xC16C_B403;
+static GPIOHANDLE_GET_LINE_VALUES_IOCTL: c_ulong = 0xC040_B408;
+static GPIOHANDLE_SET_LINE_VALUES_IOCTL: c_ulong = 0xC040_B409;
+static GPIOHANDLE_REQUEST_INPUT: u32 = 1 << 0;
+static GPIOHANDLE_REQUEST_OUTPUT: u32 = 1 << 1;
+static GPIOHANDLES_MAX: usize = 64;
+
+// largest transfer spidev accepts, unless the module says otherwise
+static SPIDEV_BUFSIZ_PATH: &str = "/sys/module/spidev/parameters/bufsiz";
+static DEFAULT_BUFSIZ: usize = 4096;
+
+custom_error! {pub SpidevError
+    Open{path: String, source: io::Error} = "cannot open {path}: {source}",
+    Ioctl{what: String, source: io::Error} = "{what} failed: {source}"
+}
+
+// what the transport gives back to the driver
+impl From<SpidevError> for io::Error {
+    fn from(error: SpidevError) -> io::Error {
+        io::Error::other(error)
+    }
+}
+
+#[repr(C)]
+struct SpiIocTransfer {
+    tx_buf: u64,
+    rx_buf: u64,
+    len: u32,
+    speed_hz: u32,
+    delay_usecs: u16,
+    bits_per_word: u8,
+    cs_change: u8,
+    tx_nbits: u8,
+    rx_nbits: u8,
+    word_delay_usecs: u8,
+    pad: u8,
+}
+
+#[repr(C)]
+struct GpioHandleRequest {
+    line_offsets: [u32; 64],
+    flags: u32,
+    default_values: [u8; 64],
+    consumer_label: [u8; 32],
+    lines: u32,
+    fd: i32,
+}
+
+#[repr(C)]
+struct GpioHandleData {
+    values: [u8; 64],
+}
+
+fn ioctl<T>(file: &File, request: c_ulong, argument: *mut T, what: &str) -> Result<(), SpidevError> {
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), request, argument as *mut c_void) };
+    if result < 0 {
+        return Err(SpidevError::Ioctl {
+            what: what.to_string(),
+            source: io::Error::last_os_error(),
+        });
+    }
+    Ok(())
+}
+
+fn open_device(path: &str) -> Result<File, SpidevError> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|source| SpidevError::Open {
+            path: path.to_string(),
+            source,
+        })
+}
+
+fn max_transfer_size() -> usize {
+    fs::read_to_string(SPIDEV_BUFSIZ_PATH)
+        .ok()
+        .and_then(|bufsiz| bufsiz.trim().parse().ok())
+        .filter(|bufsiz| *bufsiz > 0)
+        .unwrap_or(DEFAULT_BUFSIZ)
+}
+
+/// One line of a GPIO character device, requested as input or output
+struct GpioLine {
+    handle: File,
+}
+
+impl GpioLine {
+    fn request(chip: &File, offset: u8, output: bool, value: u8) -> Result<GpioLine, SpidevError> {
+        let mut request = GpioHandleRequest {
+            line_offsets: [0; GPIOHANDLES_MAX],
+            flags: if output {
+                GPIOHANDLE_REQUEST_OUTPUT
+            } else {
+                GPIOHANDLE_REQUEST_INPUT
+            },
+            default_values: [0; GPIOHANDLES_MAX],
+            consumer_label: [0; 32],
+            lines: 1,
+            fd: -1,
+        };
+        request.line_offsets[0] = offset as u32;
+        request.default_values[0] = value;
+        request.consumer_label[..7].copy_from_slice(b"ardoise");
+        ioctl(
+            chip,
+            GPIO_GET_LINEHANDLE_IOCTL,
+            &mut request,
+            &format!("requesting GPIO {}", offset),
+        )?;
+        Ok(GpioLine {
+            handle: unsafe { File::from_raw_fd(request.fd) },
+        })
+    }
+
+    fn get(&self) -> io::Result<bool> {
+        let mut data = GpioHandleData {
+            values: [0; GPIOHANDLES_MAX],
+        };
+        ioctl(
+            &self.handle,
+            GPIOHANDLE_GET_LINE_VALUES_IOCTL,
+            &mut data,
+            "reading GPIO",
+        )?;
+        Ok(data.values[0] != 0)
+    }
+
+    fn set(&self, high: bool) -> io::Result<()> {
+        let mut data = GpioHandleData {
+            values: [0; GPIOHANDLES_MAX],
+        };
+        data.values[0] = high as u8;
+        ioctl(
+            &self.handle,
+            GPIOHANDLE_SET_LINE_VALUES_IOCTL,
+            &mut data,
+            "writing GPIO",
+        )?;
+        Ok(())
+    }
+}
+
+/// Transport through the kernel drivers: /dev/spidevB.C for the bus and a
+/// /dev/gpiochipN for CS, HRDY and RESET. Needs neither root nor libbcm2835,
+/// only the spi and gpio groups, and the SPI driver leaving the CS pin alone
+/// (dtoverlay=spi0-0cs).
+pub struct SpidevInterface {
+    spi: File,
+    // given with each transfer
+    speed_hz: Cell<u32>,
+    max_transfer_size: usize,
+    cs: GpioLine,
+    hrdy: GpioLine,
+    reset: GpioLine,
+}
+
+impl SpidevInterface {
+    pub fn open(
+        spi_path: &str,
+        gpio_chip_path: &str,
+        cs: u8,
+        hrdy: u8,
+        reset: u8,
+    ) -> Result<SpidevInterface, SpidevError> {
+        let spi = open_device(spi_path)?;
+        let mut mode: u8 = SPI_MODE_0 | SPI_NO_CS;
+        ioctl(&spi, SPI_IOC_WR_MODE, &mut mode, "setting the SPI mode")?;
+        let mut bits_per_word: u8 = 8;
+        ioctl(
+            &spi,
+            SPI_IOC_WR_BITS_PER_WORD,
+            &mut bits_per_word,
+            "setting the SPI word size",
+        )?;
+        let mut speed_hz: u32 = DEFAULT_SPEED_HZ;
+        ioctl(
+            &spi,
+            SPI_IOC_WR_MAX_SPEED_HZ,
+            &mut speed_hz,
+            "setting the SPI speed",
+        )?;
+
+        let chip = open_device(gpio_chip_path)?;
+        Ok(SpidevInterface {
+            spi,
+            speed_hz: Cell::new(speed_hz),
+            max_transfer_size: max_transfer_size(),
+            cs: GpioLine::request(&chip, cs, true, 1)?,
+            hrdy: GpioLine::request(&chip, hrdy, false, 0)?,
+            reset: GpioLine::request(&chip, reset, true, 1)?,
+        })
+    }
+
+    // one SPI message, rx_buf 0 drops what is read
+    fn message(&self, tx: &[u8], rx_buf: u64) -> io::Result<()> {
+        let mut transfer = SpiIocTransfer {
+            tx_buf: tx.as_ptr() as u64,
+            rx_buf,
+            len: tx.len() as u32,
+            speed_hz: self.speed_hz.get(),
+            delay_usecs: 0,
+            bits_per_word: 8,
+            cs_change: 0,
+            tx_nbits: 0,
+            rx_nbits: 0,
+            word_delay_usecs: 0,
+            pad: 0,
+        };
+        ioctl(&self.spi, SPI_IOC_MESSAGE_1, &mut transfer, "SPI transfer")?;
+        Ok(())
+    }
+}
+
+impl Transport for SpidevInterface {
+    fn spi_transfer(&self, value: u8) -> io::Result<u8> {
+        let mut rx: [u8; 1] = [0];
+        self.message(&[value], rx.as_mut_ptr() as u64)?;
+        Ok(rx[0])
+    }
+
+    fn spi_write(&self, bytes: &[u8]) -> io::Result<()> {
+        for chunk in bytes.chunks(self.max_transfer_size) {
+            self.message(chunk, 0)?;
+        }
+        Ok(())
+    }
+
+    fn set_speed(&self, speed_hz: u32) {
+        self.speed_hz.set(speed_hz);
+    }
+
+    fn set_chip_select(&self, selected: bool) -> io::Result<()> {
+        // active low
+        self.cs.set(!selected)
+    }
+
+    fn host_ready(&self) -> io::Result<bool> {
+        self.hrdy.get()
+    }
+
+    fn set_reset(&self, high: bool) -> io::Result<()> {
+        self.reset.set(high)
+    }
+
+    fn delay(&self, millis: u16) {
+        thread::sleep(Duration::from_millis(millis as u64));
+    }
+}
+
+#[test]
+fn test_kernel_struct_sizes() {
+    // the sizes are part of the ioctl numbers
+    assert_eq!(std::mem::size_of::<SpiIocTransfer>(), 32);
+    assert_eq!(std::mem::size_of::<GpioHandleRequest>(), 364);
+    assert_eq!(std::mem::size_of::<GpioHandleData>(), 64);
+    assert_eq!((SPI_IOC_MESSAGE_1 >> 16) & 0x3FFF, 32);
+    assert_eq!((GPIO_GET_LINEHANDLE_IOCTL >> 16) & 0x3FFF, 364);
+}
@@ -0,0 +1,413 @@
+use libc::{c_char, c_int, pollfd, POLLIN};
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use x11cap::Bgr8;
+
+use crate::capture::{limit_damage, CaptureError, CaptureSource, DAMAGE_WAIT};
+use crate::font::Font;
+use crate::imagery::{new_bgr8, Rect};
+use crate::keyboard::{Keyboard, KeyboardInput};
+use crate::vt::Screen;
+
+// once the program wrote, how long more of its output is waited for before drawing, in ms
+static OUTPUT_WAIT: c_int = 10;
+// a program writing without a pause is drawn that often all the same
+static MAX_OUTPUT_TIME: Duration = Duration::from_millis(100);
+// what the program is told it runs in
+static TERM: &str = "xterm";
+static DEFAULT_SHELL: &str = "/bin/sh";
+
+/// What `ardoise term` runs, and how it is drawn
+pub struct TerminalSettings {
+    /// Program and its arguments, $SHELL when empty
+    pub command: Vec<String>,
+    pub font: Font,
+    /// Each pixel of the font drawn as a square of font_scale panel pixels
+    pub font_scale: u16,
+    pub keyboard: Keyboard,
+}
+
+fn open_error(doing: &str, error: io::Error) -> CaptureError {
+    CaptureError::Open {
+        reason: format!("{}: {}", doing, error),
+    }
+}
+
+/// A terminal drawn in black and white, for a program run in a pseudo terminal
+/// with the keys of the keyboard. The cells it changes are the damage.
+pub struct TerminalCapture {
+    master: File,
+    child: Child,
+    screen: Screen,
+    // None once stdin is closed, e.g. when run by a service
+    keyboard: Option<KeyboardInput>,
+    font: Font,
+    font_scale: u16,
+    // top left corner of the first cell, the cells are centred in the frame
+    origin: (u16, u16),
+    width: u16,
+    height: u16,
+    frame: Vec<Bgr8>,
+    // cell the cursor is drawn on, None when hidden
+    drawn_cursor: Option<(usize, usize)>,
+}
+
+impl TerminalCapture {
+    /// Start the program in a terminal filling a frame of `view_size`, the
+    /// panel once rotated
+    pub fn open(
+        settings: &TerminalSettings,
+        view_size: (u16, u16),
+    ) -> Result<TerminalCapture, CaptureError> {
+        let (width, height) = view_size;
+        let (font_width, font_height) = settings.font.cell_size();
+        let cell_width = font_width as u32 * settings.font_scale as u32;
+        let cell_height = font_height as u32 * settings.font_scale as u32;
+        let cols = width as u32 / cell_width;
+        let rows = height as u32 / cell_height;
+        if cols == 0 || rows == 0 {
+            return Err(CaptureError::Open {
+                reason: "the font is too big for the panel".to_string(),
+            });
+        }
+
+        let (master, slave) = open_pty().map_err(|error| open_error("pseudo terminal", error))?;
+        let window_size = libc::winsize {
+            ws_row: rows as u16,
+            ws_col: cols as u16,
+            ws_xpixel: (cols * cell_width) as u16,
+            ws_ypixel: (rows * cell_height) as u16,
+        };
+        if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &window_size) } < 0 {
+            return Err(open_error("terminal size", io::Error::last_os_error()));
+        }
+        let keyboard = KeyboardInput::open(&settings.keyboard)
+            .map_err(|error| open_error("keyboard", error))?;
+        let child = spawn(&settings.command, slave)
+            .map_err(|error| open_error("starting the program", error))?;
+
+        Ok(TerminalCapture {
+            master,
+            child,
+            screen: Screen::new(cols as usize, rows as usize),
+            keyboard: Some(keyboard),
+            font: settings.font.clone(),
+            font_scale: settings.font_scale,
+            origin: (
+                ((width as u32 - cols * cell_width) / 2) as u16,
+                ((height as u32 - rows * cell_height) / 2) as u16,
+            ),
+            width,
+            height,
+            frame: vec![new_bgr8(255, 255, 255); width as usize * height as usize],
+            drawn_cursor: None,
+        })
+    }
+
+    // width and height of a cell on the panel
+    fn cell_size(&self) -> (u16, u16) {
+        let (font_width, font_height) = self.font.cell_size();
+        (font_width * self.font_scale, font_height * self.font_scale)
+    }
+
+    // the program ended, or at least closed the terminal
+    fn ended(&mut self) -> CaptureError {
+        let status = match self.child.wait() {
+            Ok(status) => status.to_string(),
+            Err(error) => error.to_string(),
+        };
+        CaptureError::Ended { status }
+    }
+
+    fn read_output(&mut self) -> Result<(), CaptureError> {
+        let mut buffer = [0u8; 16384];
+        match self.master.read(&mut buffer) {
+            Ok(0) => Err(self.ended()),
+            Ok(length) => {
+                self.screen.feed(&buffer[..length]);
+                Ok(())
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => Ok(()),
+            // the last descriptor of the terminal is closed
+            Err(ref error) if error.raw_os_error() == Some(libc::EIO) => Err(self.ended()),
+            Err(error) => Err(CaptureError::Grab {
+                reason: format!("reading the terminal: {}", error),
+            }),
+        }
+    }
+
+    fn write_input(&mut self, bytes: &[u8]) -> Result<(), CaptureError> {
+        match self.master.write_all(bytes) {
+            Ok(()) => Ok(()),
+            Err(ref error) if error.raw_os_error() == Some(libc::EIO) => Err(self.ended()),
+            Err(error) => Err(CaptureError::Grab {
+                reason: format!("writing to the terminal: {}", error),
+            }),
+        }
+    }
+
+    fn forward_keys(&mut self) -> Result<(), CaptureError> {
+        let application_cursor = self.screen.application_cursor();
+        let keys = match &mut self.keyboard {
+            Some(keyboard) => keyboard.read(application_cursor),
+            None => return Ok(()),
+        };
+        match keys {
+            Ok(bytes) => self.write_input(&bytes),
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Err(error) => {
+                println!("no more keys: {}", error);
+                self.keyboard = None;
+                Ok(())
+            }
+        }
+    }
+
+    /// Draw the changed cells and the cursor, their rectangles on the frame
+    fn draw(&mut self) -> Vec<Rect> {
+        let cursor = self.screen.cursor();
+        if cursor != self.drawn_cursor {
+            for &(x, y) in self.drawn_cursor.iter().chain(cursor.iter()) {
+                self.screen.mark_dirty(x, y);
+            }
+            self.drawn_cursor = cursor;
+        }
+        let (cell_width, cell_height) = self.cell_size();
+        let mut rects = Vec::new();
+        for (row, first, end) in self.screen.take_dirty() {
+            for col in first..end {
+                self.draw_cell(col, row);
+            }
+            rects.push(Rect {
+                x: self.origin.0 + first as u16 * cell_width,
+                y: self.origin.1 + row as u16 * cell_height,
+                width: (end - first) as u16 * cell_width,
+                height: cell_height,
+            });
+        }
+        limit_damage(rects)
+    }
+
+    fn draw_cell(&mut self, col: usize, row: usize) {
+        let cell = self.screen.cell(col, row);
+        let inverse = cell.inverse != (self.drawn_cursor == Some((col, row)));
+        let glyph = self.font.glyph(cell.c);
+        let (font_width, font_height) = self.font.cell_size();
+        let (cell_width, cell_height) = self.cell_size();
+        let scale = self.font_scale as usize;
+        let left = self.origin.0 as usize + col * cell_width as usize;
+        let top = self.origin.1 as usize + row * cell_height as usize;
+        let underline_y = font_height - 1 - font_height / 16;
+
+        for y in 0..font_height {
+            for x in 0..font_width {
+                let mut ink = self.font.pixel(glyph, x, y);
+                // bold : the strokes one pixel wider
+                if cell.bold && x > 0 {
+                    ink = ink || self.font.pixel(glyph, x - 1, y);
+                }
+                if cell.underline && y == underline_y {
+                    ink = true;
+                }
+                let colour = if ink != inverse {
+                    new_bgr8(0, 0, 0)
+                } else {
+                    new_bgr8(255, 255, 255)
+                };
+                for line in 0..scale {
+                    let start = (top + y as usize * scale + line) * self.width as usize
+                        + left
+                        + x as usize * scale;
+                    for pixel in &mut self.frame[start..start + scale] {
+                        *pixel = colour;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl CaptureSource for TerminalCapture {
+    fn geometry(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+
+    fn capture(&mut self) -> Result<Option<Vec<Rect>>, CaptureError> {
+        let deadline = Instant::now() + Duration::from_millis(DAMAGE_WAIT as u64);
+        // the program wrote, more is read before drawing
+        let mut output_start: Option<Instant> = None;
+        loop {
+            let timeout = match output_start {
+                Some(start) if start.elapsed() >= MAX_OUTPUT_TIME => break,
+                Some(_) => OUTPUT_WAIT,
+                None => deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_millis() as c_int,
+            };
+            let mut fds = vec![pollfd {
+                fd: self.master.as_raw_fd(),
+                events: POLLIN,
+                revents: 0,
+            }];
+            if let Some(keyboard) = &self.keyboard {
+                fds.push(pollfd {
+                    fd: keyboard.as_raw_fd(),
+                    events: POLLIN,
+                    revents: 0,
+                });
+            }
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            // interrupted by a signal, e.g. SIGTERM : the loop checks it
+            if ready < 0 && output_start.is_none() {
+                return Ok(Some(Vec::new()));
+            }
+            if ready <= 0 {
+                if output_start.is_some() || Instant::now() >= deadline {
+                    break;
+                }
+                continue;
+            }
+            if fds.len() > 1 && fds[1].revents != 0 {
+                self.forward_keys()?;
+            }
+            if fds[0].revents != 0 {
+                self.read_output()?;
+                output_start.get_or_insert_with(Instant::now);
+            }
+        }
+
+        let replies = self.screen.take_replies();
+        if !replies.is_empty() {
+            self.write_input(&replies)?;
+        }
+        Ok(Some(self.draw()))
+    }
+
+    fn frame(&self) -> &[Bgr8] {
+        &self.frame
+    }
+}
+
+impl Drop for TerminalCapture {
+    fn drop(&mut self) {
+        // the program leads its own process group
+        unsafe { libc::kill(-(self.child.id() as libc::pid_t), libc::SIGHUP) };
+    }
+}
+
+/// The master and slave sides of a new pseudo terminal
+fn open_pty() -> Result<(File, File), io::Error> {
+    let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+    if master < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let master = unsafe { File::from_raw_fd(master) };
+    let mut name = [0 as c_char; 128];
+    let path = unsafe {
+        if libc::grantpt(master.as_raw_fd()) < 0
+            || libc::unlockpt(master.as_raw_fd()) < 0
+            || libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned()
+    };
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    Ok((master, slave))
+}
+
+/// Run `command`, or the shell, with the slave side as its controlling terminal
+fn spawn(command: &[String], slave: File) -> Result<Child, io::Error> {
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| DEFAULT_SHELL.to_string());
+    let (program, arguments) = match command.split_first() {
+        Some((program, arguments)) => (program.clone(), arguments),
+        None => (shell, &[][..]),
+    };
+    let mut child = Command::new(program);
+    child
+        .args(arguments)
+        .env("TERM", TERM)
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+    unsafe {
+        child.pre_exec(|| {
+            // a session of its own, Ctrl-C and the hang up reach it and its children
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    child.spawn()
+}
+
+#[test]
+fn test_run_a_program() {
+    let settings = TerminalSettings {
+        command: vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "stty size; printf '\\033[7mX\\033[m'; read line; printf \"<$line>\"".to_string(),
+        ],
+        font: Font::bundled(),
+        font_scale: 1,
+        keyboard: Keyboard::Stdin,
+    };
+    // 5 columns of 16 pixels and 3 rows of 32, centred
+    let mut capture = TerminalCapture::open(&settings, (90, 112)).unwrap();
+    assert_eq!(capture.geometry(), (90, 112));
+    assert_eq!(capture.origin, (5, 8));
+    // the tests do not type
+    capture.keyboard = None;
+
+    let mut text = String::new();
+    let mut damage = Vec::new();
+    while !text.contains('X') {
+        damage.extend(capture.capture().unwrap().unwrap());
+        text = (0..5).map(|x| capture.screen.cell(x, 1).c).collect();
+    }
+    assert_eq!(
+        (0..5)
+            .map(|x| capture.screen.cell(x, 0).c)
+            .collect::<String>(),
+        "3 5  "
+    );
+    assert!(capture.screen.cell(0, 1).inverse);
+    assert!(damage
+        .iter()
+        .all(|rect| rect.x >= 5 && rect.x + rect.width <= 85));
+
+    // an inverse X, then the cursor after it
+    let frame = capture.frame();
+    let black = |x: usize, y: usize| frame[y * 90 + x].r == 0;
+    assert!(black(5, 40 + 2));
+    assert!(black(21, 40 + 2));
+    assert!(!black(37, 40 + 2));
+    // nothing drawn in the margin
+    assert!((0..90).all(|x| !black(x, 4)));
+
+    capture.write_input(b"hi\r").unwrap();
+    loop {
+        match capture.capture() {
+            Ok(_) => (),
+            Err(CaptureError::Ended { status }) => {
+                assert!(status.contains('0'));
+                break;
+            }
+            Err(error) => panic!("{}", error),
+        }
+    }
+    let line: String = (0..5).map(|x| capture.screen.cell(x, 2).c).collect();
+    assert_eq!(line, "<hi> ");
+}
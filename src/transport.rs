@@ -0,0 +1,213 @@
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::io;
+#[cfg(test)]
+use std::rc::Rc;
+use std::time::Instant;
+
+/// SPI clock of both the reads and the writes unless told otherwise :
+/// BCM2835_SPI_CLOCK_DIVIDER_32 of the 250 MHz core clock
+pub static DEFAULT_SPEED_HZ: u32 = 7_812_500;
+
+/// The wires between the Pi and the IT8951: SPI bus, chip select, HRDY and RESET.
+/// The IT8951 driver only speaks through this, whatever library moves the pins.
+/// A failed access of the bus or of a pin is an error, the driver gives up on it.
+pub trait Transport {
+    /// Send one byte on the SPI bus and return the byte read meanwhile
+    fn spi_transfer(&self, value: u8) -> io::Result<u8>;
+
+    /// Send bytes on the SPI bus, what is read meanwhile is dropped.
+    /// Backends able to send a whole buffer at once override this.
+    fn spi_write(&self, bytes: &[u8]) -> io::Result<()> {
+        for byte in bytes {
+            self.spi_transfer(*byte)?;
+        }
+        Ok(())
+    }
+
+    /// SPI clock of the next transfers, backends round it down to what they can do
+    fn set_speed(&self, _speed_hz: u32) {}
+
+    /// Select (true) or release (false) the IT8951 on the SPI bus
+    fn set_chip_select(&self, selected: bool) -> io::Result<()>;
+
+    /// Level of HRDY, true when the IT8951 is ready for the next word
+    fn host_ready(&self) -> io::Result<bool>;
+
+    /// Drive the RESET pin, false holds the IT8951 in reset
+    fn set_reset(&self, high: bool) -> io::Result<()>;
+
+    fn delay(&self, millis: u16);
+}
+
+/// Bytes per second sent through spi_transfer one byte at a time, then through
+/// spi_write, for `byte_count` bytes. The chip select stays released, so the
+/// IT8951 ignores them.
+pub fn measure_throughput(transport: &dyn Transport, byte_count: usize) -> io::Result<(f64, f64)> {
+    let bytes: Vec<u8> = (0..byte_count).map(|n| n as u8).collect();
+    transport.set_chip_select(false)?;
+
+    let start = Instant::now();
+    for byte in bytes.iter() {
+        transport.spi_transfer(*byte)?;
+    }
+    let bytewise = byte_count as f64 / start.elapsed().as_secs_f64();
+
+    let start = Instant::now();
+    transport.spi_write(&bytes)?;
+    let bulk = byte_count as f64 / start.elapsed().as_secs_f64();
+
+    Ok((bytewise, bulk))
+}
+
+/// What a MockTransport saw and has to answer
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockBus {
+    /// Bytes sent during each chip select, in order
+    pub frames: Vec<Vec<u8>>,
+    /// Words answered to the reads, a read starts with the 0x1000 preamble
+    pub read_words: VecDeque<u16>,
+    pub resets: u32,
+    /// Every SPI clock asked for, in order
+    pub speeds: Vec<u32>,
+    /// HRDY held low, as by a controller gone away
+    pub busy: bool,
+    /// Every access fails, as with the SPI device unplugged
+    pub broken: bool,
+    selected: bool,
+    // low byte of a word being read
+    pending_low_byte: Option<u8>,
+}
+
+/// Transport without hardware for the tests: records the SPI frames and
+/// answers the reads with queued words. HRDY is ready unless the bus is busy.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    bus: Rc<RefCell<MockBus>>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// The bus shared by every clone of this transport
+    pub fn bus(&self) -> Rc<RefCell<MockBus>> {
+        self.bus.clone()
+    }
+}
+
+#[cfg(test)]
+impl MockTransport {
+    fn check(&self) -> io::Result<()> {
+        if self.bus.borrow().broken {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "mock bus broken"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Transport for MockTransport {
+    fn spi_transfer(&self, value: u8) -> io::Result<u8> {
+        self.check()?;
+        let mut bus = self.bus.borrow_mut();
+        if !bus.selected {
+            return Ok(0);
+        }
+        let frame_index = bus.frames.len() - 1;
+        bus.frames[frame_index].push(value);
+
+        // preamble then two dummy bytes, words after that
+        let frame = &bus.frames[frame_index];
+        if frame.len() <= 4 || frame[0] != 0x10 || frame[1] != 0x00 {
+            return Ok(0);
+        }
+        if let Some(low_byte) = bus.pending_low_byte.take() {
+            return Ok(low_byte);
+        }
+        let word = bus.read_words.pop_front().unwrap_or(0);
+        bus.pending_low_byte = Some(word as u8);
+        Ok((word >> 8) as u8)
+    }
+
+    fn set_speed(&self, speed_hz: u32) {
+        self.bus.borrow_mut().speeds.push(speed_hz);
+    }
+
+    fn set_chip_select(&self, selected: bool) -> io::Result<()> {
+        self.check()?;
+        let mut bus = self.bus.borrow_mut();
+        if selected && !bus.selected {
+            bus.frames.push(Vec::new());
+        }
+        bus.selected = selected;
+        bus.pending_low_byte = None;
+        Ok(())
+    }
+
+    fn host_ready(&self) -> io::Result<bool> {
+        self.check()?;
+        Ok(!self.bus.borrow().busy)
+    }
+
+    fn set_reset(&self, high: bool) -> io::Result<()> {
+        self.check()?;
+        if !high {
+            self.bus.borrow_mut().resets += 1;
+        }
+        Ok(())
+    }
+
+    fn delay(&self, _millis: u16) {}
+}
+
+#[test]
+fn test_mock_transport_reads_words() {
+    let transport = MockTransport::new();
+    transport.bus().borrow_mut().read_words.push_back(0x1234);
+
+    transport.set_chip_select(true).unwrap();
+    let answers: Vec<u8> = [0x10, 0x00, 0, 0, 0, 0]
+        .iter()
+        .map(|byte| transport.spi_transfer(*byte).unwrap())
+        .collect();
+    transport.set_chip_select(false).unwrap();
+
+    assert_eq!(answers, vec![0, 0, 0, 0, 0x12, 0x34]);
+    assert_eq!(transport.bus().borrow().frames.len(), 1);
+}
+
+#[test]
+fn test_spi_write_sends_bytes_in_order() {
+    let transport = MockTransport::new();
+    transport.set_chip_select(true).unwrap();
+    transport.spi_write(&[0x00, 0x00, 0x12, 0x34]).unwrap();
+    transport.set_chip_select(false).unwrap();
+
+    assert_eq!(transport.bus().borrow().frames, vec![vec![0x00, 0x00, 0x12, 0x34]]);
+}
+
+#[test]
+fn test_measure_throughput_leaves_chip_released() {
+    let transport = MockTransport::new();
+    let (bytewise, bulk) = measure_throughput(&transport, 1000).unwrap();
+
+    assert!(bytewise > 0.0 && bulk > 0.0);
+    assert!(transport.bus().borrow().frames.is_empty());
+}
+
+#[test]
+fn test_broken_bus_fails() {
+    let transport = MockTransport::new();
+    transport.bus().borrow_mut().broken = true;
+
+    assert!(transport.set_chip_select(true).is_err());
+    assert!(transport.spi_write(&[0x00]).is_err());
+    assert!(measure_throughput(&transport, 10).is_err());
+}
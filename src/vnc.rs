@@ -0,0 +1,1083 @@
+use libc::{c_int, pollfd, POLLIN};
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush};
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+use x11cap::Bgr8;
+
+use crate::capture::{limit_damage, CaptureError, CaptureSource, DAMAGE_WAIT};
+use crate::imagery::{new_bgr8, Rect};
+
+// port of display 0, display n listens on 5900 + n
+static DEFAULT_PORT: u16 = 5900;
+// a server silent in the middle of a message is gone
+static READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// from RFC 6143
+static SECURITY_INVALID: u32 = 0;
+static SECURITY_NONE: u32 = 1;
+static SECURITY_VNC: u32 = 2;
+static SET_PIXEL_FORMAT: u8 = 0;
+static SET_ENCODINGS: u8 = 2;
+static FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+static FRAMEBUFFER_UPDATE: u8 = 0;
+static SET_COLOUR_MAP_ENTRIES: u8 = 1;
+static BELL: u8 = 2;
+static SERVER_CUT_TEXT: u8 = 3;
+static ENCODING_RAW: i32 = 0;
+static ENCODING_COPY_RECT: i32 = 1;
+static ENCODING_ZRLE: i32 = 16;
+static ENCODING_DESKTOP_SIZE: i32 = -223;
+// most preferred first
+static ENCODINGS: [i32; 4] = [
+    ENCODING_COPY_RECT,
+    ENCODING_ZRLE,
+    ENCODING_RAW,
+    ENCODING_DESKTOP_SIZE,
+];
+static ZRLE_TILE_SIZE: u16 = 64;
+
+// 32 bits true colour, blue first in memory like Bgr8 : each ZRLE CPIXEL is
+// then blue, green and red
+static PIXEL_FORMAT: [u8; 16] = [32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0];
+
+/// Host and port of a VNC server, e.g. `pi.local:1` or `localhost::5901` : like
+/// vncviewer, numbers below 100 are display numbers
+pub fn parse_address(address: &str) -> Result<(String, u16), String> {
+    let (host, port) = match address.find(':') {
+        Some(colon) => (&address[..colon], Some(&address[colon + 1..])),
+        None => (address, None),
+    };
+    let host = if host.is_empty() { "localhost" } else { host };
+    let port = match port {
+        None => DEFAULT_PORT,
+        Some(port) if port.starts_with(':') => port[1..]
+            .parse()
+            .map_err(|_| format!("bad port in {}", address))?,
+        Some(display) => match display.parse::<u16>() {
+            Ok(display) if display < 100 => DEFAULT_PORT + display,
+            Ok(port) => port,
+            Err(_) => return Err(format!("bad display in {}", address)),
+        },
+    };
+    Ok((host.to_string(), port))
+}
+
+static DES_IP: [u8; 64] = [
+    58, 50, 42, 34, 26, 18, 10, 2, 60, 52, 44, 36, 28, 20, 12, 4, 62, 54, 46, 38, 30, 22, 14, 6,
+    64, 56, 48, 40, 32, 24, 16, 8, 57, 49, 41, 33, 25, 17, 9, 1, 59, 51, 43, 35, 27, 19, 11, 3, 61,
+    53, 45, 37, 29, 21, 13, 5, 63, 55, 47, 39, 31, 23, 15, 7,
+];
+static DES_FP: [u8; 64] = [
+    40, 8, 48, 16, 56, 24, 64, 32, 39, 7, 47, 15, 55, 23, 63, 31, 38, 6, 46, 14, 54, 22, 62, 30,
+    37, 5, 45, 13, 53, 21, 61, 29, 36, 4, 44, 12, 52, 20, 60, 28, 35, 3, 43, 11, 51, 19, 59, 27,
+    34, 2, 42, 10, 50, 18, 58, 26, 33, 1, 41, 9, 49, 17, 57, 25,
+];
+static DES_E: [u8; 48] = [
+    32, 1, 2, 3, 4, 5, 4, 5, 6, 7, 8, 9, 8, 9, 10, 11, 12, 13, 12, 13, 14, 15, 16, 17, 16, 17, 18,
+    19, 20, 21, 20, 21, 22, 23, 24, 25, 24, 25, 26, 27, 28, 29, 28, 29, 30, 31, 32, 1,
+];
+static DES_P: [u8; 32] = [
+    16, 7, 20, 21, 29, 12, 28, 17, 1, 15, 23, 26, 5, 18, 31, 10, 2, 8, 24, 14, 32, 27, 3, 9, 19,
+    13, 30, 6, 22, 11, 4, 25,
+];
+static DES_PC1: [u8; 56] = [
+    57, 49, 41, 33, 25, 17, 9, 1, 58, 50, 42, 34, 26, 18, 10, 2, 59, 51, 43, 35, 27, 19, 11, 3, 60,
+    52, 44, 36, 63, 55, 47, 39, 31, 23, 15, 7, 62, 54, 46, 38, 30, 22, 14, 6, 61, 53, 45, 37, 29,
+    21, 13, 5, 28, 20, 12, 4,
+];
+static DES_PC2: [u8; 48] = [
+    14, 17, 11, 24, 1, 5, 3, 28, 15, 6, 21, 10, 23, 19, 12, 4, 26, 8, 16, 7, 27, 20, 13, 2, 41, 52,
+    31, 37, 47, 55, 30, 40, 51, 45, 33, 48, 44, 49, 39, 56, 34, 53, 46, 42, 50, 36, 29, 32,
+];
+static DES_SHIFTS: [u32; 16] = [1, 1, 2, 2, 2, 2, 2, 2, 1, 2, 2, 2, 2, 2, 2, 1];
+static DES_S: [[u8; 64]; 8] = [
+    [
+        14, 4, 13, 1, 2, 15, 11, 8, 3, 10, 6, 12, 5, 9, 0, 7, 0, 15, 7, 4, 14, 2, 13, 1, 10, 6, 12,
+        11, 9, 5, 3, 8, 4, 1, 14, 8, 13, 6, 2, 11, 15, 12, 9, 7, 3, 10, 5, 0, 15, 12, 8, 2, 4, 9,
+        1, 7, 5, 11, 3, 14, 10, 0, 6, 13,
+    ],
+    [
+        15, 1, 8, 14, 6, 11, 3, 4, 9, 7, 2, 13, 12, 0, 5, 10, 3, 13, 4, 7, 15, 2, 8, 14, 12, 0, 1,
+        10, 6, 9, 11, 5, 0, 14, 7, 11, 10, 4, 13, 1, 5, 8, 12, 6, 9, 3, 2, 15, 13, 8, 10, 1, 3, 15,
+        4, 2, 11, 6, 7, 12, 0, 5, 14, 9,
+    ],
+    [
+        10, 0, 9, 14, 6, 3, 15, 5, 1, 13, 12, 7, 11, 4, 2, 8, 13, 7, 0, 9, 3, 4, 6, 10, 2, 8, 5,
+        14, 12, 11, 15, 1, 13, 6, 4, 9, 8, 15, 3, 0, 11, 1, 2, 12, 5, 10, 14, 7, 1, 10, 13, 0, 6,
+        9, 8, 7, 4, 15, 14, 3, 11, 5, 2, 12,
+    ],
+    [
+        7, 13, 14, 3, 0, 6, 9, 10, 1, 2, 8, 5, 11, 12, 4, 15, 13, 8, 11, 5, 6, 15, 0, 3, 4, 7, 2,
+        12, 1, 10, 14, 9, 10, 6, 9, 0, 12, 11, 7, 13, 15, 1, 3, 14, 5, 2, 8, 4, 3, 15, 0, 6, 10, 1,
+        13, 8, 9, 4, 5, 11, 12, 7, 2, 14,
+    ],
+    [
+        2, 12, 4, 1, 7, 10, 11, 6, 8, 5, 3, 15, 13, 0, 14, 9, 14, 11, 2, 12, 4, 7, 13, 1, 5, 0, 15,
+        10, 3, 9, 8, 6, 4, 2, 1, 11, 10, 13, 7, 8, 15, 9, 12, 5, 6, 3, 0, 14, 11, 8, 12, 7, 1, 14,
+        2, 13, 6, 15, 0, 9, 10, 4, 5, 3,
+    ],
+    [
+        12, 1, 10, 15, 9, 2, 6, 8, 0, 13, 3, 4, 14, 7, 5, 11, 10, 15, 4, 2, 7, 12, 9, 5, 6, 1, 13,
+        14, 0, 11, 3, 8, 9, 14, 15, 5, 2, 8, 12, 3, 7, 0, 4, 10, 1, 13, 11, 6, 4, 3, 2, 12, 9, 5,
+        15, 10, 11, 14, 1, 7, 6, 0, 8, 13,
+    ],
+    [
+        4, 11, 2, 14, 15, 0, 8, 13, 3, 12, 9, 7, 5, 10, 6, 1, 13, 0, 11, 7, 4, 9, 1, 10, 14, 3, 5,
+        12, 2, 15, 8, 6, 1, 4, 11, 13, 12, 3, 7, 14, 10, 15, 6, 8, 0, 5, 9, 2, 6, 11, 13, 8, 1, 4,
+        10, 7, 9, 5, 0, 15, 14, 2, 3, 12,
+    ],
+    [
+        13, 2, 8, 4, 6, 15, 11, 1, 10, 9, 3, 14, 5, 0, 12, 7, 1, 15, 13, 8, 10, 3, 7, 4, 12, 5, 6,
+        11, 0, 14, 9, 2, 7, 11, 4, 1, 9, 12, 14, 2, 0, 6, 10, 13, 15, 3, 5, 8, 2, 1, 14, 7, 4, 10,
+        8, 13, 15, 12, 9, 0, 3, 5, 6, 11,
+    ],
+];
+
+// bits of `input` in the order of `table`, bit 1 being the most significant one
+fn permute(input: u64, input_bits: u32, table: &[u8]) -> u64 {
+    table.iter().fold(0, |output, bit| {
+        (output << 1) | ((input >> (input_bits - *bit as u32)) & 1)
+    })
+}
+
+/// One block through DES, all VNC authentication needs
+fn des_encrypt(key: [u8; 8], block: [u8; 8]) -> [u8; 8] {
+    let rotate = |half: u64, shift: u32| ((half << shift) | (half >> (28 - shift))) & 0x0FFF_FFFF;
+    let key = permute(u64::from_be_bytes(key), 64, &DES_PC1);
+    let (mut c, mut d) = (key >> 28, key & 0x0FFF_FFFF);
+
+    let block = permute(u64::from_be_bytes(block), 64, &DES_IP);
+    let (mut left, mut right) = (block >> 32, block & 0xFFFF_FFFF);
+    for shift in DES_SHIFTS.iter() {
+        c = rotate(c, *shift);
+        d = rotate(d, *shift);
+        let subkey = permute((c << 28) | d, 56, &DES_PC2);
+        let expanded = permute(right, 32, &DES_E) ^ subkey;
+        let substituted = DES_S.iter().enumerate().fold(0, |output, (n, s_box)| {
+            let six = (expanded >> (42 - 6 * n)) & 0x3F;
+            let row = ((six & 0x20) >> 4) | (six & 1);
+            let column = (six >> 1) & 0xF;
+            (output << 4) | s_box[(row * 16 + column) as usize] as u64
+        });
+        let next_right = left ^ permute(substituted, 32, &DES_P);
+        left = right;
+        right = next_right;
+    }
+    permute((right << 32) | left, 64, &DES_FP).to_be_bytes()
+}
+
+/// Answer of VNC authentication : the challenge through DES, keyed by the
+/// first 8 bytes of the password with their bits mirrored
+fn vnc_auth_response(password: &str, challenge: &[u8; 16]) -> [u8; 16] {
+    let mut key = [0u8; 8];
+    for (key_byte, byte) in key.iter_mut().zip(password.bytes()) {
+        *key_byte = byte.reverse_bits();
+    }
+    let mut response = [0u8; 16];
+    for (half, block) in challenge.chunks(8).enumerate() {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(block);
+        response[half * 8..half * 8 + 8].copy_from_slice(&des_encrypt(key, bytes));
+    }
+    response
+}
+
+// a ZRLE CPIXEL, blue, green then red
+fn cpixel(bytes: &[u8]) -> Bgr8 {
+    new_bgr8(bytes[0], bytes[1], bytes[2])
+}
+
+fn zrle_error(reason: &str) -> CaptureError {
+    CaptureError::Grab {
+        reason: format!("bad ZRLE data: {}", reason),
+    }
+}
+
+/// Reads the bytes of a decompressed ZRLE rectangle in order
+struct ZrleData<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ZrleData<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], CaptureError> {
+        if self.position + length > self.bytes.len() {
+            return Err(zrle_error("too short"));
+        }
+        self.position += length;
+        Ok(&self.bytes[self.position - length..self.position])
+    }
+
+    fn byte(&mut self) -> Result<u8, CaptureError> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn pixel(&mut self) -> Result<Bgr8, CaptureError> {
+        self.take(3).map(cpixel)
+    }
+
+    fn palette(&mut self, size: usize) -> Result<Vec<Bgr8>, CaptureError> {
+        (0..size).map(|_| self.pixel()).collect()
+    }
+
+    // 255 while the run goes on, then the rest
+    fn run_length(&mut self) -> Result<usize, CaptureError> {
+        let mut length = 1;
+        loop {
+            let byte = self.byte()?;
+            length += byte as usize;
+            if byte != 255 {
+                return Ok(length);
+            }
+        }
+    }
+}
+
+// fill `tile` with runs of pixels, `next_run` giving each pixel and its length
+fn fill_runs<F>(tile: &mut [Bgr8], mut next_run: F) -> Result<(), CaptureError>
+where
+    F: FnMut() -> Result<(Bgr8, usize), CaptureError>,
+{
+    let mut filled = 0;
+    while filled < tile.len() {
+        let (pixel, length) = next_run()?;
+        if filled + length > tile.len() {
+            return Err(zrle_error("run past the tile"));
+        }
+        for tile_pixel in tile[filled..filled + length].iter_mut() {
+            *tile_pixel = pixel;
+        }
+        filled += length;
+    }
+    Ok(())
+}
+
+/// The remote framebuffer, updated rectangle after rectangle
+struct RemoteFrame {
+    width: u16,
+    height: u16,
+    frame: Vec<Bgr8>,
+    // one zlib stream for every ZRLE rectangle of the connection
+    inflater: Box<InflateState>,
+}
+
+impl RemoteFrame {
+    fn new(width: u16, height: u16) -> RemoteFrame {
+        RemoteFrame {
+            width,
+            height,
+            frame: vec![new_bgr8(255, 255, 255); width as usize * height as usize],
+            inflater: InflateState::new_boxed(DataFormat::Zlib),
+        }
+    }
+
+    // the zlib stream goes on
+    fn resize(&mut self, width: u16, height: u16) {
+        self.width = width;
+        self.height = height;
+        self.frame = vec![new_bgr8(255, 255, 255); width as usize * height as usize];
+    }
+
+    fn contains(&self, rect: &Rect) -> bool {
+        rect.x as u32 + rect.width as u32 <= self.width as u32
+            && rect.y as u32 + rect.height as u32 <= self.height as u32
+    }
+
+    // `pixels` line after line, `rect.width` of them each
+    fn put(&mut self, rect: &Rect, pixels: &[Bgr8]) {
+        for (line_n, line) in pixels.chunks(rect.width as usize).enumerate() {
+            let start = (rect.y as usize + line_n) * self.width as usize + rect.x as usize;
+            self.frame[start..start + line.len()].copy_from_slice(line);
+        }
+    }
+
+    fn apply_raw(&mut self, rect: &Rect, bytes: &[u8]) {
+        let pixels: Vec<Bgr8> = bytes.chunks_exact(4).map(cpixel).collect();
+        self.put(rect, &pixels);
+    }
+
+    fn apply_copy(&mut self, rect: &Rect, source_x: u16, source_y: u16) {
+        let width = rect.width as usize;
+        let mut pixels: Vec<Bgr8> = Vec::with_capacity(width * rect.height as usize);
+        for line_n in 0..rect.height as usize {
+            let start = (source_y as usize + line_n) * self.width as usize + source_x as usize;
+            pixels.extend_from_slice(&self.frame[start..start + width]);
+        }
+        self.put(rect, &pixels);
+    }
+
+    fn inflate(&mut self, compressed: &[u8]) -> Result<Vec<u8>, CaptureError> {
+        let mut data: Vec<u8> = Vec::new();
+        let mut chunk = vec![0u8; 1 << 16];
+        let mut input = compressed;
+        loop {
+            let result = inflate(&mut self.inflater, input, &mut chunk, MZFlush::None);
+            input = &input[result.bytes_consumed..];
+            data.extend_from_slice(&chunk[..result.bytes_written]);
+            match result.status {
+                Ok(_) | Err(MZError::Buf) => (),
+                Err(error) => return Err(zrle_error(&format!("{:?}", error))),
+            }
+            let stuck = result.bytes_consumed == 0 && result.bytes_written == 0;
+            if stuck || (input.is_empty() && result.bytes_written < chunk.len()) {
+                return Ok(data);
+            }
+        }
+    }
+
+    fn apply_zrle(&mut self, rect: &Rect, compressed: &[u8]) -> Result<(), CaptureError> {
+        let bytes = self.inflate(compressed)?;
+        let mut data = ZrleData {
+            bytes: &bytes,
+            position: 0,
+        };
+        for tile_y in (rect.y..rect.y + rect.height).step_by(ZRLE_TILE_SIZE as usize) {
+            for tile_x in (rect.x..rect.x + rect.width).step_by(ZRLE_TILE_SIZE as usize) {
+                let tile_rect = Rect {
+                    x: tile_x,
+                    y: tile_y,
+                    width: ZRLE_TILE_SIZE.min(rect.x + rect.width - tile_x),
+                    height: ZRLE_TILE_SIZE.min(rect.y + rect.height - tile_y),
+                };
+                let tile = decode_tile(&mut data, tile_rect.width, tile_rect.height)?;
+                self.put(&tile_rect, &tile);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode_tile(data: &mut ZrleData, width: u16, height: u16) -> Result<Vec<Bgr8>, CaptureError> {
+    let mut tile = vec![new_bgr8(0, 0, 0); width as usize * height as usize];
+    let subencoding = data.byte()?;
+    match subencoding {
+        // raw
+        0 => {
+            for pixel in tile.iter_mut() {
+                *pixel = data.pixel()?;
+            }
+        }
+        // solid
+        1 => {
+            let colour = data.pixel()?;
+            for pixel in tile.iter_mut() {
+                *pixel = colour;
+            }
+        }
+        // packed palette, each line starts on a new byte
+        2..=16 => {
+            let palette = data.palette(subencoding as usize)?;
+            let bits = match palette.len() {
+                2 => 1,
+                3..=4 => 2,
+                _ => 4,
+            };
+            let line_length = (width as usize * bits).div_ceil(8);
+            for line in tile.chunks_mut(width as usize) {
+                let packed = data.take(line_length)?;
+                for (n, pixel) in line.iter_mut().enumerate() {
+                    let bit = n * bits;
+                    let index =
+                        (packed[bit / 8] >> (8 - bits - bit % 8)) as usize & ((1 << bits) - 1);
+                    *pixel = *palette
+                        .get(index)
+                        .ok_or_else(|| zrle_error("index past the palette"))?;
+                }
+            }
+        }
+        // plain RLE
+        128 => fill_runs(&mut tile, || Ok((data.pixel()?, data.run_length()?)))?,
+        // palette RLE
+        130..=255 => {
+            let palette = data.palette(subencoding as usize - 128)?;
+            fill_runs(&mut tile, || {
+                let index = data.byte()?;
+                let length = if index & 128 != 0 {
+                    data.run_length()?
+                } else {
+                    1
+                };
+                let pixel = palette
+                    .get((index & 127) as usize)
+                    .ok_or_else(|| zrle_error("index past the palette"))?;
+                Ok((*pixel, length))
+            })?
+        }
+        _ => return Err(zrle_error(&format!("subencoding {}", subencoding))),
+    }
+    Ok(tile)
+}
+
+// wait until `stream` can be read, false once `deadline` is over
+fn wait_readable(stream: &TcpStream, deadline: Instant) -> bool {
+    let left = deadline.saturating_duration_since(Instant::now());
+    let mut connection = pollfd {
+        fd: stream.as_raw_fd(),
+        events: POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut connection, 1, left.as_millis() as c_int) > 0 }
+}
+
+/// Mirrors the desktop of a VNC server, e.g. Xvnc on another machine. The
+/// rectangles the server updates are the damage.
+pub struct VncCapture {
+    stream: BufReader<TcpStream>,
+    address: String,
+    frame: RemoteFrame,
+    update_requested: bool,
+    // the next update is a whole frame
+    first_frame: bool,
+    // encoding of each rectangle received
+    #[cfg(test)]
+    received: Vec<i32>,
+}
+
+impl VncCapture {
+    /// Connect to `host` and `port` as a shared client, with VNC authentication
+    /// when the server asks for it
+    pub fn open(host: &str, port: u16, password: Option<&str>) -> Result<VncCapture, CaptureError> {
+        VncCapture::connect(host, port, password, &ENCODINGS)
+    }
+
+    fn connect(
+        host: &str,
+        port: u16,
+        password: Option<&str>,
+        encodings: &[i32],
+    ) -> Result<VncCapture, CaptureError> {
+        let address = format!("{}:{}", host, port);
+        let stream = TcpStream::connect((host, port)).map_err(|error| CaptureError::Open {
+            reason: format!("{}: {}", address, error),
+        })?;
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .and_then(|_| stream.set_nodelay(true))
+            .map_err(|error| CaptureError::Open {
+                reason: format!("{}: {}", address, error),
+            })?;
+        let mut capture = VncCapture {
+            stream: BufReader::new(stream),
+            address,
+            frame: RemoteFrame::new(0, 0),
+            update_requested: false,
+            first_frame: true,
+            #[cfg(test)]
+            received: Vec::new(),
+        };
+        capture
+            .handshake(password, encodings)
+            .map_err(|error| match error {
+                CaptureError::Grab { reason } => CaptureError::Open { reason },
+                error => error,
+            })?;
+        Ok(capture)
+    }
+
+    fn io_error(&self, error: io::Error) -> CaptureError {
+        let reason = if error.kind() == io::ErrorKind::UnexpectedEof {
+            "the server closed the connection".to_string()
+        } else {
+            error.to_string()
+        };
+        CaptureError::Grab {
+            reason: format!("{}: {}", self.address, reason),
+        }
+    }
+
+    fn refused(&self, reason: &str) -> CaptureError {
+        CaptureError::Open {
+            reason: format!("{}: {}", self.address, reason),
+        }
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<Vec<u8>, CaptureError> {
+        let mut bytes = vec![0; length];
+        match self.stream.read_exact(&mut bytes) {
+            Ok(()) => Ok(bytes),
+            Err(error) => Err(self.io_error(error)),
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, CaptureError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, CaptureError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, CaptureError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // a length then that many bytes, e.g. the reason of a failure
+    fn read_string(&mut self) -> Result<String, CaptureError> {
+        let length = self.read_u32()? as usize;
+        let bytes = self.read_bytes(length)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), CaptureError> {
+        match self.stream.get_mut().write_all(bytes) {
+            Ok(()) => Ok(()),
+            Err(error) => Err(self.io_error(error)),
+        }
+    }
+
+    fn handshake(&mut self, password: Option<&str>, encodings: &[i32]) -> Result<(), CaptureError> {
+        let version = self.read_bytes(12)?;
+        let minor: u32 = match std::str::from_utf8(&version) {
+            Ok(version) if version.starts_with("RFB 003.") => version[8..11].parse().unwrap_or(0),
+            _ => return Err(self.refused("not a VNC server")),
+        };
+        // 3.3, 3.7 or 3.8, whichever the server speaks
+        let minor = match minor {
+            0..=6 => 3,
+            7 => 7,
+            _ => 8,
+        };
+        self.write(format!("RFB 003.00{}\n", minor).as_bytes())?;
+
+        let security = if minor == 3 {
+            self.read_u32()?
+        } else {
+            let count = self.read_u8()?;
+            let offered: Vec<u32> = self
+                .read_bytes(count as usize)?
+                .iter()
+                .map(|security| *security as u32)
+                .collect();
+            let chosen = if offered.contains(&SECURITY_NONE) {
+                SECURITY_NONE
+            } else if offered.contains(&SECURITY_VNC) {
+                SECURITY_VNC
+            } else {
+                SECURITY_INVALID
+            };
+            if count == 0 {
+                chosen
+            } else if chosen == SECURITY_INVALID {
+                return Err(self.refused(&format!(
+                    "no security type ardoise knows among {:?}",
+                    offered
+                )));
+            } else {
+                self.write(&[chosen as u8])?;
+                chosen
+            }
+        };
+        if security == SECURITY_INVALID {
+            let reason = self.read_string()?;
+            return Err(self.refused(&reason));
+        }
+
+        if security == SECURITY_VNC {
+            let password = password.ok_or_else(|| {
+                self.refused("the server wants a password, give it with --vnc-password-file")
+            })?;
+            let mut challenge = [0u8; 16];
+            challenge.copy_from_slice(&self.read_bytes(16)?);
+            self.write(&vnc_auth_response(password, &challenge))?;
+        } else if security != SECURITY_NONE {
+            return Err(self.refused(&format!("unknown security type {}", security)));
+        }
+        // only 3.8 tells the result of no authentication
+        if (security == SECURITY_VNC || minor == 8) && self.read_u32()? != 0 {
+            let reason = if minor == 8 {
+                self.read_string()?
+            } else {
+                "authentication failed".to_string()
+            };
+            return Err(self.refused(&reason));
+        }
+
+        // shared : other viewers stay connected
+        self.write(&[1])?;
+        let width = self.read_u16()?;
+        let height = self.read_u16()?;
+        // the pixel format of the server, ours is asked for next
+        self.read_bytes(16)?;
+        let name = self.read_string()?;
+        println!("VNC desktop {}, {}x{}", name, width, height);
+        self.frame.resize(width, height);
+
+        let mut set_pixel_format = vec![SET_PIXEL_FORMAT, 0, 0, 0];
+        set_pixel_format.extend_from_slice(&PIXEL_FORMAT);
+        self.write(&set_pixel_format)?;
+        let mut set_encodings = vec![SET_ENCODINGS, 0];
+        set_encodings.extend_from_slice(&(encodings.len() as u16).to_be_bytes());
+        for encoding in encodings.iter() {
+            set_encodings.extend_from_slice(&encoding.to_be_bytes());
+        }
+        self.write(&set_encodings)
+    }
+
+    fn request_update(&mut self) -> Result<(), CaptureError> {
+        let mut request = vec![FRAMEBUFFER_UPDATE_REQUEST, !self.first_frame as u8];
+        for value in [0, 0, self.frame.width, self.frame.height].iter() {
+            request.extend_from_slice(&value.to_be_bytes());
+        }
+        self.write(&request)?;
+        self.update_requested = true;
+        Ok(())
+    }
+
+    // apply a FramebufferUpdate, None when it is a whole new frame
+    fn read_update(&mut self) -> Result<Option<Vec<Rect>>, CaptureError> {
+        self.read_u8()?;
+        let count = self.read_u16()?;
+        let mut resized = false;
+        let mut rects: Vec<Rect> = Vec::new();
+        for _ in 0..count {
+            let rect = Rect {
+                x: self.read_u16()?,
+                y: self.read_u16()?,
+                width: self.read_u16()?,
+                height: self.read_u16()?,
+            };
+            let encoding = self.read_u32()? as i32;
+            #[cfg(test)]
+            self.received.push(encoding);
+            if encoding == ENCODING_DESKTOP_SIZE {
+                self.frame.resize(rect.width, rect.height);
+                resized = true;
+                continue;
+            }
+            if !self.frame.contains(&rect) {
+                return Err(CaptureError::Grab {
+                    reason: format!("{}: {:?} is out of the desktop", self.address, rect),
+                });
+            }
+
+            if encoding == ENCODING_RAW {
+                let bytes = self.read_bytes(rect.width as usize * rect.height as usize * 4)?;
+                self.frame.apply_raw(&rect, &bytes);
+            } else if encoding == ENCODING_COPY_RECT {
+                let source = Rect {
+                    x: self.read_u16()?,
+                    y: self.read_u16()?,
+                    ..rect
+                };
+                if !self.frame.contains(&source) {
+                    return Err(CaptureError::Grab {
+                        reason: format!("{}: copy from out of the desktop", self.address),
+                    });
+                }
+                self.frame.apply_copy(&rect, source.x, source.y);
+            } else if encoding == ENCODING_ZRLE {
+                let length = self.read_u32()? as usize;
+                let compressed = self.read_bytes(length)?;
+                self.frame.apply_zrle(&rect, &compressed)?;
+            } else {
+                return Err(CaptureError::Grab {
+                    reason: format!("{}: unknown encoding {}", self.address, encoding),
+                });
+            }
+            if rect.width > 0 && rect.height > 0 {
+                rects.push(rect);
+            }
+        }
+        let whole_frame = self.first_frame || resized;
+        // after a resize, the next update brings the whole frame
+        self.first_frame = resized;
+        if whole_frame {
+            Ok(None)
+        } else {
+            Ok(Some(limit_damage(rects)))
+        }
+    }
+}
+
+impl CaptureSource for VncCapture {
+    fn geometry(&self) -> (u16, u16) {
+        (self.frame.width, self.frame.height)
+    }
+
+    fn capture(&mut self) -> Result<Option<Vec<Rect>>, CaptureError> {
+        if !self.update_requested {
+            self.request_update()?;
+        }
+        // the server answers an incremental request once something changed
+        let deadline = Instant::now() + Duration::from_millis(DAMAGE_WAIT as u64);
+        loop {
+            if self.stream.buffer().is_empty() && !wait_readable(self.stream.get_ref(), deadline) {
+                return Ok(Some(Vec::new()));
+            }
+            let message_type = self.read_u8()?;
+            if message_type == FRAMEBUFFER_UPDATE {
+                self.update_requested = false;
+                return self.read_update();
+            } else if message_type == SET_COLOUR_MAP_ENTRIES {
+                // unused in true colour
+                self.read_bytes(3)?;
+                let count = self.read_u16()?;
+                self.read_bytes(count as usize * 6)?;
+            } else if message_type == SERVER_CUT_TEXT {
+                self.read_bytes(3)?;
+                self.read_string()?;
+            } else if message_type != BELL {
+                return Err(CaptureError::Grab {
+                    reason: format!("{}: unknown message {}", self.address, message_type),
+                });
+            }
+        }
+    }
+
+    fn frame(&self) -> &[Bgr8] {
+        self.frame.frame.as_slice()
+    }
+}
+
+#[test]
+fn test_parse_address() {
+    let address = |host: &str, port: u16| Ok((host.to_string(), port));
+    assert_eq!(parse_address("pi.local"), address("pi.local", 5900));
+    assert_eq!(parse_address("pi.local:1"), address("pi.local", 5901));
+    assert_eq!(parse_address("pi.local:5999"), address("pi.local", 5999));
+    assert_eq!(parse_address("localhost::5901"), address("localhost", 5901));
+    assert_eq!(parse_address(":2"), address("localhost", 5902));
+    assert!(parse_address("pi.local:one").is_err());
+    assert!(parse_address("pi.local::").is_err());
+}
+
+#[test]
+fn test_des() {
+    // the usual worked example
+    let key = 0x1334_5779_9BBC_DFF1u64.to_be_bytes();
+    let block = 0x0123_4567_89AB_CDEFu64.to_be_bytes();
+    assert_eq!(
+        u64::from_be_bytes(des_encrypt(key, block)),
+        0x85E8_1354_0F0A_B405
+    );
+
+    // "secret" with each byte mirrored, padded with zeros
+    let challenge: [u8; 16] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
+        0xFF,
+    ];
+    let response = vnc_auth_response("secret", &challenge);
+    let key = [0xCE, 0xA6, 0xC6, 0x4E, 0xA6, 0x2E, 0, 0];
+    assert_eq!(
+        response[..8],
+        des_encrypt(key, [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77])
+    );
+    assert_eq!(
+        response[8..],
+        des_encrypt(key, [0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF])
+    );
+    // only 8 characters count
+    assert_eq!(
+        vnc_auth_response("password", &challenge),
+        vnc_auth_response("password1", &challenge)
+    );
+}
+
+#[test]
+fn test_zrle_tiles() {
+    use miniz_oxide::deflate::core::CompressorOxide;
+    use miniz_oxide::deflate::stream::deflate;
+
+    // the rectangles follow each other in one zlib stream
+    let mut compressor = CompressorOxide::default();
+    let mut compress = |data: &[u8]| {
+        let mut compressed = vec![0u8; 4096];
+        let result = deflate(&mut compressor, data, &mut compressed, MZFlush::Sync);
+        compressed.truncate(result.bytes_written);
+        compressed
+    };
+    let (black, white) = ([0, 0, 0], [255, 255, 255]);
+    let (red, blue, green) = ([0, 0, 255], [255, 0, 0], [0, 255, 0]);
+
+    let mut frame = RemoteFrame::new(70, 3);
+    // 64x2 then 6x2 tiles
+    let mut data: Vec<u8> = vec![2];
+    data.extend_from_slice(&black);
+    data.extend_from_slice(&white);
+    data.extend_from_slice(&[0xAA; 8]);
+    data.extend_from_slice(&[0x00; 8]);
+    data.push(130);
+    data.extend_from_slice(&red);
+    data.extend_from_slice(&blue);
+    data.extend_from_slice(&[128, 4, 1, 129, 5]);
+    let rect = Rect {
+        x: 0,
+        y: 0,
+        width: 70,
+        height: 2,
+    };
+    frame.apply_zrle(&rect, &compress(&data)).unwrap();
+
+    // plain RLE, raw, then solid over two tiles
+    let mut data: Vec<u8> = vec![128];
+    data.extend_from_slice(&green);
+    data.push(1);
+    data.extend_from_slice(&[1, 2, 3, 0]);
+    let rect = Rect {
+        x: 0,
+        y: 2,
+        width: 3,
+        height: 1,
+    };
+    frame.apply_zrle(&rect, &compress(&data)).unwrap();
+    let mut data: Vec<u8> = vec![0];
+    data.extend_from_slice(&red);
+    data.extend_from_slice(&blue);
+    let rect = Rect {
+        x: 3,
+        y: 2,
+        width: 2,
+        height: 1,
+    };
+    frame.apply_zrle(&rect, &compress(&data)).unwrap();
+    let mut data: Vec<u8> = vec![1];
+    data.extend_from_slice(&green);
+    data.push(1);
+    data.extend_from_slice(&black);
+    let rect = Rect {
+        x: 5,
+        y: 2,
+        width: 65,
+        height: 1,
+    };
+    frame.apply_zrle(&rect, &compress(&data)).unwrap();
+
+    let pixel = |x: usize, y: usize| frame.frame[y * 70 + x];
+    let colour = |bytes: [u8; 3]| cpixel(&bytes);
+    assert_eq!((pixel(0, 0), pixel(1, 0)), (colour(white), colour(black)));
+    assert_eq!((pixel(62, 0), pixel(63, 0)), (colour(white), colour(black)));
+    assert_eq!(pixel(30, 1), colour(black));
+    assert_eq!((pixel(68, 0), pixel(69, 0)), (colour(red), colour(blue)));
+    assert_eq!((pixel(64, 1), pixel(69, 1)), (colour(blue), colour(blue)));
+    assert_eq!(
+        (pixel(1, 2), pixel(2, 2)),
+        (colour(green), colour([1, 2, 3]))
+    );
+    assert_eq!((pixel(3, 2), pixel(4, 2)), (colour(red), colour(blue)));
+    assert_eq!(
+        (pixel(5, 2), pixel(68, 2), pixel(69, 2)),
+        (colour(green), colour(green), colour(black))
+    );
+
+    // a run past its tile
+    let mut data: Vec<u8> = vec![128];
+    data.extend_from_slice(&green);
+    data.push(200);
+    let rect = Rect {
+        x: 0,
+        y: 0,
+        width: 2,
+        height: 2,
+    };
+    assert!(frame.apply_zrle(&rect, &compress(&data)).is_err());
+}
+
+#[test]
+fn test_capture_from_a_server() {
+    use miniz_oxide::deflate::core::CompressorOxide;
+    use miniz_oxide::deflate::stream::deflate;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // a 4x3 desktop behind a password : white, then 3 pixels go black
+    let server = thread::spawn(move || {
+        let (socket, _) = listener.accept().unwrap();
+        let read = |length: usize| {
+            let mut bytes = vec![0u8; length];
+            (&socket).read_exact(&mut bytes).unwrap();
+            bytes
+        };
+        let send = |bytes: &[u8]| (&socket).write_all(bytes).unwrap();
+
+        send(b"RFB 003.008\n");
+        send(&[1, SECURITY_VNC as u8]);
+        send(&[7; 16]);
+        send(&[0, 0, 0, 0]);
+        assert_eq!(read(12), b"RFB 003.008\n");
+        assert_eq!(read(1), vec![SECURITY_VNC as u8]);
+        assert_eq!(read(16), vnc_auth_response("secret", &[7; 16]).to_vec());
+
+        assert_eq!(read(1), vec![1]);
+        let mut server_init: Vec<u8> = vec![0, 4, 0, 3];
+        server_init.extend_from_slice(&[0; 16]);
+        server_init.extend_from_slice(&[0, 0, 0, 4]);
+        server_init.extend_from_slice(b"test");
+        send(&server_init);
+        assert_eq!(read(4), vec![SET_PIXEL_FORMAT, 0, 0, 0]);
+        assert_eq!(read(16), PIXEL_FORMAT.to_vec());
+        let header = read(4);
+        assert_eq!(header[0], SET_ENCODINGS);
+        read(header[3] as usize * 4);
+
+        // the whole desktop, raw
+        assert_eq!(
+            read(10),
+            vec![FRAMEBUFFER_UPDATE_REQUEST, 0, 0, 0, 0, 0, 0, 4, 0, 3]
+        );
+        let mut update: Vec<u8> = vec![FRAMEBUFFER_UPDATE, 0, 0, 1, 0, 0, 0, 0, 0, 4, 0, 3];
+        update.extend_from_slice(&ENCODING_RAW.to_be_bytes());
+        update.extend_from_slice(&[0xFF; 4 * 3 * 4]);
+        send(&update);
+
+        // some text and a bell first, then a black ZRLE tile and a copy of it
+        assert_eq!(read(10)[..2], [FRAMEBUFFER_UPDATE_REQUEST, 1]);
+        let mut update: Vec<u8> = vec![SERVER_CUT_TEXT, 0, 0, 0, 0, 0, 0, 2, b'h', b'i', BELL];
+        update.extend_from_slice(&[FRAMEBUFFER_UPDATE, 0, 0, 2, 0, 1, 0, 1, 0, 2, 0, 1]);
+        update.extend_from_slice(&ENCODING_ZRLE.to_be_bytes());
+        let mut compressed = vec![0u8; 256];
+        let result = deflate(
+            &mut CompressorOxide::default(),
+            &[1, 0, 0, 0],
+            &mut compressed,
+            MZFlush::Sync,
+        );
+        update.extend_from_slice(&(result.bytes_written as u32).to_be_bytes());
+        update.extend_from_slice(&compressed[..result.bytes_written]);
+        update.extend_from_slice(&[0, 0, 0, 2, 0, 1, 0, 1]);
+        update.extend_from_slice(&ENCODING_COPY_RECT.to_be_bytes());
+        update.extend_from_slice(&[0, 1, 0, 1]);
+        send(&update);
+    });
+
+    let mut capture = VncCapture::open("127.0.0.1", port, Some("secret")).unwrap();
+    assert_eq!(capture.geometry(), (4, 3));
+    assert_eq!(capture.capture().unwrap(), None);
+    assert!(capture
+        .frame()
+        .iter()
+        .all(|pixel| *pixel == new_bgr8(255, 255, 255)));
+
+    let rect = |x: u16, y: u16, width: u16| Rect {
+        x,
+        y,
+        width,
+        height: 1,
+    };
+    assert_eq!(
+        capture.capture().unwrap(),
+        Some(vec![rect(1, 1, 2), rect(0, 2, 1)])
+    );
+    let black: Vec<usize> = (0..12)
+        .filter(|n| capture.frame()[*n] == new_bgr8(0, 0, 0))
+        .collect();
+    assert_eq!(black, vec![5, 6, 8]);
+    server.join().unwrap();
+}
+
+/// Needs an Xvnc without a password, e.g. `Xvnc :1 -SecurityTypes None`, then
+/// `ARDOISE_TEST_VNC=:1 cargo test -- --ignored test_capture_from_xvnc`
+#[ignore]
+#[test]
+fn test_capture_from_xvnc() {
+    use libc::c_ulong;
+    use std::ffi::CString;
+    use std::ptr;
+    use x11::xlib;
+
+    let display_name = std::env::var("ARDOISE_TEST_VNC").expect("ARDOISE_TEST_VNC, e.g. :1");
+    let (host, port) = parse_address(&display_name).unwrap();
+    // the desktop of the Xvnc is drawn through its X display
+    let name = CString::new(display_name).unwrap();
+    let display = unsafe { xlib::XOpenDisplay(name.as_ptr()) };
+    assert!(!display.is_null(), "cannot open the X display of the Xvnc");
+    let root = unsafe { xlib::XDefaultRootWindow(display) };
+    let gc = unsafe { xlib::XCreateGC(display, root, 0, ptr::null_mut()) };
+    let fill = |x: i32, y: i32, width: u32, height: u32, rgb: c_ulong| unsafe {
+        xlib::XSetForeground(display, gc, rgb);
+        xlib::XFillRectangle(display, root, gc, x, y, width, height);
+    };
+    // what a client should see, read back from the X server
+    let x_frame = |width: u16, height: u16| -> Vec<Bgr8> {
+        unsafe {
+            xlib::XSync(display, xlib::False);
+            let image = xlib::XGetImage(
+                display,
+                root,
+                0,
+                0,
+                width as u32,
+                height as u32,
+                xlib::XAllPlanes(),
+                xlib::ZPixmap,
+            );
+            assert!(!image.is_null());
+            let mut frame: Vec<Bgr8> = Vec::with_capacity(width as usize * height as usize);
+            for y in 0..height as i32 {
+                for x in 0..width as i32 {
+                    let pixel = xlib::XGetPixel(image, x, y);
+                    frame.push(new_bgr8(
+                        pixel as u8,
+                        (pixel >> 8) as u8,
+                        (pixel >> 16) as u8,
+                    ));
+                }
+            }
+            xlib::XDestroyImage(image);
+            frame
+        }
+    };
+    let differences = |capture: &VncCapture| {
+        let (width, height) = capture.geometry();
+        let expected = x_frame(width, height);
+        capture
+            .frame()
+            .iter()
+            .zip(expected.iter())
+            .filter(|(pixel, expected)| pixel != expected)
+            .count()
+    };
+
+    // flat areas for the runs and palettes of ZRLE, noise for its raw tiles
+    fill(0, 0, 640, 480, 0xFF_FFFF);
+    fill(10, 10, 200, 100, 0x00_0000);
+    fill(50, 150, 100, 130, 0x33_66CC);
+    fill(130, 200, 3, 150, 0xCC_3300);
+    for y in 0..96 {
+        for x in 0..96 {
+            let rgb = (x * 7919 + y * 104_729) as c_ulong & 0xFF_FFFF;
+            fill(300 + x, 40 + y, 1, 1, rgb);
+        }
+    }
+
+    for encoding in [ENCODING_RAW, ENCODING_ZRLE].iter() {
+        let mut capture =
+            VncCapture::connect(&host, port, None, &[*encoding, ENCODING_DESKTOP_SIZE]).unwrap();
+        assert_eq!(capture.capture().unwrap(), None);
+        assert!(capture.received.contains(encoding));
+        assert_eq!(differences(&capture), 0, "encoding {}", encoding);
+    }
+
+    // the server sends the copy of an area of the screen as such
+    let mut capture = VncCapture::connect(
+        &host,
+        port,
+        None,
+        &[ENCODING_COPY_RECT, ENCODING_RAW, ENCODING_DESKTOP_SIZE],
+    )
+    .unwrap();
+    assert_eq!(capture.capture().unwrap(), None);
+    unsafe {
+        xlib::XCopyArea(display, root, root, gc, 0, 0, 400, 140, 200, 300);
+        xlib::XSync(display, xlib::False);
+    }
+    for _ in 0..20 {
+        capture.capture().unwrap();
+        if capture.received.contains(&ENCODING_COPY_RECT) && differences(&capture) == 0 {
+            break;
+        }
+    }
+    assert!(capture.received.contains(&ENCODING_COPY_RECT));
+    assert_eq!(differences(&capture), 0);
+
+    unsafe {
+        xlib::XFreeGC(display, gc);
+        xlib::XCloseDisplay(display);
+    }
+}
@@ -0,0 +1,972 @@
+use std::mem;
+
+// answers of xterm to the device attributes queries
+static PRIMARY_ATTRIBUTES: &[u8] = b"\x1b[?1;2c";
+static SECONDARY_ATTRIBUTES: &[u8] = b"\x1b[>0;95;0c";
+// numbers kept from a CSI sequence, the others are dropped
+static MAX_PARAMS: usize = 16;
+static TAB_WIDTH: usize = 8;
+
+// the 16 colours of xterm, for telling dark backgrounds from light ones
+static ANSI_COLOURS: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+static CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// DEC special graphics, from _ to ~
+static DEC_GRAPHICS: [char; 32] = [
+    ' ', '◆', '▒', '␉', '␌', '␍', '␊', '°', '±', '␤', '␋', '┘', '┐', '┌', '└', '┼', '⎺', '⎻', '─',
+    '⎼', '⎽', '├', '┤', '┴', '┬', '│', '≤', '≥', 'π', '≠', '£', '·',
+];
+
+/// A character of the screen. The panel shows black and white only: colours
+/// are dropped and dark backgrounds drawn inverse.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cell {
+    pub c: char,
+    pub bold: bool,
+    pub underline: bool,
+    // white on black
+    pub inverse: bool,
+}
+
+impl Cell {
+    fn blank(inverse: bool) -> Cell {
+        Cell {
+            c: ' ',
+            bold: false,
+            underline: false,
+            inverse,
+        }
+    }
+}
+
+/// Rendition set by SGR, for the characters written after it
+#[derive(Debug, Clone, Copy, Default)]
+struct Pen {
+    bold: bool,
+    underline: bool,
+    reverse: bool,
+    dark_background: bool,
+}
+
+impl Pen {
+    fn cell(&self, c: char) -> Cell {
+        Cell {
+            c,
+            bold: self.bold,
+            underline: self.underline,
+            inverse: self.reverse != self.dark_background,
+        }
+    }
+
+    // erased cells take the background colour, not the other attributes
+    fn blank(&self) -> Cell {
+        Cell::blank(self.dark_background)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Charset {
+    Ascii,
+    DecGraphics,
+}
+
+/// What DECSC saves and DECRC restores
+#[derive(Debug, Clone, Copy)]
+struct SavedCursor {
+    x: usize,
+    y: usize,
+    pen: Pen,
+    origin_mode: bool,
+    charsets: [Charset; 2],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    // ESC then an intermediate byte, e.g. ESC ( for the G0 character set
+    EscapeIntermediate(u8),
+    Csi,
+    // OSC, DCS, APC, PM or SOS text, up to BEL or ST
+    Text,
+    // ESC in the text, ST when \ follows
+    TextEscape,
+}
+
+/// The screen of a VT100/xterm terminal: the characters the program writes,
+/// its escape sequences applied, and which cells changed since they were last drawn
+pub struct Screen {
+    cols: usize,
+    rows: usize,
+    cells: Vec<Cell>,
+    // the main screen while the alternate one is shown
+    main_cells: Option<Vec<Cell>>,
+    dirty: Vec<bool>,
+    x: usize,
+    y: usize,
+    // the last column was written, the next character goes to the next line
+    wrap_pending: bool,
+    pen: Pen,
+    saved: SavedCursor,
+    // scrolling region, bottom included
+    top: usize,
+    bottom: usize,
+    tabs: Vec<bool>,
+    charsets: [Charset; 2],
+    // G1 is used, after SO
+    shifted: bool,
+    autowrap: bool,
+    origin_mode: bool,
+    insert_mode: bool,
+    cursor_visible: bool,
+    application_cursor: bool,
+    // for REP
+    last_char: char,
+    state: State,
+    params: Vec<u16>,
+    // < = > or ? right after CSI, 0 when none
+    private: u8,
+    intermediate: u8,
+    utf8: Vec<u8>,
+    utf8_length: usize,
+    // answers to the queries, to be written back to the program
+    replies: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(cols: usize, rows: usize) -> Screen {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        Screen {
+            cols,
+            rows,
+            cells: vec![Cell::blank(false); cols * rows],
+            main_cells: None,
+            // the first frame draws everything
+            dirty: vec![true; cols * rows],
+            x: 0,
+            y: 0,
+            wrap_pending: false,
+            pen: Pen::default(),
+            saved: SavedCursor {
+                x: 0,
+                y: 0,
+                pen: Pen::default(),
+                origin_mode: false,
+                charsets: [Charset::Ascii; 2],
+            },
+            top: 0,
+            bottom: rows - 1,
+            tabs: (0..cols).map(|x| x % TAB_WIDTH == 0).collect(),
+            charsets: [Charset::Ascii; 2],
+            shifted: false,
+            autowrap: true,
+            origin_mode: false,
+            insert_mode: false,
+            cursor_visible: true,
+            application_cursor: false,
+            last_char: ' ',
+            state: State::Ground,
+            params: Vec::new(),
+            private: 0,
+            intermediate: 0,
+            utf8: Vec::new(),
+            utf8_length: 0,
+            replies: Vec::new(),
+        }
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> Cell {
+        self.cells[y * self.cols + x]
+    }
+
+    /// Column and row of the cursor, None when the program hid it
+    pub fn cursor(&self) -> Option<(usize, usize)> {
+        if self.cursor_visible {
+            Some((self.x, self.y))
+        } else {
+            None
+        }
+    }
+
+    /// The arrow keys send ESC O instead of ESC [, set by DECCKM
+    pub fn application_cursor(&self) -> bool {
+        self.application_cursor
+    }
+
+    /// Answers to the queries of the program, e.g. the cursor position
+    pub fn take_replies(&mut self) -> Vec<u8> {
+        mem::take(&mut self.replies)
+    }
+
+    /// Draw this cell again, e.g. the cursor left it
+    pub fn mark_dirty(&mut self, x: usize, y: usize) {
+        if x < self.cols && y < self.rows {
+            self.dirty[y * self.cols + x] = true;
+        }
+    }
+
+    /// Changed cells since the last call, as (row, first column, end column)
+    /// for each row
+    pub fn take_dirty(&mut self) -> Vec<(usize, usize, usize)> {
+        let mut spans = Vec::new();
+        for (y, line) in self.dirty.chunks_mut(self.cols).enumerate() {
+            let first = line.iter().position(|&dirty| dirty);
+            let last = line.iter().rposition(|&dirty| dirty);
+            if let (Some(first), Some(last)) = (first, last) {
+                spans.push((y, first, last + 1));
+                for dirty in line.iter_mut() {
+                    *dirty = false;
+                }
+            }
+        }
+        spans
+    }
+
+    /// Apply what the program wrote
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.input(byte);
+        }
+    }
+
+    fn input(&mut self, byte: u8) {
+        match self.state {
+            State::Text => match byte {
+                0x07 | 0x18 | 0x1a => self.state = State::Ground,
+                0x1b => self.state = State::TextEscape,
+                _ => (),
+            },
+            State::TextEscape => {
+                if byte == b'\\' {
+                    self.state = State::Ground;
+                } else {
+                    self.state = State::Escape;
+                    self.input(byte);
+                }
+            }
+            _ if byte == 0x1b => {
+                self.utf8.clear();
+                self.state = State::Escape;
+            }
+            _ if byte == 0x18 || byte == 0x1a => self.state = State::Ground,
+            _ if byte < 0x20 => self.control(byte),
+            State::Ground => self.ground(byte),
+            State::Escape => self.escape(byte),
+            State::EscapeIntermediate(intermediate) => {
+                self.state = State::Ground;
+                self.designate(intermediate, byte);
+            }
+            State::Csi => self.csi(byte),
+        }
+    }
+
+    fn ground(&mut self, byte: u8) {
+        if byte < 0x80 {
+            if self.utf8_length != 0 {
+                self.utf8_length = 0;
+                self.print('\u{FFFD}');
+            }
+            if byte != 0x7f {
+                self.print(byte as char);
+            }
+            return;
+        }
+        if byte & 0xC0 == 0x80 && self.utf8_length != 0 {
+            self.utf8.push(byte);
+        } else {
+            if self.utf8_length != 0 {
+                self.print('\u{FFFD}');
+            }
+            self.utf8.clear();
+            self.utf8.push(byte);
+            self.utf8_length = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+        }
+        if self.utf8.len() == self.utf8_length {
+            self.utf8_length = 0;
+            let c = std::str::from_utf8(&self.utf8)
+                .ok()
+                .and_then(|text| text.chars().next())
+                .unwrap_or('\u{FFFD}');
+            self.print(c);
+        }
+    }
+
+    fn control(&mut self, byte: u8) {
+        match byte {
+            0x08 => {
+                self.wrap_pending = false;
+                self.x = self.x.saturating_sub(1);
+            }
+            0x09 => self.tab(1),
+            0x0a..=0x0c => self.linefeed(),
+            0x0d => {
+                self.wrap_pending = false;
+                self.x = 0;
+            }
+            0x0e => self.shifted = true,
+            0x0f => self.shifted = false,
+            _ => (),
+        }
+    }
+
+    fn escape(&mut self, byte: u8) {
+        self.state = State::Ground;
+        match byte {
+            b'[' => {
+                self.params.clear();
+                self.private = 0;
+                self.intermediate = 0;
+                self.state = State::Csi;
+            }
+            b']' | b'P' | b'_' | b'^' | b'X' => self.state = State::Text,
+            b' '..=b'/' => self.state = State::EscapeIntermediate(byte),
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            b'D' => self.linefeed(),
+            b'E' => {
+                self.x = 0;
+                self.linefeed();
+            }
+            b'H' => self.tabs[self.x] = true,
+            b'M' => self.reverse_index(),
+            b'c' => self.reset(),
+            _ => (),
+        }
+    }
+
+    /// ESC followed by `intermediate` and `byte`
+    fn designate(&mut self, intermediate: u8, byte: u8) {
+        let charset = if byte == b'0' {
+            Charset::DecGraphics
+        } else {
+            Charset::Ascii
+        };
+        match (intermediate, byte) {
+            (b'(', _) => self.charsets[0] = charset,
+            (b')', _) => self.charsets[1] = charset,
+            // DECALN : fill the screen with E
+            (b'#', b'8') => {
+                let cell = Pen::default().cell('E');
+                self.fill(0, self.cells.len(), cell);
+            }
+            _ => (),
+        }
+    }
+
+    fn csi(&mut self, byte: u8) {
+        match byte {
+            b'0'..=b'9' => {
+                if self.params.is_empty() {
+                    self.params.push(0);
+                }
+                let last = self.params.len() - 1;
+                let digit = (byte - b'0') as u16;
+                self.params[last] = self.params[last].saturating_mul(10).saturating_add(digit);
+            }
+            b';' | b':' => {
+                if self.params.is_empty() {
+                    self.params.push(0);
+                }
+                if self.params.len() < MAX_PARAMS {
+                    self.params.push(0);
+                }
+            }
+            b'<'..=b'?' => self.private = byte,
+            b' '..=b'/' => self.intermediate = byte,
+            b'@'..=b'~' => {
+                self.state = State::Ground;
+                self.dispatch(byte);
+            }
+            _ => self.state = State::Ground,
+        }
+    }
+
+    // parameter n, default when missing or 0
+    fn param(&self, n: usize, default: u16) -> usize {
+        match self.params.get(n) {
+            Some(&value) if value != 0 => value as usize,
+            _ => default as usize,
+        }
+    }
+
+    fn dispatch(&mut self, command: u8) {
+        // everything but the queries and the modes moves the cursor or edits
+        if !b"mnchlgtq".contains(&command) {
+            self.wrap_pending = false;
+        }
+        let n = self.param(0, 1);
+        match (self.private, self.intermediate, command) {
+            (0, 0, b'@') => self.insert_blanks(n),
+            (0, 0, b'A') => self.cursor_up(n),
+            (0, 0, b'B') | (0, 0, b'e') => self.cursor_down(n),
+            (0, 0, b'C') | (0, 0, b'a') => self.x = (self.x + n).min(self.cols - 1),
+            (0, 0, b'D') => self.x = self.x.saturating_sub(n),
+            (0, 0, b'E') => {
+                self.cursor_down(n);
+                self.x = 0;
+            }
+            (0, 0, b'F') => {
+                self.cursor_up(n);
+                self.x = 0;
+            }
+            (0, 0, b'G') | (0, 0, b'`') => self.x = (n - 1).min(self.cols - 1),
+            (0, 0, b'H') | (0, 0, b'f') => {
+                let x = self.param(1, 1) - 1;
+                self.move_to(x, n - 1);
+            }
+            (0, 0, b'I') => self.tab(n),
+            (_, 0, b'J') => self.erase_display(self.param(0, 0)),
+            (_, 0, b'K') => self.erase_line(self.param(0, 0)),
+            (0, 0, b'L') => self.insert_lines(n),
+            (0, 0, b'M') => self.delete_lines(n),
+            (0, 0, b'P') => self.delete_chars(n),
+            (0, 0, b'S') => self.scroll_up(self.top, self.bottom, n),
+            // with more parameters, mouse tracking
+            (0, 0, b'T') if self.params.len() <= 1 => self.scroll_down(self.top, self.bottom, n),
+            (0, 0, b'X') => {
+                let start = self.y * self.cols + self.x;
+                let end = self.y * self.cols + (self.x + n).min(self.cols);
+                let blank = self.pen.blank();
+                self.fill(start, end, blank);
+            }
+            (0, 0, b'Z') => {
+                for _ in 0..n {
+                    self.x = (0..self.x).rev().find(|&x| self.tabs[x]).unwrap_or(0);
+                }
+            }
+            (0, 0, b'b') => {
+                let c = self.last_char;
+                for _ in 0..n.min(self.cols * self.rows) {
+                    self.print(c);
+                }
+            }
+            (0, 0, b'c') => self.replies.extend_from_slice(PRIMARY_ATTRIBUTES),
+            (b'>', 0, b'c') => self.replies.extend_from_slice(SECONDARY_ATTRIBUTES),
+            (0, 0, b'd') => {
+                let x = self.x;
+                self.move_to(x, n - 1);
+            }
+            (0, 0, b'g') => match self.param(0, 0) {
+                0 => self.tabs[self.x] = false,
+                3 => self.tabs.iter_mut().for_each(|tab| *tab = false),
+                _ => (),
+            },
+            (0, 0, b'h') | (b'?', 0, b'h') => self.set_modes(true),
+            (0, 0, b'l') | (b'?', 0, b'l') => self.set_modes(false),
+            (0, 0, b'm') => self.select_rendition(),
+            (0, 0, b'n') => match self.param(0, 0) {
+                5 => self.replies.extend_from_slice(b"\x1b[0n"),
+                6 => {
+                    let row = if self.origin_mode {
+                        self.y - self.top
+                    } else {
+                        self.y
+                    };
+                    let report = format!("\x1b[{};{}R", row + 1, self.x + 1);
+                    self.replies.extend_from_slice(report.as_bytes());
+                }
+                _ => (),
+            },
+            (0, 0, b'r') => {
+                let top = self.param(0, 1) - 1;
+                let bottom = self.param(1, self.rows as u16) - 1;
+                if top < bottom && bottom < self.rows {
+                    self.top = top;
+                    self.bottom = bottom;
+                    self.move_to(0, 0);
+                }
+            }
+            (0, 0, b's') => self.save_cursor(),
+            (0, 0, b'u') => self.restore_cursor(),
+            (0, b'!', b'p') => self.soft_reset(),
+            _ => (),
+        }
+    }
+
+    fn set_modes(&mut self, on: bool) {
+        let modes: Vec<u16> = self.params.clone();
+        for mode in modes {
+            match (self.private, mode) {
+                (0, 4) => self.insert_mode = on,
+                (b'?', 1) => self.application_cursor = on,
+                (b'?', 6) => {
+                    self.origin_mode = on;
+                    self.move_to(0, 0);
+                }
+                (b'?', 7) => self.autowrap = on,
+                (b'?', 25) => self.cursor_visible = on,
+                (b'?', 47) | (b'?', 1047) => self.alternate_screen(on),
+                (b'?', 1048) if on => self.save_cursor(),
+                (b'?', 1048) => self.restore_cursor(),
+                (b'?', 1049) => {
+                    if on {
+                        self.save_cursor();
+                        self.alternate_screen(true);
+                    } else {
+                        self.alternate_screen(false);
+                        self.restore_cursor();
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn select_rendition(&mut self) {
+        if self.params.is_empty() {
+            self.params.push(0);
+        }
+        let params = self.params.clone();
+        let mut n = 0;
+        while n < params.len() {
+            match params[n] {
+                0 => self.pen = Pen::default(),
+                1 => self.pen.bold = true,
+                4 => self.pen.underline = true,
+                7 => self.pen.reverse = true,
+                21 | 22 => self.pen.bold = false,
+                24 => self.pen.underline = false,
+                27 => self.pen.reverse = false,
+                index @ 40..=47 => self.pen.dark_background = is_dark(colour(index - 40)),
+                49 => self.pen.dark_background = false,
+                index @ 100..=107 => self.pen.dark_background = is_dark(colour(index - 92)),
+                // 256 colours or RGB, for the text or the background
+                extended @ 38 | extended @ 48 => {
+                    let rgb = match params.get(n + 1) {
+                        Some(5) => {
+                            n += 2;
+                            params.get(n).map(|&index| colour(index))
+                        }
+                        Some(2) => {
+                            n += 4;
+                            params
+                                .get(n - 2..=n)
+                                .map(|rgb| (rgb[0] as u8, rgb[1] as u8, rgb[2] as u8))
+                        }
+                        _ => None,
+                    };
+                    if let (48, Some(rgb)) = (extended, rgb) {
+                        self.pen.dark_background = is_dark(rgb);
+                    }
+                }
+                // text colours, dim, italic, blink... the panel draws them plain
+                _ => (),
+            }
+            n += 1;
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        // combining marks and zero width spaces
+        let code = c as u32;
+        if (0x300..=0x36F).contains(&code) || (0x200B..=0x200F).contains(&code) {
+            return;
+        }
+        let charset = self.charsets[self.shifted as usize];
+        let c = match (charset, c) {
+            (Charset::DecGraphics, '_'..='~') => DEC_GRAPHICS[c as usize - '_' as usize],
+            _ => c,
+        };
+        if self.wrap_pending && self.autowrap {
+            self.x = 0;
+            self.linefeed();
+        }
+        self.wrap_pending = false;
+        if self.insert_mode {
+            self.insert_blanks(1);
+        }
+        let index = self.y * self.cols + self.x;
+        self.cells[index] = self.pen.cell(c);
+        self.dirty[index] = true;
+        self.last_char = c;
+        if self.x + 1 < self.cols {
+            self.x += 1;
+        } else if self.autowrap {
+            self.wrap_pending = true;
+        }
+    }
+
+    fn tab(&mut self, count: usize) {
+        self.wrap_pending = false;
+        for _ in 0..count {
+            self.x = (self.x + 1..self.cols)
+                .find(|&x| self.tabs[x])
+                .unwrap_or(self.cols - 1);
+        }
+    }
+
+    fn linefeed(&mut self) {
+        if self.y == self.bottom {
+            self.scroll_up(self.top, self.bottom, 1);
+        } else if self.y + 1 < self.rows {
+            self.y += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        if self.y == self.top {
+            self.scroll_down(self.top, self.bottom, 1);
+        } else if self.y > 0 {
+            self.y -= 1;
+        }
+    }
+
+    fn cursor_up(&mut self, count: usize) {
+        let limit = if self.y >= self.top { self.top } else { 0 };
+        self.y = self.y.saturating_sub(count).max(limit);
+    }
+
+    fn cursor_down(&mut self, count: usize) {
+        let limit = if self.y <= self.bottom {
+            self.bottom
+        } else {
+            self.rows - 1
+        };
+        self.y = (self.y + count).min(limit);
+    }
+
+    /// CUP, rows counted from the top of the scrolling region in origin mode
+    fn move_to(&mut self, x: usize, y: usize) {
+        self.wrap_pending = false;
+        self.x = x.min(self.cols - 1);
+        self.y = if self.origin_mode {
+            (self.top + y).min(self.bottom)
+        } else {
+            y.min(self.rows - 1)
+        };
+    }
+
+    /// Move the lines from `top` to `bottom` up by `count`, blank lines below
+    fn scroll_up(&mut self, top: usize, bottom: usize, count: usize) {
+        let count = count.min(bottom + 1 - top);
+        let cols = self.cols;
+        self.cells
+            .copy_within((top + count) * cols..(bottom + 1) * cols, top * cols);
+        let blank = self.pen.blank();
+        self.fill((bottom + 1 - count) * cols, (bottom + 1) * cols, blank);
+        self.dirty[top * cols..(bottom + 1) * cols]
+            .iter_mut()
+            .for_each(|dirty| *dirty = true);
+    }
+
+    /// Move the lines from `top` to `bottom` down by `count`, blank lines above
+    fn scroll_down(&mut self, top: usize, bottom: usize, count: usize) {
+        let count = count.min(bottom + 1 - top);
+        let cols = self.cols;
+        self.cells.copy_within(
+            top * cols..(bottom + 1 - count) * cols,
+            (top + count) * cols,
+        );
+        let blank = self.pen.blank();
+        self.fill(top * cols, (top + count) * cols, blank);
+        self.dirty[top * cols..(bottom + 1) * cols]
+            .iter_mut()
+            .for_each(|dirty| *dirty = true);
+    }
+
+    fn insert_lines(&mut self, count: usize) {
+        if self.y >= self.top && self.y <= self.bottom {
+            self.scroll_down(self.y, self.bottom, count);
+            self.x = 0;
+        }
+    }
+
+    fn delete_lines(&mut self, count: usize) {
+        if self.y >= self.top && self.y <= self.bottom {
+            self.scroll_up(self.y, self.bottom, count);
+            self.x = 0;
+        }
+    }
+
+    fn insert_blanks(&mut self, count: usize) {
+        let line = self.y * self.cols;
+        let count = count.min(self.cols - self.x);
+        self.cells.copy_within(
+            line + self.x..line + self.cols - count,
+            line + self.x + count,
+        );
+        let blank = self.pen.blank();
+        self.fill(line + self.x, line + self.x + count, blank);
+        self.mark_line_from(self.x);
+    }
+
+    fn delete_chars(&mut self, count: usize) {
+        let line = self.y * self.cols;
+        let count = count.min(self.cols - self.x);
+        self.cells
+            .copy_within(line + self.x + count..line + self.cols, line + self.x);
+        let blank = self.pen.blank();
+        self.fill(line + self.cols - count, line + self.cols, blank);
+        self.mark_line_from(self.x);
+    }
+
+    fn mark_line_from(&mut self, x: usize) {
+        let line = self.y * self.cols;
+        self.dirty[line + x..line + self.cols]
+            .iter_mut()
+            .for_each(|dirty| *dirty = true);
+    }
+
+    fn erase_display(&mut self, how: usize) {
+        let cursor = self.y * self.cols + self.x;
+        let (start, end) = match how {
+            0 => (cursor, self.cells.len()),
+            1 => (0, cursor + 1),
+            _ => (0, self.cells.len()),
+        };
+        let blank = self.pen.blank();
+        self.fill(start, end, blank);
+    }
+
+    fn erase_line(&mut self, how: usize) {
+        let line = self.y * self.cols;
+        let (start, end) = match how {
+            0 => (line + self.x, line + self.cols),
+            1 => (line, line + self.x + 1),
+            _ => (line, line + self.cols),
+        };
+        let blank = self.pen.blank();
+        self.fill(start, end, blank);
+    }
+
+    // set the cells from start to end, only those that change are dirty
+    fn fill(&mut self, start: usize, end: usize, cell: Cell) {
+        for index in start..end {
+            if self.cells[index] != cell {
+                self.cells[index] = cell;
+                self.dirty[index] = true;
+            }
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = SavedCursor {
+            x: self.x,
+            y: self.y,
+            pen: self.pen,
+            origin_mode: self.origin_mode,
+            charsets: self.charsets,
+        };
+    }
+
+    fn restore_cursor(&mut self) {
+        self.x = self.saved.x.min(self.cols - 1);
+        self.y = self.saved.y.min(self.rows - 1);
+        self.pen = self.saved.pen;
+        self.origin_mode = self.saved.origin_mode;
+        self.charsets = self.saved.charsets;
+        self.wrap_pending = false;
+    }
+
+    /// Show the alternate screen, cleared, or back the main one
+    fn alternate_screen(&mut self, on: bool) {
+        if on == self.main_cells.is_some() {
+            return;
+        }
+        let blank = vec![self.pen.blank(); self.cols * self.rows];
+        if on {
+            self.main_cells = Some(mem::replace(&mut self.cells, blank));
+        } else if let Some(main_cells) = self.main_cells.take() {
+            self.cells = main_cells;
+        }
+        self.dirty.iter_mut().for_each(|dirty| *dirty = true);
+    }
+
+    fn soft_reset(&mut self) {
+        self.pen = Pen::default();
+        self.origin_mode = false;
+        self.autowrap = true;
+        self.insert_mode = false;
+        self.cursor_visible = true;
+        self.application_cursor = false;
+        self.top = 0;
+        self.bottom = self.rows - 1;
+        self.charsets = [Charset::Ascii; 2];
+        self.shifted = false;
+        self.saved.x = 0;
+        self.saved.y = 0;
+    }
+
+    /// RIS : back to the state of a new screen, everything redrawn
+    fn reset(&mut self) {
+        let replies = self.take_replies();
+        *self = Screen::new(self.cols, self.rows);
+        self.replies = replies;
+    }
+}
+
+/// RGB of an xterm colour: the 16 ANSI colours, the 6x6x6 cube, the greys
+fn colour(index: u16) -> (u8, u8, u8) {
+    match index {
+        0..=15 => ANSI_COLOURS[index as usize],
+        16..=231 => {
+            let cube = index as usize - 16;
+            (
+                CUBE_LEVELS[cube / 36],
+                CUBE_LEVELS[cube / 6 % 6],
+                CUBE_LEVELS[cube % 6],
+            )
+        }
+        _ => {
+            let grey = (8 + 10 * (index.min(255) - 232)) as u8;
+            (grey, grey, grey)
+        }
+    }
+}
+
+fn is_dark(rgb: (u8, u8, u8)) -> bool {
+    let (r, g, b) = rgb;
+    (299 * r as u32 + 587 * g as u32 + 114 * b as u32) < 128_000
+}
+
+#[test]
+fn test_print_and_wrap() {
+    let mut screen = Screen::new(4, 3);
+    let line = |screen: &Screen, y: usize| (0..4).map(|x| screen.cell(x, y).c).collect::<String>();
+
+    screen.feed(b"abcdef\r\nxy");
+    assert_eq!(line(&screen, 0), "abcd");
+    assert_eq!(line(&screen, 1), "ef  ");
+    assert_eq!(line(&screen, 2), "xy  ");
+    assert_eq!(screen.cursor(), Some((2, 2)));
+
+    // the last column written, the cursor waits there
+    screen.feed(b"zw");
+    assert_eq!(screen.cursor(), Some((3, 2)));
+    screen.feed(b"\r\n");
+    assert_eq!(line(&screen, 0), "ef  ");
+    assert_eq!(line(&screen, 1), "xyzw");
+    assert_eq!(line(&screen, 2), "    ");
+
+    screen.feed("é\u{301}€".as_bytes());
+    assert_eq!(line(&screen, 2), "é€  ");
+    screen.feed(b"\xff!");
+    assert_eq!(line(&screen, 2), "é€\u{FFFD}!");
+}
+
+#[test]
+fn test_cursor_and_erase() {
+    let mut screen = Screen::new(5, 3);
+    let line = |screen: &Screen, y: usize| (0..5).map(|x| screen.cell(x, y).c).collect::<String>();
+
+    screen.feed(b"hello\r\nworld\r\nagain");
+    screen.feed(b"\x1b[2;3H");
+    assert_eq!(screen.cursor(), Some((2, 1)));
+    screen.feed(b"\x1b[K");
+    assert_eq!(line(&screen, 1), "wo   ");
+    screen.feed(b"\x1b[1J");
+    assert_eq!(line(&screen, 0), "     ");
+    assert_eq!(line(&screen, 1), "     ");
+    assert_eq!(line(&screen, 2), "again");
+
+    screen.feed(b"\x1b[3;2H\x1b[2P");
+    assert_eq!(line(&screen, 2), "ain  ");
+    screen.feed(b"\x1b[2@");
+    assert_eq!(line(&screen, 2), "a  in");
+    screen.feed(b"\x1b[5G\x1b[10D\x1b[A\x1b[99C");
+    assert_eq!(screen.cursor(), Some((4, 1)));
+    screen.feed(b"\x1b[?25l");
+    assert_eq!(screen.cursor(), None);
+
+    screen.feed(b"\x1b[6n\x1b[c");
+    assert_eq!(screen.take_replies(), b"\x1b[2;5R\x1b[?1;2c".to_vec());
+    assert!(screen.take_replies().is_empty());
+}
+
+#[test]
+fn test_scrolling_region() {
+    let mut screen = Screen::new(3, 4);
+    let line = |screen: &Screen, y: usize| (0..3).map(|x| screen.cell(x, y).c).collect::<String>();
+
+    screen.feed(b"aaa\r\nbbb\r\nccc\r\nddd");
+    screen.feed(b"\x1b[2;3r");
+    assert_eq!(screen.cursor(), Some((0, 0)));
+    screen.feed(b"\x1b[3H\n");
+    assert_eq!(line(&screen, 0), "aaa");
+    assert_eq!(line(&screen, 1), "ccc");
+    assert_eq!(line(&screen, 2), "   ");
+    assert_eq!(line(&screen, 3), "ddd");
+
+    screen.feed(b"\x1b[2H\x1bM");
+    assert_eq!(line(&screen, 1), "   ");
+    assert_eq!(line(&screen, 2), "ccc");
+
+    screen.feed(b"\x1b[2H\x1b[M");
+    assert_eq!(line(&screen, 1), "ccc");
+    screen.feed(b"\x1b[L");
+    assert_eq!(line(&screen, 1), "   ");
+    assert_eq!(line(&screen, 2), "ccc");
+    assert_eq!(line(&screen, 3), "ddd");
+}
+
+#[test]
+fn test_alternate_screen_and_rendition() {
+    let mut screen = Screen::new(3, 2);
+
+    screen.feed(b"\x1b[1;4mab\x1b[0;7mc");
+    assert!(screen.cell(0, 0).bold && screen.cell(0, 0).underline);
+    assert!(!screen.cell(1, 0).inverse);
+    assert!(screen.cell(2, 0).inverse && !screen.cell(2, 0).bold);
+
+    // dark backgrounds are drawn inverse, light ones are not
+    screen.feed(b"\x1b[0;44m\r\nd\x1b[43me\x1b[48;5;232mf");
+    assert!(screen.cell(0, 1).inverse);
+    assert!(!screen.cell(1, 1).inverse);
+    assert!(screen.cell(2, 1).inverse);
+    screen.feed(b"\x1b[48;2;250;250;250;7m\x1b[2;1Hg");
+    assert!(screen.cell(0, 1).inverse);
+
+    screen.feed(b"\x1b[m\x1b[?1049h");
+    assert_eq!(screen.cell(0, 0).c, ' ');
+    screen.feed(b"\x1b[2;1H\x1b(0qx\x1b(B");
+    assert_eq!(screen.cell(0, 1).c, '─');
+    assert_eq!(screen.cell(1, 1).c, '│');
+    screen.feed(b"\x1b[?1049l");
+    assert_eq!(screen.cell(0, 0).c, 'a');
+    assert_eq!(screen.cursor(), Some((1, 1)));
+}
+
+#[test]
+fn test_dirty_cells() {
+    let mut screen = Screen::new(6, 3);
+    assert_eq!(screen.take_dirty(), vec![(0, 0, 6), (1, 0, 6), (2, 0, 6)]);
+    assert!(screen.take_dirty().is_empty());
+
+    screen.feed(b"\x1b[2;3Hab\x1b[3;6Hc");
+    assert_eq!(screen.take_dirty(), vec![(1, 2, 4), (2, 5, 6)]);
+
+    // erasing blank cells changes nothing
+    screen.feed(b"\x1b[1;1H\x1b[K");
+    assert!(screen.take_dirty().is_empty());
+
+    // the title is not printed
+    screen.feed(b"\x1b]0;title\x07\x1b]2;other\x1b\\");
+    assert!(screen.take_dirty().is_empty());
+
+    screen.mark_dirty(4, 0);
+    screen.feed(b"\x1b[3;1H\n");
+    assert_eq!(screen.take_dirty(), vec![(0, 0, 6), (1, 0, 6), (2, 0, 6)]);
+}
@@ -0,0 +1,1092 @@
+use libc::{c_int, c_void, pollfd, POLLIN};
+use std::env;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{ptr, slice};
+use x11cap::Bgr8;
+
+use crate::capture::{clip_to_monitor, limit_damage, CaptureError, CaptureSource, DAMAGE_WAIT};
+use crate::framebuffer::{PixelFormat, POLL_INTERVAL};
+use crate::imagery::{new_bgr8, Rect};
+
+// the object every connection starts with
+static DISPLAY_ID: u32 = 1;
+
+// opcodes, from wayland.xml and wlr-screencopy-unstable-v1.xml
+static DISPLAY_SYNC: u16 = 0;
+static DISPLAY_GET_REGISTRY: u16 = 1;
+static DISPLAY_ERROR: u16 = 0;
+static DISPLAY_DELETE_ID: u16 = 1;
+static REGISTRY_BIND: u16 = 0;
+static REGISTRY_GLOBAL: u16 = 0;
+static REGISTRY_GLOBAL_REMOVE: u16 = 1;
+static CALLBACK_DONE: u16 = 0;
+static SHM_CREATE_POOL: u16 = 0;
+static SHM_POOL_CREATE_BUFFER: u16 = 0;
+static SHM_POOL_DESTROY: u16 = 1;
+static BUFFER_DESTROY: u16 = 0;
+static OUTPUT_NAME: u16 = 4;
+static SCREENCOPY_CAPTURE_OUTPUT: u16 = 0;
+static FRAME_COPY: u16 = 0;
+static FRAME_DESTROY: u16 = 1;
+static FRAME_COPY_WITH_DAMAGE: u16 = 2;
+static FRAME_BUFFER: u16 = 0;
+static FRAME_FLAGS: u16 = 1;
+static FRAME_READY: u16 = 2;
+static FRAME_FAILED: u16 = 3;
+static FRAME_DAMAGE: u16 = 4;
+static FRAME_BUFFER_DONE: u16 = 6;
+
+static FRAME_FLAG_Y_INVERT: u32 = 1;
+
+// newest versions spoken : buffer_done came with 3, output names with 4
+static SCREENCOPY_VERSION: u32 = 3;
+static OUTPUT_VERSION: u32 = 4;
+
+// wl_shm formats, the two first are special, the others are DRM fourcc codes
+static SHM_ARGB8888: u32 = 0;
+static SHM_XRGB8888: u32 = 1;
+
+// most file descriptors along one message, as in libwayland
+static MAX_FDS: usize = 28;
+
+fn protocol_error(reason: String) -> CaptureError {
+    CaptureError::Grab {
+        reason: format!("Wayland protocol: {}", reason),
+    }
+}
+
+fn padded(length: usize) -> usize {
+    length.next_multiple_of(4)
+}
+
+fn word(bytes: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// A request or an event of the Wayland wire protocol : the object it goes to
+/// or comes from, its opcode, then its arguments in 32 bits words
+#[derive(Debug, Clone, PartialEq)]
+struct Message {
+    object: u32,
+    opcode: u16,
+    arguments: Vec<u8>,
+    // sent beside the bytes
+    fds: Vec<RawFd>,
+    // next argument read
+    position: usize,
+}
+
+impl Message {
+    fn new(object: u32, opcode: u16) -> Message {
+        Message {
+            object,
+            opcode,
+            arguments: Vec::new(),
+            fds: Vec::new(),
+            position: 0,
+        }
+    }
+
+    fn uint(mut self, value: u32) -> Message {
+        self.arguments.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn int(self, value: i32) -> Message {
+        self.uint(value as u32)
+    }
+
+    fn string(self, value: &str) -> Message {
+        // the length counts the terminating zero
+        let length = value.len() + 1;
+        let mut message = self.uint(length as u32);
+        message.arguments.extend_from_slice(value.as_bytes());
+        let end = message.arguments.len() + padded(length) - value.len();
+        message.arguments.resize(end, 0);
+        message
+    }
+
+    fn fd(mut self, fd: RawFd) -> Message {
+        self.fds.push(fd);
+        self
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let size = 8 + self.arguments.len();
+        let mut bytes = Vec::with_capacity(size);
+        bytes.extend_from_slice(&self.object.to_ne_bytes());
+        bytes.extend_from_slice(&((size as u32) << 16 | self.opcode as u32).to_ne_bytes());
+        bytes.extend_from_slice(&self.arguments);
+        bytes
+    }
+
+    /// The message `bytes` start with and its length, None until it is whole
+    fn decode(bytes: &[u8]) -> Result<Option<(Message, usize)>, CaptureError> {
+        if bytes.len() < 8 {
+            return Ok(None);
+        }
+        let header = word(bytes, 4);
+        let size = (header >> 16) as usize;
+        if size < 8 || !size.is_multiple_of(4) {
+            return Err(protocol_error(format!("message of {} bytes", size)));
+        }
+        if bytes.len() < size {
+            return Ok(None);
+        }
+        let mut message = Message::new(word(bytes, 0), header as u16);
+        message.arguments.extend_from_slice(&bytes[8..size]);
+        Ok(Some((message, size)))
+    }
+
+    fn next_uint(&mut self) -> Result<u32, CaptureError> {
+        if self.position + 4 > self.arguments.len() {
+            return Err(protocol_error(format!(
+                "message {} of object {} is too short",
+                self.opcode, self.object
+            )));
+        }
+        let value = word(&self.arguments, self.position);
+        self.position += 4;
+        Ok(value)
+    }
+
+    fn next_string(&mut self) -> Result<String, CaptureError> {
+        let length = self.next_uint()? as usize;
+        // a null string
+        if length == 0 {
+            return Ok(String::new());
+        }
+        let end = self.position + padded(length);
+        if end > self.arguments.len() {
+            return Err(protocol_error(format!(
+                "string of message {} of object {} is too long",
+                self.opcode, self.object
+            )));
+        }
+        let bytes = &self.arguments[self.position..self.position + length - 1];
+        let value = String::from_utf8_lossy(bytes).into_owned();
+        self.position = end;
+        Ok(value)
+    }
+}
+
+/// Socket to the compositor, with the bytes received but not decoded yet
+struct Connection {
+    socket: UnixStream,
+    input: Vec<u8>,
+}
+
+impl Connection {
+    fn new(socket: UnixStream) -> Connection {
+        Connection {
+            socket,
+            input: Vec::new(),
+        }
+    }
+
+    fn send(&self, message: &Message) -> Result<(), CaptureError> {
+        let bytes = message.encode();
+        let mut iov = libc::iovec {
+            iov_base: bytes.as_ptr() as *mut c_void,
+            iov_len: bytes.len(),
+        };
+        let fds_length = message.fds.len() * mem::size_of::<RawFd>();
+        let space = unsafe { libc::CMSG_SPACE(fds_length as u32) } as usize;
+        // u64 for the alignment of the headers
+        let mut control: Vec<u64> = vec![0; space.div_ceil(8)];
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_iov = &mut iov;
+        header.msg_iovlen = 1;
+        if !message.fds.is_empty() {
+            header.msg_control = control.as_mut_ptr() as *mut c_void;
+            header.msg_controllen = space as _;
+            unsafe {
+                let fds_header = libc::CMSG_FIRSTHDR(&header);
+                (*fds_header).cmsg_level = libc::SOL_SOCKET;
+                (*fds_header).cmsg_type = libc::SCM_RIGHTS;
+                (*fds_header).cmsg_len = libc::CMSG_LEN(fds_length as u32) as _;
+                ptr::copy_nonoverlapping(
+                    message.fds.as_ptr() as *const u8,
+                    libc::CMSG_DATA(fds_header),
+                    fds_length,
+                );
+            }
+        }
+
+        let written = |error: io::Error| CaptureError::Grab {
+            reason: format!("writing to the compositor: {}", error),
+        };
+        let sent = loop {
+            let sent =
+                unsafe { libc::sendmsg(self.socket.as_raw_fd(), &header, libc::MSG_NOSIGNAL) };
+            if sent >= 0 {
+                break sent as usize;
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(written(error));
+            }
+        };
+        // the file descriptors went with the first bytes
+        (&self.socket).write_all(&bytes[sent..]).map_err(written)
+    }
+
+    /// Wait until something can be received, false once `deadline` is over
+    fn wait(&self, deadline: Option<Instant>) -> bool {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                left.as_millis() as c_int
+            }
+            None => -1,
+        };
+        let mut connection = pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut connection, 1, timeout) > 0 }
+    }
+
+    /// Read what was sent without waiting, and the file descriptors that came along
+    fn receive(&mut self) -> Result<Vec<File>, CaptureError> {
+        let mut bytes = [0u8; 4096];
+        let mut iov = libc::iovec {
+            iov_base: bytes.as_mut_ptr() as *mut c_void,
+            iov_len: bytes.len(),
+        };
+        let space = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) };
+        let mut control: Vec<u64> = vec![0; (space as usize).div_ceil(8)];
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_iov = &mut iov;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr() as *mut c_void;
+        header.msg_controllen = space as _;
+
+        let received = unsafe {
+            libc::recvmsg(
+                self.socket.as_raw_fd(),
+                &mut header,
+                libc::MSG_DONTWAIT | libc::MSG_CMSG_CLOEXEC,
+            )
+        };
+        if received < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(Vec::new()),
+                _ => Err(CaptureError::Grab {
+                    reason: format!("reading from the compositor: {}", error),
+                }),
+            };
+        }
+        if received == 0 {
+            return Err(CaptureError::Grab {
+                reason: "the compositor closed the connection".to_string(),
+            });
+        }
+
+        let mut fds: Vec<File> = Vec::new();
+        unsafe {
+            let mut fds_header = libc::CMSG_FIRSTHDR(&header);
+            while !fds_header.is_null() {
+                if (*fds_header).cmsg_level == libc::SOL_SOCKET
+                    && (*fds_header).cmsg_type == libc::SCM_RIGHTS
+                {
+                    let length = (*fds_header).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    let data = libc::CMSG_DATA(fds_header) as *const RawFd;
+                    for n in 0..length / mem::size_of::<RawFd>() {
+                        fds.push(File::from_raw_fd(ptr::read_unaligned(data.add(n))));
+                    }
+                }
+                fds_header = libc::CMSG_NXTHDR(&header, fds_header);
+            }
+        }
+        self.input.extend_from_slice(&bytes[..received as usize]);
+        Ok(fds)
+    }
+
+    fn next_message(&mut self) -> Result<Option<Message>, CaptureError> {
+        match Message::decode(&self.input)? {
+            Some((message, length)) => {
+                self.input.drain(..length);
+                Ok(Some(message))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+// DRM fourcc codes, e.g. fourcc(b"XB24")
+fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+/// How the pixels of a wl_shm format are read, None when they cannot be
+fn shm_format(format: u32) -> Option<PixelFormat> {
+    let is = |codes: &[&[u8; 4]]| codes.iter().any(|code| fourcc(code) == format);
+    if format == SHM_ARGB8888 || format == SHM_XRGB8888 {
+        Some(PixelFormat::xrgb8888())
+    } else if is(&[b"AB24", b"XB24"]) {
+        Some(PixelFormat::new(32, (0, 8), (8, 8), (16, 8)))
+    } else if is(&[b"AR30", b"XR30"]) {
+        PixelFormat::from_depth(32, 30)
+    } else if is(&[b"AB30", b"XB30"]) {
+        Some(PixelFormat::new(32, (0, 10), (10, 10), (20, 10)))
+    } else if is(&[b"RG24"]) {
+        Some(PixelFormat::new(24, (16, 8), (8, 8), (0, 8)))
+    } else if is(&[b"BG24"]) {
+        Some(PixelFormat::new(24, (0, 8), (8, 8), (16, 8)))
+    } else if is(&[b"RG16"]) {
+        Some(PixelFormat::rgb565())
+    } else {
+        None
+    }
+}
+
+// $WAYLAND_DISPLAY, relative to $XDG_RUNTIME_DIR unless absolute
+fn socket_path() -> Result<PathBuf, CaptureError> {
+    let display = env::var_os("WAYLAND_DISPLAY").unwrap_or_else(|| "wayland-0".into());
+    let display = PathBuf::from(display);
+    if display.is_absolute() {
+        return Ok(display);
+    }
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(directory) => Ok(PathBuf::from(directory).join(display)),
+        None => Err(CaptureError::Open {
+            reason: "XDG_RUNTIME_DIR is not set, cannot find the compositor".to_string(),
+        }),
+    }
+}
+
+fn memfd(name: &str) -> io::Result<File> {
+    let name = CString::new(name).unwrap();
+    let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+#[derive(Debug, Clone)]
+struct Global {
+    name: u32,
+    interface: String,
+    version: u32,
+}
+
+struct Output {
+    id: u32,
+    // from version 4, e.g. HDMI-A-1
+    name: Option<String>,
+}
+
+/// Layout of the buffers the compositor copies a frame in
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BufferLayout {
+    format: u32,
+    width: u16,
+    height: u16,
+    stride: usize,
+}
+
+/// A zwlr_screencopy_frame_v1 until it is ready or failed
+struct FrameRequest {
+    id: u32,
+    layout: Option<BufferLayout>,
+    // the lines come last to first
+    y_invert: bool,
+    // x, y, width and height of each damage event
+    damage: Vec<(i32, i32, u32, u32)>,
+    ready: bool,
+    failed: bool,
+}
+
+/// A wl_buffer in shared memory, mapped to read what the compositor copied
+struct ShmBuffer {
+    id: u32,
+    layout: BufferLayout,
+    pointer: *mut c_void,
+    length: usize,
+}
+
+impl Drop for ShmBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.pointer, self.length);
+        }
+    }
+}
+
+/// Captures an output of a wlroots compositor (sway, labwc, wayfire...) with
+/// wlr-screencopy. The compositor tells what changed when it has version 2,
+/// otherwise the whole output is read each time.
+pub struct WaylandCapture {
+    connection: Connection,
+    // next new object, then the ones the compositor deleted
+    next_id: u32,
+    free_ids: Vec<u32>,
+    registry: u32,
+    globals: Vec<Global>,
+    // a wl_callback the compositor did not answer yet
+    sync_callback: Option<u32>,
+    shm: u32,
+    screencopy: u32,
+    screencopy_version: u32,
+    outputs: Vec<Output>,
+    output: u32,
+    request: Option<FrameRequest>,
+    buffer: Option<ShmBuffer>,
+    width: u16,
+    height: u16,
+    frame: Vec<Bgr8>,
+    first_frame: bool,
+}
+
+impl WaylandCapture {
+    /// Connect to the compositor of $WAYLAND_DISPLAY and capture `output`, by
+    /// name or number, the first one when None
+    pub fn open(output: Option<&str>) -> Result<WaylandCapture, CaptureError> {
+        let path = socket_path()?;
+        let socket = UnixStream::connect(&path).map_err(|error| CaptureError::Open {
+            reason: format!("{}: {}", path.display(), error),
+        })?;
+        WaylandCapture::connect(socket, output)
+    }
+
+    fn connect(socket: UnixStream, output: Option<&str>) -> Result<WaylandCapture, CaptureError> {
+        let mut capture = WaylandCapture {
+            connection: Connection::new(socket),
+            next_id: DISPLAY_ID + 1,
+            free_ids: Vec::new(),
+            registry: 0,
+            globals: Vec::new(),
+            sync_callback: None,
+            shm: 0,
+            screencopy: 0,
+            screencopy_version: 0,
+            outputs: Vec::new(),
+            output: 0,
+            request: None,
+            buffer: None,
+            width: 0,
+            height: 0,
+            frame: Vec::new(),
+            first_frame: true,
+        };
+        capture.registry = capture.new_id();
+        capture
+            .connection
+            .send(&Message::new(DISPLAY_ID, DISPLAY_GET_REGISTRY).uint(capture.registry))?;
+        capture.roundtrip()?;
+
+        let global = |interface: &str| {
+            capture
+                .globals
+                .iter()
+                .find(|global| global.interface == interface)
+                .cloned()
+                .ok_or(CaptureError::Open {
+                    reason: format!("the compositor has no {}", interface),
+                })
+        };
+        let shm = global("wl_shm")?;
+        let screencopy = global("zwlr_screencopy_manager_v1")?;
+        capture.shm = capture.bind(&shm, 1)?;
+        capture.screencopy_version = screencopy.version.min(SCREENCOPY_VERSION);
+        capture.screencopy = capture.bind(&screencopy, capture.screencopy_version)?;
+        let outputs: Vec<Global> = capture
+            .globals
+            .iter()
+            .filter(|global| global.interface == "wl_output")
+            .cloned()
+            .collect();
+        for output in outputs.iter() {
+            let id = capture.bind(output, output.version.min(OUTPUT_VERSION))?;
+            capture.outputs.push(Output { id, name: None });
+        }
+        capture.roundtrip()?;
+
+        capture.output = capture.find_output(output)?;
+        capture.request_frame()?;
+        // the size of the output comes with the first buffer
+        while capture.buffer.is_none() {
+            if capture
+                .request
+                .as_ref()
+                .is_some_and(|request| request.failed)
+            {
+                return Err(CaptureError::Open {
+                    reason: "the compositor cannot copy the output".to_string(),
+                });
+            }
+            capture.dispatch(None)?;
+        }
+        let layout = capture.buffer.as_ref().unwrap().layout;
+        capture.resize(layout.width, layout.height);
+        Ok(capture)
+    }
+
+    fn find_output(&self, wanted: Option<&str>) -> Result<u32, CaptureError> {
+        let found = match wanted {
+            None => self.outputs.first(),
+            Some(wanted) => self
+                .outputs
+                .iter()
+                .enumerate()
+                .find(|(n, output)| {
+                    output.name.as_deref() == Some(wanted) || n.to_string() == wanted
+                })
+                .map(|(_, output)| output),
+        };
+        found.map(|output| output.id).ok_or_else(|| {
+            let names: Vec<String> = self
+                .outputs
+                .iter()
+                .enumerate()
+                .map(|(n, output)| output.name.clone().unwrap_or_else(|| n.to_string()))
+                .collect();
+            CaptureError::Open {
+                reason: format!(
+                    "no output {}, the compositor has {}",
+                    wanted.unwrap_or(""),
+                    names.join(", ")
+                ),
+            }
+        })
+    }
+
+    fn new_id(&mut self) -> u32 {
+        self.free_ids.pop().unwrap_or_else(|| {
+            self.next_id += 1;
+            self.next_id - 1
+        })
+    }
+
+    fn bind(&mut self, global: &Global, version: u32) -> Result<u32, CaptureError> {
+        let id = self.new_id();
+        self.connection.send(
+            &Message::new(self.registry, REGISTRY_BIND)
+                .uint(global.name)
+                .string(&global.interface)
+                .uint(version)
+                .uint(id),
+        )?;
+        Ok(id)
+    }
+
+    // wait until the compositor went through every request sent so far
+    fn roundtrip(&mut self) -> Result<(), CaptureError> {
+        let callback = self.new_id();
+        self.sync_callback = Some(callback);
+        self.connection
+            .send(&Message::new(DISPLAY_ID, DISPLAY_SYNC).uint(callback))?;
+        while self.sync_callback.is_some() {
+            self.dispatch(None)?;
+        }
+        Ok(())
+    }
+
+    // handle what the compositor sent, false when nothing came before `deadline`
+    fn dispatch(&mut self, deadline: Option<Instant>) -> Result<bool, CaptureError> {
+        if !self.connection.wait(deadline) {
+            return Ok(false);
+        }
+        // none of the events handled carries a file descriptor
+        self.connection.receive()?;
+        while let Some(message) = self.connection.next_message()? {
+            self.handle(message)?;
+        }
+        Ok(true)
+    }
+
+    fn handle(&mut self, mut message: Message) -> Result<(), CaptureError> {
+        let (object, opcode) = (message.object, message.opcode);
+        if object == DISPLAY_ID && opcode == DISPLAY_ERROR {
+            let failed_object = message.next_uint()?;
+            let code = message.next_uint()?;
+            return Err(protocol_error(format!(
+                "error {} on object {}: {}",
+                code,
+                failed_object,
+                message.next_string()?
+            )));
+        } else if object == DISPLAY_ID && opcode == DISPLAY_DELETE_ID {
+            self.free_ids.push(message.next_uint()?);
+        } else if object == self.registry && opcode == REGISTRY_GLOBAL {
+            self.globals.push(Global {
+                name: message.next_uint()?,
+                interface: message.next_string()?,
+                version: message.next_uint()?,
+            });
+        } else if object == self.registry && opcode == REGISTRY_GLOBAL_REMOVE {
+            let name = message.next_uint()?;
+            self.globals.retain(|global| global.name != name);
+        } else if Some(object) == self.sync_callback && opcode == CALLBACK_DONE {
+            self.sync_callback = None;
+        } else if opcode == OUTPUT_NAME && self.outputs.iter().any(|output| output.id == object) {
+            let name = message.next_string()?;
+            for output in self.outputs.iter_mut().filter(|output| output.id == object) {
+                output.name = Some(name.clone());
+            }
+        } else if self.request.as_ref().map(|request| request.id) == Some(object) {
+            self.handle_frame_event(message)?;
+        }
+        Ok(())
+    }
+
+    fn handle_frame_event(&mut self, mut message: Message) -> Result<(), CaptureError> {
+        let version = self.screencopy_version;
+        let request = self.request.as_mut().unwrap();
+        let opcode = message.opcode;
+        if opcode == FRAME_BUFFER {
+            let layout = BufferLayout {
+                format: message.next_uint()?,
+                width: message.next_uint()? as u16,
+                height: message.next_uint()? as u16,
+                stride: message.next_uint()? as usize,
+            };
+            // the first shm format that can be read and fits its stride
+            let readable = shm_format(layout.format).is_some_and(|format| {
+                layout.stride >= layout.width as usize * format.bytes_per_pixel()
+            });
+            if readable && request.layout.is_none() {
+                request.layout = Some(layout);
+            }
+            // without buffer_done, there is a single buffer event
+            if version < 3 {
+                self.copy()?;
+            }
+        } else if opcode == FRAME_BUFFER_DONE {
+            self.copy()?;
+        } else if opcode == FRAME_FLAGS {
+            request.y_invert = message.next_uint()? & FRAME_FLAG_Y_INVERT != 0;
+        } else if opcode == FRAME_DAMAGE {
+            request.damage.push((
+                message.next_uint()? as i32,
+                message.next_uint()? as i32,
+                message.next_uint()?,
+                message.next_uint()?,
+            ));
+        } else if opcode == FRAME_READY {
+            request.ready = true;
+        } else if opcode == FRAME_FAILED {
+            request.failed = true;
+        }
+        Ok(())
+    }
+
+    // ask for the next frame of the output, without the cursor like the X11 grabs
+    fn request_frame(&mut self) -> Result<(), CaptureError> {
+        let id = self.new_id();
+        self.connection.send(
+            &Message::new(self.screencopy, SCREENCOPY_CAPTURE_OUTPUT)
+                .uint(id)
+                .int(0)
+                .uint(self.output),
+        )?;
+        self.request = Some(FrameRequest {
+            id,
+            layout: None,
+            y_invert: false,
+            damage: Vec::new(),
+            ready: false,
+            failed: false,
+        });
+        Ok(())
+    }
+
+    // the buffers are known : copy the frame in a buffer of the layout wanted
+    fn copy(&mut self) -> Result<(), CaptureError> {
+        let request = self.request.as_ref().unwrap();
+        let request_id = request.id;
+        let layout = request.layout.ok_or(CaptureError::Open {
+            reason: "the compositor offers no pixel format ardoise can read".to_string(),
+        })?;
+        if self.buffer.as_ref().map(|buffer| buffer.layout) != Some(layout) {
+            if let Some(buffer) = self.buffer.take() {
+                self.connection
+                    .send(&Message::new(buffer.id, BUFFER_DESTROY))?;
+            }
+            self.buffer = Some(self.create_buffer(layout)?);
+        }
+        let buffer_id = self.buffer.as_ref().unwrap().id;
+
+        // the first copy after a resolution change takes the whole frame
+        let resized = (layout.width, layout.height) != (self.width, self.height);
+        let opcode = if self.screencopy_version >= 2 && !self.first_frame && !resized {
+            FRAME_COPY_WITH_DAMAGE
+        } else {
+            FRAME_COPY
+        };
+        self.connection
+            .send(&Message::new(request_id, opcode).uint(buffer_id))
+    }
+
+    fn create_buffer(&mut self, layout: BufferLayout) -> Result<ShmBuffer, CaptureError> {
+        let length = layout.stride * layout.height as usize;
+        let failed = |error: io::Error| CaptureError::Grab {
+            reason: format!("cannot share memory with the compositor: {}", error),
+        };
+        let memory = memfd("ardoise-screencopy").map_err(failed)?;
+        memory.set_len(length as u64).map_err(failed)?;
+        let pointer = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                length,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                memory.as_raw_fd(),
+                0,
+            )
+        };
+        if pointer == libc::MAP_FAILED {
+            return Err(failed(io::Error::last_os_error()));
+        }
+        let buffer = ShmBuffer {
+            id: self.new_id(),
+            layout,
+            pointer,
+            length,
+        };
+
+        // the pool only lives until the buffer is made
+        let pool = self.new_id();
+        self.connection.send(
+            &Message::new(self.shm, SHM_CREATE_POOL)
+                .uint(pool)
+                .fd(memory.as_raw_fd())
+                .int(length as i32),
+        )?;
+        self.connection.send(
+            &Message::new(pool, SHM_POOL_CREATE_BUFFER)
+                .uint(buffer.id)
+                .int(0)
+                .int(layout.width as i32)
+                .int(layout.height as i32)
+                .int(layout.stride as i32)
+                .uint(layout.format),
+        )?;
+        self.connection
+            .send(&Message::new(pool, SHM_POOL_DESTROY))?;
+        Ok(buffer)
+    }
+
+    fn resize(&mut self, width: u16, height: u16) {
+        self.width = width;
+        self.height = height;
+        self.frame = vec![new_bgr8(255, 255, 255); width as usize * height as usize];
+        self.first_frame = true;
+    }
+
+    // convert what changed in the buffer, None when it is the whole frame
+    fn read_frame(&mut self, request: FrameRequest) -> Option<Vec<Rect>> {
+        let layout = self.buffer.as_ref().unwrap().layout;
+        if (layout.width, layout.height) != (self.width, self.height) {
+            self.resize(layout.width, layout.height);
+        }
+        let whole_frame = self.first_frame || self.screencopy_version < 2;
+        self.first_frame = false;
+
+        let output = (0, 0, self.width, self.height);
+        let rects: Vec<Rect> = if whole_frame {
+            clip_to_monitor(0, 0, self.width as u32, self.height as u32, output)
+                .into_iter()
+                .collect()
+        } else {
+            limit_damage(
+                request
+                    .damage
+                    .iter()
+                    .filter_map(|(x, y, width, height)| {
+                        // the damage is in buffer lines, last to first when inverted
+                        let y = if request.y_invert {
+                            self.height as i32 - *y - *height as i32
+                        } else {
+                            *y
+                        };
+                        clip_to_monitor(*x, y, *width, *height, output)
+                    })
+                    .collect(),
+            )
+        };
+
+        let buffer = self.buffer.as_ref().unwrap();
+        let format = shm_format(layout.format).unwrap();
+        let bytes_per_pixel = format.bytes_per_pixel();
+        let raw: &[u8] =
+            unsafe { slice::from_raw_parts(buffer.pointer as *const u8, buffer.length) };
+        for rect in rects.iter() {
+            let bytes_length = rect.width as usize * bytes_per_pixel;
+            for line_n in rect.y..rect.y + rect.height {
+                let buffer_line = if request.y_invert {
+                    self.height - 1 - line_n
+                } else {
+                    line_n
+                };
+                let start =
+                    buffer_line as usize * layout.stride + rect.x as usize * bytes_per_pixel;
+                let first_pixel = line_n as usize * self.width as usize + rect.x as usize;
+                format.convert(
+                    &raw[start..start + bytes_length],
+                    &mut self.frame[first_pixel..first_pixel + rect.width as usize],
+                );
+            }
+        }
+
+        if whole_frame {
+            None
+        } else {
+            Some(rects)
+        }
+    }
+}
+
+impl CaptureSource for WaylandCapture {
+    fn geometry(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+
+    fn capture(&mut self) -> Result<Option<Vec<Rect>>, CaptureError> {
+        if self.request.is_none() {
+            // without damage, the output is read as often as a framebuffer
+            if self.screencopy_version < 2 {
+                thread::sleep(POLL_INTERVAL);
+            }
+            self.request_frame()?;
+        }
+
+        // copy_with_damage only answers once something changed
+        let deadline = Instant::now() + Duration::from_millis(DAMAGE_WAIT as u64);
+        while !self
+            .request
+            .as_ref()
+            .is_some_and(|request| request.ready || request.failed)
+        {
+            if !self.dispatch(Some(deadline))? {
+                return Ok(Some(Vec::new()));
+            }
+        }
+
+        let request = self.request.take().unwrap();
+        self.connection
+            .send(&Message::new(request.id, FRAME_DESTROY))?;
+        if request.failed {
+            return Err(CaptureError::Grab {
+                reason: "the compositor could not copy the output".to_string(),
+            });
+        }
+        Ok(self.read_frame(request))
+    }
+
+    fn frame(&self) -> &[Bgr8] {
+        self.frame.as_slice()
+    }
+}
+
+#[test]
+fn test_message_encoding() {
+    let message = Message::new(2, REGISTRY_BIND)
+        .uint(7)
+        .string("wl_shm")
+        .uint(1)
+        .int(-1);
+    let bytes = message.encode();
+    // header, name, string length, "wl_shm\0" and a padding byte, version, new id
+    assert_eq!(bytes.len(), 8 + 4 + 4 + 8 + 4 + 4);
+    assert_eq!(word(&bytes, 4), 32 << 16 | REGISTRY_BIND as u32);
+    assert_eq!(&bytes[16..24], b"wl_shm\0\0");
+
+    // incomplete until the last byte
+    assert_eq!(Message::decode(&bytes[..31]).unwrap(), None);
+    let (mut decoded, length) = Message::decode(&bytes).unwrap().unwrap();
+    assert_eq!(length, 32);
+    assert_eq!((decoded.object, decoded.opcode), (2, REGISTRY_BIND));
+    assert_eq!(decoded.next_uint().unwrap(), 7);
+    assert_eq!(decoded.next_string().unwrap(), "wl_shm");
+    assert_eq!(decoded.next_uint().unwrap(), 1);
+    assert_eq!(decoded.next_uint().unwrap() as i32, -1);
+    assert!(decoded.next_uint().is_err());
+
+    // a string as long as a word still ends with a whole word of zeros
+    let bytes = Message::new(1, 0).string("abc").string("abcd").encode();
+    assert_eq!(bytes.len(), 8 + 4 + 4 + 4 + 8);
+    let mut bad_size = bytes.clone();
+    bad_size[4..8].copy_from_slice(&(6u32 << 16).to_ne_bytes());
+    assert!(Message::decode(&bad_size).is_err());
+}
+
+#[test]
+fn test_shm_formats() {
+    let mut pixel = vec![new_bgr8(0, 0, 0)];
+    // memory order B, G, R, X then R, G, B, X
+    shm_format(SHM_XRGB8888)
+        .unwrap()
+        .convert(&[0x10, 0x20, 0x30, 0xFF], &mut pixel);
+    assert_eq!(pixel[0], new_bgr8(0x10, 0x20, 0x30));
+    shm_format(fourcc(b"XB24"))
+        .unwrap()
+        .convert(&[0x30, 0x20, 0x10, 0xFF], &mut pixel);
+    assert_eq!(pixel[0], new_bgr8(0x10, 0x20, 0x30));
+    assert_eq!(fourcc(b"XB24"), 0x3432_4258);
+    assert_eq!(shm_format(fourcc(b"RG16")), Some(PixelFormat::rgb565()));
+    assert_eq!(shm_format(fourcc(b"NV12")), None);
+}
+
+#[test]
+fn test_capture_from_a_compositor() {
+    use std::os::unix::fs::FileExt;
+
+    // a compositor with a 4x3 output : white, then pixel (1, 1) of the buffer goes black,
+    // or (1, 0) of a buffer with the lines last to first
+    fn compositor(socket: UnixStream, y_invert: bool) -> usize {
+        let mut connection = Connection::new(socket);
+        let mut objects: Vec<(u32, String)> = vec![(DISPLAY_ID, "wl_display".to_string())];
+        let mut fds: Vec<File> = Vec::new();
+        let mut memory: Option<File> = None;
+        let mut copies = 0;
+        loop {
+            connection.wait(None);
+            match connection.receive() {
+                Ok(received) => fds.extend(received),
+                Err(_) => return copies,
+            }
+            while let Some(mut request) = connection.next_message().unwrap() {
+                let interface = objects
+                    .iter()
+                    .find(|(id, _)| *id == request.object)
+                    .map(|(_, interface)| interface.clone())
+                    .unwrap_or_default();
+                // a client gone away is seen by the next receive
+                let send = |message: Message| connection.send(&message).ok();
+                match (interface.as_str(), request.opcode) {
+                    ("wl_display", 0) => {
+                        let callback = request.next_uint().unwrap();
+                        send(Message::new(callback, CALLBACK_DONE).uint(0));
+                        send(Message::new(DISPLAY_ID, DISPLAY_DELETE_ID).uint(callback));
+                    }
+                    ("wl_display", 1) => {
+                        let registry = request.next_uint().unwrap();
+                        objects.push((registry, "wl_registry".to_string()));
+                        for (name, global, version) in [
+                            (1, "wl_shm", 1),
+                            (2, "wl_output", 4),
+                            (3, "zwlr_screencopy_manager_v1", 3),
+                        ]
+                        .iter()
+                        {
+                            send(
+                                Message::new(registry, REGISTRY_GLOBAL)
+                                    .uint(*name)
+                                    .string(global)
+                                    .uint(*version),
+                            );
+                        }
+                    }
+                    ("wl_registry", 0) => {
+                        request.next_uint().unwrap();
+                        let global = request.next_string().unwrap();
+                        request.next_uint().unwrap();
+                        let id = request.next_uint().unwrap();
+                        if global == "wl_output" {
+                            send(Message::new(id, OUTPUT_NAME).string("HEADLESS-1"));
+                        }
+                        objects.push((id, global));
+                    }
+                    ("zwlr_screencopy_manager_v1", 0) => {
+                        let frame = request.next_uint().unwrap();
+                        objects.push((frame, "frame".to_string()));
+                        send(
+                            Message::new(frame, FRAME_BUFFER)
+                                .uint(SHM_XRGB8888)
+                                .uint(4)
+                                .uint(3)
+                                .uint(16),
+                        );
+                        send(Message::new(frame, FRAME_BUFFER_DONE));
+                    }
+                    ("wl_shm", 0) => {
+                        objects.push((request.next_uint().unwrap(), "wl_shm_pool".to_string()));
+                        memory = Some(fds.remove(0));
+                    }
+                    ("frame", 0) | ("frame", 2) => {
+                        let memory = memory.as_ref().unwrap();
+                        let line = if y_invert { 0 } else { 1 };
+                        if copies == 0 {
+                            memory.write_all_at(&[0xFF; 16 * 3], 0).unwrap();
+                        } else {
+                            memory.write_all_at(&[0; 4], 16 * line + 4).unwrap();
+                        }
+                        copies += 1;
+                        // damage only goes with copy_with_damage
+                        assert_eq!(request.opcode == 2, copies > 1);
+                        if request.opcode == 2 {
+                            send(
+                                Message::new(request.object, FRAME_DAMAGE)
+                                    .uint(1)
+                                    .uint(line as u32)
+                                    .uint(1)
+                                    .uint(1),
+                            );
+                        }
+                        let flags = if y_invert { FRAME_FLAG_Y_INVERT } else { 0 };
+                        send(Message::new(request.object, FRAME_FLAGS).uint(flags));
+                        send(
+                            Message::new(request.object, FRAME_READY)
+                                .uint(0)
+                                .uint(0)
+                                .uint(0),
+                        );
+                    }
+                    ("frame", 1) => {
+                        send(Message::new(DISPLAY_ID, DISPLAY_DELETE_ID).uint(request.object));
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    let (client, server) = UnixStream::pair().unwrap();
+    let compositor_thread = thread::spawn(move || compositor(server, false));
+    assert!(WaylandCapture::connect(client, Some("DP-1")).is_err());
+    assert_eq!(compositor_thread.join().unwrap(), 0);
+
+    // the black pixel is (1, 1) on the panel, or (1, 2) from an inverted buffer
+    for (y_invert, line) in [(false, 1), (true, 2)].iter() {
+        let y_invert = *y_invert;
+        let (client, server) = UnixStream::pair().unwrap();
+        let compositor_thread = thread::spawn(move || compositor(server, y_invert));
+        let mut capture = WaylandCapture::connect(client, Some("HEADLESS-1")).unwrap();
+        assert_eq!(capture.geometry(), (4, 3));
+        assert_eq!(capture.capture().unwrap(), None);
+        assert!(capture
+            .frame()
+            .iter()
+            .all(|pixel| *pixel == new_bgr8(255, 255, 255)));
+
+        assert_eq!(
+            capture.capture().unwrap(),
+            Some(vec![Rect {
+                x: 1,
+                y: *line,
+                width: 1,
+                height: 1,
+            }])
+        );
+        let black: Vec<usize> = (0..12)
+            .filter(|n| capture.frame()[*n] == new_bgr8(0, 0, 0))
+            .collect();
+        assert_eq!(black, vec![*line as usize * 4 + 1]);
+
+        drop(capture);
+        assert_eq!(compositor_thread.join().unwrap(), 2);
+    }
+}